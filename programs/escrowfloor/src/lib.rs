@@ -1,235 +1,7871 @@
+// Program paths must surface failures as `EscrowError`s rather than abort
+#![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used, clippy::panic))]
+
 use anchor_lang::prelude::*;
-use anchor_lang::solana_program::clock::Clock;
+use anchor_lang::solana_program::clock::{Clock, DEFAULT_MS_PER_SLOT};
+use anchor_lang::solana_program::hash::hash;
+use anchor_spl::token::{
+    self, CloseAccount, InitializeAccount3, Mint, MintTo, SyncNative, Token, TokenAccount,
+    TransferChecked,
+};
+use std::cmp::Ordering;
 
 declare_id!("4gjmWmuanYNZTsU1vXnUSUsphL9BYBNSkh6UoU5ym9i4");
 
 // Constants for profit calculation
-pub const PROFIT_PERCENTAGE: u64 = 20; // 20% profit for correct prediction
+pub const PROFIT_PERCENTAGE: u64 = 20; // 20% profit for correct prediction, under `PayoutMode::ProfitShare`
+
+// Basis point denominator used by all bps-denominated settings
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+// Limits on pushing expiry out so funds can't be parked indefinitely
+pub const MAX_EXTENSIONS: u8 = 3;
+pub const MAX_TOTAL_EXTENSION_SECS: i64 = 7 * 24 * 60 * 60; // one week
+
+// Escrow pubkeys held by a single collection index chunk
+pub const COLLECTION_INDEX_CAPACITY: usize = 64;
+
+// Escrows a single settle_batch call may settle, to stay within compute limits
+pub const MAX_BATCH_SETTLE: usize = 8;
+// Accounts per escrow in settle_batch's remaining accounts: escrow,
+// collection_index, tensor_oracle, pyth_price_update, winner, counterparty
+// and settlement_record, in that order
+pub const SETTLE_BATCH_GROUP_LEN: usize = 7;
+
+// Price sources an escrow may fall back through at settlement
+pub const MAX_PRICE_SOURCES: usize = 3;
+
+// Collections a basket escrow may price alongside its primary collection
+pub const MAX_BASKET_SIZE: usize = 4;
+
+// Most lopsided fixed odds allowed, either way round
+pub const MAX_ODDS_RATIO: u32 = 100;
+
+// Bounds on auto-rollover so a chain of follow-on escrows stays finite
+pub const MAX_ROLLOVERS: u8 = 12;
+pub const MAX_ROLLOVER_DURATION_SECS: i64 = 30 * 24 * 60 * 60; // thirty days
+
+// Longest accept cool-down the config may set, so it stays a short race guard
+pub const MAX_ACCEPT_COOLDOWN_SECS: u32 = 5 * 60;
+
+// Keepers the settle allowlist can hold
+pub const MAX_SETTLE_ALLOWLIST: usize = 8;
+
+// Destinations the payout allowlist can hold
+pub const MAX_PAYOUT_ALLOWLIST: usize = 16;
+
+// Longest a party can be made to wait past expiry before voiding an
+// unsettleable escrow
+pub const MAX_VOID_GRACE_SECS: u32 = 30 * 24 * 60 * 60; // thirty days
+
+// Collections the market maker's allowlist can hold
+pub const MAX_MM_COLLECTIONS: usize = 8;
+
+// Categories the config can restrict new escrows to
+pub const MAX_CATEGORIES: usize = 16;
+
+// Most wallets the fee exemption list can hold
+pub const MAX_FEE_EXEMPT: usize = 16;
+
+// Most collections the oracle registry can map
+pub const MAX_REGISTERED_ORACLES: usize = 16;
+
+// Samples a collection's price history keeps before overwriting the oldest
+pub const MAX_PRICE_SAMPLES: usize = 32;
+
+// Highest bps-of-entry prediction, a tenfold move, for escrows predicting
+// in `PredictionUnit::BpsFromEntry`
+pub const MAX_PREDICTION_BPS: u64 = 10 * BPS_DENOMINATOR;
+
+// Length of the UTC day the per-wallet volume limit resets on
+pub const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Decimal places of SOL; one lamport is `10^-SOL_DECIMALS` SOL
+pub const SOL_DECIMALS: u8 = 9;
+
+// Pyth receiver program that owns posted price update accounts
+pub const PYTH_RECEIVER_ID: Pubkey = pubkey!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
 
 #[program]
 pub mod escrowfloor {
     use super::*;
 
+    pub fn initialize_config(ctx: Context<InitializeConfig>, params: ConfigParams) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.admin.key();
+        config.bump = ctx.bumps.config;
+        ctx.accounts.stats.bump = ctx.bumps.stats;
+        config.apply(params)
+    }
+
+    pub fn update_config(ctx: Context<UpdateConfig>, params: ConfigParams) -> Result<()> {
+        ctx.accounts.config.apply(params)
+    }
+
+    /// Offers the admin role to `new_admin`, who must `accept_admin` before
+    /// anything changes hands. `None` withdraws an offer still pending.
+    pub fn propose_admin(ctx: Context<ProposeAdmin>, new_admin: Option<Pubkey>) -> Result<()> {
+        ctx.accounts.config.pending_admin = new_admin;
+        Ok(())
+    }
+
+    /// Completes a handover proposed by `propose_admin`
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let new_admin = ctx.accounts.new_admin.key();
+        ctx.accounts.config.accept_admin(new_admin)
+    }
+
+    /// Opens the escrow at `["escrow", trader, idempotency_nonce]`. Clients
+    /// pick the nonce once per logical escrow, usually the profile's
+    /// `escrow_count`, and reuse it on every retry: a retry after the first
+    /// attempt landed hits the same, already initialized account and fails
+    /// without opening a second escrow. Nonces below `escrow_count` are
+    /// spent, so a closed escrow's address is never reopened.
     pub fn initialize_escrow(
         ctx: Context<InitializeEscrow>,
-        collection_id: String,
-        predicted_floor: u64,
-        expiry_timestamp: i64,
-        margin_amount: u64,
+        params: EscrowParams,
+        index_chunk: u32,
+        idempotency_nonce: u64,
     ) -> Result<()> {
-        let escrow_key = ctx.accounts.escrow.key();
-        let escrow = &mut ctx.accounts.escrow;
+        let accounts = ctx.accounts;
+        open_escrow(
+            OpenEscrow {
+                trader: &accounts.trader,
+                trader_profile: &mut accounts.trader_profile,
+                escrow: &mut accounts.escrow,
+                collection_index: &mut accounts.collection_index,
+                config: &accounts.config,
+                stats: &mut accounts.stats,
+                vault: accounts.vault.as_mut(),
+                system_program: &accounts.system_program,
+                tensor_oracle: tensor_oracle_key(&accounts.tensor_oracle)?,
+                fee_exempt: FeeExemptList::lists(accounts.fee_exempt.as_deref(), accounts.trader.key),
+                oracle_registry: OracleRegistry::load(&accounts.oracle_registry)?,
+                collection_paused: CollectionState::is_paused(&accounts.collection_state)?,
+                wsol: WsolDeposit::from_accounts(
+                    accounts.wsol_source.as_ref(),
+                    accounts.wsol_scratch.as_ref(),
+                    ctx.bumps.wsol_scratch,
+                    accounts.native_mint.as_ref(),
+                    accounts.token_program.as_ref(),
+                ),
+                trader_profile_bump: ctx.bumps.trader_profile,
+                collection_index_bump: ctx.bumps.collection_index,
+            },
+            params,
+            index_chunk,
+            idempotency_nonce,
+        )
+    }
 
-        // For testing, we'll skip collection verification
-        // In production, this would verify against Tensor's API
+    pub fn create_template(
+        ctx: Context<CreateTemplate>,
+        template_id: u64,
+        params: TemplateParams,
+    ) -> Result<()> {
+        require!(params.duration > 0, EscrowError::InvalidExpiry);
 
-        escrow.trader = ctx.accounts.trader.key();
-        escrow.collection_id = collection_id;
-        escrow.predicted_floor = predicted_floor;
-        escrow.expiry_timestamp = expiry_timestamp;
-        escrow.margin_amount = margin_amount;
-        escrow.is_initialized = true;
+        let template = &mut ctx.accounts.template;
+        template.owner = ctx.accounts.owner.key();
+        template.template_id = template_id;
+        template.collection_hash = hash_collection_id(&params.collection_id);
+        template.predicted_floor = params.predicted_floor;
+        template.tolerance = params.tolerance;
+        template.margin_amount = params.margin_amount;
+        template.duration = params.duration;
+        template.bump = ctx.bumps.template;
 
-        // Transfer margin amount from trader to escrow account
-        let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
-            &ctx.accounts.trader.key(),
-            &escrow_key,
-            margin_amount,
-        );
+        Ok(())
+    }
 
-        anchor_lang::solana_program::program::invoke(
-            &transfer_instruction,
-            &[
-                ctx.accounts.trader.to_account_info(),
-                ctx.accounts.escrow.to_account_info(),
-                ctx.accounts.system_program.to_account_info(),
-            ],
+    pub fn create_from_template(
+        ctx: Context<CreateFromTemplate>,
+        collection_id: String,
+        overrides: TemplateOverrides,
+        index_chunk: u32,
+    ) -> Result<()> {
+        let params = ctx.accounts.template.escrow_params(
+            collection_id,
+            overrides,
+            ctx.accounts.config.fee_bps,
+            current_timestamp()?,
         )?;
 
-        Ok(())
+        let accounts = ctx.accounts;
+        let nonce = accounts.trader_profile.escrow_count;
+        open_escrow(
+            OpenEscrow {
+                trader: &accounts.trader,
+                trader_profile: &mut accounts.trader_profile,
+                escrow: &mut accounts.escrow,
+                collection_index: &mut accounts.collection_index,
+                config: &accounts.config,
+                stats: &mut accounts.stats,
+                vault: accounts.vault.as_mut(),
+                system_program: &accounts.system_program,
+                wsol: None,
+                tensor_oracle: tensor_oracle_key(&accounts.tensor_oracle)?,
+                fee_exempt: FeeExemptList::lists(accounts.fee_exempt.as_deref(), accounts.trader.key),
+                oracle_registry: OracleRegistry::load(&accounts.oracle_registry)?,
+                collection_paused: CollectionState::is_paused(&accounts.collection_state)?,
+                trader_profile_bump: ctx.bumps.trader_profile,
+                collection_index_bump: ctx.bumps.collection_index,
+            },
+            params,
+            index_chunk,
+            nonce,
+        )
     }
 
-    pub fn accept_escrow(ctx: Context<AcceptEscrow>) -> Result<()> {
+    /// `collection_id` is only needed for escrows predicting in
+    /// `BpsFromEntry`, whose entry price is read here from the escrow's
+    /// price sources. `expected_margin` is the trader's margin the
+    /// counterparty signed up to match; a top-up landing first makes the
+    /// accept fail rather than take a larger stake than agreed.
+    pub fn accept_escrow(
+        ctx: Context<AcceptEscrow>,
+        counterparty_prediction: Option<u64>,
+        collection_id: Option<String>,
+        expected_margin: u64,
+    ) -> Result<()> {
         let trader = &ctx.accounts.trader;
         let escrow = &ctx.accounts.escrow;
 
-        // Verify escrow state
-        require!(!escrow.settled, EscrowError::AlreadySettled);
-        require!(escrow.is_initialized, EscrowError::NotInitialized);
-        require!(Clock::get()?.unix_timestamp < escrow.expiry_timestamp, EscrowError::Expired);
+        let clock = current_clock()?;
+        let now = clock.unix_timestamp;
+        escrow.check_accept(trader.key, expected_margin, &clock)?;
+        require!(
+            !CollectionState::is_paused(&ctx.accounts.collection_state)?,
+            EscrowError::CollectionPaused
+        );
+        require!(
+            !ctx.accounts.config.has_conflict(trader.key),
+            EscrowError::ConflictOfInterest
+        );
+        require!(
+            !ctx.accounts.config.accept_cooling_down(escrow.created_at, now),
+            EscrowError::AcceptCooldown
+        );
+        if escrow.prediction_unit == PredictionUnit::BpsFromEntry {
+            require!(
+                counterparty_prediction.is_none_or(bps_prediction_is_sane),
+                EscrowError::InvalidPredictionBps
+            );
+        }
+        if escrow.prediction_unit == PredictionUnit::Rank {
+            let basket_len = escrow.basket.len();
+            require!(
+                counterparty_prediction
+                    .is_none_or(|prediction| rank_prediction_is_sane(prediction, basket_len)),
+                EscrowError::InvalidRankPrediction
+            );
+        }
+        // In an over/under escrow a prediction just names a side, which has
+        // to be the one the trader left open
+        if let Some(direction) = escrow.direction {
+            require!(
+                counterparty_prediction.is_none_or(|prediction| {
+                    Direction::of(prediction, escrow.predicted_floor) == Some(direction.opposite())
+                }),
+                EscrowError::SameDirection
+            );
+        }
 
-        // Transfer margin amount from trader to escrow account
-        let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
-            &trader.key(),
-            &escrow.key(),
-            escrow.margin_amount,
+        // Counterparty must cover their stake at the escrow's odds and stay
+        // rent exempt afterwards
+        let stake = escrow.counterparty_stake()?;
+        if escrow.denomination == Denomination::WrappedSol {
+            let accounts = &ctx.accounts;
+            WsolDeposit::from_accounts(
+                accounts.wsol_source.as_ref(),
+                accounts.wsol_scratch.as_ref(),
+                ctx.bumps.wsol_scratch,
+                accounts.native_mint.as_ref(),
+                accounts.token_program.as_ref(),
+            )
+            .ok_or(EscrowError::MissingWsolAccounts)?
+            .unwrap(trader, &accounts.system_program, stake)?;
+        }
+        let rent_buffer = Rent::get()?.minimum_balance(0);
+        require!(
+            trader.lamports() >= stake.saturating_add(rent_buffer),
+            EscrowError::InsufficientFunds
         );
 
-        anchor_lang::solana_program::program::invoke(
-            &transfer_instruction,
-            &[
-                trader.to_account_info(),
-                ctx.accounts.escrow.to_account_info(),
-                ctx.accounts.system_program.to_account_info(),
-            ],
+        ctx.accounts
+            .stats
+            .lock(stake, ctx.accounts.config.max_tvl)?;
+
+        // Transfer the counterparty's stake from trader to escrow account
+        deposit_margin(
+            &trader.to_account_info(),
+            &mut ctx.accounts.escrow,
+            ctx.accounts.vault.as_mut(),
+            &ctx.accounts.system_program,
+            stake,
         )?;
         
         // Update escrow state after transfer
         let escrow = &mut ctx.accounts.escrow;
         escrow.counterparty = Some(trader.key());
+        escrow.counterparty_prediction = counterparty_prediction;
+        escrow.counterparty_deposit = stake;
+        escrow.accepted_at = now;
+        let escrow_key = escrow.key();
+        escrow.transition(
+            escrow_key,
+            EscrowStatus::Open,
+            EscrowStatus::Active,
+            trader.key(),
+            now,
+        )?;
+
+        // Bps predictions are measured from the price at the moment both
+        // sides are committed
+        if escrow.prediction_unit == PredictionUnit::BpsFromEntry {
+            let collection_id = collection_id.ok_or(EscrowError::MissingEntryPrice)?;
+            let tensor_oracle = ctx
+                .accounts
+                .tensor_oracle
+                .as_ref()
+                .ok_or(EscrowError::MissingOracleAccount)?;
+            let feeds = PriceFeeds {
+                collection_id: &collection_id,
+                basket_ids: &[],
+                tensor_oracle,
+                pyth_price_update: ctx.accounts.pyth_price_update.as_ref(),
+            };
+            let entry_price = feeds.price(escrow, &ctx.accounts.config, now)?;
+            require!(entry_price > 0, EscrowError::MissingEntryPrice);
+            escrow.entry_price = Some(entry_price);
+        }
+
+        // Reward the maker for getting filled, unless the two sides called
+        // the same floor: that always splits, so it risks nothing and
+        // would only farm rewards
+        let duplicate = counterparty_prediction.is_some_and(|prediction| {
+            ctx.accounts
+                .config
+                .is_duplicate_prediction(escrow.predicted_floor, prediction)
+        });
+        if duplicate {
+            msg!("Counterparty duplicated the trader's prediction, no maker reward");
+        } else {
+            ctx.accounts.mint_maker_reward(ctx.bumps.reward_authority, now)?;
+        }
         
         Ok(())
     }
 
-    pub fn settle_escrow(ctx: Context<SettleEscrow>) -> Result<()> {
-        let escrow = &ctx.accounts.escrow;
-        let tensor_oracle = &ctx.accounts.tensor_oracle;
-        
-        // Verify escrow state
-        require!(!escrow.settled, EscrowError::AlreadySettled);
-        require!(escrow.is_initialized, EscrowError::NotInitialized);
-        require!(escrow.counterparty.is_some(), EscrowError::NoSecondTrader);
-        require!(Clock::get()?.unix_timestamp >= escrow.expiry_timestamp, EscrowError::NotExpiredYet);
-
-        // Get current floor price from Tensor oracle
-        let current_floor_price = tensor_oracle.get_floor_price(&escrow.collection_id)?;
-
-        // Determine winner based on predicted floor vs actual floor
-        let winner_key = if (escrow.predicted_floor as i64 - current_floor_price as i64).abs() <= 100 {
-            // Trader wins if prediction is within 100 lamports
-            escrow.trader
-        } else {
-            // Counterparty wins
-            escrow.counterparty.unwrap()
+    /// Takes the other side of an open escrow as the config's
+    /// `market_maker`, which signs as `trader`. Only escrows within the
+    /// market maker's limits qualify: a collection on `mm_collections` and
+    /// a counter-stake of at most `mm_max_margin`. Otherwise behaves like
+    /// `accept_escrow`.
+    pub fn mm_accept(
+        ctx: Context<AcceptEscrow>,
+        counterparty_prediction: Option<u64>,
+        collection_id: Option<String>,
+        expected_margin: u64,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        require!(
+            config.market_maker == Some(ctx.accounts.trader.key()),
+            EscrowError::NotMarketMaker
+        );
+        require!(
+            config.market_maker_takes(&ctx.accounts.escrow)?,
+            EscrowError::OutsideMarketMakerLimits
+        );
+
+        accept_escrow(ctx, counterparty_prediction, collection_id, expected_margin)
+    }
+
+    /// `collection_id` is the full identifier the escrow was opened with,
+    /// used for the oracle lookup and checked against the stored hash.
+    ///
+    /// Every payout, the keeper reward included, lands in this instruction.
+    /// Nothing is left to claim afterwards, so the keeper is compensated
+    /// whatever the winner (or either side of a split) does next.
+    ///
+    /// A winner who signs may pass `payout_recipient` to have their share
+    /// sent there instead, e.g. to a cold wallet.
+    ///
+    /// Failures emit a [`SettleFailed`] event before the error is returned,
+    /// so it shows up in the transaction logs for clients to explain.
+    ///
+    /// `basket_ids` lists a basket escrow's other collections in the order
+    /// they were opened with, and is empty otherwise.
+    ///
+    /// A trader who opted into rollover and wins outright has their share
+    /// re-escrowed instead of paid out, into a new open escrow at
+    /// `trader_profile`'s next nonce, passed as `rollover_escrow`. Its rent
+    /// comes out of the winnings. If what's left wouldn't make a valid
+    /// escrow, e.g. over `max_margin`, the share is paid out as usual.
+    ///
+    /// Passing `expected_winner`, e.g. from `get_expected_winner`, reverts
+    /// with `WinnerChanged` if the price moved the outcome in the meantime.
+    /// Likewise `acceptable_price_range` reverts with
+    /// `OraclePriceOutOfExpectedRange` on an oracle price outside it, so a
+    /// client can refuse to settle on an obviously wrong print.
+    ///
+    /// Past `settlement_window_secs` after expiry, or on a `Refund` ruling,
+    /// the escrow is voided and both sides refunded instead, with the
+    /// trader passed as `winner`.
+    pub fn settle_escrow(
+        ctx: Context<SettleEscrow>,
+        collection_id: String,
+        basket_ids: Vec<String>,
+        expected_winner: Option<Outcome>,
+        acceptable_price_range: Option<PriceRange>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        let keeper = accounts.keeper.to_account_info();
+        let escrow_key = accounts.escrow.key();
+        let expiry_timestamp = accounts.escrow.expiry_timestamp;
+        let system_program = accounts.system_program.clone();
+        let bump = ctx.bumps.settlement_record;
+        let clock = current_clock()?;
+        let now = clock.unix_timestamp;
+
+        let result = settle(
+            Settlement {
+                escrow: &mut accounts.escrow,
+                collection_index: &mut accounts.collection_index,
+                feeds: PriceFeeds {
+                    collection_id: &collection_id,
+                    basket_ids: &basket_ids,
+                    tensor_oracle: &accounts.tensor_oracle,
+                    pyth_price_update: accounts.pyth_price_update.as_ref(),
+                },
+                price_history: accounts.price_history.as_deref(),
+                winner: &accounts.winner,
+                winner_balance: accounts.winner_balance.as_mut(),
+                payout_recipient: accounts.payout_recipient.as_ref(),
+                trader: accounts.trader.as_ref(),
+                counterparty: accounts.counterparty.as_ref(),
+                disputer: accounts.disputer.as_ref(),
+                keeper: &keeper,
+                vault: accounts.vault.as_mut(),
+                treasury: accounts.treasury.as_ref(),
+                config: &accounts.config,
+                stats: &mut accounts.stats,
+                expected_winner,
+                acceptable_price_range,
+                rollover: match (accounts.trader_profile.as_mut(), accounts.rollover_escrow.as_ref()) {
+                    (Some(trader_profile), Some(escrow)) => Some(RolloverAccounts {
+                        trader_profile,
+                        escrow,
+                        system_program: &system_program,
+                    }),
+                    _ => None,
+                },
+                wsol: WsolPayout {
+                    payee: accounts.payee_wsol.as_ref(),
+                    other: accounts.other_wsol.as_ref(),
+                    token_program: accounts.token_program.as_ref(),
+                },
+            },
+            &clock,
+        );
+        match result {
+            Ok(record) => {
+                let keeper_token_reward = accounts.pay_keeper_token_reward(
+                    ctx.bumps.reward_authority,
+                    record.keeper_token_reward,
+                )?;
+                accounts.settlement_record.set_inner(SettlementRecord {
+                    keeper_token_reward,
+                    bump,
+                    ..record
+                });
+                // Only once everything above is paid out
+                if accounts.config.close_on_settle {
+                    accounts.close_settled_escrow()?;
+                }
+                Ok(())
+            }
+            Err(err) => {
+                emit!(SettleFailed::new(escrow_key, &err, now, expiry_timestamp));
+                Err(err)
+            }
+        }
+    }
+
+    /// Settles up to `MAX_BATCH_SETTLE` escrows passed through
+    /// `remaining_accounts`, one group per `collection_ids` entry, skipping
+    /// any that aren't ready or need `settle_escrow`. Returns how many settled.
+    pub fn settle_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SettleBatch<'info>>,
+        collection_ids: Vec<String>,
+    ) -> Result<u32> {
+        let remaining = ctx.remaining_accounts;
+        require!(
+            remaining.len().is_multiple_of(SETTLE_BATCH_GROUP_LEN)
+                && remaining.len() / SETTLE_BATCH_GROUP_LEN == collection_ids.len(),
+            EscrowError::InvalidBatch
+        );
+        require!(
+            remaining.len() / SETTLE_BATCH_GROUP_LEN <= MAX_BATCH_SETTLE,
+            EscrowError::BatchTooLarge
+        );
+
+        let clock = current_clock()?;
+        let now = clock.unix_timestamp;
+        let keeper = ctx.accounts.keeper.to_account_info();
+        let system_program = ctx.accounts.system_program.clone();
+        let mut settled = 0u32;
+        let groups = remaining.chunks_exact(SETTLE_BATCH_GROUP_LEN);
+        for (group, collection_id) in groups.zip(&collection_ids) {
+            // The Pyth slot is only read by escrows that list Pyth, the
+            // counterparty is only paid on a split, and the record is the
+            // escrow's uncreated settlement record PDA
+            let [escrow_info, index_info, tensor_oracle, pyth_price_update, winner, counterparty, record_info] =
+                group
+            else {
+                return err!(EscrowError::InvalidBatch);
+            };
+
+            // Skip escrows not yet settleable, and any with terms only
+            // `settle_escrow` takes the accounts for
+            let mut escrow = Account::<EscrowState>::try_from(escrow_info)?;
+            if escrow.status != EscrowStatus::Active
+                || !escrow.settles_at(&clock)
+                || !ctx
+                    .accounts
+                    .config
+                    .dispute_window_closed(escrow.expiry_timestamp, now)
+                || !escrow.basket.is_empty()
+                || escrow.rollover.is_some()
+                || escrow.payout_mode != PayoutMode::WinnerTakesAll
+                || escrow.denomination != Denomination::Native
+                || escrow.disputer.is_some()
+                || escrow.settlement_price != SettlementPriceKind::Spot
+            {
+                continue;
+            }
+            let mut collection_index = Account::<CollectionIndex>::try_from(index_info)?;
+            require!(
+                collection_index.collection_hash == escrow.collection_hash
+                    && collection_index.chunk == escrow.index_chunk,
+                EscrowError::InvalidBatch
+            );
+
+            let record = settle(
+                Settlement {
+                    escrow: &mut escrow,
+                    collection_index: &mut collection_index,
+                    feeds: PriceFeeds {
+                        collection_id,
+                        basket_ids: &[],
+                        tensor_oracle,
+                        pyth_price_update: Some(pyth_price_update),
+                    },
+                    price_history: None,
+                    winner,
+                    winner_balance: None,
+                    payout_recipient: None,
+                    trader: None,
+                    counterparty: Some(counterparty),
+                    disputer: None,
+                    keeper: &keeper,
+                    vault: ctx.accounts.vault.as_mut(),
+                    treasury: ctx.accounts.treasury.as_ref(),
+                    config: &ctx.accounts.config,
+                    stats: &mut ctx.accounts.stats,
+                    expected_winner: None,
+                    acceptable_price_range: None,
+                    rollover: None,
+                    wsol: WsolPayout {
+                        payee: None,
+                        other: None,
+                        token_program: None,
+                    },
+                },
+                &clock,
+            )?;
+            // No reward token accounts come with a batch
+            let record = SettlementRecord {
+                keeper_token_reward: 0,
+                ..record
+            };
+            create_settlement_record(record_info, &keeper, &system_program, record)?;
+
+            // Persist now so a duplicate group later in the batch sees it settled
+            escrow.exit(&crate::ID)?;
+            collection_index.exit(&crate::ID)?;
+            settled += 1;
+        }
+
+        Ok(settled)
+    }
+
+    /// Who would take the pot if the escrow settled on the current oracle
+    /// price. Changes nothing and skips the expiry check, so clients can
+    /// simulate it mid-bet for a live "who's ahead" view. An upheld
+    /// dispute's ruling is returned as is.
+    pub fn get_expected_winner(
+        ctx: Context<GetExpectedWinner>,
+        collection_id: String,
+        basket_ids: Vec<String>,
+    ) -> Result<Outcome> {
+        let accounts = ctx.accounts;
+        let feeds = PriceFeeds {
+            collection_id: &collection_id,
+            basket_ids: &basket_ids,
+            tensor_oracle: &accounts.tensor_oracle,
+            pyth_price_update: accounts.pyth_price_update.as_ref(),
+        };
+
+        expected_outcome(&accounts.escrow, &accounts.config, &feeds, current_timestamp()?)
+    }
+
+    /// Everything a client shows for an escrow, read in one call. Like
+    /// `get_expected_winner` it changes nothing; the projection is left
+    /// empty when the escrow isn't active or no price can be read.
+    pub fn get_summary(
+        ctx: Context<GetSummary>,
+        collection_id: String,
+        basket_ids: Vec<String>,
+    ) -> Result<EscrowSummary> {
+        let accounts = ctx.accounts;
+        let escrow = &accounts.escrow;
+        let now = current_timestamp()?;
+
+        let projection = match (escrow.status, &accounts.tensor_oracle) {
+            (EscrowStatus::Active, Some(tensor_oracle)) => {
+                let feeds = PriceFeeds {
+                    collection_id: &collection_id,
+                    basket_ids: &basket_ids,
+                    tensor_oracle,
+                    pyth_price_update: accounts.pyth_price_update.as_ref(),
+                };
+                expected_outcome(escrow, &accounts.config, &feeds, now)
+                    .ok()
+                    .map(|outcome| {
+                        settlement_costs(escrow, &accounts.config, outcome, now)
+                            .map(|costs| (outcome, costs.payouts))
+                    })
+                    .transpose()?
+            }
+            _ => None,
         };
 
-        // Calculate total amount to transfer
-        let total_amount = escrow.margin_amount * 2;
+        Ok(EscrowSummary {
+            escrow: escrow.key(),
+            status: escrow.status,
+            trader: escrow.trader,
+            counterparty: escrow.counterparty,
+            predicted_floor: escrow.predicted_floor,
+            lower_tolerance: escrow.lower_tolerance,
+            upper_tolerance: escrow.upper_tolerance,
+            counterparty_prediction: escrow.counterparty_prediction,
+            prediction_unit: escrow.prediction_unit,
+            entry_price: escrow.entry_price,
+            margin_amount: escrow.margin_amount,
+            counterparty_stake: escrow.counterparty_stake()?,
+            payout_mode: escrow.payout_mode,
+            denomination: escrow.denomination,
+            direction: escrow.direction,
+            category: escrow.category,
+            created_at: escrow.created_at,
+            accepted_at: escrow.accepted_at,
+            expiry_timestamp: escrow.expiry_timestamp,
+            expiry_slot: escrow.expiry_slot,
+            projected_winner: projection.map(|(outcome, _)| outcome),
+            projected_payouts: projection.map(|(_, payouts)| payouts),
+        })
+    }
+
+    /// The address `trader`'s escrow at `nonce` will have and the lamports
+    /// opening it with `margin_amount` takes, so clients can fund the
+    /// trader before sending `initialize_escrow`. Changes nothing.
+    pub fn get_escrow_funding(
+        _ctx: Context<GetEscrowFunding>,
+        trader: Pubkey,
+        nonce: u64,
+        margin_amount: u64,
+    ) -> Result<EscrowFunding> {
+        EscrowFunding::new(&trader, nonce, margin_amount, &Rent::get()?)
+    }
 
-        // Get bump
-        let bump = ctx.bumps.escrow;
+    pub fn extend_expiry(ctx: Context<ExtendExpiry>, new_expiry: i64) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
 
-        // Transfer funds to winner
-        let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
-            &escrow.key(),
-            &winner_key,
-            total_amount,
+        // Both parties sign, and only while the bet is still running
+        require!(escrow.status == EscrowStatus::Active, EscrowError::NoSecondTrader);
+        require!(
+            escrow.counterparty == Some(ctx.accounts.counterparty.key()),
+            EscrowError::Unauthorized
         );
+        require!(!escrow.settles_at(&current_clock()?), EscrowError::Expired);
 
-        anchor_lang::solana_program::program::invoke_signed(
-            &transfer_instruction,
-            &[
-                ctx.accounts.escrow.to_account_info(),
-                ctx.accounts.winner.to_account_info(),
-                ctx.accounts.system_program.to_account_info(),
-            ],
-            &[&[b"escrow", escrow.trader.as_ref(), &[bump]]],
+        escrow.extend_to(new_expiry)
+    }
+
+    /// Pushes out an escrow nobody has accepted yet, keeping the unfilled
+    /// order live for longer. The trader alone signs, and the extension
+    /// counts against the same limits as `extend_expiry`.
+    pub fn extend_unaccepted(ctx: Context<ExtendUnaccepted>, new_expiry: i64) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        require!(
+            escrow.status == EscrowStatus::Open && escrow.counterparty.is_none(),
+            EscrowError::NotOpen
+        );
+        require!(escrow.accepts_at(&current_clock()?), EscrowError::Expired);
+
+        escrow.extend_to(new_expiry)
+    }
+
+    /// Adds to the trader's margin while the escrow is still open, which
+    /// raises the stake a counterparty must match on accept. The top-up
+    /// counts towards the trader's daily volume like the opening margin,
+    /// and closes at expiry, once the price it would bet on is known.
+    pub fn increase_margin(ctx: Context<IncreaseMargin>, additional: u64) -> Result<()> {
+        let escrow = &ctx.accounts.escrow;
+        require!(escrow.status == EscrowStatus::Open, EscrowError::NotOpen);
+        let clock = current_clock()?;
+        require!(!escrow.settles_at(&clock), EscrowError::Expired);
+        ctx.accounts.trader_profile.record_volume(
+            additional,
+            clock.unix_timestamp,
+            ctx.accounts.config.daily_volume_limit,
+        )?;
+
+        let margin_amount = escrow
+            .margin_amount
+            .checked_add(additional)
+            .ok_or(EscrowError::MathOverflow)?;
+        require!(
+            ctx.accounts.config.allows_margin(margin_amount),
+            EscrowError::MarginTooLarge
+        );
+        ctx.accounts
+            .stats
+            .lock(additional, ctx.accounts.config.max_tvl)?;
+
+        deposit_margin(
+            &ctx.accounts.trader.to_account_info(),
+            &mut ctx.accounts.escrow,
+            ctx.accounts.vault.as_mut(),
+            &ctx.accounts.system_program,
+            additional,
         )?;
 
-        // Update escrow state after transfer
         let escrow = &mut ctx.accounts.escrow;
-        escrow.settled = true;
-        
+        escrow.margin_amount = margin_amount;
+        escrow.trader_deposit = escrow
+            .trader_deposit
+            .checked_add(additional)
+            .ok_or(EscrowError::MathOverflow)?;
+
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct InitializeEscrow<'info> {
-    #[account(mut)]
-    pub trader: Signer<'info>,
-    
-    #[account(
-        init,
-        payer = trader,
-        space = EscrowState::LEN,
-        seeds = [b"escrow", trader.key().as_ref()],
-        bump
-    )]
-    pub escrow: Account<'info, EscrowState>,
-    
-    /// CHECK: This is Tensor's oracle account for floor price
-    pub tensor_oracle: AccountInfo<'info>,
-    
-    pub system_program: Program<'info, System>,
-}
+    pub fn cancel_escrow(ctx: Context<CancelEscrow>) -> Result<()> {
+        // Only an escrow nobody has joined can be withdrawn
+        require!(
+            ctx.accounts.escrow.status == EscrowStatus::Open,
+            EscrowError::NotOpen
+        );
 
-#[derive(Accounts)]
-pub struct AcceptEscrow<'info> {
-    #[account(mut)]
-    pub trader: Signer<'info>,
-    
-    #[account(mut)]
-    pub escrow: Account<'info, EscrowState>,
-    
-    pub system_program: Program<'info, System>,
-}
+        let deposit = ctx.accounts.escrow.trader_deposit;
+        release_margin(
+            &mut ctx.accounts.escrow,
+            ctx.accounts.vault.as_mut(),
+            deposit,
+        )?;
+        ctx.accounts.trader.add_lamports(deposit)?;
 
-#[derive(Accounts)]
-pub struct SettleEscrow<'info> {
-    /// CHECK: Winner account to receive funds
-    #[account(mut)]
-    pub winner: AccountInfo<'info>,
-    
-    #[account(mut,
-        seeds = [b"escrow", escrow.trader.as_ref()],
-        bump
-    )]
-    pub escrow: Account<'info, EscrowState>,
-    
-    /// CHECK: This is Tensor's oracle account for floor price
-    pub tensor_oracle: AccountInfo<'info>,
-    
-    pub system_program: Program<'info, System>,
-}
+        let escrow_key = ctx.accounts.escrow.key();
+        ctx.accounts.escrow.transition(
+            escrow_key,
+            EscrowStatus::Open,
+            EscrowStatus::Cancelled,
+            ctx.accounts.trader.key(),
+            current_timestamp()?,
+        )?;
+        ctx.accounts.collection_index.remove(&escrow_key);
+        ctx.accounts.stats.unlock(deposit)?;
 
-#[account]
-pub struct EscrowState {
-    pub trader: Pubkey,
-    pub counterparty: Option<Pubkey>,
-    pub collection_id: String,
-    pub predicted_floor: u64,
-    pub expiry_timestamp: i64,
-    pub margin_amount: u64,
-    pub is_initialized: bool,
-    pub settled: bool,
-}
+        Ok(())
+    }
 
-impl EscrowState {
-    pub const LEN: usize = 8 + // discriminator
-        32 + // trader
-        33 + // counterparty (Option<Pubkey>)
-        36 + // collection_id (max 32 chars + 4 bytes for length)
-        8 + // predicted_floor
-        8 + // expiry_timestamp
-        8 + // margin_amount
-        1 + // is_initialized
-        1; // settled
-}
+    pub fn close_escrow(ctx: Context<CloseEscrow>) -> Result<()> {
+        // Closing hands the remaining lamports to the trader, so refuse while
+        // anyone's margin is still inside
+        match ctx.accounts.escrow.status {
+            EscrowStatus::Settled | EscrowStatus::Cancelled | EscrowStatus::Voided => Ok(()),
+            EscrowStatus::Active => err!(EscrowError::CounterpartyFundsLocked),
+            EscrowStatus::Open => err!(EscrowError::NotFinalized),
+        }
+    }
 
-/// Custom trait for Tensor oracle interactions
-pub trait TensorOracle {
-    fn get_floor_price(&self, collection_id: &str) -> Result<u64>;
-}
+    /// Marks a collection as delisted from its oracle, which lets the admin
+    /// void its escrows with `cancel_delisted`
+    pub fn set_collection_delisted(
+        ctx: Context<SetCollectionDelisted>,
+        collection_id: String,
+        delisted: bool,
+    ) -> Result<()> {
+        let collection_state = &mut ctx.accounts.collection_state;
+        collection_state.collection_hash = hash_collection_id(&collection_id);
+        collection_state.delisted = delisted;
+        collection_state.bump = ctx.bumps.collection_state;
 
-impl TensorOracle for AccountInfo<'_> {
-    fn get_floor_price(&self, _collection_id: &str) -> Result<u64> {
-        // For testing, we'll return a mock floor price
-        // In production, this would make an HTTP call to Tensor's API
-        Ok(10 * anchor_lang::solana_program::native_token::LAMPORTS_PER_SOL)
+        Ok(())
     }
-}
 
-#[error_code]
-pub enum EscrowError {
-    #[msg("Escrow is already settled")]
-    AlreadySettled,
-    #[msg("Escrow is not initialized")]
-    NotInitialized,
-    #[msg("Escrow has expired")]
-    Expired,
-    #[msg("Escrow has not expired yet")]
-    NotExpiredYet,
-    #[msg("No second trader has accepted the escrow")]
-    NoSecondTrader,
+    /// Pauses or resumes a single collection: while paused, no escrow on it
+    /// can be opened or accepted. Escrows already running on it still
+    /// settle, dispute and void as usual.
+    pub fn set_collection_paused(
+        ctx: Context<SetCollectionPaused>,
+        collection_id: String,
+        paused: bool,
+    ) -> Result<()> {
+        let collection_state = &mut ctx.accounts.collection_state;
+        collection_state.collection_hash = hash_collection_id(&collection_id);
+        collection_state.paused = paused;
+        collection_state.bump = ctx.bumps.collection_state;
+
+        Ok(())
+    }
+
+    /// Creates the shared vault that holds margins while `use_vault` is set
+    pub fn initialize_vault(ctx: Context<InitializeVault>) -> Result<()> {
+        ctx.accounts.vault.bump = ctx.bumps.vault;
+        Ok(())
+    }
+
+    /// Creates the `["reward_vault"]` token account keepers are paid from
+    /// under `keeper_reward_in_token`. Anyone may fund it with a plain
+    /// token transfer.
+    pub fn initialize_reward_vault(_ctx: Context<InitializeRewardVault>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Replaces the wallets whose escrows settle fee-free, creating the
+    /// `["fee_exempt"]` list on first use
+    pub fn set_fee_exempt(ctx: Context<SetFeeExempt>, wallets: Vec<Pubkey>) -> Result<()> {
+        require!(wallets.len() <= MAX_FEE_EXEMPT, EscrowError::InvalidConfig);
+        let list = &mut ctx.accounts.fee_exempt;
+        list.wallets = wallets;
+        list.bump = ctx.bumps.fee_exempt;
+        Ok(())
+    }
+
+    /// Maps `collection_id` to the Tensor oracle that prices it, or drops
+    /// its mapping when `oracle` is `None`, creating the
+    /// `["oracle_registry"]` on first use
+    pub fn set_oracle(
+        ctx: Context<SetOracle>,
+        collection_id: String,
+        oracle: Option<Pubkey>,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.oracle_registry;
+        registry.set(hash_collection_id(&collection_id), oracle)?;
+        registry.bump = ctx.bumps.oracle_registry;
+        Ok(())
+    }
+
+    /// Opts `collection_id` into an on-chain price history, bound to
+    /// `tensor_oracle` so it can only ever be fed from that account
+    pub fn initialize_price_history(
+        ctx: Context<InitializePriceHistory>,
+        collection_id: String,
+    ) -> Result<()> {
+        let history = &mut ctx.accounts.price_history;
+        history.collection_hash = hash_collection_id(&collection_id);
+        history.tensor_oracle = ctx.accounts.tensor_oracle.key();
+        history.bump = ctx.bumps.price_history;
+        Ok(())
+    }
+
+    /// Appends the collection's current floor to its price history,
+    /// overwriting the oldest sample once the buffer is full. Anyone may
+    /// crank it, at most once a second per collection.
+    pub fn record_price(ctx: Context<RecordPrice>, collection_id: String) -> Result<()> {
+        let price = ctx.accounts.tensor_oracle.get_floor_price(&collection_id)?;
+        ctx.accounts.price_history.push(price, current_timestamp()?)
+    }
+
+    /// Time-weighted average floor over the last `window_secs`, from the
+    /// collection's price history. Changes nothing.
+    pub fn get_twap(
+        ctx: Context<GetTwap>,
+        _collection_id: String,
+        window_secs: u32,
+    ) -> Result<u64> {
+        ctx.accounts
+            .price_history
+            .twap(window_secs, current_timestamp()?)
+            .ok_or(error!(EscrowError::InsufficientPriceHistory))
+    }
+
+    /// Flags an expired, unsettled escrow's price as manipulated, holding
+    /// `config.dispute_bond` from the disputer until the arbiter rules.
+    /// Only open during the dispute window; settlement waits for the ruling.
+    pub fn raise_dispute(ctx: Context<RaiseDispute>) -> Result<()> {
+        let escrow = &ctx.accounts.escrow;
+        require!(escrow.status == EscrowStatus::Active, EscrowError::NoSecondTrader);
+        let clock = current_clock()?;
+        require!(escrow.settles_at(&clock), EscrowError::NotExpiredYet);
+        require!(
+            !ctx.accounts
+                .config
+                .dispute_window_closed(escrow.expiry_timestamp, clock.unix_timestamp),
+            EscrowError::DisputeWindowClosed
+        );
+        require!(escrow.disputer.is_none(), EscrowError::AlreadyDisputed);
+
+        let bond = ctx.accounts.config.dispute_bond;
+        deposit(
+            &ctx.accounts.disputer.to_account_info(),
+            &ctx.accounts.dispute.to_account_info(),
+            &ctx.accounts.system_program,
+            bond,
+        )?;
+
+        let disputer = ctx.accounts.disputer.key();
+        let dispute = &mut ctx.accounts.dispute;
+        dispute.escrow = escrow.key();
+        dispute.disputer = disputer;
+        dispute.bond = bond;
+        dispute.raised_at = clock.unix_timestamp;
+        dispute.bump = ctx.bumps.dispute;
+        ctx.accounts.escrow.disputer = Some(disputer);
+
+        Ok(())
+    }
+
+    /// The arbiter's verdict on a dispute. Passing the outcome the price
+    /// should have given upholds it: the escrow settles on that outcome
+    /// and the disputer gets their bond back now and `dispute_reward_bps`
+    /// of the pot at settlement. `None` rejects it, forfeiting the bond to
+    /// the treasury and letting the escrow settle on its price as usual.
+    pub fn resolve_dispute(ctx: Context<ResolveDispute>, ruling: Option<Outcome>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        require!(escrow.status == EscrowStatus::Active, EscrowError::NoSecondTrader);
+
+        match ruling {
+            Some(outcome) => escrow.ruling = Some(outcome),
+            None => {
+                escrow.disputer = None;
+                let treasury = ctx
+                    .accounts
+                    .treasury
+                    .as_ref()
+                    .ok_or(EscrowError::MissingTreasuryAccount)?;
+                let bond = ctx.accounts.dispute.bond;
+                ctx.accounts.dispute.sub_lamports(bond)?;
+                treasury.add_lamports(bond)?;
+            }
+        }
+
+        // Closing returns the remaining lamports, the bond included when
+        // upheld, to the disputer
+        Ok(())
+    }
+
+    /// Decommissions the program-wide accounts, refunding their rent to
+    /// `recipient`. Config and stats are created together and close
+    /// together, and only once no escrow has funds locked.
+    pub fn close_config(ctx: Context<CloseConfig>) -> Result<()> {
+        require!(
+            ctx.accounts.stats.total_locked == 0,
+            EscrowError::FundsStillLocked
+        );
+        Ok(())
+    }
+
+    /// Drops a collection's flags, refunding rent to `recipient`. A
+    /// delisted collection's escrows rely on the flag to be voided, so it
+    /// must be cleared first.
+    pub fn close_collection_state(ctx: Context<CloseCollectionState>) -> Result<()> {
+        require!(
+            !ctx.accounts.collection_state.delisted,
+            EscrowError::CollectionStillDelisted
+        );
+        Ok(())
+    }
+
+    /// Voids an open or active escrow on a delisted collection, returning
+    /// each side's margin since no fair settlement price will ever exist
+    pub fn cancel_delisted(ctx: Context<CancelDelisted>) -> Result<()> {
+        let accounts = ctx.accounts;
+        void_escrow(Refund {
+            escrow: &mut accounts.escrow,
+            trader: &accounts.trader,
+            counterparty: accounts.counterparty.as_ref(),
+            collection_index: &mut accounts.collection_index,
+            stats: &mut accounts.stats,
+            vault: accounts.vault.as_mut(),
+            actor: accounts.admin.key(),
+            now: current_timestamp()?,
+        })
+    }
+
+    /// Voids an active escrow nobody has managed to settle by
+    /// `void_grace_secs` past expiry, e.g. because its oracle went away,
+    /// returning each side's margin. Either party may call it, so neither
+    /// is ever left worse off than a full refund. Escrows with a dispute
+    /// on them wait for the arbiter, but only `void_grace_secs` from when
+    /// it was raised; the disputer then gets their bond back.
+    pub fn void_and_refund(ctx: Context<VoidAndRefund>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let escrow = &accounts.escrow;
+        let party = accounts.party.key();
+        require!(
+            escrow.trader == party || escrow.counterparty == Some(party),
+            EscrowError::Unauthorized
+        );
+        require!(escrow.status == EscrowStatus::Active, EscrowError::NoSecondTrader);
+        let now = current_timestamp()?;
+        require!(
+            accounts.config.void_available(escrow.expiry_timestamp, now),
+            EscrowError::VoidTooEarly
+        );
+        // A ruled dispute's account is already closed
+        let pending = escrow.disputer.is_some() && escrow.ruling.is_none();
+        if pending {
+            let dispute = accounts
+                .dispute
+                .as_ref()
+                .ok_or(EscrowError::MissingDisputeAccount)?;
+            require!(
+                accounts.config.void_available(dispute.raised_at, now),
+                EscrowError::DisputePending
+            );
+            let disputer = accounts
+                .disputer
+                .as_ref()
+                .ok_or(EscrowError::MissingDisputerAccount)?;
+            require_keys_eq!(disputer.key(), dispute.disputer, EscrowError::InvalidRefundAccount);
+            dispute.close(disputer.clone())?;
+        }
+
+        void_escrow(Refund {
+            escrow: &mut accounts.escrow,
+            trader: &accounts.trader,
+            counterparty: Some(&accounts.counterparty),
+            collection_index: &mut accounts.collection_index,
+            stats: &mut accounts.stats,
+            vault: accounts.vault.as_mut(),
+            actor: party,
+            now,
+        })
+    }
+
+    /// Lets either party walk away from an active escrow before expiry on
+    /// their own, forfeiting `early_exit_penalty_bps` of their stake to the
+    /// other side, who gets their own stake back on top. The escrow is
+    /// voided; with no penalty configured the exit is unavailable.
+    pub fn exit_early(ctx: Context<ExitEarly>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let escrow = &accounts.escrow;
+        let party = accounts.party.key();
+        require!(
+            escrow.trader == party || escrow.counterparty == Some(party),
+            EscrowError::Unauthorized
+        );
+        require!(escrow.status == EscrowStatus::Active, EscrowError::NoSecondTrader);
+        require!(escrow.disputer.is_none(), EscrowError::DisputePending);
+        let clock = current_clock()?;
+        let now = clock.unix_timestamp;
+        require!(!escrow.settles_at(&clock), EscrowError::Expired);
+        require!(
+            accounts.config.early_exit_penalty_bps > 0,
+            EscrowError::EarlyExitDisabled
+        );
+        require!(
+            escrow.counterparty == Some(accounts.counterparty.key()),
+            EscrowError::InvalidRefundAccount
+        );
+
+        let trader_stake = escrow.trader_deposit;
+        let counterparty_stake = escrow.counterparty_deposit;
+        let pot = escrow.pot()?;
+        let (trader_refund, counterparty_refund) = if party == escrow.trader {
+            let penalty = accounts.config.early_exit_penalty(trader_stake)?;
+            (
+                trader_stake
+                    .checked_sub(penalty)
+                    .ok_or(EscrowError::MathOverflow)?,
+                counterparty_stake
+                    .checked_add(penalty)
+                    .ok_or(EscrowError::MathOverflow)?,
+            )
+        } else {
+            let penalty = accounts.config.early_exit_penalty(counterparty_stake)?;
+            (
+                trader_stake
+                    .checked_add(penalty)
+                    .ok_or(EscrowError::MathOverflow)?,
+                counterparty_stake
+                    .checked_sub(penalty)
+                    .ok_or(EscrowError::MathOverflow)?,
+            )
+        };
+
+        release_margin(&mut accounts.escrow, accounts.vault.as_mut(), pot)?;
+        accounts.trader.add_lamports(trader_refund)?;
+        accounts.counterparty.add_lamports(counterparty_refund)?;
+
+        let escrow = &mut accounts.escrow;
+        let escrow_key = escrow.key();
+        escrow.transition(
+            escrow_key,
+            EscrowStatus::Active,
+            EscrowStatus::Voided,
+            party,
+            now,
+        )?;
+        accounts.collection_index.remove(&escrow_key);
+        accounts.stats.unlock(pot)
+    }
+
+    /// Moves an escrow the first release left at `["escrow", trader]` into
+    /// one at the trader's next nonce, stakes included, and closes the old
+    /// account, refunding its rent to `authority`, who pays for the new
+    /// one. Either party or the admin may sign. It all happens in this one
+    /// instruction, so a migration either completes or never happened, and
+    /// the closed account can't be migrated twice. The first release kept
+    /// no timestamps, so the escrow counts as opened and accepted no later
+    /// than its expiry, and an expired one isn't held up for a fresh
+    /// observation period.
+    pub fn migrate_to_v2(
+        ctx: Context<MigrateToV2>,
+        collection_id: String,
+        index_chunk: u32,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        let legacy_info = accounts.legacy_escrow.to_account_info();
+        let legacy = LegacyEscrowState::load(&legacy_info)?;
+        require!(legacy.is_initialized, EscrowError::NotInitialized);
+        require!(!legacy.settled, EscrowError::AlreadySettled);
+        let authority = accounts.authority.key();
+        require!(
+            authority == legacy.trader
+                || legacy.counterparty == Some(authority)
+                || authority == accounts.config.admin,
+            EscrowError::Unauthorized
+        );
+        require!(
+            collection_id == legacy.collection_id,
+            EscrowError::CollectionIdMismatch
+        );
+
+        // Stakes go to the new escrow, whatever else the old one held
+        // (its rent) back to the authority
+        let held = legacy.held()?;
+        let rent = legacy_info
+            .lamports()
+            .checked_sub(held)
+            .ok_or(EscrowError::InsufficientFunds)?;
+        legacy_info.sub_lamports(held + rent)?;
+        accounts.escrow.add_lamports(held)?;
+        accounts.authority.add_lamports(rent)?;
+        legacy_info.assign(&System::id());
+        legacy_info.realloc(0, false)?;
+
+        let escrow_key = accounts.escrow.key();
+        let index = &mut accounts.collection_index;
+        require!(
+            index.escrows.len() < COLLECTION_INDEX_CAPACITY,
+            EscrowError::CollectionIndexFull
+        );
+        index.collection_hash = hash_collection_id(&collection_id);
+        index.chunk = index_chunk;
+        index.bump = ctx.bumps.collection_index;
+        index.escrows.push(escrow_key);
+
+        // The stakes were already in the program, so the TVL cap doesn't
+        // apply to them moving
+        accounts.stats.lock(held, 0)?;
+        let sequence = accounts.stats.next_sequence()?;
+        let profile = &mut accounts.trader_profile;
+        let nonce = profile.escrow_count;
+        profile.trader = legacy.trader;
+        profile.escrow_count = nonce.checked_add(1).ok_or(EscrowError::MathOverflow)?;
+        profile.bump = ctx.bumps.trader_profile;
+
+        let now = current_timestamp()?;
+        let opened_by = now.min(legacy.expiry_timestamp);
+        let (status, accepted_at) = match legacy.counterparty {
+            Some(_) => (EscrowStatus::Active, opened_by),
+            None => (EscrowStatus::Open, 0),
+        };
+        accounts.escrow.set_inner(EscrowState {
+            trader: legacy.trader,
+            sequence,
+            collection_hash: index.collection_hash,
+            predicted_floor: legacy.predicted_floor,
+            lower_tolerance: LegacyEscrowState::TOLERANCE,
+            upper_tolerance: LegacyEscrowState::TOLERANCE,
+            expiry_timestamp: legacy.expiry_timestamp,
+            margin_amount: legacy.margin_amount,
+            odds_numerator: 1,
+            odds_denominator: 1,
+            counterparty: legacy.counterparty,
+            status,
+            created_at: opened_by,
+            accepted_at,
+            requested_expiry: legacy.expiry_timestamp,
+            original_expiry: legacy.expiry_timestamp,
+            index_chunk,
+            nonce,
+            price_sources: vec![PriceSource::Tensor],
+            tensor_oracle: tensor_oracle_key(&accounts.tensor_oracle)?,
+            trader_deposit: legacy.margin_amount,
+            counterparty_deposit: held - legacy.margin_amount,
+            // The first release agreed to no fee cap
+            max_fee_bps: u16::MAX,
+            ..EscrowState::default()
+        });
+        emit!(EscrowOpened::new(escrow_key, &accounts.escrow));
+        Ok(())
+    }
+
+    /// Opens the owner's `["balance", owner]`, which settlements can credit
+    /// winnings to instead of paying them out one by one
+    pub fn open_balance(ctx: Context<OpenBalance>) -> Result<()> {
+        let balance = &mut ctx.accounts.balance;
+        balance.owner = ctx.accounts.owner.key();
+        balance.bump = ctx.bumps.balance;
+        Ok(())
+    }
+
+    /// Sweeps everything credited to the owner's balance to their wallet in
+    /// one go, leaving the account open for more
+    pub fn withdraw_balance(ctx: Context<WithdrawBalance>) -> Result<()> {
+        let amount = ctx.accounts.balance.take();
+        ctx.accounts.balance.sub_lamports(amount)?;
+        ctx.accounts.owner.add_lamports(amount)?;
+        Ok(())
+    }
+}
+
+/// Accounts for calling off an escrow and refunding every margin
+struct Refund<'a, 'info> {
+    escrow: &'a mut Account<'info, EscrowState>,
+    trader: &'a AccountInfo<'info>,
+    counterparty: Option<&'a AccountInfo<'info>>,
+    collection_index: &'a mut Account<'info, CollectionIndex>,
+    stats: &'a mut Stats,
+    vault: Option<&'a mut Account<'info, Vault>>,
+    /// Who called the escrow off, for the `StatusChanged` event
+    actor: Pubkey,
+    now: i64,
+}
+
+/// Voids an open or active escrow, handing each side back its own margin
+fn void_escrow(mut accounts: Refund) -> Result<()> {
+    let escrow = &accounts.escrow;
+    let refunds = escrow.refunds();
+    let pot = escrow.pot()?;
+    let status = escrow.status;
+    match status {
+        EscrowStatus::Open => {}
+        EscrowStatus::Active => {
+            let counterparty = accounts
+                .counterparty
+                .ok_or(EscrowError::MissingCounterpartyAccount)?;
+            require!(
+                escrow.counterparty == Some(counterparty.key()),
+                EscrowError::InvalidRefundAccount
+            );
+            release_margin(
+                accounts.escrow,
+                accounts.vault.as_deref_mut(),
+                refunds.counterparty,
+            )?;
+            counterparty.add_lamports(refunds.counterparty)?;
+        }
+        EscrowStatus::Settled => return err!(EscrowError::AlreadySettled),
+        EscrowStatus::Cancelled | EscrowStatus::Voided => return err!(EscrowError::NotOpen),
+    }
+
+    release_margin(accounts.escrow, accounts.vault, refunds.trader)?;
+    accounts.trader.add_lamports(refunds.trader)?;
+
+    let escrow_key = accounts.escrow.key();
+    accounts.escrow.transition(
+        escrow_key,
+        status,
+        EscrowStatus::Voided,
+        accounts.actor,
+        accounts.now,
+    )?;
+    accounts.collection_index.remove(&escrow_key);
+    accounts.stats.unlock(pot)?;
+
+    Ok(())
+}
+
+/// Moves `amount` lamports from a signer into the escrow, then checks the
+/// escrow really was credited that much before the caller records it
+fn deposit<'info>(
+    from: &AccountInfo<'info>,
+    escrow: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    amount: u64,
+) -> Result<()> {
+    let before = escrow.lamports();
+    let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
+        from.key,
+        escrow.key,
+        amount,
+    );
+    anchor_lang::solana_program::program::invoke(
+        &transfer_instruction,
+        &[from.clone(), escrow.clone(), system_program.to_account_info()],
+    )?;
+    verify_credit(before, escrow.lamports(), amount)
+}
+
+/// Deposits `amount` from a signer wherever the escrow keeps its funds:
+/// its own account, or the shared vault against its `vault_balance`
+fn deposit_margin<'info>(
+    from: &AccountInfo<'info>,
+    escrow: &mut Account<'info, EscrowState>,
+    vault: Option<&mut Account<'info, Vault>>,
+    system_program: &Program<'info, System>,
+    amount: u64,
+) -> Result<()> {
+    if !escrow.vaulted {
+        return deposit(from, &escrow.to_account_info(), system_program, amount);
+    }
+    let vault = vault.ok_or(EscrowError::MissingVaultAccount)?;
+    deposit(from, &vault.to_account_info(), system_program, amount)?;
+    escrow.credit_vault(amount)?;
+    vault.credit(amount)?;
+    check_vault_reserve(vault)
+}
+
+/// Takes `amount` out of wherever the escrow keeps its funds, for the
+/// caller to pay on. A vaulted escrow can only draw on its own
+/// `vault_balance`, never on another escrow's share.
+fn release_margin<'info>(
+    escrow: &mut Account<'info, EscrowState>,
+    vault: Option<&mut Account<'info, Vault>>,
+    amount: u64,
+) -> Result<()> {
+    if !escrow.vaulted {
+        escrow.sub_lamports(amount)?;
+        return Ok(());
+    }
+    let vault = vault.ok_or(EscrowError::MissingVaultAccount)?;
+    escrow.debit_vault(amount)?;
+    vault.debit(amount)?;
+    vault.sub_lamports(amount)?;
+    check_vault_reserve(vault)
+}
+
+/// With the `vault-invariant` feature, fails with `VaultReserveMismatch`
+/// unless the vault holds exactly its rent plus every vaulted escrow's
+/// share. Off by default since anyone can send the vault lamports, which
+/// would fail it without anything being wrong.
+fn check_vault_reserve(vault: &Account<Vault>) -> Result<()> {
+    if cfg!(feature = "vault-invariant") {
+        let rent = Rent::get()?.minimum_balance(Vault::LEN);
+        verify_reserve(vault.get_lamports(), rent, vault.tracked)?;
+    }
+    Ok(())
+}
+
+/// Checks the vault's `lamports` are its `rent` plus the `tracked` margins
+fn verify_reserve(lamports: u64, rent: u64, tracked: u64) -> Result<()> {
+    require!(
+        rent.checked_add(tracked) == Some(lamports),
+        EscrowError::VaultReserveMismatch
+    );
+    Ok(())
+}
+
+fn verify_credit(before: u64, after: u64, amount: u64) -> Result<()> {
+    require!(
+        after.checked_sub(before) == Some(amount),
+        EscrowError::TransferFailed
+    );
+    Ok(())
+}
+
+/// Checks a freshly opened escrow holds its rent plus `margin`. Lamports
+/// sent to the address before it was created stay on top, so this is a
+/// floor rather than an exact match.
+fn verify_funded(lamports: u64, rent: u64, margin: u64) -> Result<()> {
+    require!(
+        rent.checked_add(margin).is_some_and(|expected| lamports >= expected),
+        EscrowError::TransferFailed
+    );
+    Ok(())
+}
+
+fn odds_are_sane(numerator: u32, denominator: u32) -> bool {
+    numerator > 0
+        && denominator > 0
+        && u64::from(numerator.max(denominator))
+            <= u64::from(numerator.min(denominator)) * u64::from(MAX_ODDS_RATIO)
+}
+
+/// Accounts touched by every instruction that opens an escrow
+struct OpenEscrow<'a, 'info> {
+    trader: &'a Signer<'info>,
+    trader_profile: &'a mut Account<'info, TraderProfile>,
+    escrow: &'a mut Account<'info, EscrowState>,
+    collection_index: &'a mut Account<'info, CollectionIndex>,
+    config: &'a Account<'info, Config>,
+    stats: &'a mut Account<'info, Stats>,
+    vault: Option<&'a mut Account<'info, Vault>>,
+    system_program: &'a Program<'info, System>,
+    /// Only needed for `Denomination::WrappedSol` escrows
+    wsol: Option<WsolDeposit<'a, 'info>>,
+    tensor_oracle: Pubkey,
+    /// Whether the trader is on the fee exemption list
+    fee_exempt: bool,
+    /// Once the registry exists, `tensor_oracle` must be the one it maps
+    /// every collection of the escrow to
+    oracle_registry: Option<OracleRegistry>,
+    /// Whether the collection's `CollectionState` has it paused
+    collection_paused: bool,
+    trader_profile_bump: u8,
+    collection_index_bump: u8,
+}
+
+/// Opens `accounts.escrow`, which the caller derived at `nonce`
+fn open_escrow(
+    accounts: OpenEscrow,
+    params: EscrowParams,
+    index_chunk: u32,
+    nonce: u64,
+) -> Result<()> {
+    let escrow_key = accounts.escrow.key();
+
+    // An empty identifier can never match an oracle feed
+    require!(!params.collection_id.is_empty(), EscrowError::EmptyCollectionId);
+    require!(!accounts.collection_paused, EscrowError::CollectionPaused);
+    // The cap only guards against later rises, never below today's fee
+    require!(
+        params.max_fee_bps >= accounts.config.fee_bps,
+        EscrowError::FeeCapBelowCurrentFee
+    );
+
+    // For testing, we'll skip collection verification
+    // In production, this would verify against Tensor's API
+    let collection_hash = hash_collection_id(&params.collection_id);
+    require!(
+        !params.price_sources.is_empty() && params.price_sources.len() <= MAX_PRICE_SOURCES,
+        EscrowError::InvalidPriceSources
+    );
+    require!(
+        params.basket.len() <= MAX_BASKET_SIZE,
+        EscrowError::BasketTooLarge
+    );
+    // A Pyth feed prices a single collection, so baskets settle on Tensor
+    let uses_pyth = params.price_sources.contains(&PriceSource::Pyth);
+    require!(
+        (params.basket.is_empty() || !uses_pyth) && params.pyth_feed_id.is_some() == uses_pyth,
+        EscrowError::InvalidPriceSources
+    );
+    if let Some(registry) = &accounts.oracle_registry {
+        for id in std::iter::once(&params.collection_id).chain(&params.basket) {
+            let oracle = registry
+                .resolve(&hash_collection_id(id))
+                .ok_or(EscrowError::UnregisteredCollection)?;
+            require_keys_eq!(oracle, accounts.tensor_oracle, EscrowError::OracleMismatch);
+        }
+    }
+    if let SettlementPriceKind::Twap { window_secs } = params.settlement_price {
+        require!(
+            window_secs > 0 && params.basket.is_empty(),
+            EscrowError::InvalidSettlementPrice
+        );
+    }
+    if let Some(rollover) = params.rollover {
+        require!(
+            rollover.duration > 0
+                && rollover.duration <= MAX_ROLLOVER_DURATION_SECS
+                && (1..=MAX_ROLLOVERS).contains(&rollover.remaining),
+            EscrowError::InvalidRollover
+        );
+    }
+
+    // List the escrow under its collection; clients move on to the next
+    // chunk once this one fills up
+    let index = accounts.collection_index;
+    require!(
+        index.escrows.len() < COLLECTION_INDEX_CAPACITY,
+        EscrowError::CollectionIndexFull
+    );
+    index.collection_hash = collection_hash;
+    index.chunk = index_chunk;
+    index.bump = accounts.collection_index_bump;
+    index.escrows.push(escrow_key);
+
+    // A stakeless escrow risks nothing and would only pad the stats
+    require!(params.margin_amount > 0, EscrowError::MarginTooSmall);
+    require!(
+        accounts.config.allows_margin(params.margin_amount),
+        EscrowError::MarginTooLarge
+    );
+    require!(
+        accounts.config.allows_category(params.category),
+        EscrowError::InvalidCategory
+    );
+    require!(
+        odds_are_sane(params.odds_numerator, params.odds_denominator),
+        EscrowError::InvalidOdds
+    );
+    match params.prediction_unit {
+        PredictionUnit::AbsoluteLamports => require!(
+            accounts.config.is_on_tick(params.predicted_floor),
+            EscrowError::InvalidPredictionTick
+        ),
+        // Entry price is read when the escrow is accepted, from a single
+        // collection's floor
+        PredictionUnit::BpsFromEntry => require!(
+            bps_prediction_is_sane(params.predicted_floor)
+                && params.lower_tolerance.max(params.upper_tolerance) <= MAX_PREDICTION_BPS
+                && params.basket.is_empty(),
+            EscrowError::InvalidPredictionBps
+        ),
+        // Ranks only mean something among a basket, as read off Tensor
+        PredictionUnit::Rank => require!(
+            rank_prediction_is_sane(params.predicted_floor, params.basket.len())
+                && !params.basket.is_empty()
+                && params.price_sources == [PriceSource::Tensor],
+            EscrowError::InvalidRankPrediction
+        ),
+    }
+    // The top of the band has to be a representable price
+    require!(
+        params.predicted_floor.checked_add(params.upper_tolerance).is_some(),
+        EscrowError::InvalidTolerance
+    );
+    require!(
+        accounts.config.allows_tolerance(
+            params.predicted_floor,
+            params.lower_tolerance.max(params.upper_tolerance)
+        ),
+        EscrowError::ToleranceTooWide
+    );
+    accounts
+        .stats
+        .lock(params.margin_amount, accounts.config.max_tvl)?;
+    let sequence = accounts.stats.next_sequence()?;
+
+    // Each escrow takes the next slot in the trader's sequence
+    let clock = current_clock()?;
+    let now = clock.unix_timestamp;
+    let profile = accounts.trader_profile;
+    profile.record_volume(
+        params.margin_amount,
+        now,
+        accounts.config.daily_volume_limit,
+    )?;
+    profile.trader = accounts.trader.key();
+    // A closed escrow's address could otherwise be opened again, onto the
+    // settlement record its first life already left behind
+    require!(nonce >= profile.escrow_count, EscrowError::NonceAlreadyUsed);
+    // Past every nonce taken so far, so rollovers and templates, which open
+    // at `escrow_count`, never land on a client-chosen one
+    profile.escrow_count = nonce.checked_add(1).ok_or(EscrowError::MathOverflow)?;
+    profile.bump = accounts.trader_profile_bump;
+
+    let (requested_expiry, expiry_timestamp) = params.expiry.resolve(&clock, accounts.config)?;
+
+    let escrow = accounts.escrow;
+    escrow.trader = accounts.trader.key();
+    escrow.sequence = sequence;
+    escrow.collection_hash = collection_hash;
+    escrow.predicted_floor = params.predicted_floor;
+    escrow.lower_tolerance = params.lower_tolerance;
+    escrow.upper_tolerance = params.upper_tolerance;
+    escrow.expiry_timestamp = expiry_timestamp;
+    escrow.margin_amount = params.margin_amount;
+    escrow.odds_numerator = params.odds_numerator;
+    escrow.odds_denominator = params.odds_denominator;
+    require!(escrow.counterparty_stake()? > 0, EscrowError::InvalidOdds);
+    escrow.status = EscrowStatus::Open;
+    escrow.created_at = now;
+    escrow.requested_expiry = requested_expiry;
+    escrow.original_expiry = expiry_timestamp;
+    escrow.expiry_slot = match params.expiry {
+        ExpiryKind::UnixTime(_) => None,
+        ExpiryKind::Slot(slot) => Some(slot),
+    };
+    escrow.index_chunk = index_chunk;
+    escrow.nonce = nonce;
+    escrow.price_sources = params.price_sources;
+    escrow.basket = params.basket.iter().map(|id| hash_collection_id(id)).collect();
+    escrow.rollover = params.rollover;
+    escrow.vaulted = accounts.config.use_vault;
+    escrow.prediction_unit = params.prediction_unit;
+    escrow.payout_mode = params.payout_mode;
+    escrow.denomination = params.denomination;
+    escrow.tensor_oracle = accounts.tensor_oracle;
+    escrow.direction = params.direction;
+    escrow.category = params.category;
+    escrow.fee_exempt = accounts.fee_exempt;
+    escrow.trader_deposit = params.margin_amount;
+    escrow.settlement_price = params.settlement_price;
+    escrow.max_fee_bps = params.max_fee_bps;
+    escrow.pyth_feed_id = params.pyth_feed_id;
+
+    if params.denomination == Denomination::WrappedSol {
+        let wsol = accounts.wsol.ok_or(EscrowError::MissingWsolAccounts)?;
+        wsol.unwrap(accounts.trader, accounts.system_program, params.margin_amount)?;
+    }
+
+    // Transfer margin amount from trader to escrow account. Anchor's `init`
+    // already created the account with its rent before this handler ran,
+    // so the margin lands on top of it.
+    deposit_margin(
+        &accounts.trader.to_account_info(),
+        escrow,
+        accounts.vault,
+        accounts.system_program,
+        params.margin_amount,
+    )?;
+
+    // A vaulted escrow's margin sits in the vault, leaving it just the rent
+    let held = if escrow.vaulted { 0 } else { params.margin_amount };
+    verify_funded(
+        escrow.to_account_info().lamports(),
+        EscrowFunding::escrow_rent(&Rent::get()?),
+        held,
+    )?;
+
+    emit!(EscrowOpened::new(escrow_key, escrow));
+    Ok(())
+}
+
+/// Accounts needed to settle a single escrow, from either settle path
+struct Settlement<'a, 'info> {
+    escrow: &'a mut Account<'info, EscrowState>,
+    collection_index: &'a mut Account<'info, CollectionIndex>,
+    feeds: PriceFeeds<'a, 'info>,
+    /// Only read by `SettlementPriceKind::Twap` escrows
+    price_history: Option<&'a PriceHistory>,
+    winner: &'a AccountInfo<'info>,
+    /// The winner's balance, credited with their share instead of paying it
+    winner_balance: Option<&'a mut Account<'info, Balance>>,
+    payout_recipient: Option<&'a AccountInfo<'info>>,
+    trader: Option<&'a AccountInfo<'info>>,
+    counterparty: Option<&'a AccountInfo<'info>>,
+    disputer: Option<&'a AccountInfo<'info>>,
+    keeper: &'a AccountInfo<'info>,
+    vault: Option<&'a mut Account<'info, Vault>>,
+    treasury: Option<&'a AccountInfo<'info>>,
+    config: &'a Config,
+    stats: &'a mut Stats,
+    /// Outcome the settler signed for; any other one reverts
+    expected_winner: Option<Outcome>,
+    /// Oracle prices the settler will settle on; any other one reverts
+    acceptable_price_range: Option<PriceRange>,
+    rollover: Option<RolloverAccounts<'a, 'info>>,
+    wsol: WsolPayout<'a, 'info>,
+}
+
+/// Accounts for opening the escrow a trader's winnings roll into
+struct RolloverAccounts<'a, 'info> {
+    trader_profile: &'a mut Account<'info, TraderProfile>,
+    escrow: &'a AccountInfo<'info>,
+    system_program: &'a Program<'info, System>,
+}
+
+impl RolloverAccounts<'_, '_> {
+    /// Turns `escrow`, already funded with the follow-on's rent and margin,
+    /// into that escrow at the trader's next nonce and lists it
+    fn open(
+        self,
+        mut followup: EscrowState,
+        collection_index: &mut CollectionIndex,
+        stats: &mut Stats,
+        config: &Config,
+    ) -> Result<()> {
+        let nonce = self.trader_profile.escrow_count;
+        let nonce_bytes = nonce.to_le_bytes();
+        let (address, bump) = Pubkey::find_program_address(
+            &[b"escrow", followup.trader.as_ref(), &nonce_bytes],
+            &crate::ID,
+        );
+        require_keys_eq!(self.escrow.key(), address, EscrowError::InvalidRolloverAccount);
+
+        // Already holds its lamports, so allocate and assign rather than
+        // create_account, which refuses funded accounts
+        let seeds: &[&[u8]] = &[b"escrow", followup.trader.as_ref(), &nonce_bytes, &[bump]];
+        let accounts = [self.escrow.clone(), self.system_program.to_account_info()];
+        anchor_lang::solana_program::program::invoke_signed(
+            &anchor_lang::solana_program::system_instruction::allocate(
+                &address,
+                EscrowState::LEN as u64,
+            ),
+            &accounts,
+            &[seeds],
+        )?;
+        anchor_lang::solana_program::program::invoke_signed(
+            &anchor_lang::solana_program::system_instruction::assign(&address, &crate::ID),
+            &accounts,
+            &[seeds],
+        )?;
+
+        // The settled escrow was just removed, so its chunk has room
+        collection_index.escrows.push(address);
+        stats.lock(followup.margin_amount, config.max_tvl)?;
+        followup.sequence = stats.next_sequence()?;
+        self.trader_profile.escrow_count =
+            nonce.checked_add(1).ok_or(EscrowError::MathOverflow)?;
+
+        followup.nonce = nonce;
+        let mut data = self.escrow.try_borrow_mut_data()?;
+        followup.try_serialize(&mut &mut data[..])?;
+
+        emit!(EscrowOpened::new(address, &followup));
+        Ok(())
+    }
+}
+
+/// Who takes the pot if `escrow` settles at `now`: an upheld dispute's
+/// ruling, a refund once the settlement window has passed, otherwise the
+/// outcome on the current price
+fn expected_outcome(
+    escrow: &EscrowState,
+    config: &Config,
+    feeds: &PriceFeeds,
+    now: i64,
+) -> Result<Outcome> {
+    if let Some(ruling) = escrow.ruling {
+        return Ok(ruling);
+    }
+    if config.settlement_window_passed(escrow.expiry_timestamp, now) {
+        return Ok(Outcome::Refund);
+    }
+    let price = feeds.price(escrow, config, now)?;
+    let price = escrow.price_in_prediction_unit(price)?;
+
+    Ok(projected_outcome(escrow, price, config))
+}
+
+/// Pays out an expired escrow and returns what it paid, for the caller to
+/// store as the escrow's `SettlementRecord`
+fn settle(accounts: Settlement, clock: &Clock) -> Result<SettlementRecord> {
+    let escrow = &accounts.escrow;
+    let config = accounts.config;
+    let now = clock.unix_timestamp;
+
+    require!(
+        config.allows_settler(accounts.keeper.key),
+        EscrowError::SettlerNotAllowed
+    );
+
+    // Verify escrow state
+    require!(escrow.status != EscrowStatus::Settled, EscrowError::AlreadySettled);
+    require!(escrow.status == EscrowStatus::Active, EscrowError::NoSecondTrader);
+    require!(escrow.settles_at(clock), EscrowError::NotExpiredYet);
+
+    let (outcome, price) = match escrow.ruling {
+        // The arbiter upheld a dispute, so its ruling replaces the price
+        Some(ruling) => (ruling, None),
+        None => {
+            require!(escrow.disputer.is_none(), EscrowError::DisputePending);
+            require!(
+                config.dispute_window_closed(escrow.expiry_timestamp, now),
+                EscrowError::DisputeWindowOpen
+            );
+            // Left so long that today's price says little about the bet
+            if config.settlement_window_passed(escrow.expiry_timestamp, now) {
+                return refund(accounts, now);
+            }
+            require!(
+                config.observed_long_enough(escrow.accepted_at, now),
+                EscrowError::InsufficientObservation
+            );
+
+            // Get current floor price from the escrow's preferred oracles,
+            // or its average over the window for a TWAP escrow
+            let current_floor_price = match escrow.settlement_price {
+                SettlementPriceKind::Spot => accounts.feeds.price(escrow, config, now)?,
+                SettlementPriceKind::Twap { window_secs } => accounts
+                    .price_history
+                    .ok_or(EscrowError::MissingPriceHistory)?
+                    .settlement_twap(escrow, window_secs, config, now)?,
+            };
+            if let Some(range) = accounts.acceptable_price_range {
+                require!(
+                    range.contains(current_floor_price),
+                    EscrowError::OraclePriceOutOfExpectedRange
+                );
+            }
+
+            // Determine winner based on predicted floor vs actual floor
+            let price = escrow.price_in_prediction_unit(current_floor_price)?;
+            (
+                projected_outcome(escrow, price, config),
+                Some(current_floor_price),
+            )
+        }
+    };
+    if outcome == Outcome::Refund {
+        return refund(accounts, now);
+    }
+    if let Some(expected) = accounts.expected_winner {
+        require!(outcome == expected, EscrowError::WinnerChanged);
+    }
+
+    let counterparty_key = escrow.counterparty.ok_or(EscrowError::NoSecondTrader)?;
+    let winner_key = match outcome {
+        Outcome::Trader | Outcome::Split | Outcome::Refund => escrow.trader,
+        Outcome::Counterparty => counterparty_key,
+    };
+    require_keys_eq!(accounts.winner.key(), winner_key, EscrowError::InvalidWinner);
+
+    // Only the winner's own signature can send their share elsewhere
+    let payee = match accounts.payout_recipient {
+        Some(recipient) => {
+            require!(accounts.winner.is_signer, EscrowError::PayoutNotAuthorized);
+            recipient
+        }
+        None => accounts.winner,
+    };
+    require!(config.allows_payout(payee.key), EscrowError::PayoutDestinationNotAllowed);
+    // The balance is the winner's own, so crediting it needs no signature;
+    // anything beyond a plain lamport payout to the winner goes out as usual
+    if accounts.winner_balance.is_some() {
+        require!(
+            accounts.payout_recipient.is_none()
+                && escrow.denomination == Denomination::Native
+                && escrow.rollover.is_none(),
+            EscrowError::BalanceCreditUnavailable
+        );
+    }
+
+    let total_amount = escrow.pot()?;
+    let SettlementCosts {
+        treasury_fee,
+        dispute_reward,
+        keeper_reward,
+        keeper_token_reward,
+        payouts,
+    } = settlement_costs(escrow, config, outcome, now)?;
+    // The winner's share goes to the payee; on a split that's the trader
+    let (winner_share, other_share) = match outcome {
+        Outcome::Counterparty => (payouts.counterparty, payouts.trader),
+        _ => (payouts.trader, payouts.counterparty),
+    };
+
+    // An opted-in trader's win funds their next escrow, rent included
+    let followup = match (outcome, escrow.rollover) {
+        (Outcome::Trader, Some(_)) => {
+            let rent = EscrowFunding::escrow_rent(&Rent::get()?);
+            escrow.rolled_over(winner_share.saturating_sub(rent), now, config)?
+        }
+        _ => None,
+    };
+    let rollover = match followup {
+        Some(_) => Some(
+            accounts
+                .rollover
+                .ok_or(EscrowError::MissingRolloverAccounts)?,
+        ),
+        None => None,
+    };
+
+    // The escrow carries data, so the system program can't debit it;
+    // move lamports directly instead. Crediting needs no cooperation
+    // from the recipient's owner, so program-owned winners (vault PDAs)
+    // are paid the same way as wallets.
+    require_writable(accounts.keeper)?;
+    require_writable(payee)?;
+    require_not_program_owned(payee)?;
+    let trader_key = escrow.trader;
+    let record = SettlementRecord {
+        escrow: escrow.key(),
+        trader: trader_key,
+        counterparty: counterparty_key,
+        predicted_floor: escrow.predicted_floor,
+        counterparty_prediction: escrow.counterparty_prediction,
+        price,
+        outcome,
+        trader_payout: payouts.trader,
+        counterparty_payout: payouts.counterparty,
+        keeper: accounts.keeper.key(),
+        keeper_reward,
+        treasury_fee,
+        dispute_reward,
+        rollover_escrow: rollover.as_ref().map(|rollover| rollover.escrow.key()),
+        settled_at: now,
+        keeper_token_reward,
+        bump: 0,
+    };
+    let disputer_key = escrow.disputer;
+    let escrow_denomination = escrow.denomination;
+    release_margin(accounts.escrow, accounts.vault, total_amount)?;
+    accounts.keeper.add_lamports(keeper_reward)?;
+    if treasury_fee > 0 {
+        let treasury = accounts
+            .treasury
+            .ok_or(EscrowError::MissingTreasuryAccount)?;
+        require_keys_eq!(treasury.key(), config.treasury, EscrowError::InvalidTreasuryAccount);
+        require_writable(treasury)?;
+        treasury.add_lamports(treasury_fee)?;
+    }
+    if dispute_reward > 0 {
+        let disputer = accounts
+            .disputer
+            .ok_or(EscrowError::MissingDisputerAccount)?;
+        require!(
+            disputer_key == Some(disputer.key()),
+            EscrowError::MissingDisputerAccount
+        );
+        require_writable(disputer)?;
+        disputer.add_lamports(dispute_reward)?;
+    }
+    match (&rollover, accounts.winner_balance) {
+        (Some(rollover), _) => {
+            rollover.escrow.add_lamports(winner_share)?;
+        }
+        (None, Some(balance)) => {
+            balance.credit(winner_share)?;
+            balance.add_lamports(winner_share)?;
+        }
+        (None, None) => accounts
+            .wsol
+            .pay(payee, accounts.wsol.payee, escrow_denomination, winner_share)?,
+    };
+    // The other side's share of a split or a profit share
+    if other_share > 0 || outcome == Outcome::Split {
+        let (other, other_key) = match outcome {
+            Outcome::Counterparty => (
+                accounts.trader.ok_or(EscrowError::MissingTraderAccount)?,
+                trader_key,
+            ),
+            _ => (
+                accounts
+                    .counterparty
+                    .ok_or(EscrowError::MissingCounterpartyAccount)?,
+                counterparty_key,
+            ),
+        };
+        require_keys_eq!(other.key(), other_key, EscrowError::InvalidWinner);
+        require!(config.allows_payout(other.key), EscrowError::PayoutDestinationNotAllowed);
+        require_writable(other)?;
+        accounts
+            .wsol
+            .pay(other, accounts.wsol.other, escrow_denomination, other_share)?;
+    }
+
+    // Update escrow state after transfer
+    let escrow = accounts.escrow;
+    let escrow_key = escrow.key();
+    escrow.transition(
+        escrow_key,
+        EscrowStatus::Active,
+        EscrowStatus::Settled,
+        accounts.keeper.key(),
+        now,
+    )?;
+    accounts.collection_index.remove(&escrow_key);
+    accounts.stats.unlock(total_amount)?;
+
+    if let (Some(followup), Some(rollover)) = (followup, rollover) {
+        rollover.open(followup, accounts.collection_index, accounts.stats, config)?;
+    }
+
+    let unit_price = price
+        .map(|price| escrow.price_in_prediction_unit(price))
+        .transpose()?;
+    emit!(EscrowSettled::new(&record, unit_price));
+    Ok(record)
+}
+
+/// What a payout strategy divides between the two sides
+pub struct PayoutContext {
+    pub outcome: Outcome,
+    /// The pot net of fees, keeper and dispute rewards
+    pub distributable: u64,
+    pub trader_stake: u64,
+    pub counterparty_stake: u64,
+}
+
+/// Each side's share of `PayoutContext::distributable`, always adding up to it
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Payouts {
+    pub trader: u64,
+    pub counterparty: u64,
+}
+
+/// How a settled pot is divided, chosen by the escrow's [`PayoutMode`]
+pub trait PayoutStrategy {
+    fn distribute(&self, ctx: &PayoutContext) -> Result<Payouts>;
+}
+
+/// Outright winner takes everything; a split is shared by stake
+pub struct WinnerTakesAllPayout;
+
+/// Shares the pot in proportion to the stakes, with any rounding
+/// remainder going to the trader. Used for every split.
+pub struct ProRataPayout;
+
+/// Outright winner gets their share back plus `profit_percentage` of the
+/// loser's, and the loser keeps the rest. A split is shared by stake.
+pub struct ProfitSharePayout {
+    pub profit_percentage: u64,
+}
+
+/// Outright loser gets `consolation_bps` of the distributable pot, rounded
+/// down, and the winner the rest, so nothing is lost to rounding. A split
+/// is shared by stake.
+pub struct ConsolationPayout {
+    pub consolation_bps: u16,
+}
+
+impl PayoutStrategy for WinnerTakesAllPayout {
+    fn distribute(&self, ctx: &PayoutContext) -> Result<Payouts> {
+        match ctx.outcome {
+            Outcome::Trader => Ok(Payouts {
+                trader: ctx.distributable,
+                counterparty: 0,
+            }),
+            Outcome::Counterparty => Ok(Payouts {
+                trader: 0,
+                counterparty: ctx.distributable,
+            }),
+            Outcome::Split | Outcome::Refund => ProRataPayout.distribute(ctx),
+        }
+    }
+}
+
+impl PayoutStrategy for ProRataPayout {
+    fn distribute(&self, ctx: &PayoutContext) -> Result<Payouts> {
+        let total = ctx
+            .trader_stake
+            .checked_add(ctx.counterparty_stake)
+            .filter(|&total| total > 0)
+            .ok_or(EscrowError::MathOverflow)?;
+        let counterparty = u64::try_from(
+            u128::from(ctx.distributable) * u128::from(ctx.counterparty_stake) / u128::from(total),
+        )
+        .map_err(|_| EscrowError::MathOverflow)?;
+        Ok(Payouts {
+            trader: ctx.distributable - counterparty,
+            counterparty,
+        })
+    }
+}
+
+impl PayoutStrategy for ProfitSharePayout {
+    fn distribute(&self, ctx: &PayoutContext) -> Result<Payouts> {
+        let shares = ProRataPayout.distribute(ctx)?;
+        let (winner, loser) = match ctx.outcome {
+            Outcome::Trader => (shares.trader, shares.counterparty),
+            Outcome::Counterparty => (shares.counterparty, shares.trader),
+            Outcome::Split | Outcome::Refund => return Ok(shares),
+        };
+        let profit = u64::try_from(
+            u128::from(loser) * u128::from(self.profit_percentage.min(100)) / 100,
+        )
+        .map_err(|_| EscrowError::MathOverflow)?;
+        let (winner, loser) = (winner + profit, loser - profit);
+        Ok(match ctx.outcome {
+            Outcome::Counterparty => Payouts {
+                trader: loser,
+                counterparty: winner,
+            },
+            _ => Payouts {
+                trader: winner,
+                counterparty: loser,
+            },
+        })
+    }
+}
+
+impl PayoutStrategy for ConsolationPayout {
+    fn distribute(&self, ctx: &PayoutContext) -> Result<Payouts> {
+        let bps = u64::from(self.consolation_bps).min(BPS_DENOMINATOR);
+        let consolation = u64::try_from(
+            u128::from(ctx.distributable) * u128::from(bps) / u128::from(BPS_DENOMINATOR),
+        )
+        .map_err(|_| EscrowError::MathOverflow)?;
+        let winner = ctx.distributable - consolation;
+        match ctx.outcome {
+            Outcome::Trader => Ok(Payouts {
+                trader: winner,
+                counterparty: consolation,
+            }),
+            Outcome::Counterparty => Ok(Payouts {
+                trader: consolation,
+                counterparty: winner,
+            }),
+            Outcome::Split | Outcome::Refund => ProRataPayout.distribute(ctx),
+        }
+    }
+}
+
+/// Settles an escrow by voiding it, each side's stake returned in full with
+/// no fees or keeper reward taken. The trader is passed as `winner`.
+fn refund(accounts: Settlement, now: i64) -> Result<SettlementRecord> {
+    let escrow = &accounts.escrow;
+    if let Some(expected) = accounts.expected_winner {
+        require!(expected == Outcome::Refund, EscrowError::WinnerChanged);
+    }
+    require_keys_eq!(accounts.winner.key(), escrow.trader, EscrowError::InvalidWinner);
+    let record = SettlementRecord {
+        escrow: escrow.key(),
+        trader: escrow.trader,
+        counterparty: escrow.counterparty.ok_or(EscrowError::NoSecondTrader)?,
+        predicted_floor: escrow.predicted_floor,
+        counterparty_prediction: escrow.counterparty_prediction,
+        price: None,
+        outcome: Outcome::Refund,
+        trader_payout: escrow.trader_deposit,
+        counterparty_payout: escrow.counterparty_deposit,
+        keeper: accounts.keeper.key(),
+        keeper_reward: 0,
+        treasury_fee: 0,
+        dispute_reward: 0,
+        rollover_escrow: None,
+        settled_at: now,
+        keeper_token_reward: 0,
+        bump: 0,
+    };
+
+    void_escrow(Refund {
+        escrow: accounts.escrow,
+        trader: accounts.winner,
+        counterparty: accounts.counterparty,
+        collection_index: accounts.collection_index,
+        stats: accounts.stats,
+        vault: accounts.vault,
+        actor: accounts.keeper.key(),
+        now,
+    })?;
+    emit!(EscrowSettled::new(&record, None));
+    Ok(record)
+}
+
+/// Token accounts a `Denomination::WrappedSol` deposit is unwrapped through
+struct WsolDeposit<'a, 'info> {
+    source: &'a Account<'info, TokenAccount>,
+    scratch: &'a AccountInfo<'info>,
+    scratch_bump: u8,
+    native_mint: &'a Account<'info, Mint>,
+    token_program: &'a Program<'info, Token>,
+}
+
+impl<'a, 'info> WsolDeposit<'a, 'info> {
+    /// `None` unless every account was passed
+    fn from_accounts(
+        source: Option<&'a Account<'info, TokenAccount>>,
+        scratch: Option<&'a UncheckedAccount<'info>>,
+        scratch_bump: Option<u8>,
+        native_mint: Option<&'a Account<'info, Mint>>,
+        token_program: Option<&'a Program<'info, Token>>,
+    ) -> Option<Self> {
+        Some(WsolDeposit {
+            source: source?,
+            scratch: scratch?,
+            scratch_bump: scratch_bump?,
+            native_mint: native_mint?,
+            token_program: token_program?,
+        })
+    }
+
+    /// Moves `amount` of `depositor`'s wSOL into a scratch token account and
+    /// closes it back to them, leaving `amount` more SOL in their wallet for
+    /// the native deposit that follows
+    fn unwrap(
+        &self,
+        depositor: &AccountInfo<'info>,
+        system_program: &Program<'info, System>,
+        amount: u64,
+    ) -> Result<()> {
+        require!(
+            self.source.is_native() && self.source.owner == depositor.key(),
+            EscrowError::InvalidWsolAccount
+        );
+
+        let depositor_key = depositor.key();
+        let seeds: &[&[u8]] = &[b"wsol_scratch", depositor_key.as_ref(), &[self.scratch_bump]];
+        anchor_lang::solana_program::program::invoke_signed(
+            &anchor_lang::solana_program::system_instruction::create_account(
+                depositor.key,
+                self.scratch.key,
+                Rent::get()?.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &token::ID,
+            ),
+            &[
+                depositor.clone(),
+                self.scratch.clone(),
+                system_program.to_account_info(),
+            ],
+            &[seeds],
+        )?;
+
+        let token_program = self.token_program.to_account_info();
+        token::initialize_account3(CpiContext::new(
+            token_program.clone(),
+            InitializeAccount3 {
+                account: self.scratch.clone(),
+                mint: self.native_mint.to_account_info(),
+                authority: depositor.clone(),
+            },
+        ))?;
+        token::transfer_checked(
+            CpiContext::new(
+                token_program.clone(),
+                TransferChecked {
+                    from: self.source.to_account_info(),
+                    mint: self.native_mint.to_account_info(),
+                    to: self.scratch.clone(),
+                    authority: depositor.clone(),
+                },
+            ),
+            amount,
+            self.native_mint.decimals,
+        )?;
+        token::close_account(CpiContext::new(
+            token_program,
+            CloseAccount {
+                account: self.scratch.clone(),
+                destination: depositor.clone(),
+                authority: depositor.clone(),
+            },
+        ))
+    }
+}
+
+/// wSOL accounts a `Denomination::WrappedSol` escrow pays its parties into
+struct WsolPayout<'a, 'info> {
+    payee: Option<&'a Account<'info, TokenAccount>>,
+    other: Option<&'a Account<'info, TokenAccount>>,
+    token_program: Option<&'a Program<'info, Token>>,
+}
+
+impl<'info> WsolPayout<'_, 'info> {
+    /// Credits `amount` to `party`, wrapped into `wsol` (which must be the
+    /// party's own wSOL account) when the escrow is in wrapped SOL
+    fn pay(
+        &self,
+        party: &AccountInfo<'info>,
+        wsol: Option<&Account<'info, TokenAccount>>,
+        denomination: Denomination,
+        amount: u64,
+    ) -> Result<()> {
+        if denomination == Denomination::Native {
+            party.add_lamports(amount)?;
+            return Ok(());
+        }
+        let (Some(wsol), Some(token_program)) = (wsol, self.token_program) else {
+            return err!(EscrowError::MissingWsolAccounts);
+        };
+        require!(
+            wsol.is_native() && wsol.owner == party.key(),
+            EscrowError::InvalidWsolAccount
+        );
+
+        // Lamports sent to a native account only count as wSOL once synced
+        let account = wsol.to_account_info();
+        account.add_lamports(amount)?;
+        token::sync_native(CpiContext::new(
+            token_program.to_account_info(),
+            SyncNative { account },
+        ))
+    }
+}
+
+/// Fees, rewards and each side's payout for settling on `outcome`
+struct SettlementCosts {
+    treasury_fee: u64,
+    dispute_reward: u64,
+    keeper_reward: u64,
+    /// Keeper reward owed in the reward token instead, outside the pot
+    keeper_token_reward: u64,
+    payouts: Payouts,
+}
+
+/// Splits an escrow's pot the way settling it on `outcome` at `now` would.
+/// A refund costs nothing and hands both stakes back.
+fn settlement_costs(
+    escrow: &EscrowState,
+    config: &Config,
+    outcome: Outcome,
+    now: i64,
+) -> Result<SettlementCosts> {
+    if outcome == Outcome::Refund {
+        return Ok(SettlementCosts {
+            treasury_fee: 0,
+            dispute_reward: 0,
+            keeper_reward: 0,
+            keeper_token_reward: 0,
+            payouts: escrow.refunds(),
+        });
+    }
+    // Costs and payouts come out of what each side actually deposited
+    let total_amount = escrow.pot()?;
+    let trader_deposit = escrow.trader_deposit;
+    let counterparty_deposit = escrow.counterparty_deposit;
+
+    // Each side pays `fee_bps` of its own stake up front, so both carry the
+    // same rate whoever wins, but never more than the escrow's
+    // `max_fee_bps`. The rest of the pot is what the keeper reward and the
+    // winner (or both sides, on a split) share.
+    let treasury_fee = if escrow.fee_exempt {
+        0
+    } else {
+        config
+            .side_fee(trader_deposit, escrow.max_fee_bps)?
+            .checked_add(config.side_fee(counterparty_deposit, escrow.max_fee_bps)?)
+            .ok_or(EscrowError::MathOverflow)?
+    };
+    let pot = total_amount
+        .checked_sub(treasury_fee)
+        .ok_or(EscrowError::MathOverflow)?;
+
+    // A disputer who overturned the result is paid before the keeper
+    let dispute_reward = match escrow.ruling {
+        Some(_) => config.dispute_reward(pot)?,
+        None => 0,
+    };
+    let pot = pot
+        .checked_sub(dispute_reward)
+        .ok_or(EscrowError::MathOverflow)?;
+
+    // Whoever cranks settlement takes a cut that grows the longer it was left,
+    // out of the pot unless it's paid in the reward token
+    let keeper_reward = config.keeper_reward(pot, now - escrow.expiry_timestamp)?;
+    let (keeper_reward, keeper_token_reward) = if config.keeper_reward_in_token {
+        (0, keeper_reward)
+    } else {
+        (keeper_reward, 0)
+    };
+
+    // Under loser-pays, costs only ever come out of the losing stake, so
+    // the winner always gets at least their own stake back
+    let loser_stake = match outcome {
+        Outcome::Trader => Some(counterparty_deposit),
+        Outcome::Counterparty => Some(trader_deposit),
+        Outcome::Split | Outcome::Refund => None,
+    };
+    let (keeper_reward, dispute_reward, treasury_fee) = match loser_stake {
+        Some(budget) if config.loser_pays_costs => {
+            costs_within(budget, keeper_reward, dispute_reward, treasury_fee)
+        }
+        _ => (keeper_reward, dispute_reward, treasury_fee),
+    };
+    let winner_amount = total_amount
+        .checked_sub(treasury_fee)
+        .and_then(|amount| amount.checked_sub(dispute_reward))
+        .and_then(|amount| amount.checked_sub(keeper_reward))
+        .ok_or(EscrowError::MathOverflow)?;
+    let payouts = escrow.payout_mode.strategy(config).distribute(&PayoutContext {
+        outcome,
+        distributable: winner_amount,
+        trader_stake: trader_deposit,
+        counterparty_stake: counterparty_deposit,
+    })?;
+
+    Ok(SettlementCosts {
+        treasury_fee,
+        dispute_reward,
+        keeper_reward,
+        keeper_token_reward,
+        payouts,
+    })
+}
+
+/// Trims settlement costs to fit inside `budget`, paying the keeper first,
+/// then the disputer, then the treasury
+fn costs_within(
+    budget: u64,
+    keeper_reward: u64,
+    dispute_reward: u64,
+    treasury_fee: u64,
+) -> (u64, u64, u64) {
+    let keeper_reward = keeper_reward.min(budget);
+    let budget = budget - keeper_reward;
+    let dispute_reward = dispute_reward.min(budget);
+    let treasury_fee = treasury_fee.min(budget - dispute_reward);
+    (keeper_reward, dispute_reward, treasury_fee)
+}
+
+/// Creates a batch-settled escrow's `["settlement_record", escrow]`
+/// account, which `settle_batch` can't declare with `init`, funded by the
+/// keeper, and writes `record` into it
+fn create_settlement_record<'info>(
+    info: &AccountInfo<'info>,
+    keeper: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    mut record: SettlementRecord,
+) -> Result<()> {
+    let escrow = record.escrow;
+    let (address, bump) =
+        Pubkey::find_program_address(&[b"settlement_record", escrow.as_ref()], &crate::ID);
+    require_keys_eq!(info.key(), address, EscrowError::InvalidSettlementRecord);
+
+    let seeds: &[&[u8]] = &[b"settlement_record", escrow.as_ref(), &[bump]];
+    anchor_lang::solana_program::program::invoke_signed(
+        &anchor_lang::solana_program::system_instruction::create_account(
+            keeper.key,
+            &address,
+            Rent::get()?.minimum_balance(SettlementRecord::LEN),
+            SettlementRecord::LEN as u64,
+            &crate::ID,
+        ),
+        &[keeper.clone(), info.clone(), system_program.to_account_info()],
+        &[seeds],
+    )?;
+
+    record.bump = bump;
+    let mut data = info.try_borrow_mut_data()?;
+    record.try_serialize(&mut &mut data[..])
+}
+
+/// Oracle accounts and collection identifiers needed to price an escrow
+struct PriceFeeds<'a, 'info> {
+    collection_id: &'a str,
+    basket_ids: &'a [String],
+    tensor_oracle: &'a AccountInfo<'info>,
+    pyth_price_update: Option<&'a AccountInfo<'info>>,
+}
+
+impl PriceFeeds<'_, '_> {
+    /// Only called from `price`, once the identifiers are verified
+    fn read_price(
+        &self,
+        source: PriceSource,
+        escrow: &EscrowState,
+        config: &Config,
+        now: i64,
+    ) -> Result<u64> {
+        match source {
+            // A basket is priced as the sum of its collections' floors
+            PriceSource::Tensor => self.basket_ids.iter().try_fold(
+                self.tensor_oracle.get_floor_price(self.collection_id)?,
+                |total, id| {
+                    total
+                        .checked_add(self.tensor_oracle.get_floor_price(id)?)
+                        .ok_or(error!(EscrowError::MathOverflow))
+                },
+            ),
+            PriceSource::Pyth => {
+                let update = self
+                    .pyth_price_update
+                    .ok_or(EscrowError::MissingOracleAccount)?;
+                let feed_id = escrow.pyth_feed_id.ok_or(EscrowError::OracleMismatch)?;
+                let price = PythPrice::load(update, &feed_id)?;
+                price.check_fresh(now, config.max_price_age_secs)?;
+                price.check_aged(now, config.min_update_age_secs)?;
+                // Too uncertain to settle on; leave it for dispute or void
+                price.confident_price(config.max_confidence_bps)
+            }
+        }
+    }
+
+    /// Rejects a Tensor oracle other than the one the escrow was opened
+    /// against
+    fn verify_oracle(&self, escrow: &EscrowState) -> Result<()> {
+        require_keys_eq!(
+            self.tensor_oracle.key(),
+            escrow.tensor_oracle,
+            EscrowError::OracleMismatch
+        );
+        Ok(())
+    }
+
+    /// Rejects collection identifiers that don't hash to the ones stored at
+    /// init. The escrow keeps only the hashes, so callers pass the strings
+    /// back in, but a string the escrow wasn't opened on never reaches the
+    /// oracle.
+    fn verify_collections(&self, escrow: &EscrowState) -> Result<()> {
+        require!(
+            hash_collection_id(self.collection_id) == escrow.collection_hash
+                && self.basket_ids.len() == escrow.basket.len()
+                && self
+                    .basket_ids
+                    .iter()
+                    .zip(&escrow.basket)
+                    .all(|(id, hash)| hash_collection_id(id) == *hash),
+            EscrowError::CollectionIdMismatch
+        );
+        Ok(())
+    }
+
+    /// Price from the first of the escrow's sources that gives a fresh,
+    /// confident reading. Every caller, from each settlement path (native,
+    /// wrapped SOL or batched) to accept and the views, prices through here,
+    /// and nothing is read until the oracle and the collection identifiers
+    /// are checked against what the escrow stored. A single-source escrow
+    /// reports that source's own error; with fallbacks, each failure is
+    /// logged and `NoValidPriceSource` returned once all of them fail.
+    ///
+    /// A `Rank` escrow gets the primary collection's floor rank among its
+    /// basket instead of a price.
+    fn price(&self, escrow: &EscrowState, config: &Config, now: i64) -> Result<u64> {
+        self.verify_oracle(escrow)?;
+        self.verify_collections(escrow)?;
+        if escrow.prediction_unit == PredictionUnit::Rank {
+            return self
+                .tensor_oracle
+                .get_floor_rank(self.collection_id, self.basket_ids);
+        }
+
+        let sources = &escrow.price_sources;
+        if let [source] = sources.as_slice() {
+            return self.read_price(*source, escrow, config, now);
+        }
+
+        for source in sources {
+            match self.read_price(*source, escrow, config, now) {
+                Ok(price) => return Ok(price),
+                Err(err) => msg!("{:?} price unavailable: {}", source, err),
+            }
+        }
+        err!(EscrowError::NoValidPriceSource)
+    }
+}
+
+/// Whether a bps-of-entry prediction is a real price, at most a tenfold move
+fn bps_prediction_is_sane(prediction: u64) -> bool {
+    (1..=MAX_PREDICTION_BPS).contains(&prediction)
+}
+
+/// Whether a rank prediction is a place among the primary collection and
+/// its `basket_len` others
+fn rank_prediction_is_sane(prediction: u64, basket_len: usize) -> bool {
+    (1..=basket_len as u64 + 1).contains(&prediction)
+}
+
+/// Who takes the pot if the floor settles at `price`. Ignores status and
+/// expiry, so it also projects the outcome of a bet still running.
+/// `price` must be in the escrow's prediction unit; see
+/// [`EscrowState::price_in_prediction_unit`]
+fn projected_outcome(escrow: &EscrowState, price: u64, config: &Config) -> Outcome {
+    // Equidistant calls optionally reward whoever committed first
+    let tie = match escrow.created_at.cmp(&escrow.accepted_at) {
+        Ordering::Less if config.tie_break_by_time => Outcome::Trader,
+        Ordering::Greater if config.tie_break_by_time => Outcome::Counterparty,
+        _ => Outcome::Split,
+    };
+    determine_winner(
+        escrow.predicted_floor,
+        escrow.counterparty_prediction,
+        price,
+        escrow.direction,
+        escrow.lower_tolerance,
+        escrow.upper_tolerance,
+        tie,
+    )
+}
+
+/// Who takes the pot when the floor settles at `price`, from the bare
+/// terms of the bet. With a `direction` it's over/under on `predicted`;
+/// otherwise, once the counterparty made its own `counter` prediction the
+/// closer call wins and `tie` settles an equidistant one, and without one
+/// the trader wins inside the tolerance band around `predicted`. All
+/// values are in the escrow's prediction unit.
+fn determine_winner(
+    predicted: u64,
+    counter: Option<u64>,
+    price: u64,
+    direction: Option<Direction>,
+    lower_tolerance: u64,
+    upper_tolerance: u64,
+    tie: Outcome,
+) -> Outcome {
+    // Over/under: only the side of the line matters, not how far
+    if let Some(direction) = direction {
+        return match Direction::of(price, predicted) {
+            Some(side) if side == direction => Outcome::Trader,
+            Some(_) => Outcome::Counterparty,
+            None => Outcome::Split,
+        };
+    }
+    match counter {
+        // Both sides predicted: whoever landed closer wins
+        Some(counter) => match predicted.abs_diff(price).cmp(&counter.abs_diff(price)) {
+            Ordering::Less => Outcome::Trader,
+            Ordering::Greater => Outcome::Counterparty,
+            Ordering::Equal => tie,
+        },
+        // Trader wins if prediction is within tolerance
+        None if within_band(predicted, lower_tolerance, upper_tolerance, price) => Outcome::Trader,
+        // Counterparty wins
+        None => Outcome::Counterparty,
+    }
+}
+
+/// Whether `price` lies from `lower` below `predicted` to `upper` above
+/// it, both edges inclusive
+fn within_band(predicted: u64, lower: u64, upper: u64, price: u64) -> bool {
+    let band = if price < predicted { lower } else { upper };
+    predicted.abs_diff(price) <= band
+}
+
+/// A settled escrow, emitted by every settlement path, refunds included.
+/// `distance` is how far the trader's prediction landed from the price, in
+/// the escrow's prediction unit, and `relative_error_bps` that distance in
+/// bps of the price, for off-chain accuracy stats. Both are `None` when no
+/// price was read, e.g. on an arbiter's ruling.
+#[event]
+pub struct EscrowSettled {
+    pub escrow: Pubkey,
+    pub outcome: Outcome,
+    /// Oracle price in lamports, or the floor rank for a `Rank` escrow
+    pub price: Option<u64>,
+    pub predicted_floor: u64,
+    pub distance: Option<u64>,
+    pub relative_error_bps: Option<u64>,
+    pub trader_payout: u64,
+    pub counterparty_payout: u64,
+    pub settled_at: i64,
+}
+
+impl EscrowSettled {
+    /// `unit_price` is the record's price in the escrow's prediction unit
+    fn new(record: &SettlementRecord, unit_price: Option<u64>) -> Self {
+        let error = unit_price.map(|price| prediction_error(record.predicted_floor, price));
+        EscrowSettled {
+            escrow: record.escrow,
+            outcome: record.outcome,
+            price: record.price,
+            predicted_floor: record.predicted_floor,
+            distance: error.map(|(distance, _)| distance),
+            relative_error_bps: error.and_then(|(_, relative)| relative),
+            trader_payout: record.trader_payout,
+            counterparty_payout: record.counterparty_payout,
+            settled_at: record.settled_at,
+        }
+    }
+}
+
+/// `|predicted - price|`, and that distance in bps of `price`, saturating
+/// at `u64::MAX`. There's no relative error against a zero price.
+fn prediction_error(predicted: u64, price: u64) -> (u64, Option<u64>) {
+    let distance = predicted.abs_diff(price);
+    let relative = (price > 0).then(|| {
+        let bps = u128::from(distance) * u128::from(BPS_DENOMINATOR) / u128::from(price);
+        u64::try_from(bps).unwrap_or(u64::MAX)
+    });
+    (distance, relative)
+}
+
+/// A new escrow, emitted when it's opened directly, from a template or as
+/// a rollover
+#[event]
+pub struct EscrowOpened {
+    pub escrow: Pubkey,
+    pub trader: Pubkey,
+    pub collection_hash: [u8; 32],
+    pub category: u8,
+    pub margin_amount: u64,
+    pub expiry_timestamp: i64,
+}
+
+impl EscrowOpened {
+    fn new(key: Pubkey, escrow: &EscrowState) -> Self {
+        EscrowOpened {
+            escrow: key,
+            trader: escrow.trader,
+            collection_hash: escrow.collection_hash,
+            category: escrow.category,
+            margin_amount: escrow.margin_amount,
+            expiry_timestamp: escrow.expiry_timestamp,
+        }
+    }
+}
+
+/// An escrow's status stepping from `from` to `to`, emitted by every
+/// transition. Together with `EscrowOpened`, which marks an escrow coming
+/// into being as `Open`, these replay its full history. `actor` is the
+/// signer behind the step: the accepting counterparty, the cancelling
+/// trader, the admin, the party walking away, or the settling keeper.
+#[event]
+pub struct StatusChanged {
+    pub escrow: Pubkey,
+    pub from: EscrowStatus,
+    pub to: EscrowStatus,
+    pub actor: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Why a `settle_escrow` call failed, emitted just before the error is
+/// returned. Events are Anchor-encoded `Program data:` log lines, which
+/// stay in the logs of failed transactions and simulations:
+///
+/// - `error_name` / `error_code`: the `EscrowError` variant and its number,
+///   or the program error and zero for non-Anchor failures
+/// - `reason`: the error's human-readable message
+/// - `now` / `expiry_timestamp`: cluster time and escrow expiry at the attempt
+#[event]
+pub struct SettleFailed {
+    pub escrow: Pubkey,
+    pub error_name: String,
+    pub error_code: u32,
+    pub reason: String,
+    pub now: i64,
+    pub expiry_timestamp: i64,
+}
+
+impl SettleFailed {
+    fn new(escrow: Pubkey, err: &Error, now: i64, expiry_timestamp: i64) -> Self {
+        let (error_name, error_code, reason) = match err {
+            Error::AnchorError(err) => (
+                err.error_name.clone(),
+                err.error_code_number,
+                err.error_msg.clone(),
+            ),
+            Error::ProgramError(err) => (
+                format!("{:?}", err.program_error),
+                0,
+                err.program_error.to_string(),
+            ),
+        };
+        Self {
+            escrow,
+            error_name,
+            error_code,
+            reason,
+            now,
+            expiry_timestamp,
+        }
+    }
+}
+
+/// Lamport recipients passed as remaining accounts aren't covered by
+/// `#[account(mut)]`, so check before crediting them rather than letting
+/// the runtime reject the whole transaction.
+fn require_writable(account: &AccountInfo) -> Result<()> {
+    require!(account.is_writable, EscrowError::AccountNotWritable);
+    Ok(())
+}
+
+/// Lamports credited to one of this program's own accounts (an escrow,
+/// the vault, a record) fall outside its bookkeeping and are stranded, so
+/// recipients the program doesn't pin by address must be owned elsewhere.
+/// Other programs' accounts, such as a strategy vault PDA, are fine.
+fn require_not_program_owned(account: &AccountInfo) -> Result<()> {
+    require_keys_neq!(*account.owner, crate::ID, EscrowError::ProgramOwnedRecipient);
+    Ok(())
+}
+
+/// Key of the Tensor oracle an escrow is bound to at open. The mock reader
+/// doesn't parse its data, so only its owner is checked: none of this
+/// program's own accounts can be Tensor's oracle.
+fn tensor_oracle_key(account: &AccountInfo) -> Result<Pubkey> {
+    require_keys_neq!(*account.owner, crate::ID, EscrowError::InvalidOracleAccount);
+    Ok(account.key())
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = Config::LEN,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = Stats::LEN,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeAdmin<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    /// Must be the config's `pending_admin`
+    pub new_admin: Signer<'info>,
+
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+#[instruction(params: EscrowParams, index_chunk: u32, idempotency_nonce: u64)]
+pub struct InitializeEscrow<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = trader,
+        space = TraderProfile::LEN,
+        seeds = [b"trader_profile", trader.key().as_ref()],
+        bump
+    )]
+    pub trader_profile: Account<'info, TraderProfile>,
+    
+    #[account(
+        init,
+        payer = trader,
+        space = EscrowState::LEN,
+        seeds = [
+            b"escrow",
+            trader.key().as_ref(),
+            &idempotency_nonce.to_le_bytes()
+        ],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(
+        init_if_needed,
+        payer = trader,
+        space = CollectionIndex::LEN,
+        seeds = [
+            b"collection_index",
+            hash_collection_id(&params.collection_id).as_ref(),
+            &index_chunk.to_le_bytes()
+        ],
+        bump
+    )]
+    pub collection_index: Account<'info, CollectionIndex>,
+
+    /// CHECK: The collection's flags, which needn't exist; only read to
+    /// refuse a paused collection
+    #[account(
+        seeds = [b"collection_state", hash_collection_id(&params.collection_id).as_ref()],
+        bump
+    )]
+    pub collection_state: UncheckedAccount<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+    
+    /// CHECK: This is Tensor's oracle account for floor price
+    pub tensor_oracle: AccountInfo<'info>,
+
+    /// Fee exemption list, only needed when the trader is on it
+    #[account(seeds = [b"fee_exempt"], bump = fee_exempt.bump)]
+    pub fee_exempt: Option<Account<'info, FeeExemptList>>,
+
+    /// CHECK: The oracle registry, which needn't exist; once it does,
+    /// `tensor_oracle` is checked against the oracle it maps the collection to
+    #[account(seeds = [b"oracle_registry"], bump)]
+    pub oracle_registry: UncheckedAccount<'info>,
+
+    /// Trader's wSOL, only needed for `Denomination::WrappedSol` escrows
+    #[account(mut)]
+    pub wsol_source: Option<Account<'info, TokenAccount>>,
+
+    /// CHECK: Uncreated token account the margin is unwrapped through,
+    /// opened and closed again within the instruction
+    #[account(mut, seeds = [b"wsol_scratch", trader.key().as_ref()], bump)]
+    pub wsol_scratch: Option<UncheckedAccount<'info>>,
+
+    #[account(address = token::spl_token::native_mint::ID @ EscrowError::InvalidWsolAccount)]
+    pub native_mint: Option<Account<'info, Mint>>,
+
+    pub token_program: Option<Program<'info, Token>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(template_id: u64)]
+pub struct CreateTemplate<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = Template::LEN,
+        seeds = [b"template", owner.key().as_ref(), &template_id.to_le_bytes()],
+        bump
+    )]
+    pub template: Account<'info, Template>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(collection_id: String, overrides: TemplateOverrides, index_chunk: u32)]
+pub struct CreateFromTemplate<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    #[account(
+        seeds = [b"template", trader.key().as_ref(), &template.template_id.to_le_bytes()],
+        bump = template.bump
+    )]
+    pub template: Account<'info, Template>,
+
+    #[account(
+        init_if_needed,
+        payer = trader,
+        space = TraderProfile::LEN,
+        seeds = [b"trader_profile", trader.key().as_ref()],
+        bump
+    )]
+    pub trader_profile: Account<'info, TraderProfile>,
+
+    #[account(
+        init,
+        payer = trader,
+        space = EscrowState::LEN,
+        seeds = [
+            b"escrow",
+            trader.key().as_ref(),
+            &trader_profile.escrow_count.to_le_bytes()
+        ],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(
+        init_if_needed,
+        payer = trader,
+        space = CollectionIndex::LEN,
+        seeds = [
+            b"collection_index",
+            template.collection_hash.as_ref(),
+            &index_chunk.to_le_bytes()
+        ],
+        bump
+    )]
+    pub collection_index: Account<'info, CollectionIndex>,
+
+    /// CHECK: The collection's flags, which needn't exist; only read to
+    /// refuse a paused collection
+    #[account(seeds = [b"collection_state", template.collection_hash.as_ref()], bump)]
+    pub collection_state: UncheckedAccount<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+
+    /// CHECK: This is Tensor's oracle account for floor price
+    pub tensor_oracle: AccountInfo<'info>,
+
+    /// Fee exemption list, only needed when the trader is on it
+    #[account(seeds = [b"fee_exempt"], bump = fee_exempt.bump)]
+    pub fee_exempt: Option<Account<'info, FeeExemptList>>,
+
+    /// CHECK: The oracle registry, which needn't exist; once it does,
+    /// `tensor_oracle` is checked against the oracle it maps the collection to
+    #[account(seeds = [b"oracle_registry"], bump)]
+    pub oracle_registry: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptEscrow<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+    
+    #[account(mut)]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: The collection's flags, which needn't exist; only read to
+    /// refuse a paused collection
+    #[account(seeds = [b"collection_state", escrow.collection_hash.as_ref()], bump)]
+    pub collection_state: UncheckedAccount<'info>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+
+    /// Maker reward accounts, only needed when `config.reward_mint` is set
+    #[account(mut)]
+    pub reward_mint: Option<Account<'info, Mint>>,
+
+    #[account(mut)]
+    pub maker_reward_account: Option<Account<'info, TokenAccount>>,
+
+    /// CHECK: PDA holding mint authority over the reward mint
+    #[account(seeds = [b"reward_authority"], bump)]
+    pub reward_authority: Option<UncheckedAccount<'info>>,
+
+    pub token_program: Option<Program<'info, Token>>,
+
+    /// CHECK: Tensor's oracle, only needed to read a `BpsFromEntry`
+    /// escrow's entry price
+    pub tensor_oracle: Option<AccountInfo<'info>>,
+
+    /// CHECK: Pyth price update for a `BpsFromEntry` escrow's entry price,
+    /// owner, layout and feed checked by `PythPrice::load`
+    pub pyth_price_update: Option<AccountInfo<'info>>,
+
+    /// Counterparty's wSOL, only needed for `Denomination::WrappedSol`
+    /// escrows, which also need `token_program`
+    #[account(mut)]
+    pub wsol_source: Option<Account<'info, TokenAccount>>,
+
+    /// CHECK: Uncreated token account the stake is unwrapped through,
+    /// opened and closed again within the instruction
+    #[account(mut, seeds = [b"wsol_scratch", trader.key().as_ref()], bump)]
+    pub wsol_scratch: Option<UncheckedAccount<'info>>,
+
+    #[account(address = token::spl_token::native_mint::ID @ EscrowError::InvalidWsolAccount)]
+    pub native_mint: Option<Account<'info, Mint>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> AcceptEscrow<'info> {
+    fn mint_maker_reward(&self, authority_bump: Option<u8>, now: i64) -> Result<()> {
+        let Some(reward_mint) = self.config.reward_mint else {
+            return Ok(());
+        };
+        // Stale orders earn less, and nothing once past the horizon
+        let amount = self.config.maker_reward(now - self.escrow.created_at)?;
+        if amount == 0 {
+            msg!("Escrow sat open past the maker reward horizon, no maker reward");
+            return Ok(());
+        }
+
+        let (Some(mint), Some(maker_account), Some(authority), Some(token_program), Some(bump)) = (
+            &self.reward_mint,
+            &self.maker_reward_account,
+            &self.reward_authority,
+            &self.token_program,
+            authority_bump,
+        ) else {
+            return err!(EscrowError::MissingRewardAccounts);
+        };
+        require_keys_eq!(mint.key(), reward_mint, EscrowError::InvalidRewardAccount);
+        require_keys_eq!(maker_account.mint, reward_mint, EscrowError::InvalidRewardAccount);
+        require_keys_eq!(
+            maker_account.owner,
+            self.escrow.trader,
+            EscrowError::InvalidRewardAccount
+        );
+
+        token::mint_to(
+            CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                MintTo {
+                    mint: mint.to_account_info(),
+                    to: maker_account.to_account_info(),
+                    authority: authority.to_account_info(),
+                },
+                &[&[b"reward_authority", &[bump]]],
+            ),
+            amount,
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct ExtendExpiry<'info> {
+    pub trader: Signer<'info>,
+
+    pub counterparty: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", trader.key().as_ref(), &escrow.nonce.to_le_bytes()],
+        bump,
+        has_one = trader @ EscrowError::Unauthorized
+    )]
+    pub escrow: Account<'info, EscrowState>,
+}
+
+#[derive(Accounts)]
+pub struct ExtendUnaccepted<'info> {
+    pub trader: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", trader.key().as_ref(), &escrow.nonce.to_le_bytes()],
+        bump,
+        has_one = trader @ EscrowError::Unauthorized
+    )]
+    pub escrow: Account<'info, EscrowState>,
+}
+
+#[derive(Accounts)]
+pub struct GetExpectedWinner<'info> {
+    #[account(
+        seeds = [b"escrow", escrow.trader.as_ref(), &escrow.nonce.to_le_bytes()],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: This is Tensor's oracle account for floor price
+    pub tensor_oracle: AccountInfo<'info>,
+
+    /// CHECK: Pyth price update for Pyth-settled escrows, owner, layout and
+    /// feed checked by `PythPrice::load`
+    pub pyth_price_update: Option<AccountInfo<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct GetSummary<'info> {
+    #[account(
+        seeds = [b"escrow", escrow.trader.as_ref(), &escrow.nonce.to_le_bytes()],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: Tensor's oracle account; without it the summary has no
+    /// projection
+    pub tensor_oracle: Option<AccountInfo<'info>>,
+
+    /// CHECK: Pyth price update for Pyth-settled escrows, owner, layout and
+    /// feed checked by `PythPrice::load`
+    pub pyth_price_update: Option<AccountInfo<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct GetEscrowFunding {}
+
+#[derive(Accounts)]
+pub struct IncreaseMargin<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", trader.key().as_ref(), &escrow.nonce.to_le_bytes()],
+        bump,
+        has_one = trader @ EscrowError::Unauthorized
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(
+        mut,
+        seeds = [b"trader_profile", trader.key().as_ref()],
+        bump = trader_profile.bump
+    )]
+    pub trader_profile: Account<'info, TraderProfile>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelEscrow<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", trader.key().as_ref(), &escrow.nonce.to_le_bytes()],
+        bump,
+        has_one = trader @ EscrowError::Unauthorized
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"collection_index",
+            escrow.collection_hash.as_ref(),
+            &escrow.index_chunk.to_le_bytes()
+        ],
+        bump = collection_index.bump
+    )]
+    pub collection_index: Account<'info, CollectionIndex>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+}
+
+#[derive(Accounts)]
+#[instruction(collection_id: String)]
+pub struct SetCollectionDelisted<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = CollectionState::LEN,
+        seeds = [b"collection_state", hash_collection_id(&collection_id).as_ref()],
+        bump
+    )]
+    pub collection_state: Account<'info, CollectionState>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(collection_id: String)]
+pub struct SetCollectionPaused<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = CollectionState::LEN,
+        seeds = [b"collection_state", hash_collection_id(&collection_id).as_ref()],
+        bump
+    )]
+    pub collection_state: Account<'info, CollectionState>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RaiseDispute<'info> {
+    #[account(mut)]
+    pub disputer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", escrow.trader.as_ref(), &escrow.nonce.to_le_bytes()],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(
+        init,
+        payer = disputer,
+        space = Dispute::LEN,
+        seeds = [b"dispute", escrow.key().as_ref()],
+        bump
+    )]
+    pub dispute: Account<'info, Dispute>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ResolveDispute<'info> {
+    pub arbiter: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = arbiter @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", escrow.trader.as_ref(), &escrow.nonce.to_le_bytes()],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(
+        mut,
+        close = disputer,
+        seeds = [b"dispute", escrow.key().as_ref()],
+        bump = dispute.bump
+    )]
+    pub dispute: Account<'info, Dispute>,
+
+    /// CHECK: Gets the dispute account's lamports back, must have raised it
+    #[account(mut, address = dispute.disputer @ EscrowError::InvalidRefundAccount)]
+    pub disputer: AccountInfo<'info>,
+
+    /// CHECK: Receives a rejected dispute's bond, must be `config.treasury`
+    #[account(mut, address = config.treasury @ EscrowError::InvalidTreasuryAccount)]
+    pub treasury: Option<AccountInfo<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseConfig<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        close = recipient,
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, close = recipient, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// CHECK: Receives the refunded rent
+    #[account(mut)]
+    pub recipient: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseCollectionState<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        close = recipient,
+        seeds = [b"collection_state", collection_state.collection_hash.as_ref()],
+        bump = collection_state.bump
+    )]
+    pub collection_state: Account<'info, CollectionState>,
+
+    /// CHECK: Receives the refunded rent
+    #[account(mut)]
+    pub recipient: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct VoidAndRefund<'info> {
+    /// The escrow's trader or counterparty
+    pub party: Signer<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", escrow.trader.as_ref(), &escrow.nonce.to_le_bytes()],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    /// CHECK: Receives the trader's refund, must be the escrow's trader
+    #[account(mut, address = escrow.trader @ EscrowError::InvalidRefundAccount)]
+    pub trader: AccountInfo<'info>,
+
+    /// CHECK: Receives the counterparty's refund, verified against the escrow
+    #[account(mut)]
+    pub counterparty: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"collection_index",
+            escrow.collection_hash.as_ref(),
+            &escrow.index_chunk.to_le_bytes()
+        ],
+        bump = collection_index.bump
+    )]
+    pub collection_index: Account<'info, CollectionIndex>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+
+    /// Dispute the arbiter never ruled on, only needed while one is pending
+    #[account(mut, seeds = [b"dispute", escrow.key().as_ref()], bump = dispute.bump)]
+    pub dispute: Option<Account<'info, Dispute>>,
+
+    /// CHECK: Gets a pending dispute's bond back, must have raised it
+    #[account(mut)]
+    pub disputer: Option<AccountInfo<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct ExitEarly<'info> {
+    /// The escrow's trader or counterparty, whichever is leaving
+    pub party: Signer<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", escrow.trader.as_ref(), &escrow.nonce.to_le_bytes()],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    /// CHECK: Receives the trader's share, must be the escrow's trader
+    #[account(mut, address = escrow.trader @ EscrowError::InvalidRefundAccount)]
+    pub trader: AccountInfo<'info>,
+
+    /// CHECK: Receives the counterparty's share, verified against the escrow
+    #[account(mut)]
+    pub counterparty: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"collection_index",
+            escrow.collection_hash.as_ref(),
+            &escrow.index_chunk.to_le_bytes()
+        ],
+        bump = collection_index.bump
+    )]
+    pub collection_index: Account<'info, CollectionIndex>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+}
+
+#[derive(Accounts)]
+#[instruction(collection_id: String, index_chunk: u32)]
+pub struct MigrateToV2<'info> {
+    /// Either party or the admin; pays for the new accounts
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// CHECK: Only seeds the legacy address; must be its trader
+    pub trader: UncheckedAccount<'info>,
+
+    /// CHECK: An escrow in the first release's layout, parsed and checked
+    /// in the handler, then closed
+    #[account(mut, seeds = [b"escrow", trader.key().as_ref()], bump)]
+    pub legacy_escrow: UncheckedAccount<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = TraderProfile::LEN,
+        seeds = [b"trader_profile", trader.key().as_ref()],
+        bump
+    )]
+    pub trader_profile: Account<'info, TraderProfile>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = EscrowState::LEN,
+        seeds = [
+            b"escrow",
+            trader.key().as_ref(),
+            &trader_profile.escrow_count.to_le_bytes()
+        ],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = CollectionIndex::LEN,
+        seeds = [
+            b"collection_index",
+            hash_collection_id(&collection_id).as_ref(),
+            &index_chunk.to_le_bytes()
+        ],
+        bump
+    )]
+    pub collection_index: Account<'info, CollectionIndex>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// CHECK: Tensor oracle the migrated escrow is bound to
+    pub tensor_oracle: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenBalance<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = Balance::LEN,
+        seeds = [b"balance", owner.key().as_ref()],
+        bump
+    )]
+    pub balance: Account<'info, Balance>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawBalance<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"balance", owner.key().as_ref()],
+        bump = balance.bump,
+        has_one = owner @ EscrowError::Unauthorized
+    )]
+    pub balance: Account<'info, Balance>,
+}
+
+#[derive(Accounts)]
+pub struct CancelDelisted<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        seeds = [b"collection_state", escrow.collection_hash.as_ref()],
+        bump = collection_state.bump,
+        constraint = collection_state.delisted @ EscrowError::CollectionNotDelisted
+    )]
+    pub collection_state: Account<'info, CollectionState>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", escrow.trader.as_ref(), &escrow.nonce.to_le_bytes()],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    /// CHECK: Receives the trader's refund, must be the escrow's trader
+    #[account(mut, address = escrow.trader @ EscrowError::InvalidRefundAccount)]
+    pub trader: AccountInfo<'info>,
+
+    /// CHECK: Receives the counterparty's refund, verified against the escrow.
+    /// Only needed once the escrow has been accepted.
+    #[account(mut)]
+    pub counterparty: Option<AccountInfo<'info>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"collection_index",
+            escrow.collection_hash.as_ref(),
+            &escrow.index_chunk.to_le_bytes()
+        ],
+        bump = collection_index.bump
+    )]
+    pub collection_index: Account<'info, CollectionIndex>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseEscrow<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    #[account(
+        mut,
+        close = trader,
+        seeds = [b"escrow", trader.key().as_ref(), &escrow.nonce.to_le_bytes()],
+        bump,
+        has_one = trader @ EscrowError::Unauthorized
+    )]
+    pub escrow: Account<'info, EscrowState>,
+}
+
+#[derive(Accounts)]
+pub struct SettleEscrow<'info> {
+    /// Anyone may settle an expired escrow and collect the keeper reward
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    /// CHECK: Winner account to receive funds, verified against the computed winner.
+    /// May be owned by any program but this one, e.g. a strategy vault PDA.
+    #[account(mut)]
+    pub winner: AccountInfo<'info>,
+
+    /// CHECK: Receives the winner's share in their place. Only honoured
+    /// when `winner` signs the transaction, and never one of this
+    /// program's own accounts.
+    #[account(mut)]
+    pub payout_recipient: Option<AccountInfo<'info>>,
+
+    /// CHECK: Trader's share of a profit-share pot the counterparty won,
+    /// verified against the escrow
+    #[account(mut)]
+    pub trader: Option<AccountInfo<'info>>,
+
+    /// CHECK: Counterparty's share of a split or profit-share pot, verified
+    /// against the escrow
+    #[account(mut)]
+    pub counterparty: Option<AccountInfo<'info>>,
+
+    /// CHECK: Reward recipient for an upheld dispute, verified against the
+    /// escrow
+    #[account(mut)]
+    pub disputer: Option<AccountInfo<'info>>,
+
+    /// CHECK: Fee recipient, verified against `config.treasury`. Only
+    /// needed while `fee_bps` is non-zero.
+    #[account(mut)]
+    pub treasury: Option<AccountInfo<'info>>,
+    
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    
+    #[account(mut,
+        seeds = [b"escrow", escrow.trader.as_ref(), &escrow.nonce.to_le_bytes()],
+        bump
+    )]
+    pub escrow: Account<'info, EscrowState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"collection_index",
+            escrow.collection_hash.as_ref(),
+            &escrow.index_chunk.to_le_bytes()
+        ],
+        bump = collection_index.bump
+    )]
+    pub collection_index: Account<'info, CollectionIndex>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+    
+    /// CHECK: This is Tensor's oracle account for floor price
+    pub tensor_oracle: AccountInfo<'info>,
+
+    /// CHECK: Pyth price update for Pyth-settled escrows, owner, layout and
+    /// feed checked by `PythPrice::load`
+    pub pyth_price_update: Option<AccountInfo<'info>>,
+
+    /// Winner's balance, to credit their share to rather than pay it out
+    #[account(mut, seeds = [b"balance", winner.key().as_ref()], bump = winner_balance.bump)]
+    pub winner_balance: Option<Account<'info, Balance>>,
+
+    /// The collection's price history, only needed for TWAP-settled escrows
+    #[account(
+        seeds = [b"price_history", escrow.collection_hash.as_ref()],
+        bump = price_history.bump
+    )]
+    pub price_history: Option<Account<'info, PriceHistory>>,
+
+    /// Trader's profile, only needed to roll a win into a new escrow
+    #[account(
+        mut,
+        seeds = [b"trader_profile", escrow.trader.as_ref()],
+        bump = trader_profile.bump
+    )]
+    pub trader_profile: Option<Account<'info, TraderProfile>>,
+
+    /// CHECK: Becomes the follow-on escrow, checked against the trader's
+    /// next escrow address
+    #[account(mut)]
+    pub rollover_escrow: Option<AccountInfo<'info>>,
+
+    /// Payee's wSOL account, which a `Denomination::WrappedSol` escrow pays
+    /// the winner's share into
+    #[account(mut)]
+    pub payee_wsol: Option<Account<'info, TokenAccount>>,
+
+    /// The other side's wSOL account, for a wrapped SOL split or profit share
+    #[account(mut)]
+    pub other_wsol: Option<Account<'info, TokenAccount>>,
+
+    pub token_program: Option<Program<'info, Token>>,
+
+    /// Keeper token reward accounts, only needed under
+    /// `config.keeper_reward_in_token`
+    pub reward_mint: Option<Account<'info, Mint>>,
+
+    #[account(mut, seeds = [b"reward_vault"], bump)]
+    pub reward_vault: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub keeper_reward_account: Option<Account<'info, TokenAccount>>,
+
+    /// CHECK: PDA owning the reward vault
+    #[account(seeds = [b"reward_authority"], bump)]
+    pub reward_authority: Option<UncheckedAccount<'info>>,
+
+    /// Audit trail of this settlement, paid for by the keeper
+    #[account(
+        init,
+        payer = keeper,
+        space = SettlementRecord::LEN,
+        seeds = [b"settlement_record", escrow.key().as_ref()],
+        bump
+    )]
+    pub settlement_record: Account<'info, SettlementRecord>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> SettleEscrow<'info> {
+    /// Closes the settled escrow, refunding its rent to the trader, who is
+    /// `winner` if they took the pot and must come as `trader` otherwise
+    fn close_settled_escrow(&self) -> Result<()> {
+        let trader_key = self.escrow.trader;
+        let trader = std::iter::once(&self.winner)
+            .chain(self.trader.as_ref())
+            .find(|account| account.key() == trader_key)
+            .ok_or(EscrowError::MissingTraderAccount)?;
+        self.escrow.close(trader.clone())
+    }
+
+    /// Pays the keeper `amount` of the reward token from the reward vault,
+    /// or whatever is left in it, returning what was paid
+    fn pay_keeper_token_reward(&self, authority_bump: Option<u8>, amount: u64) -> Result<u64> {
+        if amount == 0 {
+            return Ok(0);
+        }
+        let (
+            Some(reward_mint),
+            Some(mint),
+            Some(reward_vault),
+            Some(keeper_account),
+            Some(authority),
+            Some(token_program),
+            Some(bump),
+        ) = (
+            self.config.reward_mint,
+            &self.reward_mint,
+            &self.reward_vault,
+            &self.keeper_reward_account,
+            &self.reward_authority,
+            &self.token_program,
+            authority_bump,
+        )
+        else {
+            return err!(EscrowError::MissingRewardAccounts);
+        };
+        require_keys_eq!(mint.key(), reward_mint, EscrowError::InvalidRewardAccount);
+        require_keys_eq!(reward_vault.mint, reward_mint, EscrowError::InvalidRewardAccount);
+        require_keys_eq!(keeper_account.mint, reward_mint, EscrowError::InvalidRewardAccount);
+        require_keys_eq!(
+            keeper_account.owner,
+            self.keeper.key(),
+            EscrowError::InvalidRewardAccount
+        );
+
+        // A short vault pays what it holds rather than blocking settlement
+        let paid = amount.min(reward_vault.amount);
+        if paid < amount {
+            msg!("Reward vault is short, keeper paid {} of {} reward tokens", paid, amount);
+        }
+        if paid == 0 {
+            return Ok(0);
+        }
+        token::transfer_checked(
+            CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                TransferChecked {
+                    from: reward_vault.to_account_info(),
+                    mint: mint.to_account_info(),
+                    to: keeper_account.to_account_info(),
+                    authority: authority.to_account_info(),
+                },
+                &[&[b"reward_authority", &[bump]]],
+            ),
+            paid,
+            mint.decimals,
+        )?;
+        Ok(paid)
+    }
+}
+
+#[derive(Accounts)]
+pub struct SettleBatch<'info> {
+    /// Anyone may settle expired escrows and collect the keeper rewards
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    /// CHECK: Fee recipient, verified against `config.treasury`. Only
+    /// needed while `fee_bps` is non-zero.
+    #[account(mut)]
+    pub treasury: Option<AccountInfo<'info>>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump = stats.bump)]
+    pub stats: Account<'info, Stats>,
+
+    /// Shared vault, only needed for escrows opened with `use_vault` set
+    #[account(mut, seeds = [b"vault"], bump = vault.bump)]
+    pub vault: Option<Account<'info, Vault>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(init, payer = admin, space = Vault::LEN, seeds = [b"vault"], bump)]
+    pub vault: Account<'info, Vault>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeRewardVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        address = config.reward_mint.unwrap_or_default() @ EscrowError::InvalidRewardAccount
+    )]
+    pub reward_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"reward_vault"],
+        bump,
+        token::mint = reward_mint,
+        token::authority = reward_authority
+    )]
+    pub reward_vault: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA the vault's tokens are paid out under
+    #[account(seeds = [b"reward_authority"], bump)]
+    pub reward_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeExempt<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = FeeExemptList::LEN,
+        seeds = [b"fee_exempt"],
+        bump
+    )]
+    pub fee_exempt: Account<'info, FeeExemptList>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetOracle<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = OracleRegistry::LEN,
+        seeds = [b"oracle_registry"],
+        bump
+    )]
+    pub oracle_registry: Account<'info, OracleRegistry>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(collection_id: String)]
+pub struct InitializePriceHistory<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ EscrowError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = PriceHistory::LEN,
+        seeds = [b"price_history", hash_collection_id(&collection_id).as_ref()],
+        bump
+    )]
+    pub price_history: Account<'info, PriceHistory>,
+
+    /// CHECK: This is Tensor's oracle account for floor price
+    pub tensor_oracle: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(collection_id: String)]
+pub struct RecordPrice<'info> {
+    #[account(
+        mut,
+        seeds = [b"price_history", hash_collection_id(&collection_id).as_ref()],
+        bump = price_history.bump,
+        has_one = tensor_oracle @ EscrowError::OracleMismatch
+    )]
+    pub price_history: Account<'info, PriceHistory>,
+
+    /// CHECK: Must be the oracle the history was bound to
+    pub tensor_oracle: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(collection_id: String)]
+pub struct GetTwap<'info> {
+    #[account(
+        seeds = [b"price_history", hash_collection_id(&collection_id).as_ref()],
+        bump = price_history.bump
+    )]
+    pub price_history: Account<'info, PriceHistory>,
+}
+
+#[account]
+#[derive(Default)]
+pub struct EscrowState {
+    pub trader: Pubkey,
+    /// Program-wide creation order, from `Stats::total_escrows`. Sits at
+    /// the fixed `SEQUENCE_OFFSET` so clients can page by it with
+    /// `memcmp` filters.
+    pub sequence: u64,
+    /// UI grouping such as blue chip or index, at the fixed
+    /// `CATEGORY_OFFSET` for the same reason
+    pub category: u8,
+    pub counterparty: Option<Pubkey>,
+    /// sha256 of the collection identifier; see [`hash_collection_id`]
+    pub collection_hash: [u8; 32],
+    pub predicted_floor: u64,
+    /// How far below `predicted_floor` the trader still wins
+    pub lower_tolerance: u64,
+    /// How far above `predicted_floor` the trader still wins
+    pub upper_tolerance: u64,
+    pub expiry_timestamp: i64,
+    pub margin_amount: u64,
+    pub status: EscrowStatus,
+    pub counterparty_prediction: Option<u64>,
+    pub created_at: i64,
+    pub accepted_at: i64,
+    pub original_expiry: i64,
+    pub extension_count: u8,
+    pub index_chunk: u32,
+    pub nonce: u64,
+    /// Oracles settlement tries, most preferred first
+    pub price_sources: Vec<PriceSource>,
+    /// Expiry the trader asked for, before rounding to `expiry_granularity_secs`
+    pub requested_expiry: i64,
+    /// Stake ratio of trader to counterparty; see [`EscrowState::counterparty_stake`]
+    pub odds_numerator: u32,
+    pub odds_denominator: u32,
+    /// Hashes of the collections priced alongside the primary one
+    pub basket: Vec<[u8; 32]>,
+    /// Re-escrow the trader's winnings at settlement; see [`Rollover`]
+    pub rollover: Option<Rollover>,
+    /// Watchdog with an open or upheld dispute against the price
+    pub disputer: Option<Pubkey>,
+    /// Outcome the arbiter ruled on an upheld dispute, used in place of the price
+    pub ruling: Option<Outcome>,
+    /// Margins are held in the shared [`Vault`] rather than this account
+    pub vaulted: bool,
+    /// Lamports of the vault that belong to this escrow
+    pub vault_balance: u64,
+    /// What `predicted_floor`, `tolerance` and `counterparty_prediction` are in
+    pub prediction_unit: PredictionUnit,
+    /// Floor at acceptance, which `BpsFromEntry` predictions are relative to
+    pub entry_price: Option<u64>,
+    pub payout_mode: PayoutMode,
+    pub denomination: Denomination,
+    /// Tensor oracle the escrow was opened against; every price read must
+    /// use it
+    pub tensor_oracle: Pubkey,
+    /// Trader's side of `predicted_floor` in an over/under escrow
+    pub direction: Option<Direction>,
+    /// Settles without the treasury fee, because the trader was on the
+    /// fee exemption list when it opened. Rollovers keep it.
+    pub fee_exempt: bool,
+    /// Lamports the trader has actually put in, margin top-ups included.
+    /// `margin_amount` and the odds set the terms; these, summed in
+    /// [`EscrowState::pot`], are what settlement distributes.
+    pub trader_deposit: u64,
+    /// Lamports the counterparty put in on accept
+    pub counterparty_deposit: u64,
+    /// Slot the escrow expires at when opened with `ExpiryKind::Slot`,
+    /// which then decides acceptance and settlement instead of
+    /// `expiry_timestamp`
+    pub expiry_slot: Option<u64>,
+    pub settlement_price: SettlementPriceKind,
+    /// Highest `fee_bps` settlement charges, whatever the config says by then
+    pub max_fee_bps: u16,
+    /// Only price updates from this Pyth feed settle the escrow
+    pub pyth_feed_id: Option<[u8; 32]>,
+}
+
+/// An escrow as clients show it, returned by `get_summary`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct EscrowSummary {
+    pub escrow: Pubkey,
+    pub status: EscrowStatus,
+    pub trader: Pubkey,
+    /// `None` until someone accepts
+    pub counterparty: Option<Pubkey>,
+    pub predicted_floor: u64,
+    pub lower_tolerance: u64,
+    pub upper_tolerance: u64,
+    pub counterparty_prediction: Option<u64>,
+    /// Unit both predictions are in
+    pub prediction_unit: PredictionUnit,
+    /// Price at acceptance, for `PredictionUnit::BpsFromEntry` escrows
+    pub entry_price: Option<u64>,
+    /// The trader's stake
+    pub margin_amount: u64,
+    pub counterparty_stake: u64,
+    pub payout_mode: PayoutMode,
+    pub denomination: Denomination,
+    pub direction: Option<Direction>,
+    pub category: u8,
+    pub created_at: i64,
+    pub accepted_at: i64,
+    pub expiry_timestamp: i64,
+    pub expiry_slot: Option<u64>,
+    /// Who takes the pot if the escrow settled now, as `get_expected_winner`
+    /// would say. `None` unless active with a readable price.
+    pub projected_winner: Option<Outcome>,
+    /// What each side would be paid on `projected_winner`, net of fees and
+    /// the keeper reward at the current time
+    pub projected_payouts: Option<Payouts>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct EscrowParams {
+    pub collection_id: String,
+    pub predicted_floor: u64,
+    /// How far below `predicted_floor` the trader still wins, in the
+    /// prediction unit
+    pub lower_tolerance: u64,
+    /// How far above `predicted_floor` the trader still wins
+    pub upper_tolerance: u64,
+    pub expiry: ExpiryKind,
+    pub margin_amount: u64,
+    /// Oracles to settle on, most preferred first, at most `MAX_PRICE_SOURCES`
+    pub price_sources: Vec<PriceSource>,
+    /// Trader's stake to the counterparty's, 1:1 for an even bet. Neither
+    /// side may outweigh the other by more than `MAX_ODDS_RATIO`.
+    pub odds_numerator: u32,
+    pub odds_denominator: u32,
+    /// Further collections, at most `MAX_BASKET_SIZE`, whose floors are
+    /// added to the primary collection's. `predicted_floor` is then the
+    /// predicted total. Basket escrows settle on Tensor only.
+    pub basket: Vec<String>,
+    /// Opt in to rolling a win into a fresh escrow instead of a payout
+    pub rollover: Option<Rollover>,
+    /// Unit `predicted_floor` and `tolerance` are given in
+    pub prediction_unit: PredictionUnit,
+    pub payout_mode: PayoutMode,
+    pub denomination: Denomination,
+    /// Turns the escrow into an over/under bet on `predicted_floor`: the
+    /// trader takes this side and the counterparty must take the other.
+    /// `None` keeps the prediction market.
+    pub direction: Option<Direction>,
+    /// Grouping for UIs; must be on `Config::categories` when that's set
+    pub category: u8,
+    pub settlement_price: SettlementPriceKind,
+    /// Most `fee_bps` the trader agrees to pay at settlement, so a later
+    /// rise in the config fee doesn't reach this escrow. `u16::MAX` takes
+    /// whatever the fee is then.
+    pub max_fee_bps: u16,
+    /// Pyth feed the escrow settles on; required exactly when
+    /// `price_sources` lists Pyth
+    pub pyth_feed_id: Option<[u8; 32]>,
+}
+
+/// What price an escrow settles on
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SettlementPriceKind {
+    /// A single read of the escrow's price sources at settlement
+    #[default]
+    Spot,
+    /// The collection's `PriceHistory` averaged over the `window_secs`
+    /// before settlement, so a floor pushed around at the expiry instant
+    /// barely moves it. Single-collection escrows only, settled through
+    /// `settle_escrow`; the views still project on the spot price.
+    Twap { window_secs: u32 },
+}
+
+/// Oracle prices a settler is prepared to settle on, `min` to `max`
+/// inclusive, in the same terms as the settlement record's `price`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PriceRange {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl PriceRange {
+    pub fn contains(&self, price: u64) -> bool {
+        (self.min..=self.max).contains(&price)
+    }
+}
+
+/// What an escrow's stakes are paid in and paid out as. Either way the
+/// escrow holds lamports; a `WrappedSol` escrow unwraps each stake from the
+/// depositor's wSOL account as it comes in and wraps each party's payout
+/// back into theirs at settlement. Refunds and cancellations return SOL.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Denomination {
+    #[default]
+    Native,
+    WrappedSol,
+}
+
+/// How an escrow's pot is divided once a side wins outright
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PayoutMode {
+    #[default]
+    WinnerTakesAll,
+    /// Winner takes `PROFIT_PERCENTAGE` of the loser's stake, the loser
+    /// keeps the rest
+    ProfitShare,
+    /// Winner takes the pot less `Config::consolation_bps` of it, which
+    /// goes back to the loser
+    Consolation,
+}
+
+impl PayoutMode {
+    pub fn strategy(self, config: &Config) -> Box<dyn PayoutStrategy> {
+        match self {
+            PayoutMode::WinnerTakesAll => Box::new(WinnerTakesAllPayout),
+            PayoutMode::ProfitShare => Box::new(ProfitSharePayout {
+                profit_percentage: PROFIT_PERCENTAGE,
+            }),
+            PayoutMode::Consolation => Box::new(ConsolationPayout {
+                consolation_bps: config.consolation_bps,
+            }),
+        }
+    }
+}
+
+/// How an escrow's predictions are expressed
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PredictionUnit {
+    /// Floor price in lamports
+    #[default]
+    AbsoluteLamports,
+    /// Floor as basis points of the entry price captured at acceptance, so
+    /// 10_000 is no change and 10_500 a 500 bps rise. Between 1 and
+    /// `MAX_PREDICTION_BPS`; basket escrows can't use it.
+    BpsFromEntry,
+    /// Rank of the primary collection's floor among itself and its basket,
+    /// 1 for the highest, as read by [`TensorOracle::get_floor_rank`]. Needs
+    /// a basket and Tensor as the only price source.
+    Rank,
+}
+
+/// Side of the line in an over/under escrow. The side the final price
+/// lands on wins; a price exactly on the line splits.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Over,
+    Under,
+}
+
+impl Direction {
+    /// Which side of `line` a prediction of `value` takes, `None` on it
+    pub fn of(value: u64, line: u64) -> Option<Direction> {
+        match value.cmp(&line) {
+            Ordering::Greater => Some(Direction::Over),
+            Ordering::Less => Some(Direction::Under),
+            Ordering::Equal => None,
+        }
+    }
+
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::Over => Direction::Under,
+            Direction::Under => Direction::Over,
+        }
+    }
+}
+
+/// When an escrow stops accepting and becomes settleable: at a unix
+/// timestamp, or at a slot for integrators who want a deterministic
+/// deadline
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExpiryKind {
+    UnixTime(i64),
+    Slot(u64),
+}
+
+impl ExpiryKind {
+    /// The requested and effective `expiry_timestamp` of an escrow opened
+    /// at `now`, failing with `InvalidExpiry` unless the expiry is still
+    /// ahead. Unix expiries snap to the configured boundary so settlements
+    /// line up. A slot expiry gets a nominal timestamp at the default slot
+    /// time, which only the seconds-denominated windows after expiry, such
+    /// as the dispute and settlement windows, are measured from.
+    pub fn resolve(self, now: &Clock, config: &Config) -> Result<(i64, i64)> {
+        match self {
+            ExpiryKind::UnixTime(requested) => {
+                let expiry = config.round_expiry(requested);
+                require!(expiry > now.unix_timestamp, EscrowError::InvalidExpiry);
+                Ok((requested, expiry))
+            }
+            ExpiryKind::Slot(slot) => {
+                require!(slot > now.slot, EscrowError::InvalidExpiry);
+                let millis = u128::from(slot - now.slot) * u128::from(DEFAULT_MS_PER_SLOT);
+                let expiry = i64::try_from(millis / 1_000)
+                    .ok()
+                    .and_then(|secs| now.unix_timestamp.checked_add(secs))
+                    .ok_or(EscrowError::MathOverflow)?;
+                Ok((expiry, expiry))
+            }
+        }
+    }
+}
+
+/// Terms for rolling a trader's winnings into a follow-on escrow with the
+/// same collection, prediction and odds
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rollover {
+    /// Seconds from settlement until the follow-on escrow expires, at most
+    /// `MAX_ROLLOVER_DURATION_SECS`
+    pub duration: i64,
+    /// Follow-on escrows still to open, at most `MAX_ROLLOVERS`. Each one
+    /// carries one fewer, and the last pays out normally.
+    pub remaining: u8,
+}
+
+/// Where settlement reads the floor price from
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PriceSource {
+    Tensor,
+    Pyth,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum EscrowStatus {
+    /// Trader's margin is deposited, waiting for a counterparty
+    #[default]
+    Open,
+    /// Both margins are deposited
+    Active,
+    /// Pot has been paid out
+    Settled,
+    /// Trader withdrew before anyone accepted
+    Cancelled,
+    /// Called off without a winner, every margin refunded less any
+    /// early-exit penalty
+    Voided,
+}
+
+impl EscrowStatus {
+    /// Whether the state machine allows moving from this status to `next`
+    pub fn can_become(self, next: EscrowStatus) -> bool {
+        match self {
+            EscrowStatus::Open => matches!(
+                next,
+                EscrowStatus::Active | EscrowStatus::Cancelled | EscrowStatus::Voided
+            ),
+            EscrowStatus::Active => {
+                matches!(next, EscrowStatus::Settled | EscrowStatus::Voided)
+            }
+            EscrowStatus::Settled | EscrowStatus::Cancelled | EscrowStatus::Voided => false,
+        }
+    }
+}
+
+/// Who takes the pot at settlement
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    Trader,
+    Counterparty,
+    /// Equidistant predictions, pot is shared evenly
+    Split,
+    /// No winner: settled past the settlement window or ruled void by the
+    /// arbiter, so each side's stake is returned
+    Refund,
+}
+
+impl EscrowState {
+    /// The escrow PDA `trader` opens at `nonce`
+    pub fn address(trader: &Pubkey, nonce: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"escrow", trader.as_ref(), &nonce.to_le_bytes()],
+            &crate::ID,
+        )
+    }
+
+    /// Moves the escrow at `key` from `from` to `to` on behalf of `actor`,
+    /// emitting `StatusChanged`. Fails with `InvalidStateTransition`,
+    /// leaving the status untouched, unless the escrow is currently in
+    /// `from` and the state machine allows the step. Every status change
+    /// after creation goes through here.
+    pub fn transition(
+        &mut self,
+        key: Pubkey,
+        from: EscrowStatus,
+        to: EscrowStatus,
+        actor: Pubkey,
+        now: i64,
+    ) -> Result<()> {
+        require!(
+            self.status == from && from.can_become(to),
+            EscrowError::InvalidStateTransition
+        );
+        self.status = to;
+        emit!(StatusChanged {
+            escrow: key,
+            from,
+            to,
+            actor,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    /// Converts an oracle price in lamports into the unit the predictions
+    /// are in, rounding down to the basis point for `BpsFromEntry`. A
+    /// `Rank` escrow's oracle reading is already a rank.
+    pub fn price_in_prediction_unit(&self, price: u64) -> Result<u64> {
+        match self.prediction_unit {
+            PredictionUnit::AbsoluteLamports | PredictionUnit::Rank => Ok(price),
+            PredictionUnit::BpsFromEntry => {
+                let entry_price = self
+                    .entry_price
+                    .filter(|&entry_price| entry_price > 0)
+                    .ok_or(EscrowError::MissingEntryPrice)?;
+                u64::try_from(
+                    u128::from(price) * u128::from(BPS_DENOMINATOR) / u128::from(entry_price),
+                )
+                .map_err(|_| error!(EscrowError::MathOverflow))
+            }
+        }
+    }
+
+    /// Byte offset of `sequence` in the account data, discriminator included
+    pub const SEQUENCE_OFFSET: usize = 8 + 32;
+
+    /// Byte offset of `category` in the account data, discriminator included
+    pub const CATEGORY_OFFSET: usize = Self::SEQUENCE_OFFSET + 8;
+
+    pub const LEN: usize = 8 + // discriminator
+        32 + // trader
+        8 + // sequence
+        1 + // category
+        33 + // counterparty (Option<Pubkey>)
+        32 + // collection_hash
+        8 + // predicted_floor
+        8 + // lower_tolerance
+        8 + // upper_tolerance
+        8 + // expiry_timestamp
+        8 + // margin_amount
+        1 + // status
+        9 + // counterparty_prediction (Option<u64>)
+        8 + // created_at
+        8 + // accepted_at
+        8 + // original_expiry
+        1 + // extension_count
+        4 + // index_chunk
+        8 + // nonce
+        4 + MAX_PRICE_SOURCES + // price_sources
+        8 + // requested_expiry
+        4 + // odds_numerator
+        4 + // odds_denominator
+        4 + 32 * MAX_BASKET_SIZE + // basket
+        1 + 8 + 1 + // rollover (Option<Rollover>)
+        33 + // disputer (Option<Pubkey>)
+        2 + // ruling (Option<Outcome>)
+        1 + // vaulted
+        8 + // vault_balance
+        1 + // prediction_unit
+        9 + // entry_price (Option<u64>)
+        1 + // payout_mode
+        1 + // denomination
+        32 + // tensor_oracle
+        2 + // direction (Option<Direction>)
+        1 + // fee_exempt
+        8 + // trader_deposit
+        8 + // counterparty_deposit
+        1 + 8 + // expiry_slot
+        1 + 4 + // settlement_price
+        2 + // max_fee_bps
+        1 + 32; // pyth_feed_id
+
+    /// Lamports the counterparty puts up against `margin_amount`. The
+    /// trader stakes `odds_numerator` for every `odds_denominator` the
+    /// counterparty does, so 3:1 odds have the counterparty risk 1 to win 3.
+    pub fn counterparty_stake(&self) -> Result<u64> {
+        let stake = u128::from(self.margin_amount) * u128::from(self.odds_denominator)
+            / u128::from(self.odds_numerator.max(1));
+        u64::try_from(stake).map_err(|_| error!(EscrowError::MathOverflow))
+    }
+
+    /// Moves expiry out to `new_expiry`, at most `MAX_EXTENSIONS` times and
+    /// no further than `MAX_TOTAL_EXTENSION_SECS` past the original expiry
+    pub fn extend_to(&mut self, new_expiry: i64) -> Result<()> {
+        // Extensions are in unix time, which doesn't move a slot deadline
+        require!(self.expiry_slot.is_none(), EscrowError::InvalidExpiry);
+        require!(new_expiry > self.expiry_timestamp, EscrowError::InvalidExpiry);
+
+        require!(
+            self.extension_count < MAX_EXTENSIONS,
+            EscrowError::MaxExtensionsReached
+        );
+        let total_extension = new_expiry
+            .checked_sub(self.original_expiry)
+            .ok_or(EscrowError::MathOverflow)?;
+        require!(
+            total_extension <= MAX_TOTAL_EXTENSION_SECS,
+            EscrowError::MaxExtensionsReached
+        );
+
+        self.expiry_timestamp = new_expiry;
+        self.extension_count += 1;
+        Ok(())
+    }
+
+    /// Records `amount` more of the shared vault as this escrow's
+    pub fn credit_vault(&mut self, amount: u64) -> Result<()> {
+        self.vault_balance = self
+            .vault_balance
+            .checked_add(amount)
+            .ok_or(EscrowError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Claims `amount` of this escrow's share of the vault, failing with
+    /// `VaultBalanceInsufficient` rather than dipping into other escrows'
+    pub fn debit_vault(&mut self, amount: u64) -> Result<()> {
+        self.vault_balance = self
+            .vault_balance
+            .checked_sub(amount)
+            .ok_or(EscrowError::VaultBalanceInsufficient)?;
+        Ok(())
+    }
+
+    /// Whether `price` lands in the band from `lower_tolerance` below
+    /// `predicted_floor` to `upper_tolerance` above it. Both edges are
+    /// inclusive: a price exactly on an edge still counts as a hit for the
+    /// trader, one lamport further does not.
+    pub fn within_tolerance(&self, price: u64) -> bool {
+        within_band(
+            self.predicted_floor,
+            self.lower_tolerance,
+            self.upper_tolerance,
+            price,
+        )
+    }
+
+    /// Checks `acceptor` may take the other side at `now`: the escrow is
+    /// still open at `expected_margin`, and isn't their own, which fails
+    /// with `SelfTrade`. Every accept path runs it, whatever the
+    /// denomination or whoever signs.
+    pub fn check_accept(&self, acceptor: &Pubkey, expected_margin: u64, now: &Clock) -> Result<()> {
+        require!(self.status != EscrowStatus::Settled, EscrowError::AlreadySettled);
+        require!(self.status == EscrowStatus::Open, EscrowError::NotOpen);
+        require_keys_neq!(self.trader, *acceptor, EscrowError::SelfTrade);
+        require!(self.margin_amount == expected_margin, EscrowError::MarginMismatch);
+        require!(self.accepts_at(now), EscrowError::Expired);
+        Ok(())
+    }
+
+    /// Whether a counterparty may still accept at `now`. The deadline is
+    /// exclusive: at `expiry_timestamp` itself, or `expiry_slot` for a
+    /// slot-based escrow, the escrow no longer accepts.
+    pub fn accepts_at(&self, now: &Clock) -> bool {
+        !self.settles_at(now)
+    }
+
+    /// Whether the escrow may settle at `now`, from `expiry_timestamp`, or
+    /// `expiry_slot` for a slot-based escrow, inclusive. Together with
+    /// [`EscrowState::accepts_at`] every moment belongs to exactly one of
+    /// the two windows.
+    pub fn settles_at(&self, now: &Clock) -> bool {
+        match self.expiry_slot {
+            Some(slot) => now.slot >= slot,
+            None => now.unix_timestamp >= self.expiry_timestamp,
+        }
+    }
+
+    /// What voiding or refunding the escrow hands back: exactly what each
+    /// side deposited
+    pub fn refunds(&self) -> Payouts {
+        Payouts {
+            trader: self.trader_deposit,
+            counterparty: self.counterparty_deposit,
+        }
+    }
+
+    /// Everything both sides deposited, all of it paid out at settlement
+    pub fn pot(&self) -> Result<u64> {
+        self.trader_deposit
+            .checked_add(self.counterparty_deposit)
+            .ok_or(error!(EscrowError::MathOverflow))
+    }
+
+    /// The open escrow a trader win settled at `now` rolls into, staking
+    /// `margin_amount` on the same terms. `None` without a rollover, or if
+    /// the margin wouldn't make a valid escrow. The nonce is left for the
+    /// caller to assign.
+    pub fn rolled_over(
+        &self,
+        margin_amount: u64,
+        now: i64,
+        config: &Config,
+    ) -> Result<Option<EscrowState>> {
+        let Some(rollover) = self.rollover else {
+            return Ok(None);
+        };
+        let requested_expiry = now
+            .checked_add(rollover.duration)
+            .ok_or(EscrowError::MathOverflow)?;
+        let expiry_timestamp = config.round_expiry(requested_expiry);
+
+        let followup = EscrowState {
+            trader: self.trader,
+            collection_hash: self.collection_hash,
+            predicted_floor: self.predicted_floor,
+            lower_tolerance: self.lower_tolerance,
+            upper_tolerance: self.upper_tolerance,
+            expiry_timestamp,
+            margin_amount,
+            status: EscrowStatus::Open,
+            created_at: now,
+            original_expiry: expiry_timestamp,
+            index_chunk: self.index_chunk,
+            price_sources: self.price_sources.clone(),
+            requested_expiry,
+            odds_numerator: self.odds_numerator,
+            odds_denominator: self.odds_denominator,
+            basket: self.basket.clone(),
+            prediction_unit: self.prediction_unit,
+            payout_mode: self.payout_mode,
+            denomination: self.denomination,
+            tensor_oracle: self.tensor_oracle,
+            direction: self.direction,
+            category: self.category,
+            fee_exempt: self.fee_exempt,
+            trader_deposit: margin_amount,
+            settlement_price: self.settlement_price,
+            max_fee_bps: self.max_fee_bps,
+            pyth_feed_id: self.pyth_feed_id,
+            rollover: match rollover.remaining {
+                0 | 1 => None,
+                remaining => Some(Rollover {
+                    remaining: remaining - 1,
+                    ..rollover
+                }),
+            },
+            ..EscrowState::default()
+        };
+        let viable = margin_amount > 0
+            && config.allows_margin(margin_amount)
+            && expiry_timestamp > now
+            && followup.counterparty_stake()? > 0;
+        Ok(viable.then_some(followup))
+    }
+}
+
+/// `EscrowState` as the first release laid it out, at `["escrow", trader]`.
+/// It shares the current discriminator, so only its length tells it apart;
+/// `migrate_to_v2` is all that reads it.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegacyEscrowState {
+    pub trader: Pubkey,
+    pub counterparty: Option<Pubkey>,
+    pub collection_id: String,
+    pub predicted_floor: u64,
+    pub expiry_timestamp: i64,
+    pub margin_amount: u64,
+    pub is_initialized: bool,
+    pub settled: bool,
+}
+
+impl LegacyEscrowState {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // trader
+        33 + // counterparty (Option<Pubkey>)
+        36 + // collection_id (max 32 chars + 4 bytes for length)
+        8 + // predicted_floor
+        8 + // expiry_timestamp
+        8 + // margin_amount
+        1 + // is_initialized
+        1; // settled
+
+    /// Band, in lamports either side of the prediction, the first release
+    /// settled with
+    pub const TOLERANCE: u64 = 100;
+
+    fn load(account: &AccountInfo) -> Result<Self> {
+        require_keys_eq!(*account.owner, crate::ID, EscrowError::NotLegacyEscrow);
+        let data = account.try_borrow_data()?;
+        require!(
+            data.len() == Self::LEN && data.starts_with(EscrowState::DISCRIMINATOR),
+            EscrowError::NotLegacyEscrow
+        );
+        Self::deserialize(&mut &data[8..]).map_err(|_| error!(EscrowError::NotLegacyEscrow))
+    }
+
+    /// Stakes the account holds on top of its rent: both sides put up the
+    /// same margin
+    fn held(&self) -> Result<u64> {
+        match self.counterparty {
+            Some(_) => self
+                .margin_amount
+                .checked_mul(2)
+                .ok_or(error!(EscrowError::MathOverflow)),
+            None => Ok(self.margin_amount),
+        }
+    }
+}
+
+/// Per-trader bookkeeping. `escrow_count` seeds the trader's next escrow,
+/// so a trader can keep any number of escrows open. It always stays past
+/// the highest nonce opened, including client-chosen ones.
+#[account]
+#[derive(Default)]
+pub struct TraderProfile {
+    pub trader: Pubkey,
+    pub escrow_count: u64,
+    pub bump: u8,
+    /// UTC day, as days since the epoch, that `daily_volume` covers
+    pub volume_day: i64,
+    /// Margin the trader has opened escrows with during `volume_day`
+    pub daily_volume: u64,
+}
+
+impl TraderProfile {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // trader
+        8 + // escrow_count
+        1 + // bump
+        8 + // volume_day
+        8; // daily_volume
+
+    /// Adds `amount` to the trader's volume for the UTC day containing
+    /// `now`, starting from zero once the day changes. Fails with
+    /// `DailyLimitExceeded` if that would pass `limit`; zero means no limit.
+    pub fn record_volume(&mut self, amount: u64, now: i64, limit: u64) -> Result<()> {
+        let day = now.div_euclid(SECONDS_PER_DAY);
+        let volume = if day == self.volume_day {
+            self.daily_volume
+        } else {
+            0
+        }
+        .checked_add(amount)
+        .ok_or(EscrowError::MathOverflow)?;
+        require!(
+            limit == 0 || volume <= limit,
+            EscrowError::DailyLimitExceeded
+        );
+
+        self.volume_day = day;
+        self.daily_volume = volume;
+        Ok(())
+    }
+}
+
+/// Reusable escrow defaults for traders who open many similar escrows
+#[account]
+pub struct Template {
+    pub owner: Pubkey,
+    pub template_id: u64,
+    pub collection_hash: [u8; 32],
+    pub predicted_floor: u64,
+    pub tolerance: u64,
+    pub margin_amount: u64,
+    /// Seconds from creation until the escrow expires
+    pub duration: i64,
+    pub bump: u8,
+}
+
+impl Template {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // owner
+        8 + // template_id
+        32 + // collection_hash
+        8 + // predicted_floor
+        8 + // tolerance
+        8 + // margin_amount
+        8 + // duration
+        1; // bump
+
+    /// Escrow terms from this template, with any overrides applied on top.
+    /// Only the hash of the collection is kept, so the caller passes the
+    /// full identifier back in. `max_fee_bps` is the fee the trader accepts,
+    /// normally the one in force as the escrow opens.
+    pub fn escrow_params(
+        &self,
+        collection_id: String,
+        overrides: TemplateOverrides,
+        max_fee_bps: u16,
+        now: i64,
+    ) -> Result<EscrowParams> {
+        let duration = overrides.duration.unwrap_or(self.duration);
+        require!(duration > 0, EscrowError::InvalidExpiry);
+
+        require!(
+            hash_collection_id(&collection_id) == self.collection_hash,
+            EscrowError::CollectionIdMismatch
+        );
+
+        Ok(EscrowParams {
+            collection_id,
+            predicted_floor: overrides.predicted_floor.unwrap_or(self.predicted_floor),
+            lower_tolerance: overrides.tolerance.unwrap_or(self.tolerance),
+            upper_tolerance: overrides.tolerance.unwrap_or(self.tolerance),
+            expiry: ExpiryKind::UnixTime(
+                now.checked_add(duration).ok_or(EscrowError::MathOverflow)?,
+            ),
+            margin_amount: overrides.margin_amount.unwrap_or(self.margin_amount),
+            price_sources: vec![PriceSource::Tensor],
+            odds_numerator: 1,
+            odds_denominator: 1,
+            basket: Vec::new(),
+            rollover: None,
+            prediction_unit: PredictionUnit::AbsoluteLamports,
+            payout_mode: PayoutMode::WinnerTakesAll,
+            denomination: Denomination::Native,
+            direction: None,
+            category: 0,
+            settlement_price: SettlementPriceKind::Spot,
+            max_fee_bps,
+            pyth_feed_id: None,
+        })
+    }
+}
+
+/// What opening an escrow takes, returned by `get_escrow_funding`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EscrowFunding {
+    pub escrow: Pubkey,
+    /// Rent-exempt minimum of the escrow account
+    pub rent: u64,
+    /// Rent plus the margin, which the trader pays on top of transaction
+    /// fees and the rent of a profile or index chunk opened alongside
+    pub required_lamports: u64,
+}
+
+impl EscrowFunding {
+    pub fn new(trader: &Pubkey, nonce: u64, margin_amount: u64, rent: &Rent) -> Result<Self> {
+        let rent = Self::escrow_rent(rent);
+        Ok(EscrowFunding {
+            escrow: EscrowState::address(trader, nonce).0,
+            rent,
+            required_lamports: rent
+                .checked_add(margin_amount)
+                .ok_or(EscrowError::MathOverflow)?,
+        })
+    }
+
+    /// Rent `initialize_escrow` holds the new escrow to
+    pub fn escrow_rent(rent: &Rent) -> u64 {
+        rent.minimum_balance(EscrowState::LEN)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct TemplateParams {
+    pub collection_id: String,
+    pub predicted_floor: u64,
+    pub tolerance: u64,
+    pub margin_amount: u64,
+    pub duration: i64,
+}
+
+/// Fields to change from the template for a single escrow; the collection
+/// always comes from the template
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct TemplateOverrides {
+    pub predicted_floor: Option<u64>,
+    pub tolerance: Option<u64>,
+    pub margin_amount: Option<u64>,
+    pub duration: Option<i64>,
+}
+
+/// Current cluster time. Sysvar failures surface as `ClockUnavailable` so
+/// they can be told apart from escrow logic errors.
+pub fn current_timestamp() -> Result<i64> {
+    timestamp_from(Clock::get())
+}
+
+fn timestamp_from(clock: std::result::Result<Clock, ProgramError>) -> Result<i64> {
+    clock
+        .map(|clock| clock.unix_timestamp)
+        .map_err(|_| error!(EscrowError::ClockUnavailable))
+}
+
+/// The whole cluster clock, for checks against a slot-based expiry, with
+/// the same `ClockUnavailable` mapping as [`current_timestamp`]
+pub fn current_clock() -> Result<Clock> {
+    Clock::get().map_err(|_| error!(EscrowError::ClockUnavailable))
+}
+
+/// On-chain key for a collection identifier of any length. Accounts store
+/// and derive from this hash; the full identifier only travels in
+/// instruction data, for hashing and oracle lookups.
+pub fn hash_collection_id(collection_id: &str) -> [u8; 32] {
+    hash(collection_id.as_bytes()).to_bytes()
+}
+
+/// `amount` base units as a decimal with `decimals` places, without
+/// trailing zeros: `format_amount(1_500, 3)` is `"1.5"`, `format_amount(2_000,
+/// 3)` is `"2"`. Exact, never rounded, so SDKs and CLIs showing predictions
+/// and margins agree with the program to the base unit.
+pub fn format_amount(amount: u64, decimals: u8) -> String {
+    let decimals = usize::from(decimals);
+    let digits = format!("{amount:0>width$}", width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{whole}.{fraction}")
+    }
+}
+
+/// Inverse of [`format_amount`]: `"1.5"` with 3 decimals is `1_500`. Accepts
+/// plain decimals only (no sign, exponent or separators) and returns `None`
+/// rather than rounding when there are more than `decimals` fractional
+/// digits, or when the amount doesn't fit a `u64`.
+pub fn parse_amount(text: &str, decimals: u8) -> Option<u64> {
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty())
+        || !is_digits(whole)
+        || !is_digits(fraction)
+        || (text.contains('.') && fraction.is_empty())
+        || fraction.len() > usize::from(decimals)
+    {
+        return None;
+    }
+    let scale = 10u64.checked_pow(u32::from(decimals))?;
+    let whole = if whole.is_empty() { 0 } else { whole.parse::<u64>().ok()? };
+    let fraction = if fraction.is_empty() {
+        0
+    } else {
+        let missing = u32::try_from(usize::from(decimals) - fraction.len()).ok()?;
+        let padding = 10u64.checked_pow(missing)?;
+        fraction.parse::<u64>().ok()?.checked_mul(padding)?
+    };
+    whole.checked_mul(scale)?.checked_add(fraction)
+}
+
+/// Lamports as SOL, e.g. `"10.25"`; see [`format_amount`].
+pub fn format_lamports_as_sol(lamports: u64) -> String {
+    format_amount(lamports, SOL_DECIMALS)
+}
+
+/// SOL as lamports, e.g. `"10.25"` is `10_250_000_000`; see [`parse_amount`].
+pub fn parse_sol_as_lamports(sol: &str) -> Option<u64> {
+    parse_amount(sol, SOL_DECIMALS)
+}
+
+/// One page of the escrows still open or active for a collection.
+///
+/// Derived from `["collection_index", collection_hash, chunk (u32 LE)]`; see
+/// [`CollectionIndex::address`].
+#[account]
+pub struct CollectionIndex {
+    pub collection_hash: [u8; 32],
+    pub chunk: u32,
+    pub escrows: Vec<Pubkey>,
+    pub bump: u8,
+}
+
+impl CollectionIndex {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // collection_hash
+        4 + // chunk
+        4 + 32 * COLLECTION_INDEX_CAPACITY + // escrows
+        1; // bump
+
+    pub fn address(collection_id: &str, chunk: u32) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[
+                b"collection_index",
+                &hash_collection_id(collection_id),
+                &chunk.to_le_bytes(),
+            ],
+            &crate::ID,
+        )
+    }
+
+    pub fn remove(&mut self, escrow: &Pubkey) {
+        self.escrows.retain(|key| key != escrow);
+    }
+}
+
+/// Per-collection flags, derived from `["collection_state", collection_hash]`
+#[account]
+pub struct CollectionState {
+    pub collection_hash: [u8; 32],
+    /// The oracle no longer prices this collection
+    pub delisted: bool,
+    /// No new escrows open or get accepted on this collection
+    pub paused: bool,
+    pub bump: u8,
+}
+
+impl CollectionState {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // collection_hash
+        1 + // delisted
+        1 + // paused
+        1; // bump
+
+    /// Whether `info`, a collection's `["collection_state"]` address, holds
+    /// flags pausing it. A collection nobody has set flags for runs as
+    /// normal.
+    pub fn is_paused(info: &AccountInfo) -> Result<bool> {
+        if info.owner != &crate::ID || info.data_is_empty() {
+            return Ok(false);
+        }
+        let data = info.try_borrow_data()?;
+        Ok(CollectionState::try_deserialize(&mut &data[..])?.paused)
+    }
+}
+
+/// A watchdog's challenge to an escrow's settlement price, holding their
+/// bond. Derived from `["dispute", escrow]`, so each escrow has at most one.
+#[account]
+pub struct Dispute {
+    pub escrow: Pubkey,
+    pub disputer: Pubkey,
+    pub bond: u64,
+    pub raised_at: i64,
+    pub bump: u8,
+}
+
+impl Dispute {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // escrow
+        32 + // disputer
+        8 + // bond
+        8 + // raised_at
+        1; // bump
+}
+
+/// What a settlement paid out and why, written once per escrow at
+/// `["settlement_record", escrow]` so settlements can be exported and
+/// audited after the escrow itself is closed
+#[account]
+pub struct SettlementRecord {
+    pub escrow: Pubkey,
+    pub trader: Pubkey,
+    pub counterparty: Pubkey,
+    pub predicted_floor: u64,
+    pub counterparty_prediction: Option<u64>,
+    /// Oracle floor the outcome was decided on, or its rank for a `Rank`
+    /// escrow. `None` when an upheld dispute's ruling decided it
+    pub price: Option<u64>,
+    pub outcome: Outcome,
+    pub trader_payout: u64,
+    pub counterparty_payout: u64,
+    pub keeper: Pubkey,
+    pub keeper_reward: u64,
+    pub treasury_fee: u64,
+    pub dispute_reward: u64,
+    /// Follow-on escrow the trader's payout rolled into, if any
+    pub rollover_escrow: Option<Pubkey>,
+    pub settled_at: i64,
+    /// Reward token base units the keeper was paid from the reward vault,
+    /// under `keeper_reward_in_token`
+    pub keeper_token_reward: u64,
+    pub bump: u8,
+}
+
+impl SettlementRecord {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // escrow
+        32 + // trader
+        32 + // counterparty
+        8 + // predicted_floor
+        1 + 8 + // counterparty_prediction
+        1 + 8 + // price
+        1 + // outcome
+        8 + // trader_payout
+        8 + // counterparty_payout
+        32 + // keeper
+        8 + // keeper_reward
+        8 + // treasury_fee
+        8 + // dispute_reward
+        1 + 32 + // rollover_escrow
+        8 + // settled_at
+        8 + // keeper_token_reward
+        1; // bump
+}
+
+/// Wallets, at most `MAX_FEE_EXEMPT`, whose escrows settle without the
+/// treasury fee, derived from `["fee_exempt"]`. The maker decides: an
+/// escrow is exempt when its trader is listed at open, whoever accepts it,
+/// and stays exempt if the wallet is later removed.
+#[account]
+pub struct FeeExemptList {
+    pub wallets: Vec<Pubkey>,
+    pub bump: u8,
+}
+
+impl FeeExemptList {
+    pub const LEN: usize = 8 + // discriminator
+        4 + 32 * MAX_FEE_EXEMPT + // wallets
+        1; // bump
+
+    /// Whether `list` was passed and has `wallet` on it
+    fn lists(list: Option<&FeeExemptList>, wallet: &Pubkey) -> bool {
+        list.is_some_and(|list| list.wallets.contains(wallet))
+    }
+}
+
+/// One collection's entry in the `OracleRegistry`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OracleMapping {
+    pub collection_hash: [u8; 32],
+    pub oracle: Pubkey,
+}
+
+/// The Tensor oracle each collection is priced by, at most
+/// `MAX_REGISTERED_ORACLES` of them, derived from `["oracle_registry"]`.
+/// Every escrow opens against the registry's address, and once it exists
+/// the program resolves the collection itself and rejects any other
+/// oracle. The escrow stays bound to the oracle it was opened with, so
+/// remapping a collection only affects escrows opened afterwards.
+#[account]
+pub struct OracleRegistry {
+    pub mappings: Vec<OracleMapping>,
+    pub bump: u8,
+}
+
+impl OracleRegistry {
+    pub const LEN: usize = 8 + // discriminator
+        4 + (32 + 32) * MAX_REGISTERED_ORACLES + // mappings
+        1; // bump
+
+    /// The registry at `info`, its `["oracle_registry"]` address, or `None`
+    /// before the admin has created it
+    pub fn load(info: &AccountInfo) -> Result<Option<Self>> {
+        if info.owner != &crate::ID || info.data_is_empty() {
+            return Ok(None);
+        }
+        let data = info.try_borrow_data()?;
+        Ok(Some(OracleRegistry::try_deserialize(&mut &data[..])?))
+    }
+
+    /// The oracle `collection_hash` is mapped to, if any
+    pub fn resolve(&self, collection_hash: &[u8; 32]) -> Option<Pubkey> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.collection_hash == *collection_hash)
+            .map(|mapping| mapping.oracle)
+    }
+
+    /// Points `collection_hash` at `oracle`, replacing any earlier mapping,
+    /// or removes it for `None`
+    fn set(&mut self, collection_hash: [u8; 32], oracle: Option<Pubkey>) -> Result<()> {
+        self.mappings.retain(|mapping| mapping.collection_hash != collection_hash);
+        if let Some(oracle) = oracle {
+            require!(
+                self.mappings.len() < MAX_REGISTERED_ORACLES,
+                EscrowError::OracleRegistryFull
+            );
+            self.mappings.push(OracleMapping {
+                collection_hash,
+                oracle,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// One floor reading in a `PriceHistory`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PriceSample {
+    pub price: u64,
+    pub timestamp: i64,
+}
+
+/// A collection's most recent floor prices, at most `MAX_PRICE_SAMPLES` of
+/// them, derived from `["price_history", collection_hash]`. Created by the
+/// admin for the collections worth averaging, then cranked by anyone
+/// through `record_price`. Once full, each sample overwrites the oldest.
+#[account]
+pub struct PriceHistory {
+    pub collection_hash: [u8; 32],
+    /// The only oracle samples are read from
+    pub tensor_oracle: Pubkey,
+    pub samples: Vec<PriceSample>,
+    /// Slot the next sample overwrites once `samples` is full, i.e. the
+    /// oldest one; zero until then
+    pub head: u8,
+    pub bump: u8,
+}
+
+impl PriceHistory {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // collection_hash
+        32 + // tensor_oracle
+        4 + (8 + 8) * MAX_PRICE_SAMPLES + // samples
+        1 + // head
+        1; // bump
+
+    /// Samples oldest first
+    pub fn chronological(&self) -> impl Iterator<Item = &PriceSample> {
+        let (newer, older) = self.samples.split_at(usize::from(self.head));
+        older.iter().chain(newer)
+    }
+
+    /// Records `price` as of `timestamp`, which must be later than the
+    /// newest sample
+    fn push(&mut self, price: u64, timestamp: i64) -> Result<()> {
+        if let Some(newest) = self.chronological().last() {
+            require!(timestamp > newest.timestamp, EscrowError::PriceAlreadyRecorded);
+        }
+        let sample = PriceSample { price, timestamp };
+        if self.samples.len() < MAX_PRICE_SAMPLES {
+            self.samples.push(sample);
+        } else if let Some(oldest) = self.samples.get_mut(usize::from(self.head)) {
+            *oldest = sample;
+            self.head = (self.head + 1) % MAX_PRICE_SAMPLES as u8;
+        }
+        Ok(())
+    }
+
+    /// Time-weighted average price over the `window_secs` up to `now`. Each
+    /// sample holds until the next one, so a sample from before the window
+    /// prices its start. `None` when no sample covers any of the window.
+    pub fn twap(&self, window_secs: u32, now: i64) -> Option<u64> {
+        let start = now.saturating_sub(i64::from(window_secs));
+        // Each span is at most `window_secs`, so neither sum can overflow
+        let mut weighted = 0u128;
+        let mut covered = 0u128;
+        let mut samples = self.chronological().peekable();
+        while let Some(sample) = samples.next() {
+            let until = samples.peek().map_or(now, |next| next.timestamp).min(now);
+            let from = sample.timestamp.max(start);
+            if until > from {
+                let span = u128::from(until.abs_diff(from));
+                weighted += u128::from(sample.price) * span;
+                covered += span;
+            }
+        }
+        (covered > 0).then(|| weighted / covered).and_then(|twap| u64::try_from(twap).ok())
+    }
+
+    /// How many samples were recorded at or after `start`
+    pub fn samples_since(&self, start: i64) -> usize {
+        self.samples.iter().filter(|sample| sample.timestamp >= start).count()
+    }
+
+    /// The TWAP `escrow` settles on, from a history fed by the oracle it
+    /// was opened against and sampled at least `config.min_price_samples`
+    /// times inside the window
+    fn settlement_twap(
+        &self,
+        escrow: &EscrowState,
+        window_secs: u32,
+        config: &Config,
+        now: i64,
+    ) -> Result<u64> {
+        require_keys_eq!(self.tensor_oracle, escrow.tensor_oracle, EscrowError::OracleMismatch);
+        require!(
+            self.samples_since(now.saturating_sub(i64::from(window_secs)))
+                >= usize::from(config.min_price_samples),
+            EscrowError::TooFewPriceSamples
+        );
+        self.twap(window_secs, now).ok_or(error!(EscrowError::InsufficientPriceHistory))
+    }
+}
+
+/// Program-wide pool for the margins of escrows opened while `use_vault`
+/// is set, derived from `["vault"]`. Each escrow's share is tracked in its
+/// `vault_balance`, and their sum in `tracked`.
+#[account]
+pub struct Vault {
+    /// Lamports held for escrows on top of the vault's rent
+    pub tracked: u64,
+    pub bump: u8,
+}
+
+impl Vault {
+    pub const LEN: usize = 8 + // discriminator
+        8 + // tracked
+        1; // bump
+
+    /// Records `amount` deposited into the vault for an escrow
+    pub fn credit(&mut self, amount: u64) -> Result<()> {
+        self.tracked = self
+            .tracked
+            .checked_add(amount)
+            .ok_or(EscrowError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Releases `amount` of the tracked margins, failing with
+    /// `VaultReserveMismatch` rather than paying out more than escrows hold
+    pub fn debit(&mut self, amount: u64) -> Result<()> {
+        self.tracked = self
+            .tracked
+            .checked_sub(amount)
+            .ok_or(EscrowError::VaultReserveMismatch)?;
+        Ok(())
+    }
+}
+
+/// Winnings credited to `owner` by settlements, derived from
+/// `["balance", owner]`, until `withdraw_balance` sweeps them out. The
+/// account holds `amount` on top of its rent.
+#[account]
+pub struct Balance {
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub bump: u8,
+}
+
+impl Balance {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // owner
+        8 + // amount
+        1; // bump
+
+    fn credit(&mut self, amount: u64) -> Result<()> {
+        self.amount = self
+            .amount
+            .checked_add(amount)
+            .ok_or(EscrowError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Empties the balance, returning what it held
+    fn take(&mut self) -> u64 {
+        std::mem::take(&mut self.amount)
+    }
+}
+
+/// Program-wide settings, owned by the admin
+#[account]
+#[derive(Default)]
+pub struct Config {
+    pub admin: Pubkey,
+    pub keeper_reward_base_bps: u16,
+    pub keeper_reward_step_bps: u16,
+    pub keeper_reward_step_secs: u32,
+    pub keeper_reward_max_bps: u16,
+    pub tie_break_by_time: bool,
+    pub max_tvl: u64,
+    pub reward_mint: Option<Pubkey>,
+    pub maker_reward_amount: u64,
+    pub max_confidence_bps: u16,
+    pub max_price_age_secs: u32,
+    pub fee_bps: u16,
+    pub treasury: Pubkey,
+    pub max_margin: u64,
+    pub expiry_granularity_secs: u32,
+    pub accept_cooldown_secs: u32,
+    pub daily_volume_limit: u64,
+    pub arbiter: Pubkey,
+    pub dispute_bond: u64,
+    pub dispute_reward_bps: u16,
+    pub dispute_window_secs: u32,
+    pub duplicate_prediction_epsilon: u64,
+    pub use_vault: bool,
+    pub prediction_tick: u64,
+    pub settle_allowlist: Vec<Pubkey>,
+    pub market_maker: Option<Pubkey>,
+    pub mm_max_margin: u64,
+    /// Hashes of the collections the market maker takes
+    pub mm_collections: Vec<[u8; 32]>,
+    pub void_grace_secs: u32,
+    pub loser_pays_costs: bool,
+    pub settlement_window_secs: u32,
+    pub maker_reward_horizon_secs: u32,
+    pub categories: Vec<u8>,
+    pub early_exit_penalty_bps: u16,
+    pub min_observation_period_secs: u32,
+    pub consolation_bps: u16,
+    pub max_tolerance_bps: u16,
+    pub keeper_reward_in_token: bool,
+    pub min_price_samples: u8,
+    /// Offered the admin role by `propose_admin`, until they accept it
+    pub pending_admin: Option<Pubkey>,
+    pub min_update_age_secs: u32,
+    pub payout_allowlist: Vec<Pubkey>,
+    pub close_on_settle: bool,
+    pub bump: u8,
+}
+
+impl Config {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // admin
+        2 + // keeper_reward_base_bps
+        2 + // keeper_reward_step_bps
+        4 + // keeper_reward_step_secs
+        2 + // keeper_reward_max_bps
+        1 + // tie_break_by_time
+        8 + // max_tvl
+        33 + // reward_mint (Option<Pubkey>)
+        8 + // maker_reward_amount
+        2 + // max_confidence_bps
+        4 + // max_price_age_secs
+        2 + // fee_bps
+        32 + // treasury
+        8 + // max_margin
+        4 + // expiry_granularity_secs
+        4 + // accept_cooldown_secs
+        8 + // daily_volume_limit
+        32 + // arbiter
+        8 + // dispute_bond
+        2 + // dispute_reward_bps
+        4 + // dispute_window_secs
+        8 + // duplicate_prediction_epsilon
+        1 + // use_vault
+        8 + // prediction_tick
+        4 + 32 * MAX_SETTLE_ALLOWLIST + // settle_allowlist
+        33 + // market_maker (Option<Pubkey>)
+        8 + // mm_max_margin
+        4 + 32 * MAX_MM_COLLECTIONS + // mm_collections
+        4 + // void_grace_secs
+        1 + // loser_pays_costs
+        4 + // settlement_window_secs
+        4 + // maker_reward_horizon_secs
+        4 + MAX_CATEGORIES + // categories
+        2 + // early_exit_penalty_bps
+        4 + // min_observation_period_secs
+        2 + // consolation_bps
+        2 + // max_tolerance_bps
+        1 + // keeper_reward_in_token
+        1 + // min_price_samples
+        33 + // pending_admin (Option<Pubkey>)
+        4 + // min_update_age_secs
+        4 + 32 * MAX_PAYOUT_ALLOWLIST + // payout_allowlist
+        1 + // close_on_settle
+        1; // bump
+
+    pub fn apply(&mut self, params: ConfigParams) -> Result<()> {
+        require!(
+            params.keeper_reward_base_bps <= params.keeper_reward_max_bps
+                && u64::from(params.keeper_reward_max_bps) <= BPS_DENOMINATOR,
+            EscrowError::InvalidConfig
+        );
+        require!(params.keeper_reward_step_secs > 0, EscrowError::InvalidConfig);
+        require!(
+            u64::from(params.fee_bps) <= BPS_DENOMINATOR,
+            EscrowError::InvalidConfig
+        );
+        require!(
+            params.accept_cooldown_secs <= MAX_ACCEPT_COOLDOWN_SECS,
+            EscrowError::InvalidConfig
+        );
+        require!(
+            u64::from(params.dispute_reward_bps) <= BPS_DENOMINATOR,
+            EscrowError::InvalidConfig
+        );
+        require!(
+            params.settle_allowlist.len() <= MAX_SETTLE_ALLOWLIST,
+            EscrowError::InvalidConfig
+        );
+        require!(
+            params.mm_collections.len() <= MAX_MM_COLLECTIONS,
+            EscrowError::InvalidConfig
+        );
+        require!(
+            params.categories.len() <= MAX_CATEGORIES,
+            EscrowError::InvalidConfig
+        );
+        require!(
+            u64::from(params.early_exit_penalty_bps) <= BPS_DENOMINATOR,
+            EscrowError::InvalidConfig
+        );
+        // A consolation above half the pot would pay the loser more
+        require!(
+            u64::from(params.consolation_bps) <= BPS_DENOMINATOR / 2,
+            EscrowError::InvalidConfig
+        );
+        // Zero would let the losing side void the moment the bet expired
+        require!(
+            (1..=MAX_VOID_GRACE_SECS).contains(&params.void_grace_secs),
+            EscrowError::InvalidConfig
+        );
+        require!(
+            !params.keeper_reward_in_token || params.reward_mint.is_some(),
+            EscrowError::InvalidConfig
+        );
+        // More than a history holds would leave every TWAP escrow unsettleable
+        require!(
+            usize::from(params.min_price_samples) <= MAX_PRICE_SAMPLES,
+            EscrowError::InvalidConfig
+        );
+        // Leave Pyth prices some age at which they're neither too fresh nor stale
+        require!(
+            params.max_price_age_secs == 0
+                || params.min_update_age_secs < params.max_price_age_secs,
+            EscrowError::InvalidConfig
+        );
+        require!(
+            params.payout_allowlist.len() <= MAX_PAYOUT_ALLOWLIST,
+            EscrowError::InvalidConfig
+        );
+
+        self.keeper_reward_base_bps = params.keeper_reward_base_bps;
+        self.keeper_reward_step_bps = params.keeper_reward_step_bps;
+        self.keeper_reward_step_secs = params.keeper_reward_step_secs;
+        self.keeper_reward_max_bps = params.keeper_reward_max_bps;
+        self.tie_break_by_time = params.tie_break_by_time;
+        self.max_tvl = params.max_tvl;
+        self.reward_mint = params.reward_mint;
+        self.maker_reward_amount = params.maker_reward_amount;
+        self.max_confidence_bps = params.max_confidence_bps;
+        self.max_price_age_secs = params.max_price_age_secs;
+        self.fee_bps = params.fee_bps;
+        self.treasury = params.treasury;
+        self.max_margin = params.max_margin;
+        self.expiry_granularity_secs = params.expiry_granularity_secs;
+        self.accept_cooldown_secs = params.accept_cooldown_secs;
+        self.daily_volume_limit = params.daily_volume_limit;
+        self.arbiter = params.arbiter;
+        self.dispute_bond = params.dispute_bond;
+        self.dispute_reward_bps = params.dispute_reward_bps;
+        self.dispute_window_secs = params.dispute_window_secs;
+        self.duplicate_prediction_epsilon = params.duplicate_prediction_epsilon;
+        self.use_vault = params.use_vault;
+        self.prediction_tick = params.prediction_tick;
+        self.settle_allowlist = params.settle_allowlist;
+        self.market_maker = params.market_maker;
+        self.mm_max_margin = params.mm_max_margin;
+        self.mm_collections = params
+            .mm_collections
+            .iter()
+            .map(|id| hash_collection_id(id))
+            .collect();
+        self.void_grace_secs = params.void_grace_secs;
+        self.loser_pays_costs = params.loser_pays_costs;
+        self.settlement_window_secs = params.settlement_window_secs;
+        self.maker_reward_horizon_secs = params.maker_reward_horizon_secs;
+        self.categories = params.categories;
+        self.early_exit_penalty_bps = params.early_exit_penalty_bps;
+        self.min_observation_period_secs = params.min_observation_period_secs;
+        self.consolation_bps = params.consolation_bps;
+        self.max_tolerance_bps = params.max_tolerance_bps;
+        self.keeper_reward_in_token = params.keeper_reward_in_token;
+        self.min_price_samples = params.min_price_samples;
+        self.min_update_age_secs = params.min_update_age_secs;
+        self.payout_allowlist = params.payout_allowlist;
+        self.close_on_settle = params.close_on_settle;
+        Ok(())
+    }
+
+    /// Makes `signer` the admin, if they're the one it was offered to
+    pub fn accept_admin(&mut self, signer: Pubkey) -> Result<()> {
+        require!(self.pending_admin == Some(signer), EscrowError::NotPendingAdmin);
+        self.admin = signer;
+        self.pending_admin = None;
+        Ok(())
+    }
+
+    /// `expiry` rounded down to a multiple of `expiry_granularity_secs`,
+    /// unchanged when rounding is off
+    pub fn round_expiry(&self, expiry: i64) -> i64 {
+        match i64::from(self.expiry_granularity_secs) {
+            0 => expiry,
+            granularity => expiry - expiry.rem_euclid(granularity),
+        }
+    }
+
+    /// Whether an escrow created at `created_at` is still too fresh to
+    /// accept at `now`. Cancelling stays open throughout.
+    pub fn accept_cooling_down(&self, created_at: i64, now: i64) -> bool {
+        now < created_at.saturating_add(i64::from(self.accept_cooldown_secs))
+    }
+
+    /// Whether an escrow accepted at `accepted_at` has been live long
+    /// enough by `now` for its price to be read at settlement
+    pub fn observed_long_enough(&self, accepted_at: i64, now: i64) -> bool {
+        now >= accepted_at.saturating_add(i64::from(self.min_observation_period_secs))
+    }
+
+    /// Whether two predictions are close enough to count as the same
+    /// one, within `duplicate_prediction_epsilon` inclusive
+    pub fn is_duplicate_prediction(&self, trader: u64, counterparty: u64) -> bool {
+        trader.abs_diff(counterparty) <= self.duplicate_prediction_epsilon
+    }
+
+    /// Whether `predicted_floor` is a whole number of `prediction_tick`s,
+    /// always true while no tick is set
+    pub fn is_on_tick(&self, predicted_floor: u64) -> bool {
+        self.prediction_tick == 0 || predicted_floor.is_multiple_of(self.prediction_tick)
+    }
+
+    /// Whether a band of `tolerance` either side of `predicted_floor` is
+    /// narrow enough, at most `max_tolerance_bps` of the prediction. The
+    /// prediction stands in for the reference price so opening needs no
+    /// oracle read; both are in the escrow's prediction unit.
+    pub fn allows_tolerance(&self, predicted_floor: u64, tolerance: u64) -> bool {
+        self.max_tolerance_bps == 0
+            || u128::from(tolerance) * u128::from(BPS_DENOMINATOR)
+                <= u128::from(predicted_floor) * u128::from(self.max_tolerance_bps)
+    }
+
+    /// Whether new escrows may open under `category`: any while the list
+    /// is empty
+    pub fn allows_category(&self, category: u8) -> bool {
+        self.categories.is_empty() || self.categories.contains(&category)
+    }
+
+    /// Whether `keeper` may settle: anyone while the allowlist is empty
+    pub fn allows_settler(&self, keeper: &Pubkey) -> bool {
+        self.settle_allowlist.is_empty() || self.settle_allowlist.contains(keeper)
+    }
+
+    /// Whether `wallet` rules on disputes and so mustn't take a side in an
+    /// escrow. The escrow's oracle is a program account with no operator
+    /// recorded here, so there's no wallet behind it to check.
+    pub fn has_conflict(&self, wallet: &Pubkey) -> bool {
+        *wallet == self.arbiter
+    }
+
+    /// Whether settlement may pay any share to `destination`: anywhere
+    /// while the payout allowlist is empty
+    pub fn allows_payout(&self, destination: &Pubkey) -> bool {
+        self.payout_allowlist.is_empty() || self.payout_allowlist.contains(destination)
+    }
+
+    /// Whether an escrow expiring at `expiry` is settled too late at `now`
+    /// to pick a winner, and is refunded instead
+    pub fn settlement_window_passed(&self, expiry: i64, now: i64) -> bool {
+        self.settlement_window_secs > 0
+            && now > expiry.saturating_add(i64::from(self.settlement_window_secs))
+    }
+
+    /// Whether an active escrow expiring at `expiry` has gone unsettled
+    /// long enough at `now` for either party to void it
+    pub fn void_available(&self, expiry: i64, now: i64) -> bool {
+        now >= expiry.saturating_add(i64::from(self.void_grace_secs))
+    }
+
+    /// Whether `escrow` is within the market maker's risk limits: an
+    /// allowlisted collection and a counter-stake it's willing to put up.
+    /// Zero `mm_max_margin` leaves the stake uncapped.
+    pub fn market_maker_takes(&self, escrow: &EscrowState) -> Result<bool> {
+        let stake = escrow.counterparty_stake()?;
+        Ok(self.mm_collections.contains(&escrow.collection_hash)
+            && (self.mm_max_margin == 0 || stake <= self.mm_max_margin))
+    }
+
+    pub fn allows_margin(&self, margin_amount: u64) -> bool {
+        self.max_margin == 0 || margin_amount <= self.max_margin
+    }
+
+    /// Fee charged on one participant's margin at settlement, at `fee_bps`
+    /// but never above the `max_fee_bps` the escrow was opened with
+    pub fn side_fee(&self, margin: u64, max_fee_bps: u16) -> Result<u64> {
+        let fee = u128::from(margin)
+            .checked_mul(u128::from(self.fee_bps.min(max_fee_bps)))
+            .ok_or(EscrowError::MathOverflow)?
+            / u128::from(BPS_DENOMINATOR);
+        u64::try_from(fee).map_err(|_| error!(EscrowError::MathOverflow))
+    }
+
+    /// Part of `stake` a party leaving early forfeits to the other side
+    pub fn early_exit_penalty(&self, stake: u64) -> Result<u64> {
+        let penalty = u128::from(stake)
+            .checked_mul(u128::from(self.early_exit_penalty_bps))
+            .ok_or(EscrowError::MathOverflow)?
+            / u128::from(BPS_DENOMINATOR);
+        u64::try_from(penalty).map_err(|_| error!(EscrowError::MathOverflow))
+    }
+
+    /// Share of the pot paid to a disputer whose dispute was upheld
+    pub fn dispute_reward(&self, pot: u64) -> Result<u64> {
+        let reward = u128::from(pot)
+            .checked_mul(u128::from(self.dispute_reward_bps))
+            .ok_or(EscrowError::MathOverflow)?
+            / u128::from(BPS_DENOMINATOR);
+        u64::try_from(reward).map_err(|_| error!(EscrowError::MathOverflow))
+    }
+
+    /// Whether disputes against an escrow expiring at `expiry` have had
+    /// their `dispute_window_secs` to be raised by `now`
+    pub fn dispute_window_closed(&self, expiry: i64, now: i64) -> bool {
+        now >= expiry.saturating_add(i64::from(self.dispute_window_secs))
+    }
+
+    /// Maker reward for an escrow accepted `age` seconds after it opened:
+    /// `maker_reward_amount * (horizon - age) / horizon`, rounded down and
+    /// zero from the horizon on. Without a horizon the reward never decays.
+    pub fn maker_reward(&self, age: i64) -> Result<u64> {
+        let horizon = u64::from(self.maker_reward_horizon_secs);
+        if horizon == 0 {
+            return Ok(self.maker_reward_amount);
+        }
+        let age = u64::try_from(age.max(0)).map_err(|_| EscrowError::MathOverflow)?;
+        let reward = u128::from(self.maker_reward_amount)
+            .checked_mul(u128::from(horizon.saturating_sub(age)))
+            .ok_or(EscrowError::MathOverflow)?
+            / u128::from(horizon);
+        u64::try_from(reward).map_err(|_| error!(EscrowError::MathOverflow))
+    }
+
+    /// Keeper reward for settling `seconds_late` seconds after expiry.
+    ///
+    /// The rate starts at `keeper_reward_base_bps` and climbs by
+    /// `keeper_reward_step_bps` for every full `keeper_reward_step_secs`
+    /// elapsed, capped at `keeper_reward_max_bps`:
+    ///
+    /// `reward = pot * min(base + step * (seconds_late / step_secs), max) / 10_000`
+    pub fn keeper_reward(&self, pot: u64, seconds_late: i64) -> Result<u64> {
+        let steps = u64::try_from(seconds_late.max(0))
+            .map_err(|_| EscrowError::MathOverflow)?
+            / u64::from(self.keeper_reward_step_secs);
+        let reward_bps = u64::from(self.keeper_reward_step_bps)
+            .checked_mul(steps)
+            .and_then(|bps| bps.checked_add(u64::from(self.keeper_reward_base_bps)))
+            .map_or(u64::from(self.keeper_reward_max_bps), |bps| {
+                bps.min(u64::from(self.keeper_reward_max_bps))
+            });
+
+        let reward = u128::from(pot)
+            .checked_mul(u128::from(reward_bps))
+            .ok_or(EscrowError::MathOverflow)?
+            / u128::from(BPS_DENOMINATOR);
+        u64::try_from(reward).map_err(|_| error!(EscrowError::MathOverflow))
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ConfigParams {
+    pub keeper_reward_base_bps: u16,
+    pub keeper_reward_step_bps: u16,
+    pub keeper_reward_step_secs: u32,
+    pub keeper_reward_max_bps: u16,
+    /// Award equidistant predictions to whoever committed first instead of splitting
+    pub tie_break_by_time: bool,
+    /// Cap on lamports locked across all escrows, zero for no cap
+    pub max_tvl: u64,
+    /// Token minted to makers when their escrow is accepted, none to disable.
+    /// Mint authority must be the `["reward_authority"]` PDA.
+    pub reward_mint: Option<Pubkey>,
+    pub maker_reward_amount: u64,
+    /// Widest Pyth confidence interval accepted, in bps of the price. Zero
+    /// disables the check.
+    pub max_confidence_bps: u16,
+    /// Oldest Pyth price accepted at settlement, in seconds. Zero disables
+    /// the check.
+    pub max_price_age_secs: u32,
+    /// Settlement fee taken from each side's margin separately, in bps.
+    /// The keeper reward and the winner's payout come from what's left.
+    pub fee_bps: u16,
+    /// Receives settlement fees
+    pub treasury: Pubkey,
+    /// Largest margin one side may stake, zero for no cap
+    pub max_margin: u64,
+    /// Round new escrows' expiry down to a multiple of this many seconds,
+    /// e.g. 3600 for the top of the hour. Zero keeps the requested expiry.
+    pub expiry_granularity_secs: u32,
+    /// Seconds after creation before an escrow can be accepted, giving the
+    /// trader a window to cancel or adjust first. At most
+    /// `MAX_ACCEPT_COOLDOWN_SECS`; zero disables the wait.
+    pub accept_cooldown_secs: u32,
+    /// Most margin one wallet may open escrows with per UTC day, zero for
+    /// no limit
+    pub daily_volume_limit: u64,
+    /// Rules on disputed settlement prices
+    pub arbiter: Pubkey,
+    /// Lamports a disputer puts up, forfeited to the treasury if rejected
+    pub dispute_bond: u64,
+    /// Share of the pot, after fees, paid to a disputer who is upheld
+    pub dispute_reward_bps: u16,
+    /// Seconds after expiry during which only a dispute, not settlement,
+    /// can happen. Zero lets escrows settle right at expiry.
+    pub dispute_window_secs: u32,
+    /// Lamports within which a counterparty's prediction counts as a copy
+    /// of the trader's. Copies earn no maker reward; zero still catches
+    /// identical predictions.
+    pub duplicate_prediction_epsilon: u64,
+    /// Hold new escrows' margins in the shared vault instead of each
+    /// escrow's own account. Existing escrows keep where they started.
+    pub use_vault: bool,
+    /// Step predictions must land on, in lamports, e.g. 10_000_000 for
+    /// 0.01 SOL. Zero accepts any prediction.
+    pub prediction_tick: u64,
+    /// Keepers allowed to settle, at most `MAX_SETTLE_ALLOWLIST`, e.g.
+    /// during a launch phase. Empty leaves settlement permissionless.
+    pub settle_allowlist: Vec<Pubkey>,
+    /// Wallet allowed to take open escrows through `mm_accept`, or `None`
+    /// to turn it off
+    pub market_maker: Option<Pubkey>,
+    /// Largest counter-stake, in lamports, the market maker puts up. Zero
+    /// leaves it uncapped.
+    pub mm_max_margin: u64,
+    /// Full identifiers of the collections the market maker takes, at most
+    /// `MAX_MM_COLLECTIONS`
+    pub mm_collections: Vec<String>,
+    /// Seconds past expiry after which either party may void an escrow
+    /// still unsettled, between 1 and `MAX_VOID_GRACE_SECS`
+    pub void_grace_secs: u32,
+    /// Take the fees, keeper reward and dispute reward out of the losing
+    /// stake alone, trimming them to fit, so an outright winner is never
+    /// paid less than their own stake. Splits are unaffected.
+    pub loser_pays_costs: bool,
+    /// Seconds past expiry within which settlement picks a winner. Later
+    /// settlements refund both sides instead. Zero never refunds.
+    pub settlement_window_secs: u32,
+    /// Seconds an escrow can sit unaccepted before its maker reward has
+    /// decayed to nothing. The reward falls linearly from the full
+    /// `maker_reward_amount` at creation; zero keeps it flat.
+    pub maker_reward_horizon_secs: u32,
+    /// Categories new escrows may be opened under, at most
+    /// `MAX_CATEGORIES`. Empty accepts any.
+    pub categories: Vec<u8>,
+    /// Share of a party's stake, in bps, forfeited to the other side when
+    /// they leave through `exit_early`. Zero turns early exits off.
+    pub early_exit_penalty_bps: u16,
+    /// Seconds an escrow must have been accepted before settlement reads
+    /// its price, however soon after acceptance it expired, so a late
+    /// accept still leaves the oracle a fair window. Zero turns it off.
+    pub min_observation_period_secs: u32,
+    /// Share of the pot, after fees and rewards, returned to the loser of a
+    /// `PayoutMode::Consolation` escrow, at most half
+    pub consolation_bps: u16,
+    /// Widest tolerance band new escrows may take either side of their
+    /// prediction, in bps of it, so the band can't make a win trivial.
+    /// Zero allows any width.
+    pub max_tolerance_bps: u16,
+    /// Pay keepers their settle reward in `reward_mint` out of the
+    /// `["reward_vault"]` token account instead of out of the pot, which
+    /// then goes to the parties untouched. The reward follows the same
+    /// schedule, as that many base units of the token, and is cut to what
+    /// the vault holds. `settle_batch` takes no token accounts, so it
+    /// settles without a keeper reward. Needs `reward_mint` set.
+    pub keeper_reward_in_token: bool,
+    /// Fewest samples a TWAP escrow's price history must have recorded
+    /// inside its window before it settles on the average. Short of that,
+    /// settle fails, leaving the escrow to wait for more samples, or to a
+    /// dispute or void. At most `MAX_PRICE_SAMPLES`; zero turns it off.
+    pub min_price_samples: u8,
+    /// Newest Pyth price accepted at settlement, in seconds since it was
+    /// published, so whoever posts an update can't settle on it in the
+    /// same breath. Must be below `max_price_age_secs` when that's set.
+    /// Zero disables the check.
+    pub min_update_age_secs: u32,
+    /// Wallets a settled escrow's winnings, and the other side's share of a
+    /// split or profit share, may be paid to, at most `MAX_PAYOUT_ALLOWLIST`,
+    /// for deployments that must only pay vetted addresses. Settling to
+    /// anyone else fails. Empty pays anywhere.
+    pub payout_allowlist: Vec<Pubkey>,
+    /// Close each escrow `settle_escrow` settles, refunding its rent to the
+    /// trader in the same instruction instead of leaving it for
+    /// `close_escrow`. The trader's account must then come along, as
+    /// `winner` or `trader`. `settle_batch` leaves its escrows open.
+    pub close_on_settle: bool,
+}
+
+/// Program-wide counters
+#[account]
+pub struct Stats {
+    pub total_locked: u64,
+    pub bump: u8,
+    /// Escrows ever created
+    pub total_escrows: u64,
+}
+
+impl Stats {
+    pub const LEN: usize = 8 + // discriminator
+        8 + // total_locked
+        1 + // bump
+        8; // total_escrows
+
+    /// Sequence number for a new escrow, counting it in `total_escrows`
+    pub fn next_sequence(&mut self) -> Result<u64> {
+        let sequence = self.total_escrows;
+        self.total_escrows = sequence.checked_add(1).ok_or(EscrowError::MathOverflow)?;
+        Ok(sequence)
+    }
+
+    pub fn lock(&mut self, amount: u64, max_tvl: u64) -> Result<()> {
+        let total_locked = self
+            .total_locked
+            .checked_add(amount)
+            .ok_or(EscrowError::MathOverflow)?;
+        require!(
+            max_tvl == 0 || total_locked <= max_tvl,
+            EscrowError::TvlCapReached
+        );
+        self.total_locked = total_locked;
+        Ok(())
+    }
+
+    pub fn unlock(&mut self, amount: u64) -> Result<()> {
+        self.total_locked = self
+            .total_locked
+            .checked_sub(amount)
+            .ok_or(EscrowError::MathOverflow)?;
+        Ok(())
+    }
+}
+
+/// Custom trait for Tensor oracle interactions
+pub trait TensorOracle {
+    fn get_floor_price(&self, collection_id: &str) -> Result<u64>;
+
+    /// Rank of `collection_id`'s floor among itself and `others`, 1 for the
+    /// highest. Collections on the same floor share the better rank.
+    fn get_floor_rank(&self, collection_id: &str, others: &[String]) -> Result<u64> {
+        let floor = self.get_floor_price(collection_id)?;
+        let mut rank = 1;
+        for id in others {
+            if self.get_floor_price(id)? > floor {
+                rank += 1;
+            }
+        }
+        Ok(rank)
+    }
+}
+
+impl TensorOracle for AccountInfo<'_> {
+    fn get_floor_price(&self, _collection_id: &str) -> Result<u64> {
+        // For testing, we'll return a mock floor price
+        // In production, this would make an HTTP call to Tensor's API
+        Ok(10 * anchor_lang::solana_program::native_token::LAMPORTS_PER_SOL)
+    }
+}
+
+/// Minimal reader for the Pyth receiver's `PriceUpdateV2` account
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct PythPriceUpdate {
+    pub write_authority: Pubkey,
+    pub verification_level: PythVerificationLevel,
+    pub price_message: PythPriceMessage,
+    pub posted_slot: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub enum PythVerificationLevel {
+    Partial { num_signatures: u8 },
+    Full,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct PythPriceMessage {
+    pub feed_id: [u8; 32],
+    pub price: i64,
+    pub conf: u64,
+    pub exponent: i32,
+    pub publish_time: i64,
+    pub prev_publish_time: i64,
+    pub ema_price: i64,
+    pub ema_conf: u64,
+}
+
+/// A Pyth floor price converted to lamports
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PythPrice {
+    pub price: u64,
+    pub confidence: u64,
+    pub publish_time: i64,
+}
+
+impl PythPrice {
+    const DISCRIMINATOR: [u8; 8] = [34, 241, 35, 99, 157, 126, 244, 205];
+
+    /// Reads a fully verified price update from `feed_id`; the feed quotes
+    /// the floor in SOL
+    pub fn load(account: &AccountInfo, feed_id: &[u8; 32]) -> Result<Self> {
+        require_keys_eq!(*account.owner, PYTH_RECEIVER_ID, EscrowError::InvalidOracleAccount);
+
+        let data = account.try_borrow_data()?;
+        require!(
+            data.len() > 8 && data[..8] == Self::DISCRIMINATOR,
+            EscrowError::InvalidOracleAccount
+        );
+        let update = PythPriceUpdate::deserialize(&mut &data[8..])
+            .map_err(|_| error!(EscrowError::InvalidOracleAccount))?;
+        require!(
+            update.verification_level == PythVerificationLevel::Full,
+            EscrowError::InvalidOracleAccount
+        );
+        // Any verified update passes the checks above, whatever it prices
+        require!(update.price_message.feed_id == *feed_id, EscrowError::OracleMismatch);
+
+        let message = update.price_message;
+        let price = u64::try_from(message.price).map_err(|_| EscrowError::InvalidOraclePrice)?;
+        require!(price > 0, EscrowError::InvalidOraclePrice);
+        Ok(Self {
+            price: Self::to_lamports(price, message.exponent)?,
+            confidence: Self::to_lamports(message.conf, message.exponent)?,
+            publish_time: message.publish_time,
+        })
+    }
+
+    /// Scales a SOL-denominated Pyth value with `exponent` to lamports
+    pub fn to_lamports(value: u64, exponent: i32) -> Result<u64> {
+        let shift = exponent
+            .checked_add(9) // LAMPORTS_PER_SOL = 10^9
+            .ok_or(EscrowError::InvalidOraclePrice)?;
+        let factor = 10u128
+            .checked_pow(shift.unsigned_abs())
+            .ok_or(EscrowError::InvalidOraclePrice)?;
+        let lamports = if shift >= 0 {
+            u128::from(value)
+                .checked_mul(factor)
+                .ok_or(EscrowError::InvalidOraclePrice)?
+        } else {
+            u128::from(value) / factor
+        };
+        u64::try_from(lamports).map_err(|_| error!(EscrowError::InvalidOraclePrice))
+    }
+
+    /// Fails with `StalePrice` if published more than `max_age_secs` before
+    /// `now`; zero accepts any age
+    pub fn check_fresh(&self, now: i64, max_age_secs: u32) -> Result<()> {
+        require!(
+            max_age_secs == 0 || now.saturating_sub(self.publish_time) <= i64::from(max_age_secs),
+            EscrowError::StalePrice
+        );
+        Ok(())
+    }
+
+    /// Fails with `PriceTooFresh` if published less than `min_age_secs`
+    /// before `now`, so an update can't be pushed and settled on at once
+    pub fn check_aged(&self, now: i64, min_age_secs: u32) -> Result<()> {
+        require!(
+            now.saturating_sub(self.publish_time) >= i64::from(min_age_secs),
+            EscrowError::PriceTooFresh
+        );
+        Ok(())
+    }
+
+    /// Price, unless the confidence interval is wider than
+    /// `max_confidence_bps` of it
+    pub fn confident_price(&self, max_confidence_bps: u16) -> Result<u64> {
+        if max_confidence_bps > 0 {
+            let max_confidence = u128::from(self.price) * u128::from(max_confidence_bps)
+                / u128::from(BPS_DENOMINATOR);
+            require!(
+                u128::from(self.confidence) <= max_confidence,
+                EscrowError::LowConfidencePrice
+            );
+        }
+        Ok(self.price)
+    }
+}
+
+#[error_code]
+pub enum EscrowError {
+    #[msg("Escrow is already settled")]
+    AlreadySettled,
+    #[msg("Escrow is not initialized")]
+    NotInitialized,
+    #[msg("Escrow has expired")]
+    Expired,
+    #[msg("Escrow has not expired yet")]
+    NotExpiredYet,
+    #[msg("No second trader has accepted the escrow")]
+    NoSecondTrader,
+    #[msg("Insufficient funds to cover the margin")]
+    InsufficientFunds,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+    #[msg("Winner account does not match the settlement outcome")]
+    InvalidWinner,
+    #[msg("Signer is not authorized for this action")]
+    Unauthorized,
+    #[msg("Invalid config parameters")]
+    InvalidConfig,
+    #[msg("Escrow is not open")]
+    NotOpen,
+    #[msg("Escrow still holds counterparty funds")]
+    CounterpartyFundsLocked,
+    #[msg("Escrow must be settled or cancelled first")]
+    NotFinalized,
+    #[msg("Counterparty account is required to split the pot")]
+    MissingCounterpartyAccount,
+    #[msg("Invalid expiry timestamp")]
+    InvalidExpiry,
+    #[msg("Escrow expiry cannot be extended any further")]
+    MaxExtensionsReached,
+    #[msg("Collection index chunk is full, use the next one")]
+    CollectionIndexFull,
+    #[msg("Deposit would exceed the program TVL cap")]
+    TvlCapReached,
+    #[msg("Reward accounts are required while a reward mint is configured")]
+    MissingRewardAccounts,
+    #[msg("Reward account does not match the configured mint or maker")]
+    InvalidRewardAccount,
+    #[msg("Clock sysvar is unavailable")]
+    ClockUnavailable,
+    #[msg("Oracle account is required for this escrow's price source")]
+    MissingOracleAccount,
+    #[msg("Oracle account is not a valid price feed")]
+    InvalidOracleAccount,
+    #[msg("Oracle price is invalid")]
+    InvalidOraclePrice,
+    #[msg("Oracle price confidence is too low to settle")]
+    LowConfidencePrice,
+    #[msg("Batch accounts are malformed")]
+    InvalidBatch,
+    #[msg("Too many escrows in one batch")]
+    BatchTooLarge,
+    #[msg("Collection id does not match the stored hash")]
+    CollectionIdMismatch,
+    #[msg("Account receiving lamports must be passed as writable")]
+    AccountNotWritable,
+    #[msg("Treasury account is required while a settlement fee is set")]
+    MissingTreasuryAccount,
+    #[msg("Treasury account does not match the config")]
+    InvalidTreasuryAccount,
+    #[msg("Collection has not been delisted")]
+    CollectionNotDelisted,
+    #[msg("Refund account does not match the escrow")]
+    InvalidRefundAccount,
+    #[msg("Escrow needs between one and three price sources")]
+    InvalidPriceSources,
+    #[msg("Oracle price is too old to settle on")]
+    StalePrice,
+    #[msg("None of the escrow's price sources gave a usable price")]
+    NoValidPriceSource,
+    #[msg("Margin exceeds the configured maximum")]
+    MarginTooLarge,
+    #[msg("Winner must sign to redirect their payout")]
+    PayoutNotAuthorized,
+    #[msg("Escrow cannot move to that status from its current one")]
+    InvalidStateTransition,
+    #[msg("Odds must be positive and within the allowed ratio")]
+    InvalidOdds,
+    #[msg("Escrow was not credited the full deposit")]
+    TransferFailed,
+    #[msg("Basket holds more collections than allowed")]
+    BasketTooLarge,
+    #[msg("Rollover duration or count is out of bounds")]
+    InvalidRollover,
+    #[msg("Trader profile and follow-on escrow are required to roll over")]
+    MissingRolloverAccounts,
+    #[msg("Follow-on escrow is not the trader's next escrow address")]
+    InvalidRolloverAccount,
+    #[msg("Settlement outcome differs from the expected winner")]
+    WinnerChanged,
+    #[msg("Escrow was created too recently to accept")]
+    AcceptCooldown,
+    #[msg("Escrows still have funds locked")]
+    FundsStillLocked,
+    #[msg("Collection is still delisted")]
+    CollectionStillDelisted,
+    #[msg("Escrow would exceed the wallet's daily volume limit")]
+    DailyLimitExceeded,
+    #[msg("Escrow already has a dispute")]
+    AlreadyDisputed,
+    #[msg("Escrow cannot settle until its dispute is resolved")]
+    DisputePending,
+    #[msg("Escrow is still inside its dispute window")]
+    DisputeWindowOpen,
+    #[msg("Disputer account is required to pay the dispute reward")]
+    MissingDisputerAccount,
+    #[msg("Vault account is required for vaulted escrows")]
+    MissingVaultAccount,
+    #[msg("Escrow's vault balance does not cover the withdrawal")]
+    VaultBalanceInsufficient,
+    #[msg("Predicted floor is not a multiple of the prediction tick")]
+    InvalidPredictionTick,
+    #[msg("Signer is not on the settle allowlist")]
+    SettlerNotAllowed,
+    #[msg("Settlement record account does not match the escrow")]
+    InvalidSettlementRecord,
+    #[msg("Bps prediction must be between 1 and MAX_PREDICTION_BPS, on a single collection")]
+    InvalidPredictionBps,
+    #[msg("Bps predictions need a non-zero entry price, read at acceptance")]
+    MissingEntryPrice,
+    #[msg("Signer is not the configured market maker")]
+    NotMarketMaker,
+    #[msg("Escrow is outside the market maker's collection or margin limits")]
+    OutsideMarketMakerLimits,
+    #[msg("Escrow can't be voided until the grace period after expiry ends")]
+    VoidTooEarly,
+    #[msg("Collection id must not be empty")]
+    EmptyCollectionId,
+    #[msg("Trader account is required to pay the trader's share")]
+    MissingTraderAccount,
+    #[msg("wSOL accounts are required for a wrapped SOL escrow")]
+    MissingWsolAccounts,
+    #[msg("wSOL account must be a native mint account owned by the party")]
+    InvalidWsolAccount,
+    #[msg("Tensor oracle doesn't match the one the escrow was opened with")]
+    OracleMismatch,
+    #[msg("Counterparty must take the opposite side of an over/under escrow")]
+    SameDirection,
+    #[msg("Tolerance band reaches past the largest representable price")]
+    InvalidTolerance,
+    #[msg("Category is not one the config allows")]
+    InvalidCategory,
+    #[msg("Early exit is turned off")]
+    EarlyExitDisabled,
+    #[msg("Escrow hasn't been accepted long enough to settle on its price")]
+    InsufficientObservation,
+    #[msg("Lamport recipient can't be one of this program's accounts")]
+    ProgramOwnedRecipient,
+    #[msg("Tolerance is too wide a share of the predicted floor")]
+    ToleranceTooWide,
+    #[msg("Account isn't an escrow in the first release's layout")]
+    NotLegacyEscrow,
+    #[msg("The oracle registry has no oracle for this collection")]
+    UnregisteredCollection,
+    #[msg("The oracle registry can't map any more collections")]
+    OracleRegistryFull,
+    #[msg("A price was already recorded for this collection this second")]
+    PriceAlreadyRecorded,
+    #[msg("Not enough price history to average over the window")]
+    InsufficientPriceHistory,
+    #[msg("TWAP settlement needs a nonzero window on a single collection")]
+    InvalidSettlementPrice,
+    #[msg("TWAP-settled escrows need the collection's price history")]
+    MissingPriceHistory,
+    #[msg("Too few price samples in the window to settle on")]
+    TooFewPriceSamples,
+    #[msg("Only the proposed admin can accept the admin role")]
+    NotPendingAdmin,
+    #[msg("Oracle price was published too recently to settle on")]
+    PriceTooFresh,
+    #[msg("Escrow's margin changed since the accept was signed")]
+    MarginMismatch,
+    #[msg("Payout destination isn't on the payout allowlist")]
+    PayoutDestinationNotAllowed,
+    #[msg("Only a plain native payout to the winner can be credited to a balance")]
+    BalanceCreditUnavailable,
+    #[msg("Vault lamports do not match the margins it tracks")]
+    VaultReserveMismatch,
+    #[msg("Traders cannot accept their own escrow")]
+    SelfTrade,
+    #[msg("Rank predictions need a basket, Tensor pricing and a rank within it")]
+    InvalidRankPrediction,
+    #[msg("Margin must be greater than zero")]
+    MarginTooSmall,
+    #[msg("The arbiter cannot take a side in an escrow")]
+    ConflictOfInterest,
+    #[msg("Oracle price is outside the range the settler accepts")]
+    OraclePriceOutOfExpectedRange,
+    #[msg("Collection is paused")]
+    CollectionPaused,
+    #[msg("Nonce was already used by an earlier escrow")]
+    NonceAlreadyUsed,
+    #[msg("Fee cap is below the current fee")]
+    FeeCapBelowCurrentFee,
+    #[msg("The dispute window has closed")]
+    DisputeWindowClosed,
+    #[msg("Dispute account is required while a dispute is pending")]
+    MissingDisputeAccount,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_errors_map_to_clock_unavailable() {
+        let err = timestamp_from(Err(ProgramError::UnsupportedSysvar)).unwrap_err();
+        assert_eq!(err, EscrowError::ClockUnavailable.into());
+    }
+
+    #[test]
+    fn clock_timestamp_passes_through() {
+        let clock = Clock {
+            unix_timestamp: 1_700_000_000,
+            ..Clock::default()
+        };
+        assert_eq!(timestamp_from(Ok(clock)).unwrap(), 1_700_000_000);
+    }
+
+    #[test]
+    fn pyth_values_scale_to_lamports() {
+        // 12.5 SOL at exponent -8
+        assert_eq!(PythPrice::to_lamports(1_250_000_000, -8).unwrap(), 12_500_000_000);
+        assert_eq!(PythPrice::to_lamports(3, 0).unwrap(), 3_000_000_000);
+        assert_eq!(PythPrice::to_lamports(5, -12).unwrap(), 0);
+        assert!(PythPrice::to_lamports(u64::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn pyth_updates_must_come_from_the_escrows_feed() {
+        let (floor_feed, other_feed) = ([7; 32], [8; 32]);
+        let update = PythPriceUpdate {
+            write_authority: Pubkey::new_unique(),
+            verification_level: PythVerificationLevel::Full,
+            price_message: PythPriceMessage {
+                feed_id: floor_feed,
+                price: 1_000_000_000,
+                conf: 0,
+                exponent: -8,
+                publish_time: 1_700_000_000,
+                prev_publish_time: 1_699_999_999,
+                ema_price: 1_000_000_000,
+                ema_conf: 0,
+            },
+            posted_slot: 1,
+        };
+        let mut data = PythPrice::DISCRIMINATOR.to_vec();
+        update.serialize(&mut data).unwrap();
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let info = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &PYTH_RECEIVER_ID,
+            false,
+            0,
+        );
+        assert_eq!(PythPrice::load(&info, &floor_feed).unwrap().price, 10_000_000_000);
+        assert_eq!(
+            PythPrice::load(&info, &other_feed).unwrap_err(),
+            EscrowError::OracleMismatch.into()
+        );
+    }
+
+    #[test]
+    fn pyth_confidence_limit_is_relative_to_price() {
+        let reading = PythPrice {
+            price: 10_000,
+            confidence: 100,
+            publish_time: 0,
+        };
+        assert_eq!(reading.confident_price(100).unwrap(), 10_000);
+        assert_eq!(
+            reading.confident_price(99).unwrap_err(),
+            EscrowError::LowConfidencePrice.into()
+        );
+        assert_eq!(reading.confident_price(0).unwrap(), 10_000);
+    }
+
+    #[test]
+    fn pyth_freshness_is_measured_from_publish_time() {
+        let reading = PythPrice {
+            price: 10_000,
+            confidence: 0,
+            publish_time: 1_000,
+        };
+        assert!(reading.check_fresh(1_060, 60).is_ok());
+        assert_eq!(
+            reading.check_fresh(1_061, 60).unwrap_err(),
+            EscrowError::StalePrice.into()
+        );
+        assert!(reading.check_fresh(1_000_000, 0).is_ok());
+    }
+
+    #[test]
+    fn pyth_prices_must_age_before_they_settle() {
+        let reading = PythPrice {
+            price: 10_000,
+            confidence: 0,
+            publish_time: 1_000,
+        };
+        assert_eq!(
+            reading.check_aged(1_029, 30).unwrap_err(),
+            EscrowError::PriceTooFresh.into()
+        );
+        assert!(reading.check_aged(1_030, 30).is_ok());
+        assert!(reading.check_aged(1_000, 0).is_ok());
+    }
+
+    fn price_history(samples: &[(u64, i64)]) -> PriceHistory {
+        let mut history = PriceHistory {
+            collection_hash: hash_collection_id("y00ts"),
+            tensor_oracle: Pubkey::new_unique(),
+            samples: Vec::new(),
+            head: 0,
+            bump: 0,
+        };
+        for &(price, timestamp) in samples {
+            history.push(price, timestamp).unwrap();
+        }
+        history
+    }
+
+    #[test]
+    fn price_history_overwrites_its_oldest_sample_once_full() {
+        let total = MAX_PRICE_SAMPLES as i64 + 3;
+        let history = price_history(&(0..total).map(|t| (t as u64, t)).collect::<Vec<_>>());
+        assert_eq!(history.samples.len(), MAX_PRICE_SAMPLES);
+        let timestamps: Vec<i64> = history.chronological().map(|sample| sample.timestamp).collect();
+        assert_eq!(timestamps, (3..total).collect::<Vec<_>>());
+
+        // Nothing fresher than the newest sample, nothing twice a second
+        let mut history = history;
+        assert_eq!(
+            history.push(1, total - 1).unwrap_err(),
+            EscrowError::PriceAlreadyRecorded.into()
+        );
+        history.push(1, total).unwrap();
+    }
+
+    #[test]
+    fn twap_weights_each_price_by_how_long_it_held() {
+        let history = price_history(&[(100, 0), (200, 10), (400, 40)]);
+        // 100 for 10s, 200 for 30s, 400 for 20s
+        assert_eq!(history.twap(60, 60), Some((1_000 + 6_000 + 8_000) / 60));
+        // The window opens mid-way through the 200 stretch
+        assert_eq!(history.twap(30, 60), Some((200 * 10 + 400 * 20) / 30));
+        // A single sample prices the whole window it covers
+        assert_eq!(history.twap(5, 60), Some(400));
+        // Nothing had been recorded during this window yet
+        assert_eq!(price_history(&[(100, 60)]).twap(30, 60), None);
+        assert_eq!(price_history(&[]).twap(30, 60), None);
+    }
+
+    #[test]
+    fn twap_settlement_shrugs_off_a_spike_at_expiry() {
+        let config = Config::default();
+        let mut history = price_history(&[(200, 0), (200, 30), (200, 50), (1_000, 59)]);
+        let escrow = EscrowState {
+            predicted_floor: 200,
+            lower_tolerance: 20,
+            upper_tolerance: 20,
+            tensor_oracle: history.tensor_oracle,
+            ..EscrowState::default()
+        };
+        // The last second's spike decides a spot settlement
+        assert_eq!(projected_outcome(&escrow, 1_000, &config), Outcome::Counterparty);
+        let twap = history.settlement_twap(&escrow, 60, &config, 60).unwrap();
+        assert_eq!(twap, (200 * 59 + 1_000) / 60);
+        assert_eq!(projected_outcome(&escrow, twap, &config), Outcome::Trader);
+
+        // A history fed from another oracle doesn't price the escrow
+        history.tensor_oracle = Pubkey::new_unique();
+        assert_eq!(
+            history.settlement_twap(&escrow, 60, &config, 60).unwrap_err(),
+            EscrowError::OracleMismatch.into()
+        );
+    }
+
+    #[test]
+    fn twap_settlement_needs_enough_samples_inside_its_window() {
+        let history = price_history(&[(200, 0), (200, 40), (200, 50)]);
+        let escrow = EscrowState {
+            tensor_oracle: history.tensor_oracle,
+            ..EscrowState::default()
+        };
+        let config = Config {
+            min_price_samples: 3,
+            ..Config::default()
+        };
+        // The first sample still prices the window's start, but wasn't
+        // taken inside it
+        assert_eq!(history.samples_since(30), 2);
+        assert_eq!(
+            history.settlement_twap(&escrow, 30, &config, 60).unwrap_err(),
+            EscrowError::TooFewPriceSamples.into()
+        );
+        assert_eq!(history.settlement_twap(&escrow, 60, &config, 60).unwrap(), 200);
+
+        let unchecked = Config::default();
+        assert_eq!(history.settlement_twap(&escrow, 30, &unchecked, 60).unwrap(), 200);
+    }
+
+    #[test]
+    fn only_an_existing_collection_state_can_pause_a_collection() {
+        let key = Pubkey::new_unique();
+        let system = anchor_lang::system_program::ID;
+        let (mut lamports, mut data) = (0, Vec::new());
+        let unset = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &system,
+            false,
+            0,
+        );
+        assert!(!CollectionState::is_paused(&unset).unwrap());
+
+        let state = |paused| {
+            let mut data = Vec::new();
+            CollectionState {
+                collection_hash: [0; 32],
+                delisted: false,
+                paused,
+                bump: 0,
+            }
+            .try_serialize(&mut data)
+            .unwrap();
+            data
+        };
+        for paused in [true, false] {
+            let (mut lamports, mut data) = (0, state(paused));
+            let info = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &crate::ID,
+                false,
+                0,
+            );
+            assert_eq!(CollectionState::is_paused(&info).unwrap(), paused);
+        }
+    }
+
+    #[test]
+    fn oracle_registry_resolves_each_collection_to_its_own_oracle() {
+        let (y00ts, degods) = (hash_collection_id("y00ts"), hash_collection_id("degods"));
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut registry = OracleRegistry {
+            mappings: Vec::new(),
+            bump: 0,
+        };
+        registry.set(y00ts, Some(first)).unwrap();
+        registry.set(degods, Some(second)).unwrap();
+        assert_eq!(registry.resolve(&y00ts), Some(first));
+        assert_eq!(registry.resolve(&degods), Some(second));
+        assert_eq!(registry.resolve(&hash_collection_id("okay_bears")), None);
+
+        // Remapping replaces the entry, `None` drops it
+        registry.set(y00ts, Some(second)).unwrap();
+        assert_eq!(registry.resolve(&y00ts), Some(second));
+        assert_eq!(registry.mappings.len(), 2);
+        registry.set(degods, None).unwrap();
+        assert_eq!(registry.resolve(&degods), None);
+
+        for i in 1..MAX_REGISTERED_ORACLES {
+            let id = format!("collection-{i}");
+            registry.set(hash_collection_id(&id), Some(first)).unwrap();
+        }
+        assert_eq!(
+            registry
+                .set(hash_collection_id("one-too-many"), Some(first))
+                .unwrap_err(),
+            EscrowError::OracleRegistryFull.into()
+        );
+        // A full registry can still remap what it already holds
+        registry.set(y00ts, Some(first)).unwrap();
+    }
+
+    #[test]
+    fn expiry_rounds_down_to_granularity() {
+        let mut config = Config::default();
+        assert_eq!(config.round_expiry(1_700_001_234), 1_700_001_234);
+
+        config.expiry_granularity_secs = 3600;
+        assert_eq!(config.round_expiry(1_700_001_234), 1_699_999_200);
+        assert_eq!(config.round_expiry(1_699_999_200), 1_699_999_200);
+    }
+
+    #[test]
+    fn accept_cooldown_runs_from_creation() {
+        let mut config = Config::default();
+        assert!(!config.accept_cooling_down(1_000, 1_000));
+
+        config.accept_cooldown_secs = 30;
+        assert!(config.accept_cooling_down(1_000, 1_029));
+        assert!(!config.accept_cooling_down(1_000, 1_030));
+    }
+
+    #[test]
+    fn admin_changes_hands_only_once_the_proposed_admin_accepts() {
+        let admin = Pubkey::new_unique();
+        let proposed = Pubkey::new_unique();
+        let mut config = Config {
+            admin,
+            ..Config::default()
+        };
+        // Nothing pending, so nobody can take over
+        assert_eq!(
+            config.accept_admin(proposed).unwrap_err(),
+            EscrowError::NotPendingAdmin.into()
+        );
+
+        config.pending_admin = Some(proposed);
+        assert_eq!(
+            config.accept_admin(Pubkey::new_unique()).unwrap_err(),
+            EscrowError::NotPendingAdmin.into()
+        );
+        assert_eq!(config.admin, admin);
+
+        config.accept_admin(proposed).unwrap();
+        assert_eq!(config.admin, proposed);
+        assert_eq!(config.pending_admin, None);
+    }
+
+    #[test]
+    fn observation_period_runs_from_acceptance() {
+        let mut config = Config::default();
+        assert!(config.observed_long_enough(1_000, 1_000));
+
+        config.min_observation_period_secs = 60;
+        assert!(!config.observed_long_enough(1_000, 1_059));
+        assert!(config.observed_long_enough(1_000, 1_060));
+    }
+
+    #[test]
+    fn tolerance_is_capped_relative_to_the_prediction() {
+        let mut config = Config::default();
+        assert!(config.allows_tolerance(1_000, u64::MAX));
+
+        config.max_tolerance_bps = 500;
+        assert!(config.allows_tolerance(10_000_000_000, 500_000_000));
+        assert!(!config.allows_tolerance(10_000_000_000, 500_000_001));
+        assert!(config.allows_tolerance(u64::MAX, u64::MAX / 20));
+        assert!(!config.allows_tolerance(0, 1));
+    }
+
+    #[test]
+    fn daily_volume_resets_at_the_utc_day_boundary() {
+        let mut profile = TraderProfile::default();
+        let last_second = 20_000 * SECONDS_PER_DAY - 1;
+
+        profile.record_volume(600, last_second - 60, 1_000).unwrap();
+        profile.record_volume(400, last_second, 1_000).unwrap();
+        assert_eq!(
+            profile.record_volume(1, last_second, 1_000).unwrap_err(),
+            EscrowError::DailyLimitExceeded.into()
+        );
+        assert_eq!(profile.daily_volume, 1_000);
+
+        // Midnight UTC starts a fresh allowance
+        profile.record_volume(1_000, last_second + 1, 1_000).unwrap();
+        assert_eq!(profile.volume_day, 20_000);
+        assert_eq!(profile.daily_volume, 1_000);
+
+        profile.record_volume(u64::MAX / 2, last_second + 2, 0).unwrap();
+    }
+
+    #[test]
+    fn disputes_hold_settlement_for_the_window() {
+        let mut config = Config::default();
+        assert!(config.dispute_window_closed(1_000, 1_000));
+        assert_eq!(config.dispute_reward(10_000).unwrap(), 0);
+
+        config.dispute_window_secs = 600;
+        config.dispute_reward_bps = 250;
+        assert!(!config.dispute_window_closed(1_000, 1_599));
+        assert!(config.dispute_window_closed(1_000, 1_600));
+        assert_eq!(config.dispute_reward(10_000).unwrap(), 250);
+    }
+
+    #[test]
+    fn near_identical_predictions_count_as_duplicates() {
+        let mut config = Config::default();
+        assert!(config.is_duplicate_prediction(10_000, 10_000));
+        assert!(!config.is_duplicate_prediction(10_000, 10_001));
+
+        config.duplicate_prediction_epsilon = 50;
+        assert!(config.is_duplicate_prediction(10_000, 9_950));
+        assert!(!config.is_duplicate_prediction(10_000, 10_051));
+    }
+
+    #[test]
+    fn counterparty_stake_follows_the_odds() {
+        let escrow = EscrowState {
+            margin_amount: 3_000,
+            odds_numerator: 3,
+            odds_denominator: 1,
+            trader_deposit: 3_000,
+            counterparty_deposit: 1_000,
+            ..EscrowState::default()
+        };
+        assert_eq!(escrow.counterparty_stake().unwrap(), 1_000);
+        assert_eq!(escrow.pot().unwrap(), 4_000);
+
+        assert!(odds_are_sane(1, 1));
+        assert!(odds_are_sane(1, MAX_ODDS_RATIO));
+        assert!(!odds_are_sane(MAX_ODDS_RATIO + 1, 1));
+        assert!(!odds_are_sane(0, 1));
+    }
+
+    #[test]
+    fn short_deposits_are_rejected() {
+        assert!(verify_credit(1_000, 1_500, 500).is_ok());
+        assert_eq!(
+            verify_credit(1_000, 1_499, 500).unwrap_err(),
+            EscrowError::TransferFailed.into()
+        );
+        // A balance that went down must not underflow into a pass
+        assert_eq!(
+            verify_credit(1_000, 900, 500).unwrap_err(),
+            EscrowError::TransferFailed.into()
+        );
+    }
+
+    fn payout_context(outcome: Outcome) -> PayoutContext {
+        PayoutContext {
+            outcome,
+            distributable: 1_000,
+            trader_stake: 750,
+            counterparty_stake: 250,
+        }
+    }
+
+    #[test]
+    fn winner_takes_all_pays_the_whole_pot_outright() {
+        let strategy = WinnerTakesAllPayout;
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Trader)).unwrap(),
+            Payouts { trader: 1_000, counterparty: 0 }
+        );
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Counterparty)).unwrap(),
+            Payouts { trader: 0, counterparty: 1_000 }
+        );
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Split)).unwrap(),
+            Payouts { trader: 750, counterparty: 250 }
+        );
+    }
+
+    #[test]
+    fn pro_rata_shares_by_stake_and_rounds_to_the_trader() {
+        let ctx = PayoutContext {
+            distributable: 1_001,
+            trader_stake: 1,
+            counterparty_stake: 2,
+            ..payout_context(Outcome::Split)
+        };
+        assert_eq!(
+            ProRataPayout.distribute(&ctx).unwrap(),
+            Payouts { trader: 334, counterparty: 667 }
+        );
+        let empty = PayoutContext {
+            trader_stake: 0,
+            counterparty_stake: 0,
+            ..ctx
+        };
+        assert!(ProRataPayout.distribute(&empty).is_err());
+    }
+
+    #[test]
+    fn profit_share_gives_the_winner_a_cut_of_the_losing_share() {
+        let strategy = ProfitSharePayout { profit_percentage: 20 };
+        // Counterparty's 250 share, of which the trader takes 20%
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Trader)).unwrap(),
+            Payouts { trader: 800, counterparty: 200 }
+        );
+        // Trader's 750 share, of which the counterparty takes 20%
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Counterparty)).unwrap(),
+            Payouts { trader: 600, counterparty: 400 }
+        );
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Split)).unwrap(),
+            Payouts { trader: 750, counterparty: 250 }
+        );
+    }
+
+    #[test]
+    fn consolation_returns_a_share_of_the_pot_to_the_loser() {
+        let strategy = ConsolationPayout { consolation_bps: 1_000 };
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Trader)).unwrap(),
+            Payouts { trader: 900, counterparty: 100 }
+        );
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Counterparty)).unwrap(),
+            Payouts { trader: 100, counterparty: 900 }
+        );
+        assert_eq!(
+            strategy.distribute(&payout_context(Outcome::Split)).unwrap(),
+            Payouts { trader: 750, counterparty: 250 }
+        );
+
+        // Rounding favours the winner and never loses a lamport
+        for distributable in [0, 1, 9, 10_001, u64::MAX] {
+            let ctx = PayoutContext {
+                distributable,
+                ..payout_context(Outcome::Trader)
+            };
+            let payouts = strategy.distribute(&ctx).unwrap();
+            assert_eq!(payouts.trader + payouts.counterparty, distributable);
+            assert_eq!(payouts.counterparty, distributable / 10);
+        }
+    }
+
+    #[test]
+    fn late_settlements_refund_past_the_window() {
+        let config = Config {
+            settlement_window_secs: 600,
+            ..Config::default()
+        };
+        assert!(!config.settlement_window_passed(1_000, 1_600));
+        assert!(config.settlement_window_passed(1_000, 1_601));
+        assert!(!Config::default().settlement_window_passed(1_000, i64::MAX));
+    }
+
+    #[test]
+    fn fee_exempt_escrows_skip_only_the_treasury_fee() {
+        let config = Config {
+            fee_bps: 200,
+            keeper_reward_base_bps: 100,
+            keeper_reward_max_bps: 100,
+            keeper_reward_step_secs: 3600,
+            ..Config::default()
+        };
+        let escrow = EscrowState {
+            margin_amount: 10_000,
+            odds_numerator: 1,
+            odds_denominator: 1,
+            counterparty: Some(Pubkey::new_unique()),
+            trader_deposit: 10_000,
+            counterparty_deposit: 10_000,
+            max_fee_bps: u16::MAX,
+            ..EscrowState::default()
+        };
+        let charged = settlement_costs(&escrow, &config, Outcome::Trader, 0).unwrap();
+        assert_eq!(charged.treasury_fee, 400);
+        assert_eq!(charged.keeper_reward, 196);
+
+        let exempt = EscrowState { fee_exempt: true, ..escrow };
+        let costs = settlement_costs(&exempt, &config, Outcome::Trader, 0).unwrap();
+        assert_eq!(costs.treasury_fee, 0);
+        assert_eq!(costs.keeper_reward, 200);
+        assert_eq!(costs.payouts.trader, 19_800);
+
+        let partner = Pubkey::new_unique();
+        let list = FeeExemptList { wallets: vec![partner], bump: 0 };
+        assert!(FeeExemptList::lists(Some(&list), &partner));
+        assert!(!FeeExemptList::lists(Some(&list), &Pubkey::new_unique()));
+        assert!(!FeeExemptList::lists(None, &partner));
+    }
+
+    #[test]
+    fn fee_hikes_stop_at_the_escrows_accepted_maximum() {
+        let config = Config {
+            fee_bps: 500,
+            keeper_reward_step_secs: 3600,
+            ..Config::default()
+        };
+        let escrow = EscrowState {
+            margin_amount: 10_000,
+            odds_numerator: 1,
+            odds_denominator: 1,
+            counterparty: Some(Pubkey::new_unique()),
+            trader_deposit: 10_000,
+            counterparty_deposit: 10_000,
+            max_fee_bps: 200,
+            ..EscrowState::default()
+        };
+        let capped = settlement_costs(&escrow, &config, Outcome::Trader, 0).unwrap();
+        assert_eq!(capped.treasury_fee, 400);
+
+        // A fee cut below the cap is passed on
+        let cut = Config { fee_bps: 100, ..config };
+        let costs = settlement_costs(&escrow, &cut, Outcome::Trader, 0).unwrap();
+        assert_eq!(costs.treasury_fee, 200);
+    }
+
+    #[test]
+    fn token_keeper_rewards_leave_the_pot_to_the_parties() {
+        let sol = Config {
+            keeper_reward_base_bps: 100,
+            keeper_reward_max_bps: 100,
+            keeper_reward_step_secs: 3600,
+            ..Config::default()
+        };
+        let token = Config {
+            keeper_reward_in_token: true,
+            ..sol.clone()
+        };
+        let escrow = EscrowState {
+            margin_amount: 10_000,
+            odds_numerator: 1,
+            odds_denominator: 1,
+            counterparty: Some(Pubkey::new_unique()),
+            trader_deposit: 10_000,
+            counterparty_deposit: 10_000,
+            ..EscrowState::default()
+        };
+        let skimmed = settlement_costs(&escrow, &sol, Outcome::Trader, 0).unwrap();
+        assert_eq!((skimmed.keeper_reward, skimmed.keeper_token_reward), (200, 0));
+        assert_eq!(skimmed.payouts.trader, 19_800);
+
+        let paid_aside = settlement_costs(&escrow, &token, Outcome::Trader, 0).unwrap();
+        assert_eq!((paid_aside.keeper_reward, paid_aside.keeper_token_reward), (0, 200));
+        assert_eq!(paid_aside.payouts.trader, 20_000);
+    }
+
+    #[test]
+    fn refunds_return_each_sides_own_deposit() {
+        let escrow = EscrowState {
+            margin_amount: 10_000,
+            odds_numerator: 1,
+            odds_denominator: 1,
+            counterparty: Some(Pubkey::new_unique()),
+            trader_deposit: 12_345,
+            counterparty_deposit: 6_789,
+            ..EscrowState::default()
+        };
+        let refunds = escrow.refunds();
+        assert_eq!(refunds, Payouts { trader: 12_345, counterparty: 6_789 });
+        assert_eq!(refunds.trader + refunds.counterparty, escrow.pot().unwrap());
+
+        let config = Config {
+            fee_bps: 100,
+            keeper_reward_base_bps: 50,
+            keeper_reward_max_bps: 50,
+            keeper_reward_step_secs: 3600,
+            ..Config::default()
+        };
+        let costs = settlement_costs(&escrow, &config, Outcome::Refund, 0).unwrap();
+        assert_eq!(costs.payouts, refunds);
+        assert_eq!(costs.treasury_fee + costs.keeper_reward + costs.dispute_reward, 0);
+    }
+
+    #[test]
+    fn settlement_distributes_exactly_what_was_deposited() {
+        let config = Config {
+            fee_bps: 100,
+            keeper_reward_base_bps: 50,
+            keeper_reward_max_bps: 50,
+            keeper_reward_step_secs: 3600,
+            ..Config::default()
+        };
+        // The trader topped up after the odds set the counterparty's stake
+        let escrow = EscrowState {
+            margin_amount: 10_000,
+            odds_numerator: 1,
+            odds_denominator: 1,
+            counterparty: Some(Pubkey::new_unique()),
+            trader_deposit: 12_345,
+            counterparty_deposit: 6_789,
+            max_fee_bps: u16::MAX,
+            ..EscrowState::default()
+        };
+        assert_eq!(escrow.pot().unwrap(), 19_134);
+        for outcome in [Outcome::Trader, Outcome::Counterparty, Outcome::Split] {
+            let costs = settlement_costs(&escrow, &config, outcome, 0).unwrap();
+            assert_eq!(costs.treasury_fee, 123 + 67);
+            assert_eq!(
+                costs.treasury_fee
+                    + costs.keeper_reward
+                    + costs.payouts.trader
+                    + costs.payouts.counterparty,
+                escrow.pot().unwrap(),
+                "{outcome:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn loser_pays_costs_fit_inside_the_losing_stake() {
+        assert_eq!(costs_within(1_000, 100, 200, 300), (100, 200, 300));
+        assert_eq!(costs_within(250, 100, 200, 300), (100, 150, 0));
+        assert_eq!(costs_within(450, 100, 0, 400), (100, 0, 350));
+        assert_eq!(costs_within(50, 100, 200, 300), (50, 0, 0));
+    }
+
+    #[test]
+    fn maker_reward_decays_linearly_to_the_horizon() {
+        let config = Config {
+            maker_reward_amount: 1_000_000,
+            maker_reward_horizon_secs: 3_600,
+            ..Config::default()
+        };
+        assert_eq!(config.maker_reward(0).unwrap(), 1_000_000);
+        assert_eq!(config.maker_reward(1_800).unwrap(), 500_000);
+        assert_eq!(config.maker_reward(3_599).unwrap(), 277);
+        assert_eq!(config.maker_reward(3_600).unwrap(), 0);
+        assert_eq!(config.maker_reward(i64::MAX).unwrap(), 0);
+
+        let flat = Config {
+            maker_reward_amount: 1_000_000,
+            ..Config::default()
+        };
+        assert_eq!(flat.maker_reward(i64::MAX).unwrap(), 1_000_000);
+    }
+
+    #[test]
+    fn early_exit_penalty_is_a_share_of_the_leavers_stake() {
+        let config = Config {
+            early_exit_penalty_bps: 1_000,
+            ..Config::default()
+        };
+        assert_eq!(config.early_exit_penalty(500_000_000).unwrap(), 50_000_000);
+        assert_eq!(config.early_exit_penalty(9).unwrap(), 0);
+        assert_eq!(Config::default().early_exit_penalty(u64::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn void_opens_once_the_grace_period_ends() {
+        let config = Config {
+            void_grace_secs: 3_600,
+            ..Config::default()
+        };
+        assert!(!config.void_available(1_000, 4_599));
+        assert!(config.void_available(1_000, 4_600));
+        assert!(config.void_available(i64::MAX, i64::MAX));
+    }
+
+    #[test]
+    fn payouts_go_anywhere_until_the_allowlist_is_set() {
+        let vetted = Pubkey::new_unique();
+        let open = Config::default();
+        assert!(open.allows_payout(&vetted));
+
+        let restricted = Config {
+            payout_allowlist: vec![vetted],
+            ..Config::default()
+        };
+        assert!(restricted.allows_payout(&vetted));
+        assert!(!restricted.allows_payout(&Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn acceptable_price_range_is_inclusive() {
+        let range = PriceRange { min: 100, max: 200 };
+        assert!(range.contains(100));
+        assert!(range.contains(200));
+        assert!(!range.contains(99));
+        assert!(!range.contains(201));
+    }
+
+    #[test]
+    fn arbiter_cannot_take_a_side() {
+        let config = Config {
+            arbiter: Pubkey::new_unique(),
+            ..Config::default()
+        };
+        let escrow = EscrowState {
+            tensor_oracle: Pubkey::new_unique(),
+            ..EscrowState::default()
+        };
+        assert!(config.has_conflict(&config.arbiter));
+        // An oracle account's key is no wallet's, so it's no conflict
+        assert!(!config.has_conflict(&escrow.tensor_oracle));
+        assert!(!config.has_conflict(&Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn market_maker_takes_allowlisted_collections_up_to_its_margin() {
+        let escrow = EscrowState {
+            collection_hash: hash_collection_id("y00ts"),
+            margin_amount: 1_000,
+            odds_numerator: 1,
+            odds_denominator: 1,
+            ..EscrowState::default()
+        };
+        let config = Config {
+            mm_collections: vec![hash_collection_id("y00ts")],
+            mm_max_margin: 1_000,
+            ..Config::default()
+        };
+        assert!(config.market_maker_takes(&escrow).unwrap());
+
+        let larger = EscrowState {
+            margin_amount: 1_001,
+            ..escrow.clone()
+        };
+        assert!(!config.market_maker_takes(&larger).unwrap());
+        let uncapped = Config {
+            mm_max_margin: 0,
+            ..config.clone()
+        };
+        assert!(uncapped.market_maker_takes(&larger).unwrap());
+
+        let elsewhere = EscrowState {
+            collection_hash: hash_collection_id("mad_lads"),
+            ..escrow
+        };
+        assert!(!config.market_maker_takes(&elsewhere).unwrap());
+    }
+
+    #[test]
+    fn opened_escrows_hold_rent_and_margin() {
+        assert!(verify_funded(1_500, 500, 1_000).is_ok());
+        assert!(verify_funded(1_600, 500, 1_000).is_ok());
+        assert_eq!(
+            verify_funded(1_499, 500, 1_000).unwrap_err(),
+            EscrowError::TransferFailed.into()
+        );
+        assert!(verify_funded(u64::MAX, 1, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn escrow_funding_quotes_the_address_and_lamports_init_takes() {
+        let trader = Pubkey::new_unique();
+        let rent = Rent::default();
+        let funding = EscrowFunding::new(&trader, 7, 1_000, &rent).unwrap();
+        let (address, _) = Pubkey::find_program_address(
+            &[b"escrow", trader.as_ref(), &7u64.to_le_bytes()],
+            &crate::ID,
+        );
+        assert_eq!(funding.escrow, address);
+        assert_eq!(funding.rent, rent.minimum_balance(EscrowState::LEN));
+        assert_eq!(funding.required_lamports, funding.rent + 1_000);
+        // Exactly what init checks the new escrow holds
+        assert!(verify_funded(funding.required_lamports, funding.rent, 1_000).is_ok());
+        assert!(verify_funded(funding.required_lamports - 1, funding.rent, 1_000).is_err());
+
+        assert_eq!(
+            EscrowFunding::new(&trader, 7, u64::MAX, &rent).unwrap_err(),
+            EscrowError::MathOverflow.into()
+        );
+    }
+
+    #[test]
+    fn balances_accumulate_until_withdrawn() {
+        let mut balance = Balance {
+            owner: Pubkey::new_unique(),
+            amount: 0,
+            bump: 0,
+        };
+        balance.credit(700).unwrap();
+        balance.credit(300).unwrap();
+        assert_eq!(balance.take(), 1_000);
+        assert_eq!(balance.amount, 0);
+
+        balance.credit(u64::MAX).unwrap();
+        assert_eq!(balance.credit(1).unwrap_err(), EscrowError::MathOverflow.into());
+    }
+
+    #[test]
+    fn escrow_len_fits_the_largest_escrow() {
+        let escrow = EscrowState {
+            counterparty: Some(Pubkey::default()),
+            counterparty_prediction: Some(0),
+            price_sources: vec![PriceSource::Tensor; MAX_PRICE_SOURCES],
+            basket: vec![[0; 32]; MAX_BASKET_SIZE],
+            rollover: Some(Rollover {
+                duration: 0,
+                remaining: 0,
+            }),
+            disputer: Some(Pubkey::default()),
+            ruling: Some(Outcome::Split),
+            entry_price: Some(0),
+            direction: Some(Direction::Over),
+            expiry_slot: Some(0),
+            settlement_price: SettlementPriceKind::Twap { window_secs: 0 },
+            pyth_feed_id: Some([0; 32]),
+            ..EscrowState::default()
+        };
+        let serialized = escrow.try_to_vec().unwrap();
+        assert_eq!(8 + serialized.len(), EscrowState::LEN);
+    }
+
+    #[test]
+    fn vault_reserve_check_catches_corrupted_accounting() {
+        let rent = 1_000;
+        let mut vault = Vault { tracked: 0, bump: 0 };
+        vault.credit(5_000).unwrap();
+        verify_reserve(rent + 5_000, rent, vault.tracked).unwrap();
+
+        // A share recorded without its lamports arriving
+        vault.credit(1).unwrap();
+        assert_eq!(
+            verify_reserve(rent + 5_000, rent, vault.tracked).unwrap_err(),
+            EscrowError::VaultReserveMismatch.into()
+        );
+        // Lamports leaving without their share being released
+        assert_eq!(
+            verify_reserve(rent + 4_000, rent, 5_000).unwrap_err(),
+            EscrowError::VaultReserveMismatch.into()
+        );
+        // Never more out than escrows put in
+        assert_eq!(
+            vault.debit(5_002).unwrap_err(),
+            EscrowError::VaultReserveMismatch.into()
+        );
+        vault.debit(5_001).unwrap();
+        assert_eq!(vault.tracked, 0);
+    }
+
+    #[test]
+    fn vault_withdrawals_are_capped_at_the_escrows_share() {
+        let mut escrow = EscrowState::default();
+        escrow.credit_vault(1_000).unwrap();
+        escrow.credit_vault(500).unwrap();
+        escrow.debit_vault(1_200).unwrap();
+        assert_eq!(escrow.vault_balance, 300);
+
+        assert_eq!(
+            escrow.debit_vault(301).unwrap_err(),
+            EscrowError::VaultBalanceInsufficient.into()
+        );
+        assert_eq!(escrow.vault_balance, 300);
+    }
+
+    #[test]
+    fn sequence_sits_at_its_fixed_offset() {
+        let escrow = EscrowState {
+            sequence: 0x0102_0304_0506_0708,
+            counterparty: Some(Pubkey::default()),
+            ..EscrowState::default()
+        };
+        let mut data = Vec::new();
+        escrow.try_serialize(&mut data).unwrap();
+        let offset = EscrowState::SEQUENCE_OFFSET;
+        assert_eq!(data[offset..offset + 8], escrow.sequence.to_le_bytes());
+    }
+
+    #[test]
+    fn category_sits_at_its_fixed_offset() {
+        let escrow = EscrowState {
+            category: 7,
+            counterparty: Some(Pubkey::default()),
+            ..EscrowState::default()
+        };
+        let mut data = Vec::new();
+        escrow.try_serialize(&mut data).unwrap();
+        assert_eq!(data[EscrowState::CATEGORY_OFFSET], 7);
+
+        let config = Config {
+            categories: vec![1, 2],
+            ..Config::default()
+        };
+        assert!(config.allows_category(2));
+        assert!(!config.allows_category(3));
+        assert!(Config::default().allows_category(u8::MAX));
+    }
+
+    #[test]
+    fn bps_predictions_resolve_against_the_entry_price() {
+        let sol = 1_000_000_000;
+        let escrow = EscrowState {
+            predicted_floor: 10_500,
+            lower_tolerance: 100,
+            upper_tolerance: 100,
+            prediction_unit: PredictionUnit::BpsFromEntry,
+            entry_price: Some(10 * sol),
+            ..EscrowState::default()
+        };
+        let config = Config::default();
+        let outcome = |price: u64| {
+            projected_outcome(&escrow, escrow.price_in_prediction_unit(price).unwrap(), &config)
+        };
+        assert_eq!(outcome(10 * sol + sol / 2), Outcome::Trader);
+        // 100 bps of the 10 SOL entry either side of +500 bps
+        assert_eq!(outcome(10 * sol + sol / 2 + sol / 10), Outcome::Trader);
+        assert_eq!(outcome(10 * sol + sol / 2 + sol / 10 + sol / 100), Outcome::Counterparty);
+        assert_eq!(outcome(10 * sol), Outcome::Counterparty);
+
+        let unaccepted = EscrowState {
+            entry_price: None,
+            ..escrow
+        };
+        assert!(unaccepted.price_in_prediction_unit(10 * sol).is_err());
+        assert!(!bps_prediction_is_sane(0));
+        assert!(bps_prediction_is_sane(MAX_PREDICTION_BPS));
+        assert!(!bps_prediction_is_sane(MAX_PREDICTION_BPS + 1));
+    }
+
+    #[test]
+    fn rank_predictions_resolve_against_the_oracle_rank() {
+        struct Floors(&'static [(&'static str, u64)]);
+        impl TensorOracle for Floors {
+            fn get_floor_price(&self, collection_id: &str) -> Result<u64> {
+                self.0
+                    .iter()
+                    .find(|(id, _)| *id == collection_id)
+                    .map(|&(_, floor)| floor)
+                    .ok_or(error!(EscrowError::CollectionIdMismatch))
+            }
+        }
+        let oracle = Floors(&[("primary", 20), ("high", 30), ("low", 10), ("tied", 20)]);
+        let others = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        assert_eq!(oracle.get_floor_rank("primary", &others(&["low"])).unwrap(), 1);
+        assert_eq!(oracle.get_floor_rank("primary", &others(&["high", "low"])).unwrap(), 2);
+        // A tie shares the better rank
+        assert_eq!(oracle.get_floor_rank("primary", &others(&["tied", "high"])).unwrap(), 2);
+
+        let escrow = EscrowState {
+            predicted_floor: 2,
+            prediction_unit: PredictionUnit::Rank,
+            counterparty_prediction: Some(1),
+            ..EscrowState::default()
+        };
+        let config = Config::default();
+        let rank = oracle.get_floor_rank("primary", &others(&["high", "low"])).unwrap();
+        let price = escrow.price_in_prediction_unit(rank).unwrap();
+        assert_eq!(projected_outcome(&escrow, price, &config), Outcome::Trader);
+        assert_eq!(projected_outcome(&escrow, 1, &config), Outcome::Counterparty);
+
+        assert!(!rank_prediction_is_sane(0, 2));
+        assert!(rank_prediction_is_sane(3, 2));
+        assert!(!rank_prediction_is_sane(4, 2));
+    }
+
+    #[test]
+    fn prices_are_only_read_for_the_stored_collections() {
+        let oracle_key = Pubkey::new_unique();
+        let (mut lamports, mut data) = (0, Vec::new());
+        let oracle = AccountInfo::new(
+            &oracle_key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &oracle_key,
+            false,
+            0,
+        );
+        let escrow = EscrowState {
+            collection_hash: hash_collection_id("stored"),
+            basket: vec![hash_collection_id("member")],
+            tensor_oracle: oracle_key,
+            price_sources: vec![PriceSource::Tensor],
+            ..EscrowState::default()
+        };
+        let config = Config::default();
+        let basket = ["member".to_string()];
+        let feeds = |collection_id, basket_ids| PriceFeeds {
+            collection_id,
+            basket_ids,
+            tensor_oracle: &oracle,
+            pyth_price_update: None,
+        };
+
+        assert!(feeds("stored", &basket).price(&escrow, &config, 0).is_ok());
+        for (collection_id, basket_ids) in [
+            ("other", &basket[..]),
+            ("stored", &[]),
+            ("stored", &["stored".to_string()]),
+        ] {
+            assert_eq!(
+                feeds(collection_id, basket_ids)
+                    .price(&escrow, &config, 0)
+                    .unwrap_err(),
+                EscrowError::CollectionIdMismatch.into()
+            );
+        }
+    }
+
+    #[test]
+    fn program_owned_accounts_are_refused_as_recipients_and_oracles() {
+        let key = Pubkey::new_unique();
+        let other_program = Pubkey::new_unique();
+        let (mut ours_lamports, mut ours_data) = (0, Vec::new());
+        let (mut theirs_lamports, mut theirs_data) = (0, Vec::new());
+        let ours = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut ours_lamports,
+            &mut ours_data,
+            &crate::ID,
+            false,
+            0,
+        );
+        let theirs = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut theirs_lamports,
+            &mut theirs_data,
+            &other_program,
+            false,
+            0,
+        );
+
+        assert!(require_not_program_owned(&ours).is_err());
+        assert!(tensor_oracle_key(&ours).is_err());
+        assert!(require_not_program_owned(&theirs).is_ok());
+        assert_eq!(tensor_oracle_key(&theirs).unwrap(), key);
+    }
+
+    #[test]
+    fn legacy_escrows_load_only_from_the_first_layout() {
+        let trader = Pubkey::new_unique();
+        let legacy = LegacyEscrowState {
+            trader,
+            counterparty: Some(Pubkey::new_unique()),
+            collection_id: "y00ts".to_string(),
+            predicted_floor: 10_000_000_000,
+            expiry_timestamp: 1_700_000_000,
+            margin_amount: 500_000_000,
+            is_initialized: true,
+            settled: false,
+        };
+        let mut data = EscrowState::DISCRIMINATOR.to_vec();
+        legacy.serialize(&mut data).unwrap();
+        data.resize(LegacyEscrowState::LEN, 0);
+        let mut current = data.clone();
+        current.resize(EscrowState::LEN, 0);
+
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &crate::ID,
+            false,
+            0,
+        );
+        let loaded = LegacyEscrowState::load(&account).unwrap();
+        assert_eq!(loaded.trader, trader);
+        assert_eq!(loaded.collection_id, "y00ts");
+        assert_eq!(loaded.held().unwrap(), 1_000_000_000);
+
+        // Today's layout and other programs' accounts aren't legacy escrows
+        let mut current_lamports = 0;
+        let current = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut current_lamports,
+            &mut current,
+            &crate::ID,
+            false,
+            0,
+        );
+        assert!(LegacyEscrowState::load(&current).is_err());
+        let other_program = Pubkey::new_unique();
+        let mut foreign_data = account.try_borrow_data().unwrap().to_vec();
+        let mut foreign_lamports = 0;
+        let foreign = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut foreign_lamports,
+            &mut foreign_data,
+            &other_program,
+            false,
+            0,
+        );
+        assert!(LegacyEscrowState::load(&foreign).is_err());
+    }
+
+    #[test]
+    fn prediction_error_is_absolute_and_relative_to_the_price() {
+        let sol = 1_000_000_000;
+        assert_eq!(prediction_error(10 * sol + sol / 5, 10 * sol), (sol / 5, Some(200)));
+        assert_eq!(prediction_error(9 * sol, 10 * sol), (sol, Some(1_000)));
+        assert_eq!(prediction_error(10_500, 10_500), (0, Some(0)));
+        assert_eq!(prediction_error(5, 0), (5, None));
+        assert_eq!(prediction_error(u64::MAX, 1), (u64::MAX - 1, Some(u64::MAX)));
+    }
+
+    #[test]
+    fn sol_amounts_format_and_parse_exactly() {
+        assert_eq!(format_lamports_as_sol(0), "0");
+        assert_eq!(format_lamports_as_sol(1), "0.000000001");
+        assert_eq!(format_lamports_as_sol(1_000_000_000), "1");
+        assert_eq!(format_lamports_as_sol(10_250_000_000), "10.25");
+        assert_eq!(format_lamports_as_sol(u64::MAX), "18446744073.709551615");
+        assert_eq!(format_amount(1_500, 3), "1.5");
+        assert_eq!(format_amount(42, 0), "42");
+
+        for lamports in [0, 1, 999_999_999, 1_000_000_001, 10_250_000_000, u64::MAX] {
+            assert_eq!(parse_sol_as_lamports(&format_lamports_as_sol(lamports)), Some(lamports));
+        }
+        assert_eq!(parse_sol_as_lamports("10"), Some(10_000_000_000));
+        assert_eq!(parse_sol_as_lamports(".5"), Some(500_000_000));
+        assert_eq!(parse_sol_as_lamports("0.100"), Some(100_000_000));
+        assert_eq!(parse_amount("42", 0), Some(42));
+
+        // Too precise, out of range or not a plain decimal
+        assert_eq!(parse_sol_as_lamports("0.0000000001"), None);
+        assert_eq!(parse_sol_as_lamports("18446744073.709551616"), None);
+        assert_eq!(parse_amount("1.5", 0), None);
+        for text in ["", ".", "1.", "-1", "+1", "1e9", "1,000", " 1", "1.2.3"] {
+            assert_eq!(parse_sol_as_lamports(text), None, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn settlement_record_fits_its_space() {
+        let record = SettlementRecord {
+            escrow: Pubkey::new_unique(),
+            trader: Pubkey::new_unique(),
+            counterparty: Pubkey::new_unique(),
+            predicted_floor: u64::MAX,
+            counterparty_prediction: Some(u64::MAX),
+            price: Some(u64::MAX),
+            outcome: Outcome::Split,
+            trader_payout: u64::MAX,
+            counterparty_payout: u64::MAX,
+            keeper: Pubkey::new_unique(),
+            keeper_reward: u64::MAX,
+            treasury_fee: u64::MAX,
+            dispute_reward: u64::MAX,
+            rollover_escrow: Some(Pubkey::new_unique()),
+            settled_at: i64::MAX,
+            keeper_token_reward: u64::MAX,
+            bump: u8::MAX,
+        };
+        let mut data = Vec::new();
+        record.try_serialize(&mut data).unwrap();
+        assert_eq!(data.len(), SettlementRecord::LEN);
+    }
+
+    #[test]
+    fn traders_cannot_accept_their_own_escrow() {
+        let trader = Pubkey::new_unique();
+        let escrow = EscrowState {
+            trader,
+            margin_amount: 100,
+            expiry_timestamp: 1_700_000_000,
+            ..EscrowState::default()
+        };
+        let now = Clock {
+            unix_timestamp: 1_699_999_000,
+            ..Clock::default()
+        };
+        assert_eq!(
+            escrow.check_accept(&trader, 100, &now).unwrap_err(),
+            EscrowError::SelfTrade.into()
+        );
+        escrow.check_accept(&Pubkey::new_unique(), 100, &now).unwrap();
+    }
+
+    #[test]
+    fn expiry_boundary_closes_accept_and_opens_settle() {
+        let escrow = EscrowState {
+            expiry_timestamp: 1_700_000_000,
+            ..EscrowState::default()
+        };
+        let at = |unix_timestamp| Clock {
+            unix_timestamp,
+            ..Clock::default()
+        };
+        assert!(escrow.accepts_at(&at(1_699_999_999)));
+        assert!(!escrow.settles_at(&at(1_699_999_999)));
+
+        assert!(!escrow.accepts_at(&at(1_700_000_000)));
+        assert!(escrow.settles_at(&at(1_700_000_000)));
+
+        assert!(!escrow.accepts_at(&at(1_700_000_001)));
+        assert!(escrow.settles_at(&at(1_700_000_001)));
+    }
+
+    #[test]
+    fn slot_expiry_is_decided_by_the_slot_alone() {
+        let escrow = EscrowState {
+            expiry_timestamp: 1_700_000_000,
+            expiry_slot: Some(500),
+            ..EscrowState::default()
+        };
+        let at = |slot, unix_timestamp| Clock {
+            slot,
+            unix_timestamp,
+            ..Clock::default()
+        };
+        // Long past the nominal timestamp, but the slot hasn't come
+        assert!(escrow.accepts_at(&at(499, i64::MAX)));
+        assert!(!escrow.settles_at(&at(499, i64::MAX)));
+        assert!(!escrow.accepts_at(&at(500, 0)));
+        assert!(escrow.settles_at(&at(500, 0)));
+        assert!(escrow.settles_at(&at(501, 0)));
+
+        let mut extended = escrow;
+        assert_eq!(
+            extended.extend_to(1_700_003_600).unwrap_err(),
+            EscrowError::InvalidExpiry.into()
+        );
+    }
+
+    #[test]
+    fn expiry_kinds_resolve_against_their_own_clock_field() {
+        let config = Config {
+            expiry_granularity_secs: 3600,
+            ..Config::default()
+        };
+        let now = Clock {
+            slot: 1_000,
+            unix_timestamp: 1_700_000_000,
+            ..Clock::default()
+        };
+        assert_eq!(
+            ExpiryKind::UnixTime(1_700_005_000).resolve(&now, &config).unwrap(),
+            (1_700_005_000, 1_700_002_800)
+        );
+        assert_eq!(
+            ExpiryKind::UnixTime(1_700_001_000).resolve(&now, &config).unwrap_err(),
+            EscrowError::InvalidExpiry.into()
+        );
+
+        // 250 slots at 400ms is 100 seconds out, and never rounded
+        assert_eq!(
+            ExpiryKind::Slot(1_250).resolve(&now, &config).unwrap(),
+            (1_700_000_100, 1_700_000_100)
+        );
+        for stale in [999, 1_000] {
+            assert_eq!(
+                ExpiryKind::Slot(stale).resolve(&now, &config).unwrap_err(),
+                EscrowError::InvalidExpiry.into()
+            );
+        }
+    }
+
+    #[test]
+    fn tolerance_is_inclusive_on_both_sides() {
+        let config = Config::default();
+        let escrow = EscrowState {
+            predicted_floor: 10_000,
+            lower_tolerance: 100,
+            upper_tolerance: 100,
+            ..EscrowState::default()
+        };
+        for (price, outcome) in [
+            (10_000, Outcome::Trader),
+            (10_100, Outcome::Trader),
+            (9_900, Outcome::Trader),
+            (10_101, Outcome::Counterparty),
+            (9_899, Outcome::Counterparty),
+        ] {
+            assert_eq!(projected_outcome(&escrow, price, &config), outcome, "price {price}");
+        }
+
+        let exact = EscrowState {
+            lower_tolerance: 0,
+            upper_tolerance: 0,
+            ..escrow
+        };
+        assert_eq!(projected_outcome(&exact, 10_000, &config), Outcome::Trader);
+        assert_eq!(projected_outcome(&exact, 10_001, &config), Outcome::Counterparty);
+    }
+
+    #[test]
+    fn prices_beyond_i64_keep_their_distance() {
+        let config = Config::default();
+        let big = i64::MAX as u64 + 1_000;
+        // Under a signed cast these wrap negative and land miles apart
+        let escrow = EscrowState {
+            predicted_floor: big,
+            lower_tolerance: 1_000,
+            upper_tolerance: 1_000,
+            ..EscrowState::default()
+        };
+        for (price, outcome) in [
+            (big, Outcome::Trader),
+            (big + 1_000, Outcome::Trader),
+            (big - 1_000, Outcome::Trader),
+            (big + 1_001, Outcome::Counterparty),
+            (i64::MAX as u64 - 1, Outcome::Counterparty),
+            (u64::MAX, Outcome::Counterparty),
+        ] {
+            assert_eq!(projected_outcome(&escrow, price, &config), outcome, "price {price}");
+        }
+        // Straddling i64::MAX is still a short hop, not a wraparound
+        let escrow = EscrowState {
+            predicted_floor: i64::MAX as u64 - 5,
+            counterparty_prediction: Some(u64::MAX),
+            ..EscrowState::default()
+        };
+        assert_eq!(projected_outcome(&escrow, big, &config), Outcome::Trader);
+        assert_eq!(prediction_error(i64::MAX as u64 - 5, big).0, 1_005);
+    }
+
+    #[test]
+    fn asymmetric_bands_are_inclusive_at_each_edge() {
+        // Trader wins from 50 below to 150 above the prediction
+        let escrow = EscrowState {
+            predicted_floor: 10_000,
+            lower_tolerance: 50,
+            upper_tolerance: 150,
+            ..EscrowState::default()
+        };
+        for (price, hit) in [
+            (9_949, false),
+            (9_950, true),
+            (10_000, true),
+            (10_150, true),
+            (10_151, false),
+        ] {
+            assert_eq!(escrow.within_tolerance(price), hit, "price {price}");
+        }
+
+        // A lower band past zero just covers every price below
+        let wide = EscrowState {
+            predicted_floor: 10,
+            lower_tolerance: u64::MAX,
+            upper_tolerance: 0,
+            ..EscrowState::default()
+        };
+        assert!(wide.within_tolerance(0));
+        assert!(!wide.within_tolerance(11));
+    }
+
+    #[test]
+    fn over_under_escrows_pay_the_side_of_the_line() {
+        let config = Config::default();
+        let over = EscrowState {
+            predicted_floor: 10_000,
+            lower_tolerance: 100,
+            upper_tolerance: 100,
+            direction: Some(Direction::Over),
+            counterparty_prediction: Some(9_000),
+            ..EscrowState::default()
+        };
+        // Distance and tolerance play no part, only the side
+        assert_eq!(projected_outcome(&over, 10_001, &config), Outcome::Trader);
+        assert_eq!(projected_outcome(&over, 9_050, &config), Outcome::Counterparty);
+        assert_eq!(projected_outcome(&over, 10_000, &config), Outcome::Split);
+
+        let under = EscrowState {
+            direction: Some(Direction::Under),
+            ..over
+        };
+        assert_eq!(projected_outcome(&under, 9_999, &config), Outcome::Trader);
+        assert_eq!(projected_outcome(&under, 10_001, &config), Outcome::Counterparty);
+
+        assert_eq!(Direction::of(10_001, 10_000), Some(Direction::Over));
+        assert_eq!(Direction::of(10_000, 10_000), None);
+        assert_eq!(Direction::Over.opposite(), Direction::Under);
+    }
+
+    #[test]
+    fn winner_is_determined_by_the_terms_alone() {
+        use Outcome::{Counterparty as C, Split as S, Trader as T};
+        let (over, under) = (Some(Direction::Over), Some(Direction::Under));
+        let max = u64::MAX;
+        // (predicted, counter, price, direction, lower, upper, tie, outcome)
+        let table = [
+            // Tolerance band, no counter-prediction
+            (100, None, 100, None, 0, 0, S, T),
+            (100, None, 101, None, 0, 0, S, C),
+            (100, None, 90, None, 10, 0, S, T),
+            (100, None, 89, None, 10, 0, S, C),
+            (100, None, 120, None, 0, 20, S, T),
+            (100, None, 121, None, 0, 20, S, C),
+            (100, None, 0, None, max, 0, S, T),
+            (0, None, max, None, 0, max, S, T),
+            (max, None, 0, None, max - 1, 0, S, C),
+            (max, None, max, None, 0, 0, S, T),
+            // Closest call wins, tolerances ignored
+            (100, Some(200), 140, None, 50, 50, S, T),
+            (100, Some(200), 160, None, 50, 50, S, C),
+            (100, Some(200), 150, None, 0, 0, S, S),
+            (100, Some(200), 150, None, 0, 0, T, T),
+            (100, Some(200), 150, None, 0, 0, C, C),
+            (100, Some(100), 500, None, 0, 0, S, S),
+            (0, Some(max), max, None, 0, 0, S, C),
+            (0, Some(max), 0, None, 0, 0, S, T),
+            (max, Some(0), max / 2 + 1, None, 0, 0, S, T),
+            // Over/under, only the side of the line counts
+            (100, Some(50), 101, over, 0, 0, T, T),
+            (100, Some(50), 99, over, 0, 0, T, C),
+            (100, Some(50), 100, over, 0, 0, T, S),
+            (100, None, 99, under, 0, 0, S, T),
+            (100, None, 101, under, 0, 0, S, C),
+            (100, None, 100, under, 0, 0, C, S),
+            (0, None, max, over, 0, 0, S, T),
+            (max, None, 0, under, 0, 0, S, T),
+        ];
+        for (predicted, counter, price, direction, lower, upper, tie, outcome) in table {
+            assert_eq!(
+                determine_winner(predicted, counter, price, direction, lower, upper, tie),
+                outcome,
+                "predicted {predicted}, counter {counter:?}, price {price}, {direction:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rollover_counts_down_to_a_plain_escrow() {
+        let config = Config::default();
+        let escrow = EscrowState {
+            predicted_floor: 10_000,
+            margin_amount: 500,
+            odds_numerator: 1,
+            odds_denominator: 1,
+            status: EscrowStatus::Settled,
+            rollover: Some(Rollover {
+                duration: 3_600,
+                remaining: 2,
+            }),
+            ..EscrowState::default()
+        };
+
+        let followup = escrow.rolled_over(900, 1_000, &config).unwrap().unwrap();
+        assert_eq!(followup.status, EscrowStatus::Open);
+        assert_eq!(followup.margin_amount, 900);
+        assert_eq!(followup.predicted_floor, 10_000);
+        assert_eq!(followup.expiry_timestamp, 4_600);
+        assert_eq!(
+            followup.rollover,
+            Some(Rollover {
+                duration: 3_600,
+                remaining: 1
+            })
+        );
+
+        let last = followup.rolled_over(900, 4_600, &config).unwrap().unwrap();
+        assert_eq!(last.rollover, None);
+        assert!(last.rolled_over(900, 8_200, &config).unwrap().is_none());
+
+        // Nothing left after rent, or over the cap: pay out instead
+        assert!(escrow.rolled_over(0, 1_000, &config).unwrap().is_none());
+        let capped = Config {
+            max_margin: 800,
+            ..Config::default()
+        };
+        assert!(escrow.rolled_over(900, 1_000, &capped).unwrap().is_none());
+    }
+
+    #[test]
+    fn status_transitions_follow_the_state_machine() {
+        use EscrowStatus::*;
+
+        let valid = [
+            (Open, Active),
+            (Open, Cancelled),
+            (Open, Voided),
+            (Active, Settled),
+            (Active, Voided),
+        ];
+        for (from, to) in valid {
+            let mut escrow = EscrowState {
+                status: from,
+                ..EscrowState::default()
+            };
+            escrow
+                .transition(Pubkey::default(), from, to, Pubkey::default(), 0)
+                .unwrap();
+            assert_eq!(escrow.status, to);
+        }
+
+        let all = [Open, Active, Settled, Cancelled, Voided];
+        for from in all {
+            for to in all {
+                if valid.contains(&(from, to)) {
+                    continue;
+                }
+                let mut escrow = EscrowState {
+                    status: from,
+                    ..EscrowState::default()
+                };
+                assert_eq!(
+                    escrow
+                        .transition(Pubkey::default(), from, to, Pubkey::default(), 0)
+                        .unwrap_err(),
+                    EscrowError::InvalidStateTransition.into()
+                );
+                assert_eq!(escrow.status, from);
+            }
+        }
+    }
+
+    #[test]
+    fn status_transition_requires_the_current_status() {
+        let mut escrow = EscrowState {
+            status: EscrowStatus::Settled,
+            ..EscrowState::default()
+        };
+        assert_eq!(
+            escrow
+                .transition(
+                    Pubkey::default(),
+                    EscrowStatus::Active,
+                    EscrowStatus::Settled,
+                    Pubkey::default(),
+                    0,
+                )
+                .unwrap_err(),
+            EscrowError::InvalidStateTransition.into()
+        );
+        assert_eq!(escrow.status, EscrowStatus::Settled);
+    }
 }
@@ -1,7 +1,18 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { PublicKey, Keypair, LAMPORTS_PER_SOL } from '@solana/web3.js';
+import {
+  NATIVE_MINT,
+  TOKEN_PROGRAM_ID,
+  createMint,
+  createWrappedNativeAccount,
+  getAccount,
+  getOrCreateAssociatedTokenAccount,
+  mintTo,
+} from '@solana/spl-token';
 import fetch from 'node-fetch';
+import { createHash } from 'crypto';
+import { expect } from 'chai';
 
 // Tensor API endpoints
 const TENSOR_API_ENDPOINT = 'https://api.tensor.so/graphql';
@@ -82,9 +93,537 @@ describe("escrowfloor", () => {
   // Tensor swap program ID
   const TENSOR_SWAP_ID = new PublicKey("TSWAPaqyCSx2KABk68Shruf4rp7CxcNi8hAsbdwmHbN");
 
+  // Pyth price updates loaded from tests/fixtures; the first two quote a 10 SOL floor
+  const PYTH_TIGHT_CONFIDENCE = new PublicKey("CAgeoE3rvpytZNgTF65LWtBU6NoQA6GGsUnepKeiTCmM"); // +/- 0.01 SOL
+  const PYTH_WIDE_CONFIDENCE = new PublicKey("Fuzk915h6zxhosmoH1rRMgjpzkyLfcsk5ZbqQJqiMHLV"); // +/- 1 SOL
+  const PYTH_HIGHER_PRICE = new PublicKey("3aHbsB12H3fZoofKAdyBwNR31Pir4i8UQbzN8gwUiRYq"); // 12 SOL
+  // Feeds the fixtures were published on; the tight and higher updates share one
+  const PYTH_FLOOR_FEED = Buffer.from(
+    "339e57b5239a1b4db5c46ba9fb76fb864707b705a56c3df1a14f55cd9f0df4b7",
+    "hex"
+  );
+  const PYTH_WIDE_FEED = Buffer.from(
+    "47c2aed5e4107a205a1d967441285e53cb0f7822d42dccf41c6e3a0d75e3386f",
+    "hex"
+  );
+
+  // First-release escrow at ["escrow", trader], loaded from tests/fixtures: accepted, 0.5 SOL
+  // a side, predicting 10 SOL for "legacy-y00ts"
+  const LEGACY_TRADER = new PublicKey("8tJi1fv54VfDbSDcXcTQyXaQsg5ZD8JWZVcjfgAoKvqJ");
+  const LEGACY_COUNTERPARTY = new PublicKey("HVGMFeUG6PCc3ZbjhmbgacM29KUGKHtXusiszatqVjpj");
+
   // Test collection - y00ts
   const COLLECTION_SLUG = "y00ts";
 
+  const [configPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("config")],
+    program.programId
+  );
+
+  const [statsPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("stats")],
+    program.programId
+  );
+
+  const [rewardAuthorityPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("reward_authority")],
+    program.programId
+  );
+
+  const [vaultPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("vault")],
+    program.programId
+  );
+  // Passed to every instruction once the vault test has created it
+  let vault: PublicKey | null = null;
+
+  const [feeExemptPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("fee_exempt")],
+    program.programId
+  );
+  // Passed to new escrows once the fee exemption test has created the list
+  let feeExempt: PublicKey | null = null;
+
+  const [rewardVaultPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("reward_vault")],
+    program.programId
+  );
+
+  const [oracleRegistryPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("oracle_registry")],
+    program.programId
+  );
+
+  const DEFAULT_CONFIG = {
+    keeperRewardBaseBps: 10,
+    keeperRewardStepBps: 10,
+    keeperRewardStepSecs: 3600,
+    keeperRewardMaxBps: 100,
+    tieBreakByTime: false,
+    maxTvl: new anchor.BN(0),
+    rewardMint: null as PublicKey | null,
+    makerRewardAmount: new anchor.BN(0),
+    maxConfidenceBps: 0,
+    maxPriceAgeSecs: 0,
+    feeBps: 0,
+    treasury: PublicKey.default,
+    maxMargin: new anchor.BN(0),
+    expiryGranularitySecs: 0,
+    acceptCooldownSecs: 0,
+    dailyVolumeLimit: new anchor.BN(0),
+    arbiter: PublicKey.default,
+    disputeBond: new anchor.BN(0),
+    disputeRewardBps: 0,
+    disputeWindowSecs: 0,
+    duplicatePredictionEpsilon: new anchor.BN(0),
+    useVault: false,
+    predictionTick: new anchor.BN(0),
+    settleAllowlist: [] as PublicKey[],
+    marketMaker: null as PublicKey | null,
+    mmMaxMargin: new anchor.BN(0),
+    mmCollections: [] as string[],
+    voidGraceSecs: 3600,
+    loserPaysCosts: false,
+    settlementWindowSecs: 0,
+    makerRewardHorizonSecs: 0,
+    categories: [] as number[],
+    earlyExitPenaltyBps: 0,
+    minObservationPeriodSecs: 0,
+    consolationBps: 0,
+    maxToleranceBps: 0,
+    keeperRewardInToken: false,
+    minPriceSamples: 0,
+    minUpdateAgeSecs: 0,
+    payoutAllowlist: [] as PublicKey[],
+    closeOnSettle: false,
+  };
+
+  async function setConfig(overrides: Partial<typeof DEFAULT_CONFIG> = {}) {
+    await program.methods
+      .updateConfig({ ...DEFAULT_CONFIG, ...overrides })
+      .accounts({ admin: provider.wallet.publicKey, config: configPDA })
+      .rpc();
+  }
+
+  before(async () => {
+    await program.methods
+      .initializeConfig(DEFAULT_CONFIG)
+      .accounts({
+        admin: provider.wallet.publicKey,
+        config: configPDA,
+        stats: statsPDA,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+  });
+
+  afterEach(async () => {
+    await setConfig();
+  });
+
+  const sleep = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms));
+
+  async function chainTime(): Promise<number> {
+    const slot = await provider.connection.getSlot("confirmed");
+    return await provider.connection.getBlockTime(slot);
+  }
+
+  async function waitUntil(timestamp: number) {
+    while ((await chainTime()) < timestamp) {
+      await sleep(500);
+    }
+  }
+
+  async function airdrop(pubkey: PublicKey, lamports: number) {
+    const sig = await provider.connection.requestAirdrop(pubkey, lamports);
+    await provider.connection.confirmTransaction(sig, "confirmed");
+  }
+
+  function profileAddress(trader: PublicKey): PublicKey {
+    const [profilePDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader_profile"), trader.toBuffer()],
+      program.programId
+    );
+    return profilePDA;
+  }
+
+  function wsolScratchAddress(owner: PublicKey): PublicKey {
+    const [scratchPDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wsol_scratch"), owner.toBuffer()],
+      program.programId
+    );
+    return scratchPDA;
+  }
+
+  function escrowAddress(trader: PublicKey, nonce = 0): PublicKey {
+    const [escrowPDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("escrow"), trader.toBuffer(), new anchor.BN(nonce).toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    return escrowPDA;
+  }
+
+  function settlementRecordAddress(escrow: PublicKey): PublicKey {
+    const [recordPDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("settlement_record"), escrow.toBuffer()],
+      program.programId
+    );
+    return recordPDA;
+  }
+
+  // Rent the keeper puts up for each settlement record
+  async function recordRent(): Promise<number> {
+    return provider.connection.getMinimumBalanceForRentExemption(
+      program.account.settlementRecord.size
+    );
+  }
+
+  // Address the trader's next escrow will be created at
+  async function nextNonce(trader: PublicKey): Promise<number> {
+    const profile = await program.account.traderProfile.fetchNullable(profileAddress(trader));
+    return profile ? profile.escrowCount.toNumber() : 0;
+  }
+
+  async function nextEscrowAddress(trader: PublicKey): Promise<PublicKey> {
+    return escrowAddress(trader, await nextNonce(trader));
+  }
+
+  function collectionHash(collectionId: string): Buffer {
+    return createHash("sha256").update(collectionId).digest();
+  }
+
+  function indexAddressFromHash(hash: Buffer, chunk = 0): PublicKey {
+    const chunkSeed = Buffer.alloc(4);
+    chunkSeed.writeUInt32LE(chunk);
+    const [indexPDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("collection_index"), hash, chunkSeed],
+      program.programId
+    );
+    return indexPDA;
+  }
+
+  function indexAddress(collectionId: string, chunk = 0): PublicKey {
+    return indexAddressFromHash(collectionHash(collectionId), chunk);
+  }
+
+  // A collection's flags, which needn't exist yet
+  function collectionStateAddress(hash: Buffer): PublicKey {
+    const [statePDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("collection_state"), hash],
+      program.programId
+    );
+    return statePDA;
+  }
+
+  function priceHistoryAddress(collectionId: string): PublicKey {
+    const [historyPDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("price_history"), collectionHash(collectionId)],
+      program.programId
+    );
+    return historyPDA;
+  }
+
+  async function initPriceHistory(collectionId: string, tensorOracle = TENSOR_SWAP_ID) {
+    await program.methods
+      .initializePriceHistory(collectionId)
+      .accounts({
+        admin: provider.wallet.publicKey,
+        config: configPDA,
+        priceHistory: priceHistoryAddress(collectionId),
+        tensorOracle,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+  }
+
+  async function recordPrice(collectionId: string, tensorOracle = TENSOR_SWAP_ID) {
+    await program.methods
+      .recordPrice(collectionId)
+      .accounts({ priceHistory: priceHistoryAddress(collectionId), tensorOracle })
+      .rpc();
+  }
+
+  // Index chunk an escrow is listed in
+  function escrowIndexAddress(state: { collectionHash: number[]; indexChunk: number }) {
+    return indexAddressFromHash(Buffer.from(state.collectionHash), state.indexChunk);
+  }
+
+  async function initEscrow(
+    trader: Keypair,
+    {
+      collectionId = COLLECTION_SLUG,
+      predictedFloor = 10 * LAMPORTS_PER_SOL,
+      expiry = null as number | null,
+      // Band either side of the prediction, unless set separately below
+      tolerance = 100,
+      lowerTolerance = null as number | null,
+      upperTolerance = null as number | null,
+      margin = 0.5 * LAMPORTS_PER_SOL,
+      indexChunk = 0,
+      priceSources = [{ tensor: {} }] as object[],
+      odds = [1, 1],
+      basket = [] as string[],
+      rollover = null as { duration: number; remaining: number } | null,
+      predictionUnit = { absoluteLamports: {} } as object,
+      payoutMode = { winnerTakesAll: {} } as object,
+      // Trader's wSOL account, which also makes it a wrapped SOL escrow
+      wsolSource = null as PublicKey | null,
+      direction = null as object | null,
+      category = 0,
+      tensorOracle = TENSOR_SWAP_ID,
+      oracleRegistry = oracleRegistryPDA,
+      // Expires at this slot instead of at `expiry`
+      expirySlot = null as number | null,
+      // Defaults to the trader's next nonce
+      nonce = null as number | null,
+      settlementPrice = { spot: {} } as object,
+      // Highest settlement fee the trader accepts
+      maxFeeBps = 10_000,
+      // Only used when priceSources lists Pyth
+      pythFeedId = PYTH_FLOOR_FEED as Buffer | null,
+    } = {}
+  ) {
+    nonce ??= await nextNonce(trader.publicKey);
+    const escrow = escrowAddress(trader.publicKey, nonce);
+    await program.methods
+      .initializeEscrow(
+        {
+          collectionId,
+          predictedFloor: new anchor.BN(predictedFloor),
+          lowerTolerance: new anchor.BN(lowerTolerance ?? tolerance),
+          upperTolerance: new anchor.BN(upperTolerance ?? tolerance),
+          expiry:
+            expirySlot === null
+              ? { unixTime: [new anchor.BN(expiry ?? (await chainTime()) + 3600)] }
+              : { slot: [new anchor.BN(expirySlot)] },
+          marginAmount: new anchor.BN(margin),
+          priceSources,
+          oddsNumerator: odds[0],
+          oddsDenominator: odds[1],
+          basket,
+          rollover: rollover && {
+            duration: new anchor.BN(rollover.duration),
+            remaining: rollover.remaining,
+          },
+          predictionUnit,
+          payoutMode,
+          denomination: wsolSource ? { wrappedSol: {} } : { native: {} },
+          direction,
+          category,
+          settlementPrice,
+          maxFeeBps,
+          pythFeedId:
+            pythFeedId && priceSources.some((source) => "pyth" in source)
+              ? [...pythFeedId]
+              : null,
+        },
+        indexChunk,
+        new anchor.BN(nonce)
+      )
+      .accounts({
+        trader: trader.publicKey,
+        traderProfile: profileAddress(trader.publicKey),
+        escrow,
+        collectionIndex: indexAddress(collectionId, indexChunk),
+        collectionState: collectionStateAddress(collectionHash(collectionId)),
+        config: configPDA,
+        stats: statsPDA,
+        vault,
+        tensorOracle,
+        feeExempt,
+        oracleRegistry,
+        wsolSource,
+        wsolScratch: wsolSource && wsolScratchAddress(trader.publicKey),
+        nativeMint: wsolSource && NATIVE_MINT,
+        tokenProgram: wsolSource && TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([trader])
+      .rpc();
+    return escrow;
+  }
+
+  async function cancel(trader: Keypair, escrow: PublicKey) {
+    const state = await program.account.escrowState.fetch(escrow);
+    await program.methods
+      .cancelEscrow()
+      .accounts({
+        trader: trader.publicKey,
+        escrow,
+        collectionIndex: escrowIndexAddress(state),
+        stats: statsPDA,
+        vault,
+      })
+      .signers([trader])
+      .rpc();
+  }
+
+  async function accept(
+    counterparty: Keypair,
+    escrow: PublicKey,
+    prediction: number | null = null,
+    rewards: { mint: PublicKey; makerAccount: PublicKey } | null = null,
+    // Feed a BpsFromEntry escrow's entry price is read from
+    entry: { collectionId: string; pythPriceUpdate: PublicKey | null } | null = null,
+    wsolSource: PublicKey | null = null,
+    // Margin the counterparty signs up to match, the escrow's current one
+    // unless given
+    expectedMargin: number | null = null
+  ) {
+    const state = await program.account.escrowState.fetch(escrow);
+    const margin =
+      expectedMargin === null ? state.marginAmount : new anchor.BN(expectedMargin);
+    return program.methods
+      .acceptEscrow(
+        prediction === null ? null : new anchor.BN(prediction),
+        entry?.collectionId ?? null,
+        margin
+      )
+      .accounts({
+        trader: counterparty.publicKey,
+        escrow,
+        config: configPDA,
+        collectionState: collectionStateAddress(Buffer.from(state.collectionHash)),
+        stats: statsPDA,
+        vault,
+        rewardMint: rewards?.mint ?? null,
+        makerRewardAccount: rewards?.makerAccount ?? null,
+        rewardAuthority: rewards ? rewardAuthorityPDA : null,
+        tokenProgram: rewards || wsolSource ? TOKEN_PROGRAM_ID : null,
+        tensorOracle: entry ? TENSOR_SWAP_ID : null,
+        pythPriceUpdate: entry?.pythPriceUpdate ?? null,
+        wsolSource,
+        wsolScratch: wsolSource && wsolScratchAddress(counterparty.publicKey),
+        nativeMint: wsolSource && NATIVE_MINT,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([counterparty])
+      .rpc();
+  }
+
+  // Opens an escrow (predicting the mock oracle's 10 SOL floor unless told
+  // otherwise) and has a fresh counterparty accept it
+  async function openAcceptedEscrow(
+    expiresIn: number,
+    {
+      margin = 0.5 * LAMPORTS_PER_SOL,
+      predictedFloor = 10 * LAMPORTS_PER_SOL,
+      counterpartyPrediction = null as number | null,
+      acceptDelayMs = 0,
+      priceSources = [{ tensor: {} }] as object[],
+      collectionId = COLLECTION_SLUG,
+      pythFeedId = PYTH_FLOOR_FEED,
+    } = {}
+  ) {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const expiry = (await chainTime()) + expiresIn;
+    const escrow = await initEscrow(trader, {
+      collectionId,
+      predictedFloor,
+      expiry,
+      margin,
+      priceSources,
+      pythFeedId,
+    });
+
+    await sleep(acceptDelayMs);
+    await accept(counterparty, escrow, counterpartyPrediction);
+
+    return { trader, counterparty, escrow, expiry };
+  }
+
+  async function settle(
+    escrow: PublicKey,
+    winner: PublicKey,
+    keeper: Keypair,
+    {
+      counterparty = null as PublicKey | null,
+      pythPriceUpdate = null as PublicKey | null,
+      collectionId = COLLECTION_SLUG,
+      treasury = null as PublicKey | null,
+      payoutRecipient = null as PublicKey | null,
+      trader = null as PublicKey | null,
+      basketIds = [] as string[],
+      rolloverEscrow = null as PublicKey | null,
+      expectedWinner = null as object | null,
+      disputer = null as PublicKey | null,
+      payeeWsol = null as PublicKey | null,
+      otherWsol = null as PublicKey | null,
+      tensorOracle = TENSOR_SWAP_ID,
+      // Keeper's token account, for a reward paid in `rewardMint`
+      keeperRewards = null as { mint: PublicKey; keeperAccount: PublicKey } | null,
+      // Overrides the token program derived from the accounts above
+      tokenProgram = undefined as PublicKey | null | undefined,
+      // The collection's price history, for a TWAP-settled escrow
+      priceHistory = null as PublicKey | null,
+      // Winner's balance, to credit their share to instead of paying it
+      winnerBalance = null as PublicKey | null,
+      // Oracle prices, in lamports, the settler accepts
+      acceptablePriceRange = null as { min: number; max: number } | null,
+    } = {}
+  ) {
+    const state = await program.account.escrowState.fetch(escrow);
+    return program.methods
+      .settleEscrow(
+        collectionId,
+        basketIds,
+        expectedWinner,
+        acceptablePriceRange && {
+          min: new anchor.BN(acceptablePriceRange.min),
+          max: new anchor.BN(acceptablePriceRange.max),
+        }
+      )
+      .accounts({
+        keeper: keeper.publicKey,
+        winner,
+        payoutRecipient,
+        trader,
+        counterparty,
+        disputer,
+        treasury,
+        config: configPDA,
+        escrow,
+        collectionIndex: escrowIndexAddress(state),
+        stats: statsPDA,
+        vault,
+        tensorOracle,
+        pythPriceUpdate,
+        priceHistory,
+        winnerBalance,
+        traderProfile: rolloverEscrow && profileAddress(state.trader),
+        rolloverEscrow,
+        payeeWsol,
+        otherWsol,
+        tokenProgram:
+          tokenProgram !== undefined
+            ? tokenProgram
+            : payeeWsol || otherWsol || keeperRewards
+            ? TOKEN_PROGRAM_ID
+            : null,
+        rewardMint: keeperRewards?.mint ?? null,
+        rewardVault: keeperRewards ? rewardVaultPDA : null,
+        keeperRewardAccount: keeperRewards?.keeperAccount ?? null,
+        rewardAuthority: keeperRewards ? rewardAuthorityPDA : null,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([keeper])
+      .rpc();
+  }
+
+  async function expectError(promise: Promise<unknown>, code: string) {
+    try {
+      await promise;
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal(code);
+      return;
+    }
+    expect.fail(`expected ${code} error`);
+  }
+
   it("Full escrow flow with real Tensor price", async () => {
     // Get current floor price
     const currentFloor = await getTensorFloorPrice(COLLECTION_SLUG);
@@ -103,23 +642,45 @@ describe("escrowfloor", () => {
     const marginAmount = new anchor.BN(0.5 * LAMPORTS_PER_SOL); // 0.5 SOL margin
 
     // Create escrow PDA
-    const [escrowPDA] = PublicKey.findProgramAddressSync(
-      [Buffer.from("escrow"), trader1.publicKey.toBuffer()],
-      program.programId
-    );
+    const escrowPDA = escrowAddress(trader1.publicKey);
 
     console.log("Creating escrow...");
     const tx1 = await program.methods
       .initializeEscrow(
-        COLLECTION_SLUG,
-        new anchor.BN(predictedFloor),
-        new anchor.BN(Date.now()/1000 + 3600), // 1 hour expiry
-        marginAmount
+        {
+          collectionId: COLLECTION_SLUG,
+          predictedFloor: new anchor.BN(predictedFloor),
+          lowerTolerance: new anchor.BN(100),
+          upperTolerance: new anchor.BN(100),
+          expiry: { unixTime: [new anchor.BN(Date.now()/1000 + 3600)] }, // 1 hour expiry
+          marginAmount,
+          priceSources: [{ tensor: {} }],
+          oddsNumerator: 1,
+          oddsDenominator: 1,
+          basket: [],
+          rollover: null,
+          predictionUnit: { absoluteLamports: {} },
+          payoutMode: { winnerTakesAll: {} },
+          denomination: { native: {} },
+          direction: null,
+          category: 0,
+          settlementPrice: { spot: {} },
+          maxFeeBps: 10_000,
+          pythFeedId: null,
+        },
+        0,
+        new anchor.BN(0)
       )
       .accounts({
         trader: trader1.publicKey,
+        traderProfile: profileAddress(trader1.publicKey),
         escrow: escrowPDA,
+        collectionIndex: indexAddress(COLLECTION_SLUG),
+        collectionState: collectionStateAddress(collectionHash(COLLECTION_SLUG)),
+        config: configPDA,
+        stats: statsPDA,
         tensorOracle: TENSOR_SWAP_ID,
+        oracleRegistry: oracleRegistryPDA,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([trader1])
@@ -129,10 +690,13 @@ describe("escrowfloor", () => {
 
     console.log("Accepting escrow...");
     const tx2 = await program.methods
-      .acceptEscrow()
+      .acceptEscrow(null, null, marginAmount)
       .accounts({
         trader: trader2.publicKey,
         escrow: escrowPDA,
+        config: configPDA,
+        collectionState: collectionStateAddress(collectionHash(COLLECTION_SLUG)),
+        stats: statsPDA,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([trader2])
@@ -143,10 +707,15 @@ describe("escrowfloor", () => {
     // For testing, we'll settle immediately instead of waiting an hour
     console.log("Settling escrow...");
     const tx3 = await program.methods
-      .settleEscrow()
+      .settleEscrow(COLLECTION_SLUG, [], null, null)
       .accounts({
+        keeper: provider.wallet.publicKey,
         winner: trader1.publicKey, // Will be determined by program
+        counterparty: null,
+        config: configPDA,
         escrow: escrowPDA,
+        collectionIndex: indexAddress(COLLECTION_SLUG),
+        stats: statsPDA,
         tensorOracle: TENSOR_SWAP_ID,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
@@ -154,4 +723,3610 @@ describe("escrowfloor", () => {
 
     console.log("Escrow settled:", tx3);
   });
+
+  it("Rejects accept from an underfunded counterparty", async () => {
+    const trader1 = Keypair.generate();
+    const trader2 = Keypair.generate();
+    await airdrop(trader1.publicKey, 2 * LAMPORTS_PER_SOL);
+    // Enough to exist, not enough to match a 0.5 SOL margin
+    await airdrop(trader2.publicKey, 0.1 * LAMPORTS_PER_SOL);
+
+    const escrowPDA = await initEscrow(trader1);
+
+    await expectError(accept(trader2, escrowPDA), "InsufficientFunds");
+
+    const escrow = await program.account.escrowState.fetch(escrowPDA);
+    expect(escrow.counterparty).to.be.null;
+  });
+
+  it("Pays keepers more the longer settlement was left", async () => {
+    await setConfig({
+      keeperRewardBaseBps: 100,
+      keeperRewardStepBps: 100,
+      keeperRewardStepSecs: 2,
+      keeperRewardMaxBps: 1_000,
+    });
+
+    const escrows = [];
+    for (let i = 0; i < 3; i++) {
+      escrows.push(await openAcceptedEscrow(5));
+    }
+    await waitUntil(Math.max(...escrows.map((e) => e.expiry)));
+
+    const keeper = Keypair.generate();
+    await airdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+    const rent = await recordRent();
+
+    const rewards = [];
+    for (const { trader, escrow } of escrows) {
+      const before = await provider.connection.getBalance(keeper.publicKey);
+      await settle(escrow, trader.publicKey, keeper);
+      const after = await provider.connection.getBalance(keeper.publicKey);
+      rewards.push(after - before + rent);
+      await sleep(4_000);
+    }
+
+    const pot = 2 * 0.5 * LAMPORTS_PER_SOL;
+    expect(rewards[0]).to.be.at.least((pot * 100) / 10_000);
+    expect(rewards[1]).to.be.greaterThan(rewards[0]);
+    expect(rewards[2]).to.be.greaterThan(rewards[1]);
+    expect(rewards[2]).to.be.at.most((pot * 1_000) / 10_000);
+  });
+
+  it("Refuses to close an escrow that still holds counterparty funds", async () => {
+    const { trader, escrow } = await openAcceptedEscrow(3600);
+
+    await expectError(
+      program.methods
+        .closeEscrow()
+        .accounts({ trader: trader.publicKey, escrow })
+        .signers([trader])
+        .rpc(),
+      "CounterpartyFundsLocked"
+    );
+
+    // A new escrow from the same trader takes the next address and leaves
+    // the live one alone
+    const next = await initEscrow(trader, { predictedFloor: 1, margin: 0.1 * LAMPORTS_PER_SOL });
+    expect(next.toBase58()).to.equal(escrowAddress(trader.publicKey, 1).toBase58());
+
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(state.status).to.deep.equal({ active: {} });
+    expect(state.predictedFloor.toNumber()).to.equal(10 * LAMPORTS_PER_SOL);
+  });
+
+  it("Closes an escrow once it is cancelled", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    const escrow = await initEscrow(trader);
+
+    await expectError(
+      program.methods
+        .closeEscrow()
+        .accounts({ trader: trader.publicKey, escrow })
+        .signers([trader])
+        .rpc(),
+      "NotFinalized"
+    );
+
+    await cancel(trader, escrow);
+    await program.methods
+      .closeEscrow()
+      .accounts({ trader: trader.publicKey, escrow })
+      .signers([trader])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(escrow)).to.be.null;
+
+    // The trader's next escrow never reuses the closed address
+    const next = await initEscrow(trader, { predictedFloor: 12 * LAMPORTS_PER_SOL });
+    expect(next.toBase58()).to.not.equal(escrow.toBase58());
+    const state = await program.account.escrowState.fetch(next);
+    expect(state.status).to.deep.equal({ open: {} });
+    expect(state.predictedFloor.toNumber()).to.equal(12 * LAMPORTS_PER_SOL);
+  });
+
+  describe("equidistant counterparty predictions", () => {
+    // Mock oracle reports 10 SOL, so 9 and 11 SOL are equally far off
+    const opts = {
+      predictedFloor: 9 * LAMPORTS_PER_SOL,
+      counterpartyPrediction: 11 * LAMPORTS_PER_SOL,
+      // Land init and accept in different seconds
+      acceptDelayMs: 2_000,
+    };
+
+    it("Splits the pot evenly by default", async () => {
+      const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4, opts);
+      await waitUntil(expiry);
+
+      const keeper = Keypair.generate();
+      await airdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+      const traderBefore = await provider.connection.getBalance(trader.publicKey);
+      const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+      const keeperBefore = await provider.connection.getBalance(keeper.publicKey);
+
+      await settle(escrow, trader.publicKey, keeper, { counterparty: counterparty.publicKey });
+
+      const traderGain = (await provider.connection.getBalance(trader.publicKey)) - traderBefore;
+      const counterpartyGain =
+        (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore;
+      const keeperGain =
+        (await provider.connection.getBalance(keeper.publicKey)) - keeperBefore + (await recordRent());
+
+      expect(traderGain + counterpartyGain + keeperGain).to.equal(LAMPORTS_PER_SOL);
+      expect(traderGain - counterpartyGain).to.be.within(0, 1);
+    });
+
+    it("Pays the keeper within the settle transaction", async () => {
+      const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4, opts);
+      await waitUntil(expiry);
+
+      const keeper = Keypair.generate();
+      await airdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+      const signature = await settle(escrow, trader.publicKey, keeper, {
+        counterparty: counterparty.publicKey,
+      });
+
+      // Base rate of 10 bps on the 1 SOL pot, credited before anyone
+      // touches their share, less the settlement record's rent
+      await provider.connection.confirmTransaction(signature, "confirmed");
+      const tx = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const keys = tx.transaction.message.getAccountKeys().staticAccountKeys;
+      const keeperIndex = keys.findIndex((key) => key.equals(keeper.publicKey));
+      expect(tx.meta.postBalances[keeperIndex] - tx.meta.preBalances[keeperIndex]).to.equal(
+        (LAMPORTS_PER_SOL * 10) / 10_000 - (await recordRent())
+      );
+    });
+
+    it("Rejects splitting without the counterparty account", async () => {
+      const { trader, escrow, expiry } = await openAcceptedEscrow(4, opts);
+      await waitUntil(expiry);
+
+      await expectError(
+        settle(escrow, trader.publicKey, provider.wallet.payer),
+        "MissingCounterpartyAccount"
+      );
+    });
+
+    it("Awards the earlier committer when tie_break_by_time is set", async () => {
+      await setConfig({ tieBreakByTime: true });
+      const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4, opts);
+      await waitUntil(expiry);
+
+      const keeper = Keypair.generate();
+      await airdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+      const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+      const traderBefore = await provider.connection.getBalance(trader.publicKey);
+
+      // Counterparty committed later and can't claim the tie
+      await expectError(settle(escrow, counterparty.publicKey, keeper), "InvalidWinner");
+      await settle(escrow, trader.publicKey, keeper);
+
+      const state = await program.account.escrowState.fetch(escrow);
+      expect(state.createdAt.toNumber()).to.be.lessThan(state.acceptedAt.toNumber());
+      expect(await provider.connection.getBalance(counterparty.publicKey)).to.equal(
+        counterpartyBefore
+      );
+      expect((await provider.connection.getBalance(trader.publicKey)) - traderBefore).to.be.greaterThan(
+        0.9 * LAMPORTS_PER_SOL
+      );
+    });
+  });
+
+  it("Pays out to a program-owned winner", async () => {
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+
+    // Hand the trader's account over to a program, the way a vault PDA is owned
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        anchor.web3.SystemProgram.assign({
+          accountPubkey: trader.publicKey,
+          programId: program.programId,
+        })
+      ),
+      [trader]
+    );
+    await waitUntil(expiry);
+
+    const before = await provider.connection.getBalance(trader.publicKey);
+    await settle(escrow, trader.publicKey, provider.wallet.payer);
+    const after = await provider.connection.getAccountInfo(trader.publicKey);
+
+    expect(after.owner.toBase58()).to.equal(program.programId.toBase58());
+    expect(after.lamports - before).to.be.greaterThan(0.9 * LAMPORTS_PER_SOL);
+  });
+
+  it("Caps the number of mutual expiry extensions", async () => {
+    const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(3600);
+
+    const extend = (newExpiry: number) =>
+      program.methods
+        .extendExpiry(new anchor.BN(newExpiry))
+        .accounts({
+          trader: trader.publicKey,
+          counterparty: counterparty.publicKey,
+          escrow,
+        })
+        .signers([trader, counterparty])
+        .rpc();
+
+    // MAX_EXTENSIONS = 3
+    for (let i = 1; i <= 3; i++) {
+      await extend(expiry + i * 3600);
+    }
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(state.extensionCount).to.equal(3);
+    expect(state.expiryTimestamp.toNumber()).to.equal(expiry + 3 * 3600);
+
+    await expectError(extend(expiry + 4 * 3600), "MaxExtensionsReached");
+  });
+
+  it("Caps the total duration added by extensions", async () => {
+    const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(3600);
+
+    // MAX_TOTAL_EXTENSION_SECS is one week past the original expiry
+    await expectError(
+      program.methods
+        .extendExpiry(new anchor.BN(expiry + 7 * 24 * 3600 + 1))
+        .accounts({
+          trader: trader.publicKey,
+          counterparty: counterparty.publicKey,
+          escrow,
+        })
+        .signers([trader, counterparty])
+        .rpc(),
+      "MaxExtensionsReached"
+    );
+  });
+
+  it("Keeps the collection index in step with the escrow lifecycle", async () => {
+    const collectionId = "index-test";
+    const index = indexAddress(collectionId);
+    const listed = async () =>
+      (await program.account.collectionIndex.fetch(index)).escrows.map((key: PublicKey) =>
+        key.toBase58()
+      );
+
+    const cancelled = Keypair.generate();
+    const settled = Keypair.generate();
+    const counterparty = Keypair.generate();
+    for (const kp of [cancelled, settled, counterparty]) {
+      await airdrop(kp.publicKey, 2 * LAMPORTS_PER_SOL);
+    }
+
+    const cancelledEscrow = await initEscrow(cancelled, { collectionId });
+    const expiry = (await chainTime()) + 4;
+    const settledEscrow = await initEscrow(settled, { collectionId, expiry });
+    expect(await listed()).to.have.members([
+      cancelledEscrow.toBase58(),
+      settledEscrow.toBase58(),
+    ]);
+
+    await cancel(cancelled, cancelledEscrow);
+    expect(await listed()).to.deep.equal([settledEscrow.toBase58()]);
+
+    await accept(counterparty, settledEscrow);
+    expect(await listed()).to.deep.equal([settledEscrow.toBase58()]);
+
+    await waitUntil(expiry);
+    await settle(settledEscrow, settled.publicKey, provider.wallet.payer, { collectionId });
+    expect(await listed()).to.be.empty;
+
+    // Popular collections spill into further chunks with their own address
+    const overflow = Keypair.generate();
+    await airdrop(overflow.publicKey, 2 * LAMPORTS_PER_SOL);
+    const overflowEscrow = await initEscrow(overflow, { collectionId, indexChunk: 1 });
+    const chunk = await program.account.collectionIndex.fetch(indexAddress(collectionId, 1));
+    expect(chunk.chunk).to.equal(1);
+    expect(chunk.escrows.map((key: PublicKey) => key.toBase58())).to.deep.equal([
+      overflowEscrow.toBase58(),
+    ]);
+  });
+
+  it("Rejects deposits past the TVL cap and frees room on settle", async () => {
+    const margin = 0.5 * LAMPORTS_PER_SOL;
+    const locked = async () =>
+      (await program.account.stats.fetch(statsPDA)).totalLocked.toNumber();
+
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+    const base = await locked();
+
+    // Room for exactly one more margin
+    await setConfig({ maxTvl: new anchor.BN(base + margin) });
+    const capped = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(capped.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+    const cappedEscrow = await initEscrow(capped, { margin });
+    expect(await locked()).to.equal(base + margin);
+
+    await expectError(accept(counterparty, cappedEscrow), "TvlCapReached");
+
+    // Settling releases the whole pot back under the cap
+    await waitUntil(expiry);
+    await settle(escrow, trader.publicKey, provider.wallet.payer);
+    expect(await locked()).to.equal(base + margin - 2 * margin);
+    await accept(counterparty, cappedEscrow);
+    expect(await locked()).to.equal(base + margin);
+  });
+
+  it("Mints the maker reward when an escrow is filled", async () => {
+    const payer = provider.wallet.payer;
+    const mint = await createMint(provider.connection, payer, rewardAuthorityPDA, null, 6);
+    await setConfig({ rewardMint: mint, makerRewardAmount: new anchor.BN(1_000_000) });
+
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+    const escrow = await initEscrow(trader);
+    const makerAccount = (
+      await getOrCreateAssociatedTokenAccount(provider.connection, payer, mint, trader.publicKey)
+    ).address;
+
+    await expectError(accept(counterparty, escrow), "MissingRewardAccounts");
+    await accept(counterparty, escrow, null, { mint, makerAccount });
+
+    const rewards = await getAccount(provider.connection, makerAccount);
+    expect(Number(rewards.amount)).to.equal(1_000_000);
+  });
+
+  it("Skips maker rewards when no reward mint is configured", async () => {
+    const { escrow } = await openAcceptedEscrow(3600);
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(state.status).to.deep.equal({ active: {} });
+  });
+
+  it("Creates two escrows from one template", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+
+    const templateId = new anchor.BN(7);
+    const [template] = PublicKey.findProgramAddressSync(
+      [Buffer.from("template"), trader.publicKey.toBuffer(), templateId.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    await program.methods
+      .createTemplate(templateId, {
+        collectionId: COLLECTION_SLUG,
+        predictedFloor: new anchor.BN(10 * LAMPORTS_PER_SOL),
+        tolerance: new anchor.BN(1_000),
+        marginAmount: new anchor.BN(0.25 * LAMPORTS_PER_SOL),
+        duration: new anchor.BN(3600),
+      })
+      .accounts({
+        owner: trader.publicKey,
+        template,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([trader])
+      .rpc();
+
+    const fromTemplate = async (overrides: object) => {
+      const escrow = await nextEscrowAddress(trader.publicKey);
+      await program.methods
+        .createFromTemplate(
+          COLLECTION_SLUG,
+          { predictedFloor: null, tolerance: null, marginAmount: null, duration: null, ...overrides },
+          0
+        )
+        .accounts({
+          trader: trader.publicKey,
+          template,
+          traderProfile: profileAddress(trader.publicKey),
+          escrow,
+          collectionIndex: indexAddress(COLLECTION_SLUG),
+          collectionState: collectionStateAddress(collectionHash(COLLECTION_SLUG)),
+          config: configPDA,
+          stats: statsPDA,
+          tensorOracle: TENSOR_SWAP_ID,
+          oracleRegistry: oracleRegistryPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([trader])
+        .rpc();
+      return program.account.escrowState.fetch(escrow);
+    };
+
+    const first = await fromTemplate({});
+    const second = await fromTemplate({ predictedFloor: new anchor.BN(11 * LAMPORTS_PER_SOL) });
+
+    expect(first.nonce.toNumber()).to.equal(0);
+    expect(second.nonce.toNumber()).to.equal(1);
+    for (const escrow of [first, second]) {
+      expect(Buffer.from(escrow.collectionHash)).to.deep.equal(collectionHash(COLLECTION_SLUG));
+      expect(escrow.lowerTolerance.toNumber()).to.equal(1_000);
+      expect(escrow.upperTolerance.toNumber()).to.equal(1_000);
+      expect(escrow.marginAmount.toNumber()).to.equal(0.25 * LAMPORTS_PER_SOL);
+      expect(escrow.expiryTimestamp.toNumber() - escrow.createdAt.toNumber()).to.equal(3600);
+    }
+    expect(first.predictedFloor.toNumber()).to.equal(10 * LAMPORTS_PER_SOL);
+    expect(second.predictedFloor.toNumber()).to.equal(11 * LAMPORTS_PER_SOL);
+  });
+
+  it("Refuses to settle on a low-confidence Pyth price", async () => {
+    // Accept intervals up to 1% of the price
+    await setConfig({ maxConfidenceBps: 100 });
+    const wide = await openAcceptedEscrow(4, {
+      priceSources: [{ pyth: {} }],
+      pythFeedId: PYTH_WIDE_FEED,
+    });
+    const tight = await openAcceptedEscrow(4, { priceSources: [{ pyth: {} }] });
+    await waitUntil(Math.max(wide.expiry, tight.expiry));
+
+    await expectError(
+      settle(wide.escrow, wide.trader.publicKey, provider.wallet.payer, {
+        pythPriceUpdate: PYTH_WIDE_CONFIDENCE,
+      }),
+      "LowConfidencePrice"
+    );
+    await expectError(
+      settle(wide.escrow, wide.trader.publicKey, provider.wallet.payer),
+      "MissingOracleAccount"
+    );
+    expect((await program.account.escrowState.fetch(wide.escrow)).status).to.deep.equal({
+      active: {},
+    });
+
+    await settle(tight.escrow, tight.trader.publicKey, provider.wallet.payer, {
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+    });
+    expect((await program.account.escrowState.fetch(tight.escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Batch-settles expired escrows and skips the rest", async () => {
+    const expired = [await openAcceptedEscrow(4), await openAcceptedEscrow(4)];
+    const pending = await openAcceptedEscrow(3600);
+    await waitUntil(Math.max(...expired.map((e) => e.expiry)));
+
+    const remainingAccounts = [];
+    for (const { trader, counterparty, escrow } of [...expired, pending]) {
+      const state = await program.account.escrowState.fetch(escrow);
+      remainingAccounts.push(
+        { pubkey: escrow, isSigner: false, isWritable: true },
+        {
+          pubkey: escrowIndexAddress(state),
+          isSigner: false,
+          isWritable: true,
+        },
+        { pubkey: TENSOR_SWAP_ID, isSigner: false, isWritable: false },
+        { pubkey: PYTH_TIGHT_CONFIDENCE, isSigner: false, isWritable: false },
+        { pubkey: trader.publicKey, isSigner: false, isWritable: true },
+        { pubkey: counterparty.publicKey, isSigner: false, isWritable: true },
+        { pubkey: settlementRecordAddress(escrow), isSigner: false, isWritable: true }
+      );
+    }
+
+    const signature = await program.methods
+      .settleBatch([COLLECTION_SLUG, COLLECTION_SLUG, COLLECTION_SLUG])
+      .accounts({
+        keeper: provider.wallet.publicKey,
+        config: configPDA,
+        stats: statsPDA,
+      })
+      .remainingAccounts(remainingAccounts)
+      .rpc({ commitment: "confirmed" });
+
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const [returnData] = tx.meta.returnData.data;
+    expect(Buffer.from(returnData, "base64").readUInt32LE(0)).to.equal(2);
+
+    for (const { escrow } of expired) {
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        settled: {},
+      });
+      const record = await program.account.settlementRecord.fetch(settlementRecordAddress(escrow));
+      expect(record.escrow.toBase58()).to.equal(escrow.toBase58());
+    }
+    expect(
+      await program.account.settlementRecord.fetchNullable(settlementRecordAddress(pending.escrow))
+    ).to.be.null;
+    expect((await program.account.escrowState.fetch(pending.escrow)).status).to.deep.equal({
+      active: {},
+    });
+  });
+
+  it("Settles an escrow on a collection id longer than 32 characters", async () => {
+    const collectionId = "a-collection-slug-that-is-far-longer-than-any-seed-allows-60";
+    expect(collectionId).to.have.lengthOf(60);
+
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, { collectionId, expiry });
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(Buffer.from(state.collectionHash)).to.deep.equal(collectionHash(collectionId));
+
+    await accept(counterparty, escrow);
+    await waitUntil(expiry);
+
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer, { collectionId: COLLECTION_SLUG }),
+      "CollectionIdMismatch"
+    );
+    await settle(escrow, trader.publicKey, provider.wallet.payer, { collectionId });
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Rejects a batch whose winner is passed read-only", async () => {
+    const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4);
+    await waitUntil(expiry);
+
+    const state = await program.account.escrowState.fetch(escrow);
+    await expectError(
+      program.methods
+        .settleBatch([COLLECTION_SLUG])
+        .accounts({
+          keeper: provider.wallet.publicKey,
+          config: configPDA,
+          stats: statsPDA,
+        })
+        .remainingAccounts([
+          { pubkey: escrow, isSigner: false, isWritable: true },
+          { pubkey: escrowIndexAddress(state), isSigner: false, isWritable: true },
+          { pubkey: TENSOR_SWAP_ID, isSigner: false, isWritable: false },
+          { pubkey: PYTH_TIGHT_CONFIDENCE, isSigner: false, isWritable: false },
+          { pubkey: trader.publicKey, isSigner: false, isWritable: false },
+          { pubkey: counterparty.publicKey, isSigner: false, isWritable: true },
+          { pubkey: settlementRecordAddress(escrow), isSigner: false, isWritable: true },
+        ])
+        .rpc(),
+      "AccountNotWritable"
+    );
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      active: {},
+    });
+
+    await settle(escrow, trader.publicKey, provider.wallet.payer);
+  });
+
+  it("Charges the settlement fee on each side's margin", async () => {
+    const treasury = Keypair.generate();
+    await airdrop(treasury.publicKey, LAMPORTS_PER_SOL);
+    await setConfig({ feeBps: 200, treasury: treasury.publicKey });
+
+    const margin = 0.5 * LAMPORTS_PER_SOL;
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4, { margin });
+    await waitUntil(expiry);
+
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer),
+      "MissingTreasuryAccount"
+    );
+
+    const keeper = Keypair.generate();
+    await airdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+    const treasuryBefore = await provider.connection.getBalance(treasury.publicKey);
+    const traderBefore = await provider.connection.getBalance(trader.publicKey);
+    const keeperBefore = await provider.connection.getBalance(keeper.publicKey);
+    await settle(escrow, trader.publicKey, keeper, { treasury: treasury.publicKey });
+
+    // 2% of each side's margin, regardless of who won
+    const sideFee = (margin * 200) / 10_000;
+    const treasuryTotal = (await provider.connection.getBalance(treasury.publicKey)) - treasuryBefore;
+    expect(treasuryTotal).to.equal(2 * sideFee);
+
+    // The keeper's gain is its reward less the settlement record's rent
+    const keeperReward =
+      (await provider.connection.getBalance(keeper.publicKey)) - keeperBefore + (await recordRent());
+    const payout = (await provider.connection.getBalance(trader.publicKey)) - traderBefore;
+    expect(payout + keeperReward).to.equal(2 * (margin - sideFee));
+  });
+
+  it("Refunds both sides when a collection is delisted mid-escrow", async () => {
+    const collectionId = "delisted-test";
+    const margin = 0.5 * LAMPORTS_PER_SOL;
+    const { trader, counterparty, escrow } = await openAcceptedEscrow(3600, {
+      margin,
+      collectionId,
+    });
+
+    const [collectionState] = PublicKey.findProgramAddressSync(
+      [Buffer.from("collection_state"), collectionHash(collectionId)],
+      program.programId
+    );
+    const setDelisted = (delisted: boolean) =>
+      program.methods
+        .setCollectionDelisted(collectionId, delisted)
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          collectionState,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    const cancelDelisted = () =>
+      program.methods
+        .cancelDelisted()
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          collectionState,
+          escrow,
+          trader: trader.publicKey,
+          counterparty: counterparty.publicKey,
+          collectionIndex: indexAddress(collectionId),
+          stats: statsPDA,
+        })
+        .rpc();
+
+    await setDelisted(false);
+    await expectError(cancelDelisted(), "CollectionNotDelisted");
+
+    await setDelisted(true);
+    const traderBefore = await provider.connection.getBalance(trader.publicKey);
+    const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+    const lockedBefore = (await program.account.stats.fetch(statsPDA)).totalLocked.toNumber();
+    await cancelDelisted();
+
+    expect((await provider.connection.getBalance(trader.publicKey)) - traderBefore).to.equal(margin);
+    expect(
+      (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore
+    ).to.equal(margin);
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      voided: {},
+    });
+    expect((await program.account.stats.fetch(statsPDA)).totalLocked.toNumber()).to.equal(
+      lockedBefore - 2 * margin
+    );
+    const index = await program.account.collectionIndex.fetch(indexAddress(collectionId));
+    expect(index.escrows).to.be.empty;
+  });
+
+  it("Falls back to the next price source when the primary is stale", async () => {
+    // The fixture update was published long before the validator's clock
+    await setConfig({ maxPriceAgeSecs: 60 });
+    const pythOnly = await openAcceptedEscrow(4, { priceSources: [{ pyth: {} }] });
+    const withFallback = await openAcceptedEscrow(4, {
+      priceSources: [{ pyth: {} }, { tensor: {} }],
+    });
+    await waitUntil(Math.max(pythOnly.expiry, withFallback.expiry));
+
+    await expectError(
+      settle(pythOnly.escrow, pythOnly.trader.publicKey, provider.wallet.payer, {
+        pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+      }),
+      "StalePrice"
+    );
+
+    await settle(withFallback.escrow, withFallback.trader.publicKey, provider.wallet.payer, {
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+    });
+    expect((await program.account.escrowState.fetch(withFallback.escrow)).status).to.deep.equal({
+      settled: {},
+    });
+
+    await setConfig();
+    await settle(pythOnly.escrow, pythOnly.trader.publicKey, provider.wallet.payer, {
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+    });
+  });
+
+  it("Raises the counter-stake when the trader increases their margin", async () => {
+    await setConfig({ maxMargin: new anchor.BN(LAMPORTS_PER_SOL) });
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 3 * LAMPORTS_PER_SOL);
+
+    const escrow = await initEscrow(trader, { margin: 0.25 * LAMPORTS_PER_SOL });
+    const increaseMargin = (lamports: number) =>
+      program.methods
+        .increaseMargin(new anchor.BN(lamports))
+        .accounts({
+          trader: trader.publicKey,
+          escrow,
+          traderProfile: profileAddress(trader.publicKey),
+          config: configPDA,
+          stats: statsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([trader])
+        .rpc();
+
+    await expectError(increaseMargin(LAMPORTS_PER_SOL), "MarginTooLarge");
+    await increaseMargin(0.5 * LAMPORTS_PER_SOL);
+    expect((await program.account.escrowState.fetch(escrow)).marginAmount.toNumber()).to.equal(
+      0.75 * LAMPORTS_PER_SOL
+    );
+
+    const escrowBefore = await provider.connection.getBalance(escrow);
+    await accept(counterparty, escrow);
+    expect((await provider.connection.getBalance(escrow)) - escrowBefore).to.equal(
+      0.75 * LAMPORTS_PER_SOL
+    );
+
+    await expectError(increaseMargin(1), "NotOpen");
+  });
+
+  it("Fails unaccepted settlement with an error rather than a panic", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, { expiry });
+    await waitUntil(expiry);
+
+    // No counterparty to resolve against, for either possible winner
+    await expectError(settle(escrow, trader.publicKey, provider.wallet.payer), "NoSecondTrader");
+    await expectError(
+      settle(escrow, Keypair.generate().publicKey, provider.wallet.payer),
+      "NoSecondTrader"
+    );
+
+    await cancel(trader, escrow);
+  });
+
+  it("Sends the payout to a recipient the winner authorizes", async () => {
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+    const coldWallet = Keypair.generate().publicKey;
+    await waitUntil(expiry);
+
+    // Without the winner's signature the recipient is refused
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer, { payoutRecipient: coldWallet }),
+      "PayoutNotAuthorized"
+    );
+
+    const state = await program.account.escrowState.fetch(escrow);
+    const ix = await program.methods
+      .settleEscrow(COLLECTION_SLUG, [], null, null)
+      .accounts({
+        keeper: provider.wallet.publicKey,
+        winner: trader.publicKey,
+        payoutRecipient: coldWallet,
+        counterparty: null,
+        treasury: null,
+        config: configPDA,
+        escrow,
+        collectionIndex: escrowIndexAddress(state),
+        stats: statsPDA,
+        tensorOracle: TENSOR_SWAP_ID,
+        pythPriceUpdate: null,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .instruction();
+    ix.keys.find((key) => key.pubkey.equals(trader.publicKey)).isSigner = true;
+
+    const traderBefore = await provider.connection.getBalance(trader.publicKey);
+    await provider.sendAndConfirm(new anchor.web3.Transaction().add(ix), [trader]);
+
+    expect(await provider.connection.getBalance(trader.publicKey)).to.equal(traderBefore);
+    expect(await provider.connection.getBalance(coldWallet)).to.be.greaterThan(
+      0.99 * LAMPORTS_PER_SOL
+    );
+  });
+
+  it("Rounds expiry down to the configured boundary", async () => {
+    await setConfig({ expiryGranularitySecs: 3600 });
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const requested = (await chainTime()) + 2 * 3600 + 1234;
+    const escrow = await initEscrow(trader, { expiry: requested });
+    const state = await program.account.escrowState.fetch(escrow);
+
+    expect(state.requestedExpiry.toNumber()).to.equal(requested);
+    expect(state.expiryTimestamp.toNumber()).to.equal(requested - (requested % 3600));
+    expect(state.originalExpiry.toNumber()).to.equal(state.expiryTimestamp.toNumber());
+
+    await cancel(trader, escrow);
+  });
+
+  it("Takes the implied counter-stake and pays the pot at 3:1 odds", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    await expectError(initEscrow(trader, { odds: [0, 1] }), "InvalidOdds");
+    await expectError(initEscrow(trader, { odds: [1_000, 1] }), "InvalidOdds");
+
+    // Counterparty risks 0.25 SOL to win the trader's 0.75 SOL
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, {
+      expiry,
+      margin: 0.75 * LAMPORTS_PER_SOL,
+      odds: [3, 1],
+    });
+    const escrowBefore = await provider.connection.getBalance(escrow);
+    await accept(counterparty, escrow);
+    expect((await provider.connection.getBalance(escrow)) - escrowBefore).to.equal(
+      0.25 * LAMPORTS_PER_SOL
+    );
+
+    await waitUntil(expiry);
+    const keeper = Keypair.generate();
+    await airdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+    const traderBefore = await provider.connection.getBalance(trader.publicKey);
+    const keeperBefore = await provider.connection.getBalance(keeper.publicKey);
+    await settle(escrow, trader.publicKey, keeper);
+
+    const payout = (await provider.connection.getBalance(trader.publicKey)) - traderBefore;
+    // The keeper's gain is its reward less the settlement record's rent
+    const keeperReward =
+      (await provider.connection.getBalance(keeper.publicKey)) - keeperBefore + (await recordRent());
+    expect(payout + keeperReward).to.equal(LAMPORTS_PER_SOL);
+  });
+
+  it("Explains a stale-oracle settle failure in a diagnostic event", async () => {
+    await setConfig({ maxPriceAgeSecs: 60 });
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4, {
+      priceSources: [{ pyth: {} }],
+    });
+    await waitUntil(expiry);
+
+    let logs: string[] = [];
+    try {
+      await settle(escrow, trader.publicKey, provider.wallet.payer, {
+        pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+      });
+      expect.fail("settle should have failed");
+    } catch (err) {
+      logs = err.logs;
+    }
+
+    const events = logs
+      .filter((line) => line.startsWith("Program data: "))
+      .map((line) => program.coder.events.decode(line.slice("Program data: ".length)))
+      .filter(Boolean);
+    expect(events).to.have.lengthOf(1);
+    const diagnostic = events[0].data;
+    expect(diagnostic.escrow.toBase58()).to.equal(escrow.toBase58());
+    expect(diagnostic.errorName).to.equal("StalePrice");
+    expect(diagnostic.reason).to.equal("Oracle price is too old to settle on");
+    expect(diagnostic.expiryTimestamp.toNumber()).to.equal(expiry);
+
+    await setConfig();
+    await settle(escrow, trader.publicKey, provider.wallet.payer, {
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+    });
+  });
+
+  it("Accepts a basket at the maximum size and rejects one more", async () => {
+    const MAX_BASKET_SIZE = 4;
+    const members = Array.from({ length: MAX_BASKET_SIZE + 1 }, (_, i) => `basket-member-${i}`);
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    await expectError(initEscrow(trader, { basket: members }), "BasketTooLarge");
+
+    // Mock oracle prices every collection at 10 SOL, so the full basket
+    // is worth 50 SOL
+    const basket = members.slice(0, MAX_BASKET_SIZE);
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, {
+      basket,
+      expiry,
+      predictedFloor: (MAX_BASKET_SIZE + 1) * 10 * LAMPORTS_PER_SOL,
+    });
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(state.basket.map((hash: number[]) => Buffer.from(hash))).to.deep.equal(
+      basket.map(collectionHash)
+    );
+
+    await accept(counterparty, escrow);
+    await waitUntil(expiry);
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer, { basketIds: basket.slice(1) }),
+      "CollectionIdMismatch"
+    );
+    await settle(escrow, trader.publicKey, provider.wallet.payer, { basketIds: basket });
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Projects the winner from the live price before expiry", async () => {
+    // Trader calls 10 SOL, counterparty 12 SOL
+    const { trader, escrow, expiry } = await openAcceptedEscrow(6, {
+      predictedFloor: 10 * LAMPORTS_PER_SOL,
+      counterpartyPrediction: 12 * LAMPORTS_PER_SOL,
+      priceSources: [{ pyth: {} }],
+    });
+    const expectedWinner = (pythPriceUpdate: PublicKey) =>
+      program.methods
+        .getExpectedWinner(COLLECTION_SLUG, [])
+        .accounts({
+          escrow,
+          config: configPDA,
+          tensorOracle: TENSOR_SWAP_ID,
+          pythPriceUpdate,
+        })
+        .view();
+
+    // Feeds quote 10 SOL and 12 SOL respectively
+    expect(await expectedWinner(PYTH_TIGHT_CONFIDENCE)).to.deep.equal({ trader: {} });
+    expect(await expectedWinner(PYTH_HIGHER_PRICE)).to.deep.equal({ counterparty: {} });
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      active: {},
+    });
+
+    await waitUntil(expiry);
+    await settle(escrow, trader.publicKey, provider.wallet.payer, {
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+    });
+  });
+
+  it("Rolls an opted-in trader's win into a follow-on escrow", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    await expectError(
+      initEscrow(trader, { rollover: { duration: 3600, remaining: 13 } }),
+      "InvalidRollover"
+    );
+
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, {
+      expiry,
+      rollover: { duration: 3600, remaining: 1 },
+    });
+    await accept(counterparty, escrow);
+    await waitUntil(expiry);
+
+    const followup = await nextEscrowAddress(trader.publicKey);
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer),
+      "MissingRolloverAccounts"
+    );
+    const traderBefore = await provider.connection.getBalance(trader.publicKey);
+    await settle(escrow, trader.publicKey, provider.wallet.payer, {
+      rolloverEscrow: followup,
+    });
+
+    // Winnings went into the new escrow, not the trader's wallet
+    expect(await provider.connection.getBalance(trader.publicKey)).to.equal(traderBefore);
+    const state = await program.account.escrowState.fetch(followup);
+    const rent = await provider.connection.getMinimumBalanceForRentExemption(
+      (await provider.connection.getAccountInfo(followup)).data.length
+    );
+    expect(state.status).to.deep.equal({ open: {} });
+    expect(state.trader.toBase58()).to.equal(trader.publicKey.toBase58());
+    expect(state.nonce.toNumber()).to.equal(1);
+    // Pot less the 10 bps keeper reward and the new account's rent
+    expect(state.marginAmount.toNumber()).to.equal(
+      LAMPORTS_PER_SOL - LAMPORTS_PER_SOL / 1000 - rent
+    );
+    expect(state.expiryTimestamp.toNumber()).to.be.greaterThan(expiry + 3000);
+    expect(state.rollover).to.be.null;
+    const index = await program.account.collectionIndex.fetch(escrowIndexAddress(state));
+    expect(index.escrows.map((key) => key.toBase58())).to.include(followup.toBase58());
+
+    await cancel(trader, followup);
+  });
+
+  it("Reverts settlement when the winner differs from the expected one", async () => {
+    // Trader calls 10 SOL, counterparty 12 SOL
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4, {
+      predictedFloor: 10 * LAMPORTS_PER_SOL,
+      counterpartyPrediction: 12 * LAMPORTS_PER_SOL,
+      priceSources: [{ pyth: {} }],
+    });
+    await waitUntil(expiry);
+
+    // Client saw 12 SOL, but the feed now reads 10 SOL
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer, {
+        pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+        expectedWinner: { counterparty: {} },
+      }),
+      "WinnerChanged"
+    );
+
+    await settle(escrow, trader.publicKey, provider.wallet.payer, {
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+      expectedWinner: { trader: {} },
+    });
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Closes accepting and opens settling at the same expiry second", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const expiry = (await chainTime()) + 6;
+    const unaccepted = await initEscrow(trader, { expiry });
+    const accepted = await initEscrow(trader, { expiry });
+    await accept(counterparty, accepted);
+    await expectError(
+      settle(accepted, trader.publicKey, provider.wallet.payer),
+      "NotExpiredYet"
+    );
+
+    // The cluster clock only moves in whole seconds, so this lands on or
+    // just past the boundary; the exact second is pinned in the unit tests
+    await waitUntil(expiry);
+    await expectError(accept(counterparty, unaccepted), "Expired");
+    await settle(accepted, trader.publicKey, provider.wallet.payer);
+
+    await cancel(trader, unaccepted);
+  });
+
+  it("Refuses accepts during the cool-down after creation", async () => {
+    await setConfig({ acceptCooldownSecs: 4 });
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    try {
+      // Stays active afterwards, so keep it out of the shared index
+      const escrow = await initEscrow(trader, { collectionId: "cooldown-collection" });
+      const { createdAt } = await program.account.escrowState.fetch(escrow);
+      await expectError(accept(counterparty, escrow), "AcceptCooldown");
+
+      await waitUntil(createdAt.toNumber() + 4);
+      await accept(counterparty, escrow);
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        active: {},
+      });
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Closes admin accounts only once nothing depends on them", async () => {
+    const recipient = Keypair.generate().publicKey;
+
+    // Active escrows from earlier tests still have funds locked
+    const { totalLocked } = await program.account.stats.fetch(statsPDA);
+    expect(totalLocked.toNumber()).to.be.greaterThan(0);
+    await expectError(
+      program.methods
+        .closeConfig()
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          stats: statsPDA,
+          recipient,
+        })
+        .rpc(),
+      "FundsStillLocked"
+    );
+
+    const collectionId = "decommissioned-collection";
+    const [collectionState] = PublicKey.findProgramAddressSync(
+      [Buffer.from("collection_state"), collectionHash(collectionId)],
+      program.programId
+    );
+    const setDelisted = (delisted: boolean) =>
+      program.methods
+        .setCollectionDelisted(collectionId, delisted)
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          collectionState,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    const closeCollectionState = () =>
+      program.methods
+        .closeCollectionState()
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          collectionState,
+          recipient,
+        })
+        .rpc();
+
+    await setDelisted(true);
+    await expectError(closeCollectionState(), "CollectionStillDelisted");
+
+    await setDelisted(false);
+    const rent = await provider.connection.getBalance(collectionState);
+    await closeCollectionState();
+    expect(await provider.connection.getAccountInfo(collectionState)).to.be.null;
+    expect(await provider.connection.getBalance(recipient)).to.equal(rent);
+  });
+
+  it("Caps the margin a wallet opens escrows with per day", async () => {
+    await setConfig({ dailyVolumeLimit: new anchor.BN(LAMPORTS_PER_SOL) });
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+
+    try {
+      const first = await initEscrow(trader, { margin: 0.4 * LAMPORTS_PER_SOL });
+      await expectError(
+        initEscrow(trader, { margin: 0.7 * LAMPORTS_PER_SOL }),
+        "DailyLimitExceeded"
+      );
+      // Topping up counts the same as opening at the larger margin
+      const increaseMargin = (lamports: number) =>
+        program.methods
+          .increaseMargin(new anchor.BN(lamports))
+          .accounts({
+            trader: trader.publicKey,
+            escrow: first,
+            traderProfile: profileAddress(trader.publicKey),
+            config: configPDA,
+            stats: statsPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([trader])
+          .rpc();
+      await expectError(increaseMargin(0.7 * LAMPORTS_PER_SOL), "DailyLimitExceeded");
+      await increaseMargin(0.2 * LAMPORTS_PER_SOL);
+      const second = await initEscrow(trader, { margin: 0.4 * LAMPORTS_PER_SOL });
+
+      const profile = await program.account.traderProfile.fetch(
+        profileAddress(trader.publicKey)
+      );
+      expect(profile.dailyVolume.toNumber()).to.equal(LAMPORTS_PER_SOL);
+      expect(profile.volumeDay.toNumber()).to.equal(Math.floor((await chainTime()) / 86400));
+
+      await cancel(trader, first);
+      await cancel(trader, second);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  describe("disputes", () => {
+    const DISPUTE_BOND = 0.1 * LAMPORTS_PER_SOL;
+    const treasury = Keypair.generate().publicKey;
+
+    beforeEach(async () => {
+      await setConfig({
+        arbiter: provider.wallet.publicKey,
+        disputeBond: new anchor.BN(DISPUTE_BOND),
+        disputeRewardBps: 500,
+        disputeWindowSecs: 30,
+        treasury,
+      });
+    });
+
+    afterEach(async () => {
+      await setConfig();
+    });
+
+    const disputeAddress = (escrow: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("dispute"), escrow.toBuffer()],
+        program.programId
+      )[0];
+
+    const raiseDispute = (disputer: Keypair, escrow: PublicKey) =>
+      program.methods
+        .raiseDispute()
+        .accounts({
+          disputer: disputer.publicKey,
+          escrow,
+          dispute: disputeAddress(escrow),
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([disputer])
+        .rpc();
+
+    const resolveDispute = (escrow: PublicKey, disputer: PublicKey, ruling: object | null) =>
+      program.methods
+        .resolveDispute(ruling)
+        .accounts({
+          arbiter: provider.wallet.publicKey,
+          config: configPDA,
+          escrow,
+          dispute: disputeAddress(escrow),
+          disputer,
+          treasury,
+        })
+        .rpc();
+
+    it("Pays an upheld disputer and settles on the arbiter's ruling", async () => {
+      // The oracle's 10 SOL hands the trader the win
+      const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4);
+      const watchdog = Keypair.generate();
+      await airdrop(watchdog.publicKey, LAMPORTS_PER_SOL);
+      await waitUntil(expiry);
+
+      await expectError(
+        settle(escrow, trader.publicKey, provider.wallet.payer),
+        "DisputeWindowOpen"
+      );
+      await raiseDispute(watchdog, escrow);
+      await expectError(raiseDispute(watchdog, escrow), "AlreadyDisputed");
+
+      const watchdogBefore = await provider.connection.getBalance(watchdog.publicKey);
+      await resolveDispute(escrow, watchdog.publicKey, { counterparty: {} });
+      expect(await provider.connection.getAccountInfo(disputeAddress(escrow))).to.be.null;
+      const refunded = await provider.connection.getBalance(watchdog.publicKey);
+      expect(refunded - watchdogBefore).to.be.greaterThan(DISPUTE_BOND);
+
+      const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+      await settle(escrow, counterparty.publicKey, provider.wallet.payer, {
+        disputer: watchdog.publicKey,
+      });
+
+      // 5% of the 1 SOL pot to the watchdog, the rest less the keeper's cut
+      // to the counterparty the arbiter ruled for
+      const reward = 0.05 * LAMPORTS_PER_SOL;
+      expect((await provider.connection.getBalance(watchdog.publicKey)) - refunded).to.equal(
+        reward
+      );
+      const keeperReward = (LAMPORTS_PER_SOL - reward) / 1000;
+      expect(
+        (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore
+      ).to.equal(LAMPORTS_PER_SOL - reward - keeperReward);
+    });
+
+    it("Forfeits a rejected disputer's bond and settles on the price", async () => {
+      const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+      const watchdog = Keypair.generate();
+      await airdrop(watchdog.publicKey, LAMPORTS_PER_SOL);
+      await waitUntil(expiry);
+
+      await raiseDispute(watchdog, escrow);
+      await expectError(
+        settle(escrow, trader.publicKey, provider.wallet.payer),
+        "DisputePending"
+      );
+
+      const treasuryBefore = await provider.connection.getBalance(treasury);
+      await resolveDispute(escrow, watchdog.publicKey, null);
+      expect((await provider.connection.getBalance(treasury)) - treasuryBefore).to.equal(
+        DISPUTE_BOND
+      );
+
+      await waitUntil(expiry + 30);
+      await settle(escrow, trader.publicKey, provider.wallet.payer);
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        settled: {},
+      });
+    });
+
+    it("Voids and refunds the bond when the arbiter never rules", async () => {
+      await setConfig({
+        arbiter: provider.wallet.publicKey,
+        disputeBond: new anchor.BN(DISPUTE_BOND),
+        disputeRewardBps: 500,
+        disputeWindowSecs: 30,
+        voidGraceSecs: 8,
+        treasury,
+      });
+      const margin = 0.5 * LAMPORTS_PER_SOL;
+      const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4, { margin });
+      const state = await program.account.escrowState.fetch(escrow);
+      const watchdog = Keypair.generate();
+      await airdrop(watchdog.publicKey, LAMPORTS_PER_SOL);
+      await waitUntil(expiry);
+      await raiseDispute(watchdog, escrow);
+      const { raisedAt } = await program.account.dispute.fetch(disputeAddress(escrow));
+
+      const voidAndRefund = (withDispute: boolean) =>
+        program.methods
+          .voidAndRefund()
+          .accounts({
+            party: trader.publicKey,
+            config: configPDA,
+            escrow,
+            trader: trader.publicKey,
+            counterparty: counterparty.publicKey,
+            collectionIndex: escrowIndexAddress(state),
+            stats: statsPDA,
+            vault,
+            dispute: withDispute ? disputeAddress(escrow) : null,
+            disputer: withDispute ? watchdog.publicKey : null,
+          })
+          .signers([trader])
+          .rpc();
+
+      await expectError(voidAndRefund(true), "VoidTooEarly");
+      await waitUntil(raisedAt.toNumber() + 8);
+      await expectError(voidAndRefund(false), "MissingDisputeAccount");
+
+      const watchdogBefore = await provider.connection.getBalance(watchdog.publicKey);
+      const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+      await voidAndRefund(true);
+      expect(await provider.connection.getAccountInfo(disputeAddress(escrow))).to.be.null;
+      expect(
+        (await provider.connection.getBalance(watchdog.publicKey)) - watchdogBefore
+      ).to.be.greaterThan(DISPUTE_BOND);
+      expect(
+        (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore
+      ).to.equal(margin);
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        voided: {},
+      });
+    });
+
+    it("Refuses disputes once the window has closed", async () => {
+      const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+      const watchdog = Keypair.generate();
+      await airdrop(watchdog.publicKey, LAMPORTS_PER_SOL);
+
+      await waitUntil(expiry + 30);
+      await expectError(raiseDispute(watchdog, escrow), "DisputeWindowClosed");
+      await settle(escrow, trader.publicKey, provider.wallet.payer);
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        settled: {},
+      });
+    });
+  });
+
+  it("Numbers escrows in creation order for paging", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+
+    const escrows = [];
+    for (let i = 0; i < 3; i++) {
+      escrows.push(await initEscrow(trader, { margin: 0.1 * LAMPORTS_PER_SOL }));
+    }
+    const sequences = await Promise.all(
+      escrows.map(async (escrow) =>
+        (await program.account.escrowState.fetch(escrow)).sequence.toNumber()
+      )
+    );
+    expect(sequences[1]).to.equal(sequences[0] + 1);
+    expect(sequences[2]).to.equal(sequences[1] + 1);
+    const { totalEscrows } = await program.account.stats.fetch(statsPDA);
+    expect(totalEscrows.toNumber()).to.equal(sequences[2] + 1);
+
+    // Sequence sits right after the discriminator and trader key
+    const SEQUENCE_OFFSET = 8 + 32;
+    const page = await program.account.escrowState.all([
+      {
+        memcmp: {
+          offset: SEQUENCE_OFFSET,
+          bytes: anchor.utils.bytes.bs58.encode(
+            new anchor.BN(sequences[1]).toArrayLike(Buffer, "le", 8)
+          ),
+        },
+      },
+    ]);
+    expect(page.map(({ publicKey }) => publicKey.toBase58())).to.deep.equal([
+      escrows[1].toBase58(),
+    ]);
+
+    for (const escrow of escrows) {
+      await cancel(trader, escrow);
+    }
+  });
+
+  it("Withholds the maker reward when the counterparty copies the prediction", async () => {
+    const payer = provider.wallet.payer;
+    const mint = await createMint(provider.connection, payer, rewardAuthorityPDA, null, 6);
+    await setConfig({
+      rewardMint: mint,
+      makerRewardAmount: new anchor.BN(1_000_000),
+      duplicatePredictionEpsilon: new anchor.BN(1_000),
+    });
+
+    try {
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+      await airdrop(counterparty.publicKey, 3 * LAMPORTS_PER_SOL);
+      const predictedFloor = 10 * LAMPORTS_PER_SOL;
+      const makerAccount = (
+        await getOrCreateAssociatedTokenAccount(provider.connection, payer, mint, trader.publicKey)
+      ).address;
+      const rewards = { mint, makerAccount };
+
+      // Within the epsilon of the trader's call: no reward
+      const collectionId = "collusion-collection";
+      const copied = await initEscrow(trader, { predictedFloor, collectionId });
+      await accept(counterparty, copied, predictedFloor + 1_000, rewards);
+      expect(Number((await getAccount(provider.connection, makerAccount)).amount)).to.equal(0);
+
+      const honest = await initEscrow(trader, { predictedFloor, collectionId });
+      await accept(counterparty, honest, predictedFloor + 1_001, rewards);
+      expect(Number((await getAccount(provider.connection, makerAccount)).amount)).to.equal(
+        1_000_000
+      );
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Keeps vaulted escrows' funds apart in the shared vault", async () => {
+    await program.methods
+      .initializeVault()
+      .accounts({
+        admin: provider.wallet.publicKey,
+        config: configPDA,
+        vault: vaultPDA,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+    vault = vaultPDA;
+    await setConfig({ useVault: true });
+
+    try {
+      const vaultBalance = () => provider.connection.getBalance(vaultPDA);
+      const tracked = async () =>
+        (await program.account.vault.fetch(vaultPDA)).tracked.toNumber();
+      const start = await vaultBalance();
+      const trackedStart = await tracked();
+      const a = await openAcceptedEscrow(4, { margin: 0.5 * LAMPORTS_PER_SOL });
+      const b = await openAcceptedEscrow(4, { margin: 0.3 * LAMPORTS_PER_SOL });
+      expect((await vaultBalance()) - start).to.equal(1.6 * LAMPORTS_PER_SOL);
+      // The vault's own total follows every deposit
+      expect((await tracked()) - trackedStart).to.equal(1.6 * LAMPORTS_PER_SOL);
+
+      // The escrow accounts hold only their rent
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(
+        (await provider.connection.getAccountInfo(a.escrow)).data.length
+      );
+      expect(await provider.connection.getBalance(a.escrow)).to.equal(rent);
+      expect(
+        (await program.account.escrowState.fetch(a.escrow)).vaultBalance.toNumber()
+      ).to.equal(LAMPORTS_PER_SOL);
+
+      await waitUntil(Math.max(a.expiry, b.expiry));
+      await settle(a.escrow, a.trader.publicKey, provider.wallet.payer);
+
+      // Settling A drew exactly its own pot, leaving B's share untouched
+      expect((await vaultBalance()) - start).to.equal(0.6 * LAMPORTS_PER_SOL);
+      expect(
+        (await program.account.escrowState.fetch(a.escrow)).vaultBalance.toNumber()
+      ).to.equal(0);
+      expect(
+        (await program.account.escrowState.fetch(b.escrow)).vaultBalance.toNumber()
+      ).to.equal(0.6 * LAMPORTS_PER_SOL);
+
+      await settle(b.escrow, b.trader.publicKey, provider.wallet.payer);
+      expect(await vaultBalance()).to.equal(start);
+      expect(await tracked()).to.equal(trackedStart);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Requires predictions on the configured tick", async () => {
+    await setConfig({ predictionTick: new anchor.BN(0.01 * LAMPORTS_PER_SOL) });
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    try {
+      await expectError(
+        initEscrow(trader, { predictedFloor: 10 * LAMPORTS_PER_SOL + 1 }),
+        "InvalidPredictionTick"
+      );
+      const tick = 0.01 * LAMPORTS_PER_SOL;
+      const escrow = await initEscrow(trader, { predictedFloor: 10 * LAMPORTS_PER_SOL + tick });
+      await cancel(trader, escrow);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Lets a trader alone extend an escrow nobody has accepted", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+    const expiry = (await chainTime()) + 3600;
+    const escrow = await initEscrow(trader, { expiry, collectionId: "extend-unaccepted" });
+
+    const extend = (newExpiry: number) =>
+      program.methods
+        .extendUnaccepted(new anchor.BN(newExpiry))
+        .accounts({ trader: trader.publicKey, escrow })
+        .signers([trader])
+        .rpc();
+
+    await extend(expiry + 3600);
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(state.expiryTimestamp.toNumber()).to.equal(expiry + 3600);
+    expect(state.extensionCount).to.equal(1);
+    await expectError(extend(expiry + 7 * 24 * 3600 + 1), "MaxExtensionsReached");
+
+    await accept(counterparty, escrow);
+    await expectError(extend(expiry + 2 * 3600), "NotOpen");
+  });
+
+  it("Restricts settlement to allowlisted keepers when the list is set", async () => {
+    const vetted = Keypair.generate();
+    const outsider = Keypair.generate();
+    await airdrop(vetted.publicKey, LAMPORTS_PER_SOL);
+    await airdrop(outsider.publicKey, LAMPORTS_PER_SOL);
+
+    const first = await openAcceptedEscrow(4);
+    const second = await openAcceptedEscrow(4);
+    await waitUntil(Math.max(first.expiry, second.expiry));
+
+    await setConfig({ settleAllowlist: [vetted.publicKey] });
+    try {
+      await expectError(
+        settle(first.escrow, first.trader.publicKey, outsider),
+        "SettlerNotAllowed"
+      );
+      await settle(first.escrow, first.trader.publicKey, vetted);
+    } finally {
+      await setConfig();
+    }
+
+    // Empty list: anyone may settle
+    await settle(second.escrow, second.trader.publicKey, outsider);
+    expect((await program.account.escrowState.fetch(second.escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Records each settlement's participants, price and payouts", async () => {
+    const treasury = Keypair.generate();
+    await airdrop(treasury.publicKey, LAMPORTS_PER_SOL);
+    await setConfig({ feeBps: 200, treasury: treasury.publicKey });
+    try {
+      const margin = 0.5 * LAMPORTS_PER_SOL;
+      const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4, { margin });
+      const state = await program.account.escrowState.fetch(escrow);
+      await waitUntil(expiry);
+
+      const keeper = Keypair.generate();
+      await airdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+      const traderBefore = await provider.connection.getBalance(trader.publicKey);
+      const treasuryBefore = await provider.connection.getBalance(treasury.publicKey);
+      const keeperBefore = await provider.connection.getBalance(keeper.publicKey);
+
+      await settle(escrow, trader.publicKey, keeper, { treasury: treasury.publicKey });
+
+      const record = await program.account.settlementRecord.fetch(settlementRecordAddress(escrow));
+      expect(record.escrow.toBase58()).to.equal(escrow.toBase58());
+      expect(record.trader.toBase58()).to.equal(trader.publicKey.toBase58());
+      expect(record.counterparty.toBase58()).to.equal(counterparty.publicKey.toBase58());
+      expect(record.keeper.toBase58()).to.equal(keeper.publicKey.toBase58());
+      expect(record.predictedFloor.toString()).to.equal(state.predictedFloor.toString());
+      expect(record.counterpartyPrediction?.toString()).to.equal(
+        state.counterpartyPrediction?.toString()
+      );
+      expect(record.price).to.not.be.null;
+      expect(record.outcome).to.deep.equal({ trader: {} });
+      expect(record.rolloverEscrow).to.be.null;
+      expect(record.settledAt.toNumber()).to.be.at.least(expiry);
+
+      const traderGain = (await provider.connection.getBalance(trader.publicKey)) - traderBefore;
+      const treasuryGain =
+        (await provider.connection.getBalance(treasury.publicKey)) - treasuryBefore;
+      const keeperGain = (await provider.connection.getBalance(keeper.publicKey)) - keeperBefore;
+      expect(record.traderPayout.toNumber()).to.equal(traderGain);
+      expect(record.counterpartyPayout.toNumber()).to.equal(0);
+      expect(record.treasuryFee.toNumber()).to.equal(treasuryGain);
+      expect(record.keeperReward.toNumber()).to.equal(keeperGain + (await recordRent()));
+      expect(record.disputeReward.toNumber()).to.equal(0);
+      expect(
+        record.traderPayout.toNumber() + record.treasuryFee.toNumber() + record.keeperReward.toNumber()
+      ).to.equal(2 * margin);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Resolves a bps prediction against the price at acceptance", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    await expectError(
+      initEscrow(trader, { predictedFloor: 0, predictionUnit: { bpsFromEntry: {} } }),
+      "InvalidPredictionBps"
+    );
+
+    // +500 bps, winning anywhere within 600 bps of it
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, {
+      expiry,
+      predictedFloor: 10_500,
+      tolerance: 600,
+      priceSources: [{ pyth: {} }],
+      predictionUnit: { bpsFromEntry: {} },
+    });
+    await expectError(accept(counterparty, escrow), "MissingEntryPrice");
+    await accept(counterparty, escrow, null, null, {
+      collectionId: COLLECTION_SLUG,
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+    });
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(state.entryPrice.toNumber()).to.equal(10 * LAMPORTS_PER_SOL);
+
+    const expectedWinner = (pythPriceUpdate: PublicKey) =>
+      program.methods
+        .getExpectedWinner(COLLECTION_SLUG, [])
+        .accounts({
+          escrow,
+          config: configPDA,
+          tensorOracle: TENSOR_SWAP_ID,
+          pythPriceUpdate,
+        })
+        .view();
+
+    // 10 SOL is 10_000 bps of entry, 500 off; 12 SOL is 12_000, 1_500 off
+    expect(await expectedWinner(PYTH_TIGHT_CONFIDENCE)).to.deep.equal({ trader: {} });
+    expect(await expectedWinner(PYTH_HIGHER_PRICE)).to.deep.equal({ counterparty: {} });
+
+    await waitUntil(expiry);
+    await settle(escrow, trader.publicKey, provider.wallet.payer, {
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+    });
+    const record = await program.account.settlementRecord.fetch(settlementRecordAddress(escrow));
+    expect(record.outcome).to.deep.equal({ trader: {} });
+    expect(record.price.toNumber()).to.equal(10 * LAMPORTS_PER_SOL);
+  });
+
+  it("Holds exactly its rent plus the margin once opened", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const margin = 0.3 * LAMPORTS_PER_SOL;
+    const escrow = await initEscrow(trader, { margin });
+    const rent = await provider.connection.getMinimumBalanceForRentExemption(
+      program.account.escrowState.size
+    );
+    expect(await provider.connection.getBalance(escrow)).to.equal(rent + margin);
+
+    await cancel(trader, escrow);
+  });
+
+  it("Lets the market maker take only escrows within its limits", async () => {
+    const maker = Keypair.generate();
+    await airdrop(maker.publicKey, 5 * LAMPORTS_PER_SOL);
+    const desk = "mm-desk";
+    await setConfig({
+      marketMaker: maker.publicKey,
+      mmMaxMargin: new anchor.BN(LAMPORTS_PER_SOL),
+      mmCollections: [desk],
+    });
+    try {
+      const mmAccept = async (taker: Keypair, escrow: PublicKey) => {
+        const state = await program.account.escrowState.fetch(escrow);
+        return program.methods
+          .mmAccept(null, null, state.marginAmount)
+          .accounts({
+            trader: taker.publicKey,
+            escrow,
+            config: configPDA,
+            collectionState: collectionStateAddress(Buffer.from(state.collectionHash)),
+            stats: statsPDA,
+            vault,
+            rewardMint: null,
+            makerRewardAccount: null,
+            rewardAuthority: null,
+            tokenProgram: null,
+            tensorOracle: null,
+            pythPriceUpdate: null,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([taker])
+          .rpc();
+      };
+
+      const trader = Keypair.generate();
+      await airdrop(trader.publicKey, 5 * LAMPORTS_PER_SOL);
+      const offDesk = await initEscrow(trader, { margin: 0.5 * LAMPORTS_PER_SOL });
+      const oversized = await initEscrow(trader, {
+        collectionId: desk,
+        margin: 2 * LAMPORTS_PER_SOL,
+      });
+      const conforming = await initEscrow(trader, {
+        collectionId: desk,
+        margin: 0.5 * LAMPORTS_PER_SOL,
+      });
+
+      await expectError(mmAccept(maker, offDesk), "OutsideMarketMakerLimits");
+      await expectError(mmAccept(maker, oversized), "OutsideMarketMakerLimits");
+      const outsider = Keypair.generate();
+      await airdrop(outsider.publicKey, 2 * LAMPORTS_PER_SOL);
+      await expectError(mmAccept(outsider, conforming), "NotMarketMaker");
+
+      await mmAccept(maker, conforming);
+      const state = await program.account.escrowState.fetch(conforming);
+      expect(state.counterparty.toBase58()).to.equal(maker.publicKey.toBase58());
+      expect(state.status).to.deep.equal({ active: {} });
+
+      await cancel(trader, offDesk);
+      await cancel(trader, oversized);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  describe("lamport conservation", () => {
+    // Runs `step` and returns each account's lamport change, asserting the
+    // step moved lamports between `accounts` without creating or
+    // destroying any. Fees are paid by the provider wallet, never tracked.
+    async function conserved(accounts: PublicKey[], step: () => Promise<unknown>) {
+      const balances = () =>
+        Promise.all(accounts.map((account) => provider.connection.getBalance(account, "confirmed")));
+      const before = await balances();
+      await step();
+      const after = await balances();
+      const deltas = after.map((balance, i) => balance - before[i]);
+      expect(deltas.reduce((sum, delta) => sum + delta, 0)).to.equal(0);
+      return deltas;
+    }
+
+    async function lifecycle({
+      counterpartyPrediction = null as number | null,
+      predictedFloor = 10 * LAMPORTS_PER_SOL,
+    } = {}) {
+      const margin = 0.5 * LAMPORTS_PER_SOL;
+      const escrowRent = await provider.connection.getMinimumBalanceForRentExemption(
+        program.account.escrowState.size
+      );
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      const keeper = Keypair.generate();
+      const treasury = Keypair.generate();
+      for (const wallet of [trader, counterparty, keeper, treasury]) {
+        await airdrop(wallet.publicKey, 2 * LAMPORTS_PER_SOL);
+      }
+      await setConfig({ feeBps: 200, treasury: treasury.publicKey });
+
+      const escrow = await nextEscrowAddress(trader.publicKey);
+      const expiry = (await chainTime()) + 4;
+      const [traderOpen, escrowOpen] = await conserved(
+        [trader.publicKey, escrow, profileAddress(trader.publicKey), indexAddress(COLLECTION_SLUG)],
+        () => initEscrow(trader, { expiry, margin, predictedFloor })
+      );
+      expect(escrowOpen).to.equal(escrowRent + margin);
+      expect(traderOpen).to.be.at.most(-(escrowRent + margin));
+
+      const [counterpartyAccept, escrowAccept] = await conserved(
+        [counterparty.publicKey, escrow],
+        () => accept(counterparty, escrow, counterpartyPrediction)
+      );
+      expect(escrowAccept).to.equal(margin);
+      expect(counterpartyAccept).to.equal(-margin);
+
+      await waitUntil(expiry);
+      const record = settlementRecordAddress(escrow);
+      const [escrowSettle, traderSettle, counterpartySettle, keeperSettle, treasurySettle, recordSettle] =
+        await conserved(
+          [escrow, trader.publicKey, counterparty.publicKey, keeper.publicKey, treasury.publicKey, record],
+          () =>
+            settle(escrow, trader.publicKey, keeper, {
+              counterparty: counterparty.publicKey,
+              treasury: treasury.publicKey,
+            })
+        );
+      const settled = await program.account.settlementRecord.fetch(record);
+      expect(escrowSettle).to.equal(-2 * margin);
+      expect(traderSettle).to.equal(settled.traderPayout.toNumber());
+      expect(counterpartySettle).to.equal(settled.counterpartyPayout.toNumber());
+      expect(treasurySettle).to.equal(settled.treasuryFee.toNumber());
+      expect(treasurySettle).to.equal((2 * margin * 200) / 10_000);
+      expect(recordSettle).to.equal(await recordRent());
+      expect(keeperSettle).to.equal(settled.keeperReward.toNumber() - recordSettle);
+      expect(await provider.connection.getBalance(escrow)).to.equal(escrowRent);
+
+      const [traderClose, escrowClose] = await conserved([trader.publicKey, escrow], () =>
+        program.methods
+          .closeEscrow()
+          .accounts({ trader: trader.publicKey, escrow })
+          .signers([trader])
+          .rpc()
+      );
+      expect(escrowClose).to.equal(-escrowRent);
+      expect(traderClose).to.equal(escrowRent);
+    }
+
+    afterEach(async () => {
+      await setConfig();
+    });
+
+    it("Conserves lamports through open, accept, settle and close", async () => {
+      await lifecycle();
+    });
+
+    it("Conserves lamports when an equidistant pot is split", async () => {
+      await lifecycle({
+        predictedFloor: 9 * LAMPORTS_PER_SOL,
+        counterpartyPrediction: 11 * LAMPORTS_PER_SOL,
+      });
+    });
+
+    it("Returns every lamport of a cancelled escrow", async () => {
+      const trader = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      const margin = 0.5 * LAMPORTS_PER_SOL;
+      const escrow = await initEscrow(trader, { margin });
+      const funded = await provider.connection.getBalance(escrow);
+
+      const [traderCancel, escrowCancel] = await conserved(
+        [trader.publicKey, escrow, escrowIndexAddress(await program.account.escrowState.fetch(escrow))],
+        () => cancel(trader, escrow)
+      );
+      expect(traderCancel).to.equal(margin);
+      expect(escrowCancel).to.equal(-margin);
+
+      const [traderClose] = await conserved([trader.publicKey, escrow], () =>
+        program.methods
+          .closeEscrow()
+          .accounts({ trader: trader.publicKey, escrow })
+          .signers([trader])
+          .rpc()
+      );
+      expect(traderClose).to.equal(funded - margin);
+    });
+  });
+
+  it("Lets either party void an escrow whose oracle never comes back", async () => {
+    // The fixture update is older than this, so the Pyth-only escrow can
+    // never settle
+    await setConfig({ maxPriceAgeSecs: 60, voidGraceSecs: 8 });
+    try {
+      const margin = 0.5 * LAMPORTS_PER_SOL;
+      const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4, {
+        margin,
+        priceSources: [{ pyth: {} }],
+      });
+      const state = await program.account.escrowState.fetch(escrow);
+      await waitUntil(expiry);
+
+      await expectError(
+        settle(escrow, trader.publicKey, provider.wallet.payer, {
+          pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+        }),
+        "StalePrice"
+      );
+
+      const voidAndRefund = (party: Keypair) =>
+        program.methods
+          .voidAndRefund()
+          .accounts({
+            party: party.publicKey,
+            config: configPDA,
+            escrow,
+            trader: trader.publicKey,
+            counterparty: counterparty.publicKey,
+            collectionIndex: escrowIndexAddress(state),
+            stats: statsPDA,
+            vault,
+            dispute: null,
+            disputer: null,
+          })
+          .signers([party])
+          .rpc();
+
+      await expectError(voidAndRefund(counterparty), "VoidTooEarly");
+      const outsider = Keypair.generate();
+      await airdrop(outsider.publicKey, LAMPORTS_PER_SOL);
+      await waitUntil(expiry + 8);
+      await expectError(voidAndRefund(outsider), "Unauthorized");
+
+      const traderBefore = await provider.connection.getBalance(trader.publicKey);
+      const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+      await voidAndRefund(counterparty);
+
+      expect((await provider.connection.getBalance(trader.publicKey)) - traderBefore).to.equal(margin);
+      expect(
+        (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore
+      ).to.equal(margin);
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        voided: {},
+      });
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Keeps a long-odds winner's own stake whole when the loser pays costs", async () => {
+    // At 100:1 the counterparty risks just 0.01 SOL, less than the 2%
+    // fees and keeper reward on the pot
+    const treasury = Keypair.generate();
+    await airdrop(treasury.publicKey, LAMPORTS_PER_SOL);
+    const margin = LAMPORTS_PER_SOL;
+
+    const payout = async (loserPaysCosts: boolean) => {
+      await setConfig({ feeBps: 200, treasury: treasury.publicKey, loserPaysCosts });
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+      const expiry = (await chainTime()) + 4;
+      const escrow = await initEscrow(trader, { expiry, margin, odds: [100, 1] });
+      await accept(counterparty, escrow);
+      await waitUntil(expiry);
+
+      const before = await provider.connection.getBalance(trader.publicKey);
+      await settle(escrow, trader.publicKey, provider.wallet.payer, {
+        treasury: treasury.publicKey,
+      });
+      return (await provider.connection.getBalance(trader.publicKey)) - before;
+    };
+
+    try {
+      const winnerPays = await payout(false);
+      const loserPays = await payout(true);
+      expect(winnerPays).to.be.lessThan(margin);
+      expect(loserPays).to.equal(margin);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Rejects an escrow on an empty collection id", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, LAMPORTS_PER_SOL);
+
+    await expectError(initEscrow(trader, { collectionId: "" }), "EmptyCollectionId");
+    expect(await program.account.traderProfile.fetchNullable(profileAddress(trader.publicKey))).to
+      .be.null;
+  });
+
+  it("Refunds both sides of an escrow settled past the settlement window", async () => {
+    await setConfig({ settlementWindowSecs: 6 });
+    try {
+      const margin = 0.5 * LAMPORTS_PER_SOL;
+      const prompt = await openAcceptedEscrow(4, { margin });
+      const late = await openAcceptedEscrow(4, { margin });
+      await waitUntil(Math.max(prompt.expiry, late.expiry));
+
+      // Inside the window the trader's exact prediction takes the pot
+      await settle(prompt.escrow, prompt.trader.publicKey, provider.wallet.payer);
+      const promptRecord = await program.account.settlementRecord.fetch(
+        settlementRecordAddress(prompt.escrow)
+      );
+      expect(promptRecord.outcome).to.deep.equal({ trader: {} });
+
+      await waitUntil(late.expiry + 7);
+      const traderBefore = await provider.connection.getBalance(late.trader.publicKey);
+      const counterpartyBefore = await provider.connection.getBalance(late.counterparty.publicKey);
+      await settle(late.escrow, late.trader.publicKey, provider.wallet.payer, {
+        counterparty: late.counterparty.publicKey,
+      });
+
+      expect((await provider.connection.getBalance(late.trader.publicKey)) - traderBefore).to.equal(
+        margin
+      );
+      expect(
+        (await provider.connection.getBalance(late.counterparty.publicKey)) - counterpartyBefore
+      ).to.equal(margin);
+      expect((await program.account.escrowState.fetch(late.escrow)).status).to.deep.equal({
+        voided: {},
+      });
+      const lateRecord = await program.account.settlementRecord.fetch(
+        settlementRecordAddress(late.escrow)
+      );
+      expect(lateRecord.outcome).to.deep.equal({ refund: {} });
+      expect(lateRecord.keeperReward.toNumber()).to.equal(0);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Pays the loser of a profit-share escrow what the winner's cut leaves", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const margin = 0.5 * LAMPORTS_PER_SOL;
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, {
+      expiry,
+      margin,
+      payoutMode: { profitShare: {} },
+    });
+    await accept(counterparty, escrow);
+    await waitUntil(expiry);
+
+    const traderBefore = await provider.connection.getBalance(trader.publicKey);
+    const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer),
+      "MissingCounterpartyAccount"
+    );
+    await settle(escrow, trader.publicKey, provider.wallet.payer, {
+      counterparty: counterparty.publicKey,
+    });
+
+    const record = await program.account.settlementRecord.fetch(settlementRecordAddress(escrow));
+    const traderGain = (await provider.connection.getBalance(trader.publicKey)) - traderBefore;
+    const counterpartyGain =
+      (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore;
+    expect(traderGain).to.equal(record.traderPayout.toNumber());
+    expect(counterpartyGain).to.equal(record.counterpartyPayout.toNumber());
+
+    // Each side's half of the pot, with 20% of the counterparty's half
+    // moving to the trader
+    const half = (2 * margin - record.keeperReward.toNumber()) / 2;
+    expect(counterpartyGain).to.be.closeTo(half * 0.8, 1);
+    expect(traderGain + counterpartyGain + record.keeperReward.toNumber()).to.equal(2 * margin);
+  });
+
+  it("Summarises an escrow in one read", async () => {
+    const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4, {
+      counterpartyPrediction: 12 * LAMPORTS_PER_SOL,
+    });
+    const summary = (tensorOracle: PublicKey | null) =>
+      program.methods
+        .getSummary(COLLECTION_SLUG, [])
+        .accounts({
+          escrow,
+          config: configPDA,
+          tensorOracle,
+          pythPriceUpdate: null,
+        })
+        .view();
+
+    const state = await program.account.escrowState.fetch(escrow);
+    const full = await summary(TENSOR_SWAP_ID);
+    expect(full.escrow.equals(escrow)).to.be.true;
+    expect(full.status).to.deep.equal(state.status);
+    expect(full.trader.equals(trader.publicKey)).to.be.true;
+    expect(full.counterparty.equals(counterparty.publicKey)).to.be.true;
+    for (const field of [
+      "predictedFloor",
+      "lowerTolerance",
+      "upperTolerance",
+      "counterpartyPrediction",
+      "marginAmount",
+      "createdAt",
+      "acceptedAt",
+      "expiryTimestamp",
+    ]) {
+      expect(full[field].toString(), field).to.equal(state[field].toString());
+    }
+    expect(full.predictionUnit).to.deep.equal(state.predictionUnit);
+    expect(full.payoutMode).to.deep.equal(state.payoutMode);
+    expect(full.entryPrice).to.equal(null);
+    expect(full.counterpartyStake.toString()).to.equal(state.marginAmount.toString());
+
+    // The mock oracle quotes 10 SOL, the trader's call; fees come off the
+    // 1 SOL pot before the trader is paid
+    expect(full.projectedWinner).to.deep.equal({ trader: {} });
+    expect(full.projectedPayouts.counterparty.toNumber()).to.equal(0);
+    expect(full.projectedPayouts.trader.toNumber()).to.be.within(1, LAMPORTS_PER_SOL);
+
+    // Without an oracle there's nothing to project
+    const bare = await summary(null);
+    expect(bare.projectedWinner).to.equal(null);
+    expect(bare.projectedPayouts).to.equal(null);
+
+    await waitUntil(expiry);
+    await settle(escrow, trader.publicKey, provider.wallet.payer);
+  });
+
+  it("Takes and pays out stakes in wrapped SOL", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+    const payer = provider.wallet.payer;
+    const wrap = (owner: Keypair) =>
+      createWrappedNativeAccount(provider.connection, payer, owner.publicKey, LAMPORTS_PER_SOL);
+    const wsolBalance = async (account: PublicKey) =>
+      Number((await getAccount(provider.connection, account)).amount);
+    const traderWsol = await wrap(trader);
+    const counterpartyWsol = await wrap(counterparty);
+
+    const margin = 0.5 * LAMPORTS_PER_SOL;
+    await expectError(
+      initEscrow(trader, { wsolSource: counterpartyWsol }),
+      "InvalidWsolAccount"
+    );
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, { expiry, margin, wsolSource: traderWsol });
+    expect((await program.account.escrowState.fetch(escrow)).denomination).to.deep.equal({
+      wrappedSol: {},
+    });
+    await accept(counterparty, escrow, null, null, null, counterpartyWsol);
+    expect(await wsolBalance(traderWsol)).to.equal(LAMPORTS_PER_SOL - margin);
+    expect(await wsolBalance(counterpartyWsol)).to.equal(LAMPORTS_PER_SOL - margin);
+
+    // The mock oracle quotes the trader's 10 SOL call
+    await waitUntil(expiry);
+    await expectError(
+      settle(escrow, trader.publicKey, payer),
+      "MissingWsolAccounts"
+    );
+    const traderBefore = await provider.connection.getBalance(trader.publicKey);
+    await settle(escrow, trader.publicKey, payer, { payeeWsol: traderWsol });
+    const record = await program.account.settlementRecord.fetch(
+      settlementRecordAddress(escrow)
+    );
+    expect(await wsolBalance(traderWsol)).to.equal(
+      LAMPORTS_PER_SOL - margin + record.traderPayout.toNumber()
+    );
+    expect(await provider.connection.getBalance(trader.publicKey)).to.equal(traderBefore);
+  });
+
+  describe("oracle binding", () => {
+    // Any account other than the one recorded at init stands in for a
+    // forged oracle
+    const forged = Keypair.generate().publicKey;
+
+    it("Rejects a substituted oracle on a native settlement", async () => {
+      const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+      expect((await program.account.escrowState.fetch(escrow)).tensorOracle.equals(TENSOR_SWAP_ID))
+        .to.be.true;
+      await waitUntil(expiry);
+      await expectError(
+        settle(escrow, trader.publicKey, provider.wallet.payer, { tensorOracle: forged }),
+        "OracleMismatch"
+      );
+      await settle(escrow, trader.publicKey, provider.wallet.payer);
+    });
+
+    it("Rejects a substituted oracle on a wrapped SOL settlement", async () => {
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+      const wrap = (owner: Keypair) =>
+        createWrappedNativeAccount(
+          provider.connection,
+          provider.wallet.payer,
+          owner.publicKey,
+          LAMPORTS_PER_SOL
+        );
+      const traderWsol = await wrap(trader);
+      const expiry = (await chainTime()) + 4;
+      const escrow = await initEscrow(trader, { expiry, wsolSource: traderWsol });
+      await accept(counterparty, escrow, null, null, null, await wrap(counterparty));
+
+      await waitUntil(expiry);
+      await expectError(
+        settle(escrow, trader.publicKey, provider.wallet.payer, {
+          payeeWsol: traderWsol,
+          tensorOracle: forged,
+        }),
+        "OracleMismatch"
+      );
+      await settle(escrow, trader.publicKey, provider.wallet.payer, { payeeWsol: traderWsol });
+    });
+
+    it("Rejects a substituted oracle in a batch settlement", async () => {
+      const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4);
+      await waitUntil(expiry);
+
+      const state = await program.account.escrowState.fetch(escrow);
+      await expectError(
+        program.methods
+          .settleBatch([COLLECTION_SLUG])
+          .accounts({
+            keeper: provider.wallet.publicKey,
+            config: configPDA,
+            stats: statsPDA,
+          })
+          .remainingAccounts([
+            { pubkey: escrow, isSigner: false, isWritable: true },
+            { pubkey: escrowIndexAddress(state), isSigner: false, isWritable: true },
+            { pubkey: forged, isSigner: false, isWritable: false },
+            { pubkey: PYTH_TIGHT_CONFIDENCE, isSigner: false, isWritable: false },
+            { pubkey: trader.publicKey, isSigner: false, isWritable: true },
+            { pubkey: counterparty.publicKey, isSigner: false, isWritable: true },
+            { pubkey: settlementRecordAddress(escrow), isSigner: false, isWritable: true },
+          ])
+          .rpc(),
+        "OracleMismatch"
+      );
+      await settle(escrow, trader.publicKey, provider.wallet.payer);
+    });
+  });
+
+  it("Decays the maker reward the longer an escrow sits open", async () => {
+    const payer = provider.wallet.payer;
+    const mint = await createMint(provider.connection, payer, rewardAuthorityPDA, null, 6);
+    // 1_000_000 per second of the horizon left at accept
+    await setConfig({
+      rewardMint: mint,
+      makerRewardAmount: new anchor.BN(6_000_000),
+      makerRewardHorizonSecs: 6,
+    });
+
+    try {
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+      await airdrop(counterparty.publicKey, 3 * LAMPORTS_PER_SOL);
+      const makerAccount = (
+        await getOrCreateAssociatedTokenAccount(provider.connection, payer, mint, trader.publicKey)
+      ).address;
+      const rewards = { mint, makerAccount };
+      const minted = async () =>
+        Number((await getAccount(provider.connection, makerAccount)).amount);
+      const collectionId = "maker-decay-collection";
+
+      // Accepted straight away: at most a second of decay
+      const fresh = await initEscrow(trader, { collectionId });
+      await accept(counterparty, fresh, null, rewards);
+      expect(await minted()).to.be.within(5_000_000, 6_000_000);
+
+      // Accepted a second before the horizon: at most one second's worth
+      const before = await minted();
+      const stale = await initEscrow(trader, { collectionId });
+      const { createdAt } = await program.account.escrowState.fetch(stale);
+      await waitUntil(createdAt.toNumber() + 5);
+      await accept(counterparty, stale, null, rewards);
+      expect((await minted()) - before).to.be.at.most(1_000_000);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Makes the counterparty take the other side of an over/under escrow", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    // Trader takes over 9 SOL; the mock oracle's 10 SOL lands above it
+    const line = 9 * LAMPORTS_PER_SOL;
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, {
+      expiry,
+      predictedFloor: line,
+      direction: { over: {} },
+    });
+    await expectError(accept(counterparty, escrow, line + LAMPORTS_PER_SOL), "SameDirection");
+    await expectError(accept(counterparty, escrow, line), "SameDirection");
+    await accept(counterparty, escrow, line - LAMPORTS_PER_SOL);
+
+    await waitUntil(expiry);
+    await expectError(
+      settle(escrow, counterparty.publicKey, provider.wallet.payer),
+      "InvalidWinner"
+    );
+    await settle(escrow, trader.publicKey, provider.wallet.payer);
+  });
+
+  it("Settles against an asymmetric tolerance band", async () => {
+    // The mock oracle quotes 10 SOL; a 1 SOL band below an 11 SOL call
+    // reaches it exactly, a 1 SOL band above a 9 SOL call too
+    const sol = LAMPORTS_PER_SOL;
+    const cases = [
+      { predictedFloor: 11 * sol, lowerTolerance: sol, upperTolerance: 0, hit: true },
+      { predictedFloor: 11 * sol, lowerTolerance: sol - 1, upperTolerance: 2 * sol, hit: false },
+      { predictedFloor: 9 * sol, lowerTolerance: 0, upperTolerance: sol, hit: true },
+      { predictedFloor: 9 * sol, lowerTolerance: 2 * sol, upperTolerance: sol - 1, hit: false },
+    ];
+    for (const { hit, ...band } of cases) {
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+      const expiry = (await chainTime()) + 4;
+      const escrow = await initEscrow(trader, { expiry, ...band });
+      await accept(counterparty, escrow);
+      await waitUntil(expiry);
+      await settle(
+        escrow,
+        hit ? trader.publicKey : counterparty.publicKey,
+        provider.wallet.payer
+      );
+    }
+  });
+
+  it("Tags escrows with a category clients can filter on", async () => {
+    await setConfig({ categories: [1, 2] });
+    try {
+      const trader = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      await expectError(initEscrow(trader, { category: 3 }), "InvalidCategory");
+
+      const escrow = await initEscrow(trader, { category: 2 });
+      expect((await program.account.escrowState.fetch(escrow)).category).to.equal(2);
+
+      // Category sits right after the sequence
+      const CATEGORY_OFFSET = 8 + 32 + 8;
+      const tagged = await program.account.escrowState.all([
+        { memcmp: { offset: 8, bytes: trader.publicKey.toBase58() } },
+        {
+          memcmp: {
+            offset: CATEGORY_OFFSET,
+            bytes: anchor.utils.bytes.bs58.encode(Buffer.from([2])),
+          },
+        },
+      ]);
+      expect(tagged.map(({ publicKey }) => publicKey.toBase58())).to.deep.equal([
+        escrow.toBase58(),
+      ]);
+      await cancel(trader, escrow);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Lets a party exit early for a penalty paid to the other side", async () => {
+    const margin = 0.5 * LAMPORTS_PER_SOL;
+    const { trader, counterparty, escrow } = await openAcceptedEscrow(3600, { margin });
+    const state = await program.account.escrowState.fetch(escrow);
+    const exitEarly = (party: Keypair) =>
+      program.methods
+        .exitEarly()
+        .accounts({
+          party: party.publicKey,
+          config: configPDA,
+          escrow,
+          trader: trader.publicKey,
+          counterparty: counterparty.publicKey,
+          collectionIndex: escrowIndexAddress(state),
+          stats: statsPDA,
+          vault,
+        })
+        .signers([party])
+        .rpc();
+
+    await expectError(exitEarly(trader), "EarlyExitDisabled");
+    await setConfig({ earlyExitPenaltyBps: 1_000 });
+    try {
+      const traderBefore = await provider.connection.getBalance(trader.publicKey);
+      const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+      await exitEarly(trader);
+
+      // 10% of the trader's stake goes to the counterparty
+      const penalty = margin / 10;
+      expect((await provider.connection.getBalance(trader.publicKey)) - traderBefore).to.equal(
+        margin - penalty
+      );
+      expect(
+        (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore
+      ).to.equal(margin + penalty);
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        voided: {},
+      });
+      await expectError(exitEarly(counterparty), "NoSecondTrader");
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Prices settlement only on the collection the escrow was opened for", async () => {
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+    await waitUntil(expiry);
+
+    // A caller-chosen collection never reaches the oracle
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer, {
+        collectionId: "some-other-collection",
+      }),
+      "CollectionIdMismatch"
+    );
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      active: {},
+    });
+
+    await settle(escrow, trader.publicKey, provider.wallet.payer);
+    const record = await program.account.settlementRecord.fetch(settlementRecordAddress(escrow));
+    expect(record.price.toNumber()).to.equal(10 * LAMPORTS_PER_SOL);
+  });
+
+  it("Emits the prediction's distance from the settlement price", async () => {
+    // Trader calls 10.2 SOL against the mock oracle's 10 SOL
+    const sol = LAMPORTS_PER_SOL;
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, {
+      expiry,
+      predictedFloor: 10.2 * sol,
+      tolerance: 0.5 * sol,
+    });
+    await accept(counterparty, escrow);
+    await waitUntil(expiry);
+
+    const signature = await settle(escrow, trader.publicKey, provider.wallet.payer);
+    await provider.connection.confirmTransaction(signature, "confirmed");
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    const [settled] = [...parser.parseLogs(tx.meta.logMessages)].filter(
+      ({ name }) => name === "escrowSettled" || name === "EscrowSettled"
+    );
+    expect(settled.data.distance.toNumber()).to.equal(0.2 * sol);
+    // 0.2 SOL off a 10 SOL price is 200 bps
+    expect(settled.data.relativeErrorBps.toNumber()).to.equal(200);
+    expect(settled.data.price.toNumber()).to.equal(10 * sol);
+  });
+
+  it("Won't settle an escrow accepted too shortly before it expired", async () => {
+    await setConfig({ minObservationPeriodSecs: 8 });
+    try {
+      // Accepted a couple of seconds before expiry, well inside the period
+      const { trader, escrow, expiry } = await openAcceptedEscrow(2);
+      await waitUntil(expiry);
+      await expectError(
+        settle(escrow, trader.publicKey, provider.wallet.payer),
+        "InsufficientObservation"
+      );
+
+      const { acceptedAt } = await program.account.escrowState.fetch(escrow);
+      await waitUntil(acceptedAt.toNumber() + 8);
+      await settle(escrow, trader.publicKey, provider.wallet.payer);
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        settled: {},
+      });
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Waives the settlement fee on escrows opened by an exempt wallet", async () => {
+    const treasury = Keypair.generate();
+    await airdrop(treasury.publicKey, LAMPORTS_PER_SOL);
+    const partner = Keypair.generate();
+    await airdrop(partner.publicKey, 4 * LAMPORTS_PER_SOL);
+    const setFeeExempt = (wallets: PublicKey[]) =>
+      program.methods
+        .setFeeExempt(wallets)
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          feeExempt: feeExemptPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    await setFeeExempt([partner.publicKey]);
+    feeExempt = feeExemptPDA;
+    await setConfig({ feeBps: 200, treasury: treasury.publicKey });
+    try {
+      const margin = 0.5 * LAMPORTS_PER_SOL;
+      const expiry = (await chainTime()) + 4;
+
+      // The partner makes one escrow and takes another's
+      const counterparty = Keypair.generate();
+      await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+      const exempt = await initEscrow(partner, { expiry, margin });
+      await accept(counterparty, exempt);
+
+      const maker = Keypair.generate();
+      await airdrop(maker.publicKey, 2 * LAMPORTS_PER_SOL);
+      const charged = await initEscrow(maker, { expiry, margin });
+      await accept(partner, charged);
+      await waitUntil(expiry);
+
+      const options = { treasury: treasury.publicKey };
+      await settle(exempt, partner.publicKey, provider.wallet.payer, options);
+      await settle(charged, maker.publicKey, provider.wallet.payer, options);
+
+      // Only the maker's listing counts
+      const exemptRecord = await program.account.settlementRecord.fetch(
+        settlementRecordAddress(exempt)
+      );
+      const chargedRecord = await program.account.settlementRecord.fetch(
+        settlementRecordAddress(charged)
+      );
+      expect(exemptRecord.treasuryFee.toNumber()).to.equal(0);
+      expect(chargedRecord.treasuryFee.toNumber()).to.equal((2 * margin * 200) / 10_000);
+    } finally {
+      await setConfig();
+      await setFeeExempt([]);
+    }
+  });
+
+  it("Refuses the program's own accounts as oracle or payout recipient", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await expectError(initEscrow(trader, { tensorOracle: configPDA }), "InvalidOracleAccount");
+
+    const { trader: winner, escrow, expiry } = await openAcceptedEscrow(4);
+    await waitUntil(expiry);
+    // The winner signs as keeper, so only the recipient's owner is at fault
+    await expectError(
+      settle(escrow, winner.publicKey, winner, { payoutRecipient: statsPDA }),
+      "ProgramOwnedRecipient"
+    );
+    await settle(escrow, winner.publicKey, winner, { payoutRecipient: Keypair.generate().publicKey });
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Returns a consolation share of the pot to the loser", async () => {
+    const treasury = Keypair.generate();
+    await airdrop(treasury.publicKey, LAMPORTS_PER_SOL);
+    await setConfig({ feeBps: 200, treasury: treasury.publicKey, consolationBps: 1_000 });
+    try {
+      const margin = 0.5 * LAMPORTS_PER_SOL;
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+      const expiry = (await chainTime()) + 4;
+      const escrow = await initEscrow(trader, {
+        expiry,
+        margin,
+        payoutMode: { consolation: {} },
+      });
+      await accept(counterparty, escrow);
+      await waitUntil(expiry);
+
+      const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+      await settle(escrow, trader.publicKey, provider.wallet.payer, {
+        treasury: treasury.publicKey,
+        counterparty: counterparty.publicKey,
+      });
+      const record = await program.account.settlementRecord.fetch(settlementRecordAddress(escrow));
+
+      // 10% of what's left after the fee and keeper reward goes to the loser
+      const distributable =
+        2 * margin - record.treasuryFee.toNumber() - record.keeperReward.toNumber();
+      expect(record.counterpartyPayout.toNumber()).to.equal(Math.floor(distributable / 10));
+      expect(
+        (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore
+      ).to.equal(record.counterpartyPayout.toNumber());
+      expect(
+        record.traderPayout.toNumber() +
+          record.counterpartyPayout.toNumber() +
+          record.treasuryFee.toNumber() +
+          record.keeperReward.toNumber()
+      ).to.equal(2 * margin);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Treats a retried init with the same nonce as a clean failure", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    const nonce = 7;
+    const escrow = await initEscrow(trader, { nonce });
+    const opened = await program.account.escrowState.fetch(escrow);
+    expect(opened.nonce.toNumber()).to.equal(nonce);
+    const escrowCount = async () =>
+      (
+        await program.account.traderProfile.fetch(profileAddress(trader.publicKey))
+      ).escrowCount.toNumber();
+    // Later escrows number on from past the chosen nonce
+    expect(await escrowCount()).to.equal(nonce + 1);
+
+    // The retry lands on the same address, which is already taken
+    const escrowBalance = await provider.connection.getBalance(escrow);
+    let logs: string[] = [];
+    try {
+      await initEscrow(trader, { nonce });
+      expect.fail("retried init should have failed");
+    } catch (err) {
+      logs = err.logs;
+    }
+    expect(logs.join("\n")).to.include("already in use");
+    expect(await provider.connection.getBalance(escrow)).to.equal(escrowBalance);
+    expect(await escrowCount()).to.equal(nonce + 1);
+
+    // Once closed, neither its nonce nor any skipped one opens again
+    await cancel(trader, escrow);
+    await expectError(initEscrow(trader, { nonce }), "NonceAlreadyUsed");
+    await expectError(initEscrow(trader, { nonce: 3 }), "NonceAlreadyUsed");
+  });
+
+  it("Rejects a tolerance too wide for the prediction", async () => {
+    await setConfig({ maxToleranceBps: 500 });
+    try {
+      const trader = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      const predictedFloor = 10 * LAMPORTS_PER_SOL;
+
+      // 5% of a 10 SOL prediction is 0.5 SOL; one lamport more is too wide
+      await expectError(
+        initEscrow(trader, { predictedFloor, tolerance: 0.5 * LAMPORTS_PER_SOL + 1 }),
+        "ToleranceTooWide"
+      );
+      await expectError(
+        initEscrow(trader, {
+          predictedFloor,
+          lowerTolerance: 0,
+          upperTolerance: 2 * LAMPORTS_PER_SOL,
+        }),
+        "ToleranceTooWide"
+      );
+      const escrow = await initEscrow(trader, { predictedFloor, tolerance: 0.5 * LAMPORTS_PER_SOL });
+      await cancel(trader, escrow);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Migrates a first-release escrow to the current layout and seeds", async () => {
+    const collectionId = "legacy-y00ts";
+    const [legacyEscrow] = PublicKey.findProgramAddressSync(
+      [Buffer.from("escrow"), LEGACY_TRADER.toBuffer()],
+      program.programId
+    );
+    const legacyBalance = await provider.connection.getBalance(legacyEscrow);
+    const migrate = async (authority: Keypair) =>
+      program.methods
+        .migrateToV2(collectionId, 0)
+        .accounts({
+          authority: authority.publicKey,
+          trader: LEGACY_TRADER,
+          legacyEscrow,
+          traderProfile: profileAddress(LEGACY_TRADER),
+          escrow: await nextEscrowAddress(LEGACY_TRADER),
+          collectionIndex: indexAddress(collectionId),
+          config: configPDA,
+          stats: statsPDA,
+          tensorOracle: TENSOR_SWAP_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([authority])
+        .rpc();
+
+    const stranger = Keypair.generate();
+    await airdrop(stranger.publicKey, LAMPORTS_PER_SOL);
+    await expectError(migrate(stranger), "Unauthorized");
+
+    const escrow = await nextEscrowAddress(LEGACY_TRADER);
+    await migrate(provider.wallet.payer);
+
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(state.status).to.deep.equal({ active: {} });
+    expect(state.trader.toBase58()).to.equal(LEGACY_TRADER.toBase58());
+    expect(state.counterparty.toBase58()).to.equal(LEGACY_COUNTERPARTY.toBase58());
+    expect(Buffer.from(state.collectionHash)).to.deep.equal(collectionHash(collectionId));
+    expect(state.predictedFloor.toNumber()).to.equal(10 * LAMPORTS_PER_SOL);
+    expect(state.marginAmount.toNumber()).to.equal(0.5 * LAMPORTS_PER_SOL);
+    expect(state.lowerTolerance.toNumber()).to.equal(100);
+    expect(state.expiryTimestamp.toNumber()).to.equal(4_102_444_800);
+    // Not yet expired, so it counts as accepted as of the migration
+    expect(state.acceptedAt.toNumber()).to.equal(state.createdAt.toNumber());
+    expect(state.acceptedAt.toNumber()).to.be.at.most(await chainTime());
+
+    // Both stakes moved over, and the old account is gone
+    const rent = await provider.connection.getMinimumBalanceForRentExemption(
+      (await provider.connection.getAccountInfo(escrow)).data.length
+    );
+    expect(await provider.connection.getBalance(escrow)).to.equal(rent + LAMPORTS_PER_SOL);
+    expect(legacyBalance).to.be.greaterThan(LAMPORTS_PER_SOL);
+    expect(await provider.connection.getAccountInfo(legacyEscrow)).to.be.null;
+    await expectError(migrate(provider.wallet.payer), "NotLegacyEscrow");
+  });
+
+  it("Tracks what each side deposited as the pot", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const escrow = await initEscrow(trader, {
+      expiry: (await chainTime()) + 60,
+      margin: 1.5 * LAMPORTS_PER_SOL,
+      odds: [3, 1],
+    });
+    const openBalance = await provider.connection.getBalance(escrow);
+    const opened = await program.account.escrowState.fetch(escrow);
+    expect(opened.traderDeposit.toNumber()).to.equal(1.5 * LAMPORTS_PER_SOL);
+    expect(opened.counterpartyDeposit.toNumber()).to.equal(0);
+
+    await accept(counterparty, escrow);
+    const state = await program.account.escrowState.fetch(escrow);
+    expect(state.traderDeposit.toNumber()).to.equal(1.5 * LAMPORTS_PER_SOL);
+    expect(state.counterpartyDeposit.toNumber()).to.equal(0.5 * LAMPORTS_PER_SOL);
+    expect(
+      (await provider.connection.getBalance(escrow)) - openBalance + 1.5 * LAMPORTS_PER_SOL
+    ).to.equal(state.traderDeposit.add(state.counterpartyDeposit).toNumber());
+  });
+
+  it("Emits a status change for every step of an escrow's life", async () => {
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    const statusChanges = async (signature: string) => {
+      await provider.connection.confirmTransaction(signature, "confirmed");
+      const tx = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      return [...parser.parseLogs(tx.meta.logMessages)]
+        .filter(({ name }) => name === "statusChanged" || name === "StatusChanged")
+        .map(({ data }) => data);
+    };
+
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, { expiry });
+
+    const accepted = await statusChanges(await accept(counterparty, escrow));
+    await waitUntil(expiry);
+    const settled = await statusChanges(
+      await settle(escrow, trader.publicKey, provider.wallet.payer)
+    );
+    const history = [...accepted, ...settled];
+
+    const steps = history.map(({ from, to }) => [Object.keys(from)[0], Object.keys(to)[0]]);
+    expect(steps).to.deep.equal([
+      ["open", "active"],
+      ["active", "settled"],
+    ]);
+    expect(history.map(({ actor }) => actor.toBase58())).to.deep.equal([
+      counterparty.publicKey.toBase58(),
+      provider.wallet.publicKey.toBase58(),
+    ]);
+    for (const change of history) {
+      expect(change.escrow.toBase58()).to.equal(escrow.toBase58());
+    }
+    expect(history[1].timestamp.toNumber()).to.be.at.least(history[0].timestamp.toNumber());
+  });
+
+  it("Pays the keeper in reward tokens and leaves the pot to the winner", async () => {
+    const payer = provider.wallet.payer;
+    const mint = await createMint(provider.connection, payer, payer.publicKey, null, 6);
+    await setConfig({
+      rewardMint: mint,
+      keeperRewardInToken: true,
+      keeperRewardBaseBps: 100,
+      keeperRewardMaxBps: 100,
+    });
+    try {
+      await program.methods
+        .initializeRewardVault()
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          rewardMint: mint,
+          rewardVault: rewardVaultPDA,
+          rewardAuthority: rewardAuthorityPDA,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+      await mintTo(provider.connection, payer, mint, rewardVaultPDA, payer, 100_000_000);
+
+      const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+      const keeper = Keypair.generate();
+      await airdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+      const keeperAccount = (
+        await getOrCreateAssociatedTokenAccount(provider.connection, payer, mint, keeper.publicKey)
+      ).address;
+      await waitUntil(expiry);
+
+      await expectError(settle(escrow, trader.publicKey, keeper), "MissingRewardAccounts");
+      const traderBefore = await provider.connection.getBalance(trader.publicKey);
+      await settle(escrow, trader.publicKey, keeper, { keeperRewards: { mint, keeperAccount } });
+
+      // 1% of the 1 SOL pot, in token base units, and the whole pot to the winner
+      const rewards = await getAccount(provider.connection, keeperAccount);
+      expect(Number(rewards.amount)).to.equal(0.01 * LAMPORTS_PER_SOL);
+      expect((await provider.connection.getBalance(trader.publicKey)) - traderBefore).to.equal(
+        LAMPORTS_PER_SOL
+      );
+      const record = await program.account.settlementRecord.fetch(settlementRecordAddress(escrow));
+      expect(record.keeperReward.toNumber()).to.equal(0);
+      expect(record.keeperTokenReward.toNumber()).to.equal(0.01 * LAMPORTS_PER_SOL);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Rejects spoofed system and token programs", async () => {
+    // Real, executable programs, just not the ones asked for
+    await expectError(
+      program.methods
+        .setFeeExempt([])
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          feeExempt: feeExemptPDA,
+          systemProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc(),
+      "InvalidProgramId"
+    );
+
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+    await waitUntil(expiry);
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer, {
+        tokenProgram: anchor.web3.SystemProgram.programId,
+      }),
+      "InvalidProgramId"
+    );
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer, {
+        tokenProgram: Keypair.generate().publicKey,
+      }),
+      "InvalidProgramId"
+    );
+    await settle(escrow, trader.publicKey, provider.wallet.payer);
+  });
+
+  it("Accepts and settles a slot-based escrow on the slot clock", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 4 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+    const currentSlot = () => provider.connection.getSlot("confirmed");
+
+    await expectError(initEscrow(trader, { expirySlot: await currentSlot() }), "InvalidExpiry");
+
+    const expirySlot = (await currentSlot()) + 15;
+    const escrow = await initEscrow(trader, { expirySlot });
+    const unfilled = await initEscrow(trader, { expirySlot });
+    const opened = await program.account.escrowState.fetch(escrow);
+    expect(opened.expirySlot.toNumber()).to.equal(expirySlot);
+
+    await accept(counterparty, escrow);
+    await expectError(settle(escrow, trader.publicKey, provider.wallet.payer), "NotExpiredYet");
+
+    while ((await currentSlot()) < expirySlot) {
+      await sleep(200);
+    }
+    await expectError(accept(counterparty, unfilled), "Expired");
+    await settle(escrow, trader.publicKey, provider.wallet.payer);
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Voids an uneven escrow back to exactly what each side put in", async () => {
+    await setConfig({ voidGraceSecs: 2 });
+    try {
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+      // 0.6 SOL plus a 0.3 SOL top-up, against a 0.3 SOL counter-stake at 3:1
+      const expiry = (await chainTime()) + 6;
+      const escrow = await initEscrow(trader, {
+        expiry,
+        margin: 0.6 * LAMPORTS_PER_SOL,
+        odds: [3, 1],
+      });
+      await program.methods
+        .increaseMargin(new anchor.BN(0.3 * LAMPORTS_PER_SOL))
+        .accounts({
+          trader: trader.publicKey,
+          escrow,
+          config: configPDA,
+          stats: statsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([trader])
+        .rpc();
+      await accept(counterparty, escrow);
+      const state = await program.account.escrowState.fetch(escrow);
+      expect(state.traderDeposit.toNumber()).to.equal(0.9 * LAMPORTS_PER_SOL);
+      expect(state.counterpartyDeposit.toNumber()).to.equal(0.3 * LAMPORTS_PER_SOL);
+
+      await waitUntil(expiry + 3);
+      const traderBefore = await provider.connection.getBalance(trader.publicKey);
+      const counterpartyBefore = await provider.connection.getBalance(counterparty.publicKey);
+      await program.methods
+        .voidAndRefund()
+        .accounts({
+          party: trader.publicKey,
+          config: configPDA,
+          escrow,
+          trader: trader.publicKey,
+          counterparty: counterparty.publicKey,
+          collectionIndex: escrowIndexAddress(state),
+          stats: statsPDA,
+          vault,
+          dispute: null,
+          disputer: null,
+        })
+        .signers([trader])
+        .rpc();
+
+      // The trader pays the fee, so their refund lands net of it
+      const fee = 5_000;
+      expect((await provider.connection.getBalance(trader.publicKey)) - traderBefore).to.equal(
+        0.9 * LAMPORTS_PER_SOL - fee
+      );
+      expect(
+        (await provider.connection.getBalance(counterparty.publicKey)) - counterpartyBefore
+      ).to.equal(0.3 * LAMPORTS_PER_SOL);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Records a collection's floor history and averages it", async () => {
+    const collectionId = "history-a";
+    const priceHistory = priceHistoryAddress(collectionId);
+    await initPriceHistory(collectionId);
+    const twap = (windowSecs: number) =>
+      program.methods
+        .getTwap(collectionId, windowSecs)
+        .accounts({ priceHistory })
+        .view()
+        .then((price: anchor.BN) => price.toNumber());
+
+    await expectError(twap(60), "InsufficientPriceHistory");
+    await recordPrice(collectionId);
+    await waitUntil((await chainTime()) + 1);
+    await recordPrice(collectionId);
+    // Only the oracle the history was opened on can feed it
+    await expectError(recordPrice(collectionId, Keypair.generate().publicKey), "OracleMismatch");
+
+    const history = await program.account.priceHistory.fetch(priceHistory);
+    expect(history.samples).to.have.lengthOf(2);
+    expect(history.samples[1].timestamp.toNumber()).to.be.above(
+      history.samples[0].timestamp.toNumber()
+    );
+    await waitUntil((await chainTime()) + 1);
+    expect(await twap(60)).to.equal(10 * LAMPORTS_PER_SOL);
+  });
+
+  it("Settles a TWAP escrow on the averaged floor from its price history", async () => {
+    const collectionId = "history-b";
+    const priceHistory = priceHistoryAddress(collectionId);
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const twap = { twap: { windowSecs: 30 } };
+    await expectError(
+      initEscrow(trader, { collectionId, settlementPrice: { twap: { windowSecs: 0 } } }),
+      "InvalidSettlementPrice"
+    );
+    await expectError(
+      initEscrow(trader, { collectionId, settlementPrice: twap, basket: ["history-c"] }),
+      "InvalidSettlementPrice"
+    );
+
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, { collectionId, expiry, settlementPrice: twap });
+    await accept(counterparty, escrow);
+    expect((await program.account.escrowState.fetch(escrow)).settlementPrice).to.deep.equal(twap);
+    await waitUntil(expiry);
+
+    const settleTwap = (history: PublicKey | null) =>
+      settle(escrow, trader.publicKey, provider.wallet.payer, {
+        collectionId,
+        priceHistory: history,
+      });
+    await expectError(settleTwap(null), "MissingPriceHistory");
+    await initPriceHistory(collectionId);
+    // Opted in, but nothing recorded yet
+    await expectError(settleTwap(priceHistory), "InsufficientPriceHistory");
+
+    await recordPrice(collectionId);
+    await waitUntil((await chainTime()) + 1);
+    await settleTwap(priceHistory);
+    const record = await program.account.settlementRecord.fetch(
+      settlementRecordAddress(escrow)
+    );
+    // The mock oracle's floor never moves, so the average is the spot price
+    expect(record.price.toNumber()).to.equal(10 * LAMPORTS_PER_SOL);
+    expect(record.outcome).to.deep.equal({ trader: {} });
+  });
+
+  it("Holds a thinly sampled TWAP escrow back until enough prices are in", async () => {
+    await setConfig({ minPriceSamples: 2 });
+    try {
+      const collectionId = "history-d";
+      const trader = Keypair.generate();
+      const counterparty = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+      await initPriceHistory(collectionId);
+
+      const expiry = (await chainTime()) + 4;
+      const escrow = await initEscrow(trader, {
+        collectionId,
+        expiry,
+        settlementPrice: { twap: { windowSecs: 60 } },
+      });
+      await accept(counterparty, escrow);
+      await recordPrice(collectionId);
+      await waitUntil(expiry);
+
+      const settleTwap = () =>
+        settle(escrow, trader.publicKey, provider.wallet.payer, {
+          collectionId,
+          priceHistory: priceHistoryAddress(collectionId),
+        });
+      await expectError(settleTwap(), "TooFewPriceSamples");
+
+      await recordPrice(collectionId);
+      await waitUntil((await chainTime()) + 1);
+      await settleTwap();
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        settled: {},
+      });
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Hands the admin role over only once the new admin accepts", async () => {
+    const newAdmin = Keypair.generate();
+    const outsider = Keypair.generate();
+    await airdrop(newAdmin.publicKey, LAMPORTS_PER_SOL);
+    await airdrop(outsider.publicKey, LAMPORTS_PER_SOL);
+    const propose = (admin: Keypair, proposed: PublicKey | null) =>
+      program.methods
+        .proposeAdmin(proposed)
+        .accounts({ admin: admin.publicKey, config: configPDA })
+        .signers([admin])
+        .rpc();
+    const acceptAdmin = (signer: Keypair) =>
+      program.methods
+        .acceptAdmin()
+        .accounts({ newAdmin: signer.publicKey, config: configPDA })
+        .signers([signer])
+        .rpc();
+
+    await propose(provider.wallet.payer, newAdmin.publicKey);
+    let config = await program.account.config.fetch(configPDA);
+    expect(config.pendingAdmin.equals(newAdmin.publicKey)).to.be.true;
+    expect(config.admin.equals(provider.wallet.publicKey)).to.be.true;
+
+    // Until they accept, the proposed admin has no say and nobody else can
+    // take the offer
+    await expectError(acceptAdmin(outsider), "NotPendingAdmin");
+    await expectError(
+      program.methods
+        .updateConfig(DEFAULT_CONFIG)
+        .accounts({ admin: newAdmin.publicKey, config: configPDA })
+        .signers([newAdmin])
+        .rpc(),
+      "Unauthorized"
+    );
+    await setConfig({ feeBps: 10 });
+
+    await acceptAdmin(newAdmin);
+    try {
+      config = await program.account.config.fetch(configPDA);
+      expect(config.admin.equals(newAdmin.publicKey)).to.be.true;
+      expect(config.pendingAdmin).to.be.null;
+      await expectError(setConfig(), "Unauthorized");
+      // The offer was used up
+      await expectError(acceptAdmin(newAdmin), "NotPendingAdmin");
+    } finally {
+      await propose(newAdmin, provider.wallet.publicKey);
+      await acceptAdmin(provider.wallet.payer);
+    }
+  });
+
+  it("Refuses to settle on a Pyth price published too recently", async () => {
+    // No window would be left between too fresh and stale
+    await expectError(setConfig({ maxPriceAgeSecs: 60, minUpdateAgeSecs: 60 }), "InvalidConfig");
+
+    // The fixture update is old, so only an enormous minimum makes it too fresh
+    await setConfig({ minUpdateAgeSecs: 4_000_000_000 });
+    try {
+      const { trader, escrow, expiry } = await openAcceptedEscrow(4, {
+        priceSources: [{ pyth: {} }],
+      });
+      await waitUntil(expiry);
+      const settlePyth = () =>
+        settle(escrow, trader.publicKey, provider.wallet.payer, {
+          pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+        });
+      await expectError(settlePyth(), "PriceTooFresh");
+
+      await setConfig({ minUpdateAgeSecs: 60 });
+      await settlePyth();
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        settled: {},
+      });
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Quotes the address and lamports an escrow takes before it's opened", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    const nonce = await nextNonce(trader.publicKey);
+    const margin = 0.4 * LAMPORTS_PER_SOL;
+    const funding = await program.methods
+      .getEscrowFunding(trader.publicKey, new anchor.BN(nonce), new anchor.BN(margin))
+      .accounts({})
+      .view();
+
+    const escrow = await initEscrow(trader, { margin, nonce });
+    expect(escrow.equals(funding.escrow)).to.be.true;
+    const opened = await provider.connection.getAccountInfo(escrow);
+    const rent = await provider.connection.getMinimumBalanceForRentExemption(opened.data.length);
+    expect(funding.rent.toNumber()).to.equal(rent);
+    expect(funding.requiredLamports.toNumber()).to.equal(rent + margin);
+    expect(opened.lamports).to.equal(funding.requiredLamports.toNumber());
+  });
+
+  it("Rejects an accept signed against the margin before a top-up", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 3 * LAMPORTS_PER_SOL);
+
+    const signedFor = 0.5 * LAMPORTS_PER_SOL;
+    const escrow = await initEscrow(trader, { margin: signedFor });
+    // The trader tops up before the counterparty's accept lands
+    await program.methods
+      .increaseMargin(new anchor.BN(0.25 * LAMPORTS_PER_SOL))
+      .accounts({
+        trader: trader.publicKey,
+        escrow,
+        config: configPDA,
+        stats: statsPDA,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([trader])
+      .rpc();
+
+    await expectError(
+      accept(counterparty, escrow, null, null, null, null, signedFor),
+      "MarginMismatch"
+    );
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({ open: {} });
+
+    await accept(counterparty, escrow, null, null, null, null, 0.75 * LAMPORTS_PER_SOL);
+    expect(
+      (await program.account.escrowState.fetch(escrow)).counterpartyDeposit.toNumber()
+    ).to.equal(0.75 * LAMPORTS_PER_SOL);
+  });
+
+  it("Only pays winnings to allowlisted destinations once the list is set", async () => {
+    const vetted = await openAcceptedEscrow(4);
+    const unvetted = await openAcceptedEscrow(4);
+    const unrestricted = await openAcceptedEscrow(4);
+    await waitUntil(Math.max(vetted.expiry, unvetted.expiry, unrestricted.expiry));
+
+    await setConfig({ payoutAllowlist: [vetted.trader.publicKey] });
+    try {
+      await expectError(
+        settle(unvetted.escrow, unvetted.trader.publicKey, provider.wallet.payer),
+        "PayoutDestinationNotAllowed"
+      );
+      await settle(vetted.escrow, vetted.trader.publicKey, provider.wallet.payer);
+    } finally {
+      await setConfig();
+    }
+
+    // Empty list: winnings go wherever they're owed
+    await settle(unrestricted.escrow, unrestricted.trader.publicKey, provider.wallet.payer);
+    for (const { escrow } of [vetted, unrestricted]) {
+      expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+        settled: {},
+      });
+    }
+  });
+
+  it("Checks the other side's share of a split against the allowlist too", async () => {
+    // Either side misses the oracle's 10 SOL by 1 SOL
+    const { trader, counterparty, escrow, expiry } = await openAcceptedEscrow(4, {
+      predictedFloor: 9 * LAMPORTS_PER_SOL,
+      counterpartyPrediction: 11 * LAMPORTS_PER_SOL,
+    });
+    await waitUntil(expiry);
+    const split = () =>
+      settle(escrow, trader.publicKey, provider.wallet.payer, {
+        counterparty: counterparty.publicKey,
+      });
+
+    await setConfig({ payoutAllowlist: [trader.publicKey] });
+    try {
+      await expectError(split(), "PayoutDestinationNotAllowed");
+      await setConfig({ payoutAllowlist: [trader.publicKey, counterparty.publicKey] });
+      await split();
+    } finally {
+      await setConfig();
+    }
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Credits several wins to a balance and withdraws them at once", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+    const [balance] = PublicKey.findProgramAddressSync(
+      [Buffer.from("balance"), trader.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .openBalance()
+      .accounts({
+        owner: trader.publicKey,
+        balance,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([trader])
+      .rpc();
+
+    const expiry = (await chainTime()) + 4;
+    const escrows: PublicKey[] = [];
+    for (let i = 0; i < 2; i++) {
+      const counterparty = Keypair.generate();
+      await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+      const escrow = await initEscrow(trader, { expiry });
+      await accept(counterparty, escrow);
+      escrows.push(escrow);
+    }
+    await waitUntil(expiry);
+
+    const traderBefore = await provider.connection.getBalance(trader.publicKey);
+    const balanceRent = await provider.connection.getBalance(balance);
+    let won = 0;
+    for (const escrow of escrows) {
+      await settle(escrow, trader.publicKey, provider.wallet.payer, { winnerBalance: balance });
+      const record = await program.account.settlementRecord.fetch(
+        settlementRecordAddress(escrow)
+      );
+      won += record.traderPayout.toNumber();
+    }
+    // Nothing reached the wallet yet; it all sits in the balance
+    expect(await provider.connection.getBalance(trader.publicKey)).to.equal(traderBefore);
+    expect((await program.account.balance.fetch(balance)).amount.toNumber()).to.equal(won);
+    expect(await provider.connection.getBalance(balance)).to.equal(balanceRent + won);
+
+    await program.methods
+      .withdrawBalance()
+      .accounts({ owner: trader.publicKey, balance })
+      .signers([trader])
+      .rpc();
+    const fee = 5_000;
+    expect(await provider.connection.getBalance(trader.publicKey)).to.equal(
+      traderBefore + won - fee
+    );
+    expect((await program.account.balance.fetch(balance)).amount.toNumber()).to.equal(0);
+    expect(await provider.connection.getBalance(balance)).to.equal(balanceRent);
+  });
+
+  describe("self-trade", () => {
+    it("Rejects a trader accepting their own escrow", async () => {
+      const trader = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      const escrow = await initEscrow(trader);
+      await expectError(accept(trader, escrow), "SelfTrade");
+      await cancel(trader, escrow);
+    });
+
+    it("Rejects a trader accepting their own wrapped SOL escrow", async () => {
+      const trader = Keypair.generate();
+      await airdrop(trader.publicKey, 3 * LAMPORTS_PER_SOL);
+      const wsol = await createWrappedNativeAccount(
+        provider.connection,
+        provider.wallet.payer,
+        trader.publicKey,
+        2 * LAMPORTS_PER_SOL
+      );
+      const escrow = await initEscrow(trader, { wsolSource: wsol });
+      await expectError(accept(trader, escrow, null, null, null, wsol), "SelfTrade");
+    });
+
+    it("Rejects the market maker accepting its own escrow", async () => {
+      const maker = Keypair.generate();
+      await airdrop(maker.publicKey, 3 * LAMPORTS_PER_SOL);
+      await setConfig({
+        marketMaker: maker.publicKey,
+        mmMaxMargin: new anchor.BN(LAMPORTS_PER_SOL),
+        mmCollections: [COLLECTION_SLUG],
+      });
+      try {
+        const escrow = await initEscrow(maker);
+        await expectError(
+          program.methods
+            .mmAccept(null, null, new anchor.BN(0.5 * LAMPORTS_PER_SOL))
+            .accounts({
+              trader: maker.publicKey,
+              escrow,
+              config: configPDA,
+              collectionState: collectionStateAddress(collectionHash(COLLECTION_SLUG)),
+              stats: statsPDA,
+              vault,
+              rewardMint: null,
+              makerRewardAccount: null,
+              rewardAuthority: null,
+              tokenProgram: null,
+              tensorOracle: null,
+              pythPriceUpdate: null,
+              systemProgram: anchor.web3.SystemProgram.programId,
+            })
+            .signers([maker])
+            .rpc(),
+          "SelfTrade"
+        );
+        await cancel(maker, escrow);
+      } finally {
+        await setConfig();
+      }
+    });
+  });
+
+  it("Settles a rank prediction on the collection's place in its basket", async () => {
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+    const rank = { rank: {} };
+    const basket = ["rank-member-a", "rank-member-b"];
+
+    await expectError(
+      initEscrow(trader, { predictionUnit: rank, predictedFloor: 1, tolerance: 0 }),
+      "InvalidRankPrediction"
+    );
+    await expectError(
+      initEscrow(trader, { predictionUnit: rank, basket, predictedFloor: 4, tolerance: 0 }),
+      "InvalidRankPrediction"
+    );
+
+    // The mock oracle ties every collection at 10 SOL, so the primary
+    // shares first place
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, {
+      predictionUnit: rank,
+      basket,
+      expiry,
+      predictedFloor: 1,
+      tolerance: 0,
+    });
+    await expectError(accept(counterparty, escrow, 4), "InvalidRankPrediction");
+    await accept(counterparty, escrow, 3);
+
+    await waitUntil(expiry);
+    await settle(escrow, trader.publicKey, provider.wallet.payer, { basketIds: basket });
+    const record = await program.account.settlementRecord.fetch(
+      settlementRecordAddress(escrow)
+    );
+    expect(record.outcome).to.deep.equal({ trader: {} });
+    expect(record.price.toNumber()).to.equal(1);
+  });
+
+  it("Rejects an escrow with no margin", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, LAMPORTS_PER_SOL);
+    await expectError(initEscrow(trader, { margin: 0 }), "MarginTooSmall");
+  });
+
+  it("Closes the escrow to its trader when settling under closeOnSettle", async () => {
+    await setConfig({ closeOnSettle: true });
+    try {
+      // Trader calls the mock oracle's 10 SOL floor, so wins
+      const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(
+        (await provider.connection.getAccountInfo(escrow)).data.length
+      );
+      const traderBefore = await provider.connection.getBalance(trader.publicKey);
+      await waitUntil(expiry);
+      await settle(escrow, trader.publicKey, provider.wallet.payer);
+
+      expect(await provider.connection.getAccountInfo(escrow)).to.be.null;
+      const record = await program.account.settlementRecord.fetch(
+        settlementRecordAddress(escrow)
+      );
+      expect(await provider.connection.getBalance(trader.publicKey)).to.equal(
+        traderBefore + record.traderPayout.toNumber() + rent
+      );
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Charges no more than the fee the trader accepted at init", async () => {
+    const treasury = Keypair.generate();
+    await airdrop(treasury.publicKey, LAMPORTS_PER_SOL);
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 2 * LAMPORTS_PER_SOL);
+
+    const margin = 0.5 * LAMPORTS_PER_SOL;
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, { expiry, margin, maxFeeBps: 200 });
+    await accept(counterparty, escrow);
+
+    // The fee goes up while the escrow is in flight
+    await setConfig({ feeBps: 500, treasury: treasury.publicKey });
+    try {
+      await waitUntil(expiry);
+      const treasuryBefore = await provider.connection.getBalance(treasury.publicKey);
+      await settle(escrow, trader.publicKey, provider.wallet.payer, {
+        treasury: treasury.publicKey,
+      });
+
+      const sideFee = (margin * 200) / 10_000;
+      const record = await program.account.settlementRecord.fetch(
+        settlementRecordAddress(escrow)
+      );
+      expect(record.treasuryFee.toNumber()).to.equal(2 * sideFee);
+      expect((await provider.connection.getBalance(treasury.publicKey)) - treasuryBefore).to.equal(
+        2 * sideFee
+      );
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Rejects the arbiter accepting an escrow it could rule on", async () => {
+    const arbiter = Keypair.generate();
+    await airdrop(arbiter.publicKey, 2 * LAMPORTS_PER_SOL);
+    await setConfig({ arbiter: arbiter.publicKey });
+    try {
+      const trader = Keypair.generate();
+      await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+      const escrow = await initEscrow(trader);
+      await expectError(accept(arbiter, escrow), "ConflictOfInterest");
+      await cancel(trader, escrow);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Refuses to settle on an oracle price outside the settler's range", async () => {
+    // The mock oracle prints 10 SOL
+    const { trader, escrow, expiry } = await openAcceptedEscrow(4);
+    await waitUntil(expiry);
+    await expectError(
+      settle(escrow, trader.publicKey, provider.wallet.payer, {
+        acceptablePriceRange: { min: 11 * LAMPORTS_PER_SOL, max: 12 * LAMPORTS_PER_SOL },
+      }),
+      "OraclePriceOutOfExpectedRange"
+    );
+    await settle(escrow, trader.publicKey, provider.wallet.payer, {
+      acceptablePriceRange: { min: 9 * LAMPORTS_PER_SOL, max: 10 * LAMPORTS_PER_SOL },
+    });
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Pauses one collection while others keep trading", async () => {
+    const paused = "paused-collection";
+    const active = "active-collection";
+    const setPaused = (value: boolean) =>
+      program.methods
+        .setCollectionPaused(paused, value)
+        .accounts({
+          admin: provider.wallet.publicKey,
+          config: configPDA,
+          collectionState: collectionStateAddress(collectionHash(paused)),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    const trader = Keypair.generate();
+    const counterparty = Keypair.generate();
+    await airdrop(trader.publicKey, 5 * LAMPORTS_PER_SOL);
+    await airdrop(counterparty.publicKey, 5 * LAMPORTS_PER_SOL);
+    const expiry = (await chainTime()) + 6;
+    const running = await initEscrow(trader, { collectionId: paused, expiry });
+    await accept(counterparty, running);
+    const waiting = await initEscrow(trader, { collectionId: paused });
+
+    await setPaused(true);
+    try {
+      await expectError(initEscrow(trader, { collectionId: paused }), "CollectionPaused");
+      await expectError(accept(counterparty, waiting), "CollectionPaused");
+      // Other collections are untouched
+      await accept(counterparty, await initEscrow(trader, { collectionId: active }));
+
+      // An escrow already running on the paused collection still settles
+      await waitUntil(expiry);
+      await settle(running, trader.publicKey, provider.wallet.payer, { collectionId: paused });
+      expect((await program.account.escrowState.fetch(running)).status).to.deep.equal({
+        settled: {},
+      });
+    } finally {
+      await setPaused(false);
+    }
+    await accept(counterparty, waiting);
+  });
+
+  it("Only settles Pyth escrows on their own feed", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, LAMPORTS_PER_SOL);
+    await expectError(
+      initEscrow(trader, { priceSources: [{ pyth: {} }], pythFeedId: null }),
+      "InvalidPriceSources"
+    );
+
+    const { escrow, trader: winner, expiry } = await openAcceptedEscrow(4, {
+      priceSources: [{ pyth: {} }],
+    });
+    await waitUntil(expiry);
+    // A verified update, but for another feed
+    await expectError(
+      settle(escrow, winner.publicKey, provider.wallet.payer, {
+        pythPriceUpdate: PYTH_WIDE_CONFIDENCE,
+      }),
+      "OracleMismatch"
+    );
+    await settle(escrow, winner.publicKey, provider.wallet.payer, {
+      pythPriceUpdate: PYTH_TIGHT_CONFIDENCE,
+    });
+    expect((await program.account.escrowState.fetch(escrow)).status).to.deep.equal({
+      settled: {},
+    });
+  });
+
+  it("Rejects a fee cap below the fee in force", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    await setConfig({ feeBps: 200 });
+    try {
+      await expectError(initEscrow(trader, { maxFeeBps: 0 }), "FeeCapBelowCurrentFee");
+      await expectError(initEscrow(trader, { maxFeeBps: 199 }), "FeeCapBelowCurrentFee");
+      const escrow = await initEscrow(trader, { maxFeeBps: 200 });
+      expect((await program.account.escrowState.fetch(escrow)).maxFeeBps).to.equal(200);
+    } finally {
+      await setConfig();
+    }
+  });
+
+  it("Rejects margin top-ups once the escrow has expired", async () => {
+    const trader = Keypair.generate();
+    await airdrop(trader.publicKey, 2 * LAMPORTS_PER_SOL);
+    const expiry = (await chainTime()) + 4;
+    const escrow = await initEscrow(trader, { expiry });
+    await waitUntil(expiry);
+
+    await expectError(
+      program.methods
+        .increaseMargin(new anchor.BN(0.1 * LAMPORTS_PER_SOL))
+        .accounts({
+          trader: trader.publicKey,
+          escrow,
+          traderProfile: profileAddress(trader.publicKey),
+          config: configPDA,
+          stats: statsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([trader])
+        .rpc(),
+      "Expired"
+    );
+    expect((await program.account.escrowState.fetch(escrow)).marginAmount.toNumber()).to.equal(
+      0.5 * LAMPORTS_PER_SOL
+    );
+  });
+
+  // Runs last: once the registry exists, every collection opened must be
+  // registered in it
+  describe("oracle registry", () => {
+    it("Resolves each collection's oracle through the registry", async () => {
+      const setOracle = (collectionId: string, oracle: PublicKey | null) =>
+        program.methods
+          .setOracle(collectionId, oracle)
+          .accounts({
+            admin: provider.wallet.publicKey,
+            config: configPDA,
+            oracleRegistry: oracleRegistryPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+      const [first, second] = [Keypair.generate().publicKey, Keypair.generate().publicKey];
+      await setOracle("registry-a", first);
+      await setOracle("registry-b", second);
+
+      const trader = Keypair.generate();
+      await airdrop(trader.publicKey, 4 * LAMPORTS_PER_SOL);
+      const open = (collectionId: string, tensorOracle: PublicKey) =>
+        initEscrow(trader, { collectionId, tensorOracle });
+
+      const a = await open("registry-a", first);
+      const b = await open("registry-b", second);
+      expect((await program.account.escrowState.fetch(a)).tensorOracle.equals(first)).to.be.true;
+      expect((await program.account.escrowState.fetch(b)).tensorOracle.equals(second)).to.be.true;
+
+      await expectError(open("registry-b", first), "OracleMismatch");
+      await expectError(open("registry-c", first), "UnregisteredCollection");
+      // Passing some other account in the registry's place doesn't skip it
+      await expectError(
+        initEscrow(trader, {
+          collectionId: "registry-c",
+          tensorOracle: first,
+          oracleRegistry: Keypair.generate().publicKey,
+        }),
+        "ConstraintSeeds"
+      );
+
+      // Dropping a mapping leaves the collection unresolvable
+      await setOracle("registry-a", null);
+      await expectError(open("registry-a", first), "UnregisteredCollection");
+    });
+  });
 });